@@ -41,6 +41,7 @@ After=network-online.target
 Type=oneshot
 User=www-data
 Group=www-data
+SuccessExitStatus=3
 EnvironmentFile=-/etc/default/cityfeed-puller
 ExecStart=/usr/local/bin/cityfeed-puller --origin ${ORIGIN}
 EOF
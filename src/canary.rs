@@ -0,0 +1,77 @@
+//! `--canary-url`: after `current` moves, ask the local web server for a few
+//! pages and roll the switch back unless every one answers 2xx. This catches
+//! a snapshot the server can't actually serve (a missing index, unreadable
+//! files) before anyone else sees it.
+
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use reqwest::blocking::Client;
+use serde_json::json;
+
+use crate::logger::Logger;
+
+#[derive(Clone, Debug)]
+pub struct Canary {
+    pub urls: Vec<String>,
+    /// Limit for each request, body included.
+    pub timeout: Duration,
+    /// Wait after the switch, for servers that cache file handles.
+    pub grace: Duration,
+}
+
+impl Canary {
+    /// Fetches every URL and fails naming each one that didn't answer 2xx.
+    /// Nothing to check passes.
+    pub fn check(&self, log: &Logger) -> Result<()> {
+        if self.urls.is_empty() {
+            return Ok(());
+        }
+        thread::sleep(self.grace);
+        // Straight to the local server: no proxy, no origin settings.
+        let client = Client::builder()
+            .timeout(self.timeout)
+            .no_proxy()
+            .build()
+            .context("build canary http client")?;
+        let mut failures = Vec::new();
+        for url in &self.urls {
+            match fetch(&client, url) {
+                Ok(status) => log.info(
+                    "canary_ok",
+                    json!({ "url": url, "status": status }),
+                    format_args!("canary {url}: {status}"),
+                ),
+                Err(err) => {
+                    log.warn(
+                        "canary_failed",
+                        json!({ "url": url, "error": format!("{err:#}") }),
+                        format_args!("canary {url}: {err:#}"),
+                    );
+                    failures.push(format!("{url}: {err:#}"));
+                }
+            }
+        }
+        if !failures.is_empty() {
+            bail!(
+                "{} of {} canary URLs failed: {}",
+                failures.len(),
+                self.urls.len(),
+                failures.join("; ")
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The status of a 2xx answer, with its body read to the end.
+fn fetch(client: &Client, url: &str) -> Result<u16> {
+    let resp = client.get(url).send()?;
+    let status = resp.status();
+    if !status.is_success() {
+        return Err(anyhow!("HTTP {status}"));
+    }
+    resp.bytes().context("read body")?;
+    Ok(status.as_u16())
+}
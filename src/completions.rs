@@ -0,0 +1,69 @@
+//! `cityfeed-puller completions <shell>`: prints a completion script for
+//! every subcommand and flag, for provisioning to drop into the shell's
+//! completion directory.
+
+use std::io::{self, Write};
+
+use clap::CommandFactory;
+use clap_complete::Shell;
+
+use crate::{Args, EXIT_FAILURE, EXIT_UPDATED};
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct CompletionsArgs {
+    /// Shell to generate the script for.
+    #[arg(value_enum)]
+    pub shell: Shell,
+}
+
+/// The completion script for `shell`.
+fn script(shell: Shell) -> Vec<u8> {
+    let mut cmd = Args::command();
+    let name = cmd.get_name().to_string();
+    let mut out = Vec::new();
+    clap_complete::generate(shell, &mut cmd, name, &mut out);
+    out
+}
+
+/// Prints the script to stdout; returns the exit code.
+pub fn main(args: &CompletionsArgs) -> i32 {
+    let mut stdout = io::stdout().lock();
+    match stdout.write_all(&script(args.shell)) {
+        Ok(()) => EXIT_UPDATED,
+        Err(err) => {
+            eprintln!("error: write completions: {err}");
+            EXIT_FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bash_script_covers_subcommands_and_flags() {
+        let bash = String::from_utf8(script(Shell::Bash)).unwrap();
+        assert!(bash.contains("complete -F _cityfeed-puller"), "{bash}");
+        for word in [
+            "--origin",
+            "--root",
+            "--keep-days",
+            "--on-switch",
+            "--header",
+            "--manifest-sha256",
+            "fsck",
+            "promote",
+            "completions",
+        ] {
+            assert!(bash.contains(word), "{word} missing");
+        }
+        // Subcommand flags too.
+        assert!(bash.contains("--bind"));
+
+        for shell in [Shell::Zsh, Shell::Fish] {
+            let text = String::from_utf8(script(shell)).unwrap();
+            assert!(text.contains("keep-days"), "{shell}");
+        }
+    }
+}
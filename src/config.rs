@@ -0,0 +1,511 @@
+//! `--config puller.toml`: the same knobs as the command line, so unit files
+//! can stay a one-line `ExecStart`. Keys are the flag names in snake_case
+//! (`connect_timeout`, `on_switch`, ...); `origins`, `on_switch`,
+//! `notify_urls`, `query_auth` and `resolve` take arrays, and `root` takes
+//! either a path or an array of them.
+//!
+//! `[auth."<origin>"]` tables give one origin its own `token` or `basic`
+//! credentials, overriding the top-level `auth_token`/`auth_basic`.
+//! `[origin_headers."<origin>"]` tables map header names to values for one
+//! origin, replacing same-named `headers` entries.
+//!
+//! `[[site]]` blocks deploy several sites in one run. Each needs its own
+//! `root` and may set `origins`; every other key applies to all sites.
+//!
+//! The file is turned back into flags and parsed by clap together with the
+//! real command line, so values are validated exactly like their flags. A key
+//! whose flag was also given on the command line is ignored: flags win.
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, Command};
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{self, Auth};
+use crate::headers::Header;
+
+/// A duration (`"10s"`) or byte count (`"500M"`); a bare integer means
+/// seconds or bytes, as on the command line.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+enum Amount {
+    Int(u64),
+    Text(String),
+}
+
+/// `root = "/srv/a"` or, for a multi-root deploy, `root = ["/srv/a", "/srv/b"]`.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+enum Roots {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// One `[[site]]` block.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Site {
+    /// Label for logs and the summary; the root path if unset.
+    pub name: Option<String>,
+    /// Falls back to the top-level `origins` (or `--origin`) when empty.
+    #[serde(default)]
+    pub origins: Vec<String>,
+    pub root: PathBuf,
+}
+
+impl Site {
+    pub fn label(&self) -> String {
+        self.name
+            .clone()
+            .unwrap_or_else(|| self.root.display().to_string())
+    }
+}
+
+/// One `[auth."<origin>"]` table: exactly one of `token` or `basic`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OriginAuth {
+    token: Option<String>,
+    basic: Option<String>,
+}
+
+impl OriginAuth {
+    fn parse(&self, origin: &str) -> Result<Auth> {
+        match (&self.token, &self.basic) {
+            (Some(token), None) => auth::parse_token(token),
+            (None, Some(basic)) => auth::parse_basic(basic),
+            _ => bail!("set exactly one of `token` or `basic`"),
+        }
+        .with_context(|| format!("[auth.\"{origin}\"]"))
+    }
+}
+
+/// Every settable flag. Unknown keys are rejected with the file position.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    origins: Option<Vec<String>>,
+    channel: Option<String>,
+    manifest_url: Option<String>,
+    manifest_sha256: Option<String>,
+    manifest_pubkey: Option<String>,
+    require_file_signatures: Option<bool>,
+    offline: Option<bool>,
+    manifest_file: Option<PathBuf>,
+    root: Option<Roots>,
+    connect_timeout: Option<Amount>,
+    request_timeout: Option<Amount>,
+    stall_timeout: Option<Amount>,
+    resolve: Option<Vec<String>>,
+    ipv4_only: Option<bool>,
+    ipv6_only: Option<bool>,
+    http2_prior_knowledge: Option<bool>,
+    pool_max_idle_per_host: Option<u64>,
+    tcp_keepalive: Option<Amount>,
+    object_layout: Option<String>,
+    origin_strategy: Option<String>,
+    race_manifest: Option<bool>,
+    manifest_policy: Option<String>,
+    max_retry_after: Option<Amount>,
+    mismatch_retries: Option<u64>,
+    keep_going: Option<bool>,
+    origin_error_budget: Option<u64>,
+    origin_time_budget: Option<Amount>,
+    circuit_cooldown: Option<Amount>,
+    max_rate: Option<Amount>,
+    output: Option<String>,
+    log_format: Option<String>,
+    quiet: Option<bool>,
+    verbose: Option<u8>,
+    watch: Option<bool>,
+    interval: Option<Amount>,
+    prefetch_only: Option<bool>,
+    on_switch: Option<Vec<String>>,
+    hook_failure: Option<String>,
+    canary_urls: Option<Vec<String>>,
+    canary_timeout: Option<Amount>,
+    canary_grace: Option<Amount>,
+    notify_urls: Option<Vec<String>>,
+    notify_on: Option<String>,
+    metrics_textfile: Option<String>,
+    #[cfg(feature = "otlp")]
+    otlp_endpoint: Option<String>,
+    min_free_bytes: Option<Amount>,
+    max_total_bytes: Option<Amount>,
+    max_file_count: Option<u64>,
+    min_files: Option<u64>,
+    min_total_bytes: Option<Amount>,
+    allow_small_manifest: Option<bool>,
+    force_current: Option<bool>,
+    allow_downgrade: Option<bool>,
+    strict_version: Option<bool>,
+    allow_case_collisions: Option<bool>,
+    verify_on_stage: Option<bool>,
+    copy_strategy: Option<String>,
+    preserve: Option<Vec<String>>,
+    prefix: Option<String>,
+    only: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+    path_portability: Option<String>,
+    max_path_component_len: Option<u64>,
+    max_path_len: Option<u64>,
+    file_mode: Option<String>,
+    dir_mode: Option<String>,
+    owner: Option<String>,
+    keep_days: Option<u64>,
+    history_max_entries: Option<u64>,
+    progress: Option<String>,
+    show_diff: Option<bool>,
+    stage_jobs: Option<u64>,
+    hash_jobs: Option<u64>,
+    site_jobs: Option<u64>,
+    auth_token: Option<String>,
+    auth_basic: Option<String>,
+    query_auth: Option<Vec<String>>,
+    query_auth_command: Option<String>,
+    headers: Option<Vec<String>>,
+    #[serde(default, skip_serializing)]
+    site: Vec<Site>,
+    #[serde(default, skip_serializing)]
+    auth: BTreeMap<String, OriginAuth>,
+    #[serde(default, skip_serializing)]
+    origin_headers: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+#[derive(Debug)]
+pub struct Loaded {
+    /// Flags set by the file that the real command line did not set.
+    pub flags: Vec<OsString>,
+    pub sites: Vec<Site>,
+    /// Origin (as written in the file) -> credentials.
+    pub auth: Vec<(String, Auth)>,
+    /// Origin (as written in the file) -> its own headers.
+    pub headers: Vec<(String, Vec<Header>)>,
+}
+
+pub fn load(path: &Path, cmd: &Command, matches: &ArgMatches) -> Result<Loaded> {
+    let text = fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+    parse(&text, cmd, matches).with_context(|| format!("config {}", path.display()))
+}
+
+fn parse(text: &str, cmd: &Command, matches: &ArgMatches) -> Result<Loaded> {
+    let config: Config = toml::from_str(text)?;
+    let sites = config.site.clone();
+    let auth = config
+        .auth
+        .iter()
+        .map(|(origin, creds)| Ok((origin.clone(), creds.parse(origin)?)))
+        .collect::<Result<_>>()?;
+    let headers = config
+        .origin_headers
+        .iter()
+        .map(|(origin, table)| {
+            let headers = table
+                .iter()
+                .map(|(name, value)| Header::new(name, value))
+                .collect::<Result<_>>()
+                .with_context(|| format!("[origin_headers.\"{origin}\"]"))?;
+            Ok((origin.clone(), headers))
+        })
+        .collect::<Result<_>>()?;
+    Ok(Loaded {
+        flags: to_flags(&config, cmd, matches)?,
+        sites,
+        auth,
+        headers,
+    })
+}
+
+fn to_flags(config: &Config, cmd: &Command, matches: &ArgMatches) -> Result<Vec<OsString>> {
+    let toml::Value::Table(table) = toml::Value::try_from(config)? else {
+        bail!("config did not serialize to a table");
+    };
+
+    let mut flags = Vec::new();
+    for (key, value) in table {
+        if matches.value_source(&key) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+        let arg = cmd
+            .get_arguments()
+            .find(|arg| arg.get_id() == key.as_str())
+            .ok_or_else(|| anyhow!("`{key}` has no matching flag"))?;
+        let flag = format!(
+            "--{}",
+            arg.get_long()
+                .ok_or_else(|| anyhow!("`{key}` has no long flag"))?
+        );
+        let values = match value {
+            toml::Value::Array(items) => items,
+            other => vec![other],
+        };
+        for value in values {
+            match value {
+                toml::Value::Boolean(true) => flags.push(flag.clone().into()),
+                toml::Value::Boolean(false) => {}
+                toml::Value::Integer(n) if matches!(arg.get_action(), ArgAction::Count) => {
+                    flags.extend((0..n).map(|_| OsString::from(&flag)));
+                }
+                toml::Value::Integer(n) => {
+                    flags.extend([flag.clone().into(), n.to_string().into()])
+                }
+                toml::Value::String(s) => flags.extend([flag.clone().into(), s.into()]),
+                other => bail!("`{key}`: unsupported value {other}"),
+            }
+        }
+    }
+    Ok(flags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    const SAMPLE: &str = r#"
+origins = ["https://a.example", "https://b.example"]
+root = "/var/www/site"
+connect_timeout = "5s"
+request_timeout = 300
+stall_timeout = "30s"
+object_layout = "sharded"
+origin_strategy = "round-robin"
+race_manifest = true
+manifest_policy = "newest"
+headers = ["X-Org-Token: abc123", "Accept: application/json"]
+channel = "beta"
+manifest_sha256 = "5e1b5c0a9d4f2e7b8c3a6d1f0e9b8a7c6d5e4f3a2b1c0d9e8f7a6b5c4d3e2f1a"
+manifest_pubkey = "8a88e3dd7409f195fd52db2d3cba5d72ca6709bf1d94121bf3748801b40f6f5c"
+require_file_signatures = true
+offline = false
+max_retry_after = "2m"
+mismatch_retries = 2
+keep_going = true
+origin_error_budget = 20
+origin_time_budget = "2m"
+circuit_cooldown = "90s"
+max_rate = "10M"
+output = "json"
+log_format = "json"
+quiet = false
+verbose = 2
+watch = true
+interval = "5m"
+prefetch_only = false
+on_switch = ["systemctl reload nginx", "touch /run/deployed"]
+hook_failure = "fail"
+canary_urls = ["http://127.0.0.1/", "http://127.0.0.1/brief/"]
+canary_timeout = "5s"
+canary_grace = "2s"
+notify_urls = ["https://hooks.example/x"]
+notify_on = "always"
+min_free_bytes = "500M"
+max_total_bytes = "20G"
+max_file_count = 50000
+min_files = 100
+min_total_bytes = "1M"
+allow_small_manifest = false
+force_current = false
+allow_downgrade = true
+strict_version = true
+allow_case_collisions = true
+verify_on_stage = true
+copy_strategy = "hardlink"
+preserve = ["robots.txt", "venue.json"]
+prefix = "daily"
+only = ["kiosk/**", "assets/**"]
+exclude = ["kiosk/video/**"]
+path_portability = "windows-safe"
+max_path_component_len = 200
+max_path_len = 220
+file_mode = "0640"
+dir_mode = "0750"
+keep_days = 14
+history_max_entries = 500
+show_diff = true
+stage_jobs = 8
+hash_jobs = 6
+site_jobs = 2
+"#;
+
+    fn flags(text: &str, cli: &[&str]) -> Result<Vec<String>> {
+        let cmd = crate::Args::command();
+        let matches = cmd
+            .clone()
+            .try_get_matches_from(["cityfeed-puller", "--config", "x.toml"].iter().chain(cli))
+            .unwrap();
+        Ok(parse(text, &cmd, &matches)?
+            .flags
+            .into_iter()
+            .map(|f| f.into_string().unwrap())
+            .collect())
+    }
+
+    #[test]
+    fn sample_config_sets_every_flag() {
+        let flags = flags(SAMPLE, &[]).unwrap();
+        let joined = flags.join(" ");
+        for expected in [
+            "--origin https://a.example --origin https://b.example",
+            "--root /var/www/site",
+            "--request-timeout 300",
+            "--origin-strategy round-robin",
+            "--race-manifest",
+            "--manifest-policy newest",
+            "--header X-Org-Token: abc123 --header Accept: application/json",
+            "--channel beta",
+            "--manifest-sha256 5e1b5c0a9d4f2e7b8c3a6d1f0e9b8a7c6d5e4f3a2b1c0d9e8f7a6b5c4d3e2f1a",
+            "--manifest-pubkey 8a88e3dd7409f195fd52db2d3cba5d72ca6709bf1d94121bf3748801b40f6f5c",
+            "--require-file-signatures",
+            "--mismatch-retries 2",
+            "--keep-going",
+            "--origin-error-budget 20",
+            "--origin-time-budget 2m",
+            "--circuit-cooldown 90s",
+            "--verbose --verbose",
+            "--on-switch systemctl reload nginx --on-switch touch /run/deployed",
+            "--canary-url http://127.0.0.1/ --canary-url http://127.0.0.1/brief/",
+            "--canary-timeout 5s",
+            "--canary-grace 2s",
+            "--notify-url https://hooks.example/x",
+            "--min-free-bytes 500M",
+            "--max-total-bytes 20G",
+            "--max-file-count 50000",
+            "--min-files 100",
+            "--min-total-bytes 1M",
+            "--allow-downgrade",
+            "--strict-version",
+            "--allow-case-collisions",
+            "--verify-on-stage",
+            "--copy-strategy hardlink",
+            "--preserve robots.txt --preserve venue.json",
+            "--prefix daily",
+            "--exclude kiosk/video/**",
+            "--only kiosk/** --only assets/**",
+            "--path-portability windows-safe",
+            "--max-path-component-len 200",
+            "--max-path-len 220",
+            "--history-max-entries 500",
+            "--show-diff",
+            "--stage-jobs 8",
+            "--hash-jobs 6",
+            "--site-jobs 2",
+        ] {
+            assert!(joined.contains(expected), "{expected:?} not in {joined:?}");
+        }
+        assert!(!flags.iter().any(|f| f == "--quiet"
+            || f == "--force-current"
+            || f == "--allow-small-manifest"
+            || f == "--offline"
+            || f == "--prefetch-only"));
+    }
+
+    #[test]
+    fn shipped_example_parses() {
+        let example = include_str!("../ops/cityfeed/puller.toml.example");
+        let flags = flags(example, &[]).unwrap();
+        assert!(flags.iter().any(|f| f == "--on-switch"));
+    }
+
+    #[test]
+    fn site_blocks_are_returned_separately() {
+        let cmd = crate::Args::command();
+        let matches = cmd
+            .clone()
+            .try_get_matches_from(["cityfeed-puller", "--config", "x.toml"])
+            .unwrap();
+        let text = r#"
+origins = ["https://shared.example"]
+
+[[site]]
+name = "brief"
+root = "/var/www/brief"
+
+[[site]]
+root = "/var/www/events"
+origins = ["https://events.example"]
+"#;
+        let loaded = parse(text, &cmd, &matches).unwrap();
+        assert_eq!(loaded.flags, ["--origin", "https://shared.example"]);
+        assert_eq!(loaded.sites.len(), 2);
+        assert_eq!(loaded.sites[0].label(), "brief");
+        assert!(loaded.sites[0].origins.is_empty());
+        assert_eq!(loaded.sites[1].label(), "/var/www/events");
+        assert_eq!(loaded.sites[1].origins, ["https://events.example"]);
+
+        let err = parse(
+            "[[site]]
+name = \"x\"
+",
+            &cmd,
+            &matches,
+        )
+        .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("missing field `root`"),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn auth_tables_give_origins_their_own_credentials() {
+        let cmd = crate::Args::command();
+        let matches = cmd
+            .clone()
+            .try_get_matches_from(["cityfeed-puller", "--config", "x.toml"])
+            .unwrap();
+        let text = r#"
+origins = ["https://public.example", "https://private.example"]
+auth_token = "shared"
+
+[auth."https://private.example"]
+basic = "kiosk:hunter2"
+"#;
+        let loaded = parse(text, &cmd, &matches).unwrap();
+        assert_eq!(
+            loaded.auth,
+            [(
+                "https://private.example".to_string(),
+                auth::parse_basic("kiosk:hunter2").unwrap()
+            )]
+        );
+        assert!(loaded
+            .flags
+            .windows(2)
+            .any(|w| w[0] == "--auth-token" && w[1] == "shared"));
+
+        let err = parse(
+            "[auth.\"https://x\"]\ntoken = \"a\"\nbasic = \"b:c\"\n",
+            &cmd,
+            &matches,
+        )
+        .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("exactly one of `token` or `basic`"),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn flags_on_the_command_line_are_not_repeated_from_the_file() {
+        let flags = flags(SAMPLE, &["--root", "/srv", "--origin", "https://c.example"]).unwrap();
+        assert!(!flags.iter().any(|f| f == "--root" || f == "--origin"));
+        assert!(flags.iter().any(|f| f == "--connect-timeout"));
+    }
+
+    #[test]
+    fn unknown_keys_and_bad_types_point_at_the_line() {
+        let err = flags("root = \"/srv\"\nkeep_snapshot = 3\n", &[]).unwrap_err();
+        let msg = format!("{err:#}");
+        assert!(msg.contains("line 2"), "{msg}");
+        assert!(msg.contains("unknown field `keep_snapshot`"), "{msg}");
+
+        let err = flags("watch = \"yes\"\n", &[]).unwrap_err();
+        assert!(format!("{err:#}").contains("line 1"), "{err:#}");
+    }
+}
@@ -0,0 +1,432 @@
+//! `--copy-strategy`: how staging puts an object's bytes into a snapshot.
+//!
+//! `reflink` clones the object (`FICLONE`, Linux on btrfs, XFS and the like):
+//! the snapshot file shares the object's blocks until either is written.
+//! `hardlink` links the object itself into the snapshot, so both are the
+//! same inode. `copy` writes the bytes out, which works everywhere.
+//!
+//! `auto` tries them in that order. The first file a strategy can't handle
+//! on this filesystem moves the rest of the run on to the next one, and the
+//! step is logged and reported in the run's stats. An explicit strategy the
+//! filesystem can't do fails the deploy instead.
+//!
+//! A hard link shares the object's permission bits and owner, so a file
+//! whose mode or `--owner` differs from its object's is copied even under
+//! `hardlink`, as is one whose object has run out of links.
+
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::hashing::Hashing;
+use crate::logger::Logger;
+use crate::perms::Perms;
+use crate::{check_digest, copy_file_atomic, fsync_dir, persist_staged};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CopyStrategy {
+    /// Reflink, else hard link, else copy; settled on by the first file.
+    Auto,
+    /// Clone the object's blocks (copy-on-write filesystems only).
+    Reflink,
+    /// Link the object itself into the snapshot.
+    Hardlink,
+    /// Write a full copy of every file.
+    Copy,
+}
+
+/// The order `auto` tries strategies in.
+const CHAIN: [CopyStrategy; 3] = [
+    CopyStrategy::Reflink,
+    CopyStrategy::Hardlink,
+    CopyStrategy::Copy,
+];
+
+/// One step down the `auto` chain.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Downgrade {
+    pub root: String,
+    pub from: CopyStrategy,
+    pub to: CopyStrategy,
+    pub reason: String,
+}
+
+/// Why a strategy could not place a file.
+enum Failed {
+    /// The filesystem can't do it at all; `auto` moves on.
+    Unsupported(io::Error),
+    Other(anyhow::Error),
+}
+
+impl From<anyhow::Error> for Failed {
+    fn from(err: anyhow::Error) -> Self {
+        Failed::Other(err)
+    }
+}
+
+/// One root's strategy for the run, shared by the staging threads.
+#[derive(Debug)]
+pub struct Copier {
+    root: String,
+    requested: CopyStrategy,
+    /// Index into `CHAIN` of the strategy in use.
+    current: AtomicUsize,
+    downgrades: Mutex<Vec<Downgrade>>,
+}
+
+impl Copier {
+    pub fn new(root: &Path, requested: CopyStrategy) -> Self {
+        let current = CHAIN.iter().position(|s| *s == requested).unwrap_or(0);
+        Self {
+            root: root.display().to_string(),
+            requested,
+            current: AtomicUsize::new(current),
+            downgrades: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn requested(&self) -> CopyStrategy {
+        self.requested
+    }
+
+    /// The strategy the run is on now.
+    pub fn current(&self) -> CopyStrategy {
+        CHAIN[self.current.load(Ordering::SeqCst)]
+    }
+
+    pub fn downgrades(&self) -> Vec<Downgrade> {
+        self.downgrades.lock().unwrap().clone()
+    }
+
+    /// Settles `auto` before staging by cloning and linking a scratch file
+    /// in `dir`, so the disk space check knows whether staging will copy. A
+    /// probe that fails for any other reason leaves the choice to the first
+    /// file.
+    pub fn probe(&self, log: &Logger, dir: &Path) {
+        if self.requested != CopyStrategy::Auto {
+            return;
+        }
+        let _ = self.place_with(log, |strategy| match strategy {
+            CopyStrategy::Reflink => probe_reflink(dir),
+            CopyStrategy::Hardlink => probe_hardlink(dir),
+            _ => Ok(()),
+        });
+    }
+
+    /// Puts `src`'s bytes at `dst`, which must not exist yet, with `mode`
+    /// and the configured owner. With `verify`, `src` is hashed first and a
+    /// digest other than `verify` fails with a `BodyMismatch`.
+    pub fn place(
+        &self,
+        log: &Logger,
+        src: &Path,
+        dst: &Path,
+        mode: u32,
+        perms: &Perms,
+        verify: Option<&str>,
+    ) -> Result<()> {
+        self.place_with(log, |strategy| match strategy {
+            CopyStrategy::Reflink => reflink_file(src, dst, mode, perms, verify),
+            CopyStrategy::Hardlink => hardlink_file(log, src, dst, mode, perms, verify),
+            _ => Ok(copy_file_atomic(src, dst, mode, perms, verify)?),
+        })
+    }
+
+    fn place_with(
+        &self,
+        log: &Logger,
+        mut attempt: impl FnMut(CopyStrategy) -> Result<(), Failed>,
+    ) -> Result<()> {
+        loop {
+            let index = self.current.load(Ordering::SeqCst);
+            let strategy = CHAIN[index];
+            let err = match attempt(strategy) {
+                Ok(()) => return Ok(()),
+                Err(Failed::Other(err)) => return Err(err),
+                Err(Failed::Unsupported(err)) => err,
+            };
+            let Some(&next) = CHAIN.get(index + 1) else {
+                return Err(err).context("copy into snapshot");
+            };
+            if self.requested != CopyStrategy::Auto {
+                return Err(anyhow!(err)).with_context(|| {
+                    format!(
+                        "--copy-strategy {} is not supported here (try auto)",
+                        name(strategy)
+                    )
+                });
+            }
+            // Another thread may have stepped down already; only the one
+            // that moves `current` records the step.
+            if self
+                .current
+                .compare_exchange(index, index + 1, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                let downgrade = Downgrade {
+                    root: self.root.clone(),
+                    from: strategy,
+                    to: next,
+                    reason: err.to_string(),
+                };
+                log.warn(
+                    "copy_strategy_downgraded",
+                    json!(&downgrade),
+                    format_args!(
+                        "{}: can't {} into snapshots ({err}); using {} for the rest of the run",
+                        self.root,
+                        name(strategy),
+                        name(next)
+                    ),
+                );
+                self.downgrades.lock().unwrap().push(downgrade);
+            }
+        }
+    }
+}
+
+pub fn name(strategy: CopyStrategy) -> &'static str {
+    match strategy {
+        CopyStrategy::Auto => "auto",
+        CopyStrategy::Reflink => "reflink",
+        CopyStrategy::Hardlink => "hardlink",
+        CopyStrategy::Copy => "copy",
+    }
+}
+
+/// Hashes all of `src` against `expected`.
+fn verify_source(src: &Path, expected: &str) -> Result<()> {
+    let file = File::open(src).with_context(|| format!("open {}", src.display()))?;
+    let mut reader = Hashing::new(file);
+    io::copy(&mut reader, &mut io::sink()).with_context(|| format!("read {}", src.display()))?;
+    check_digest(reader, src, expected)
+}
+
+fn reflink_file(
+    src: &Path,
+    dst: &Path,
+    mode: u32,
+    perms: &Perms,
+    verify: Option<&str>,
+) -> Result<(), Failed> {
+    #[cfg(target_os = "linux")]
+    {
+        use rustix::io::Errno;
+
+        let parent = dst
+            .parent()
+            .ok_or_else(|| anyhow!("destination has no parent: {}", dst.display()))?;
+        if let Some(expected) = verify {
+            verify_source(src, expected)?;
+        }
+        let src_f = File::open(src)
+            .with_context(|| format!("open {}", src.display()))
+            .map_err(Failed::Other)?;
+        let tmp = tempfile::NamedTempFile::new_in(parent)
+            .context("create temp snapshot file")
+            .map_err(Failed::Other)?;
+        match rustix::fs::ioctl_ficlone(tmp.as_file(), &src_f) {
+            Ok(()) => {}
+            // Out of space or quota is the same failure for any strategy.
+            Err(err @ (Errno::NOSPC | Errno::DQUOT | Errno::IO)) => {
+                return Err(Failed::Other(
+                    anyhow!(io::Error::from(err)).context(format!("reflink {}", src.display())),
+                ))
+            }
+            Err(err) => return Err(Failed::Unsupported(err.into())),
+        }
+        Ok(persist_staged(tmp, dst, mode, perms)?)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (src, dst, mode, perms, verify);
+        Err(Failed::Unsupported(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "reflinks are only made on Linux",
+        )))
+    }
+}
+
+/// Clones a one-byte scratch file in `dir`.
+fn probe_reflink(dir: &Path) -> Result<(), Failed> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::io::Write;
+
+        let mut src = tempfile::NamedTempFile::new_in(dir)
+            .context("create reflink probe")
+            .map_err(Failed::Other)?;
+        src.write_all(b"x")
+            .context("write reflink probe")
+            .map_err(Failed::Other)?;
+        let dst = tempfile::NamedTempFile::new_in(dir)
+            .context("create reflink probe")
+            .map_err(Failed::Other)?;
+        rustix::fs::ioctl_ficlone(dst.as_file(), src.as_file())
+            .map_err(|err| Failed::Unsupported(err.into()))
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = dir;
+        Err(Failed::Unsupported(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "reflinks are only made on Linux",
+        )))
+    }
+}
+
+/// Hard-links a scratch file in `dir` under a second name.
+fn probe_hardlink(dir: &Path) -> Result<(), Failed> {
+    let src = tempfile::NamedTempFile::new_in(dir).context("create hardlink probe")?;
+    let links = tempfile::tempdir_in(dir).context("create hardlink probe")?;
+    fs::hard_link(src.path(), links.path().join("probe")).map_err(Failed::Unsupported)
+}
+
+fn hardlink_file(
+    log: &Logger,
+    src: &Path,
+    dst: &Path,
+    mode: u32,
+    perms: &Perms,
+    verify: Option<&str>,
+) -> Result<(), Failed> {
+    let parent = dst
+        .parent()
+        .ok_or_else(|| anyhow!("destination has no parent: {}", dst.display()))?;
+    let meta = fs::metadata(src).with_context(|| format!("stat {}", src.display()))?;
+    if !shares_attributes(&meta, mode, perms) {
+        return Ok(copy_file_atomic(src, dst, mode, perms, verify)?);
+    }
+    if let Some(expected) = verify {
+        verify_source(src, expected)?;
+    }
+    match fs::hard_link(src, dst) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::TooManyLinks => {
+            log.debug(
+                "object_link_limit",
+                json!({ "object": src, "error": err.to_string() }),
+                format_args!("can't hard-link {}: {err}; copying it", src.display()),
+            );
+            return Ok(copy_file_atomic(src, dst, mode, perms, None)?);
+        }
+        Err(err)
+            if matches!(
+                err.kind(),
+                io::ErrorKind::CrossesDevices
+                    | io::ErrorKind::PermissionDenied
+                    | io::ErrorKind::Unsupported
+            ) =>
+        {
+            return Err(Failed::Unsupported(err))
+        }
+        Err(err) => {
+            return Err(Failed::Other(anyhow!(err).context(format!(
+                "link {} -> {}",
+                dst.display(),
+                src.display()
+            ))))
+        }
+    }
+    fsync_dir(parent).with_context(|| format!("fsync dir {}", parent.display()))?;
+    Ok(())
+}
+
+/// Whether a link to the object would have the snapshot file's mode and
+/// owner.
+fn shares_attributes(meta: &fs::Metadata, mode: u32, perms: &Perms) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        meta.mode() & crate::MODE_MASK == mode
+            && perms
+                .owner
+                .as_ref()
+                .is_none_or(|owner| owner.uid == meta.uid() && owner.gid == meta.gid())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (meta, mode, perms);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auto_steps_down_the_chain_once() {
+        let copier = Copier::new(Path::new("/srv/site"), CopyStrategy::Auto);
+        let log = Logger::new(crate::logger::LogFormat::Text, -1);
+        let mut tried = Vec::new();
+        let unsupported = || Failed::Unsupported(io::Error::from(io::ErrorKind::Unsupported));
+        copier
+            .place_with(&log, |strategy| {
+                tried.push(strategy);
+                match strategy {
+                    CopyStrategy::Copy => Ok(()),
+                    _ => Err(unsupported()),
+                }
+            })
+            .unwrap();
+        assert_eq!(
+            tried,
+            [
+                CopyStrategy::Reflink,
+                CopyStrategy::Hardlink,
+                CopyStrategy::Copy
+            ]
+        );
+        // Later files start where the first one ended up.
+        tried.clear();
+        copier
+            .place_with(&log, |strategy| {
+                tried.push(strategy);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(tried, [CopyStrategy::Copy]);
+        assert_eq!(copier.current(), CopyStrategy::Copy);
+        let steps: Vec<_> = copier
+            .downgrades()
+            .into_iter()
+            .map(|d| (d.from, d.to))
+            .collect();
+        assert_eq!(
+            steps,
+            [
+                (CopyStrategy::Reflink, CopyStrategy::Hardlink),
+                (CopyStrategy::Hardlink, CopyStrategy::Copy)
+            ]
+        );
+    }
+
+    #[test]
+    fn explicit_strategy_fails_instead_of_degrading() {
+        let copier = Copier::new(Path::new("/srv/site"), CopyStrategy::Reflink);
+        let log = Logger::new(crate::logger::LogFormat::Text, -1);
+        let err = copier
+            .place_with(&log, |_| {
+                Err(Failed::Unsupported(io::Error::from(
+                    io::ErrorKind::Unsupported,
+                )))
+            })
+            .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("--copy-strategy reflink is not supported"),
+            "{err:#}"
+        );
+        assert_eq!(copier.current(), CopyStrategy::Reflink);
+        assert!(copier.downgrades().is_empty());
+    }
+}
@@ -0,0 +1,511 @@
+//! What a deploy changes on this box, worked out by comparing the new
+//! manifest with the one recorded when the previous version was applied.
+//!
+//! Applied manifests are kept in `root/manifests/<version>.json`, in the same
+//! schema as `latest.json` (with variants already expanded into files).
+//!
+//! `cityfeed-puller diff <a> <b>` compares two snapshots on the box the same
+//! way, by content hash. A version without a record is described by hashing
+//! its snapshot instead.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::logger::Logger;
+use crate::promote::from_snapshot;
+use crate::{
+    fsync_dir, validate_rel_path, validate_version, Args, Manifest, OutputFormat, EXIT_FAILURE,
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct DiffCounts {
+    pub added: usize,
+    pub removed: usize,
+    pub modified: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct ManifestDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// Same path, different content (or a file that became a symlink).
+    pub modified: Vec<String>,
+}
+
+impl ManifestDiff {
+    pub fn between(old: &Manifest, new: &Manifest) -> Self {
+        let (old, new) = (entries(old), entries(new));
+        let mut diff = Self::default();
+        for (path, content) in &new {
+            match old.get(path) {
+                None => diff.added.push(path.clone()),
+                Some(prev) if prev != content => diff.modified.push(path.clone()),
+                Some(_) => {}
+            }
+        }
+        diff.removed = old
+            .keys()
+            .filter(|path| !new.contains_key(*path))
+            .cloned()
+            .collect();
+        diff
+    }
+
+    pub fn counts(&self) -> DiffCounts {
+        DiffCounts {
+            added: self.added.len(),
+            removed: self.removed.len(),
+            modified: self.modified.len(),
+        }
+    }
+}
+
+/// Paths that moving from `previous` to `manifest` adds, removes or
+/// modifies, sorted. Without a recorded previous manifest (including on a
+/// first deploy) every path of `manifest` counts as changed.
+pub fn changed_paths(
+    manifests_dir: &Path,
+    previous: Option<&str>,
+    manifest: &Manifest,
+) -> Vec<String> {
+    let old = previous.and_then(|version| load(manifests_dir, version).ok().flatten());
+    let Some(old) = old else {
+        return entries(manifest).into_keys().collect();
+    };
+    let diff = ManifestDiff::between(&old, manifest);
+    let mut paths = [diff.added, diff.removed, diff.modified].concat();
+    paths.sort();
+    paths
+}
+
+/// `path` normalized, so `a/./b` and `a/b` match.
+fn key(path: &str) -> String {
+    validate_rel_path(path)
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| path.to_string())
+}
+
+/// Path -> what it holds.
+fn entries(manifest: &Manifest) -> BTreeMap<String, String> {
+    let files = manifest
+        .files
+        .iter()
+        .map(|f| (key(&f.path), f.hash.clone()));
+    let links = manifest
+        .symlinks
+        .iter()
+        .map(|l| (key(&l.path), format!("-> {}", l.target)));
+    files.chain(links).collect()
+}
+
+/// Where the manifest for `version` is recorded.
+pub fn record_path(manifests_dir: &Path, version: &str) -> PathBuf {
+    manifests_dir.join(format!("{version}.json"))
+}
+
+/// Stores `manifest` as the record for its version.
+pub fn record(manifests_dir: &Path, manifest: &Manifest) -> Result<()> {
+    let path = record_path(manifests_dir, &manifest.version);
+    let mut tmp = tempfile::NamedTempFile::new_in(manifests_dir)
+        .with_context(|| format!("create temp file in {}", manifests_dir.display()))?;
+    serde_json::to_writer_pretty(&mut tmp, &to_json(manifest)).context("write manifest")?;
+    tmp.write_all(b"\n").context("write manifest")?;
+    tmp.as_file().sync_all().context("fsync manifest")?;
+    tmp.persist(&path)
+        .with_context(|| format!("persist {}", path.display()))?;
+    fsync_dir(manifests_dir).context("fsync manifests dir")
+}
+
+/// The manifest recorded for `version`, if there is one.
+pub fn load(manifests_dir: &Path, version: &str) -> Result<Option<Manifest>> {
+    let path = record_path(manifests_dir, version);
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).with_context(|| format!("read {}", path.display())),
+    };
+    let manifest =
+        serde_json::from_str(&text).with_context(|| format!("parse {}", path.display()))?;
+    Ok(Some(manifest))
+}
+
+fn to_json(manifest: &Manifest) -> Value {
+    let files = manifest.files.iter().map(|f| {
+        let mut entry = json!({ "path": &f.path, "hash": &f.hash, "size": f.size });
+        if let Some(mode) = f.mode {
+            entry["mode"] = json!(format!("{mode:04o}"));
+        }
+        if let Some(t) = &f.transfer {
+            entry["transfer"] = json!({ "encoding": &t.encoding, "hash": &t.hash, "size": t.size });
+        }
+        if !f.parts.is_empty() {
+            let parts: Vec<_> = f
+                .parts
+                .iter()
+                .map(|p| json!({ "hash": &p.hash, "size": p.size }))
+                .collect();
+            entry["parts"] = json!(parts);
+        }
+        if let Some(signature) = &f.signature {
+            entry["signature"] = json!(signature);
+        }
+        entry
+    });
+    let links = manifest
+        .symlinks
+        .iter()
+        .map(|l| json!({ "path": &l.path, "symlink": &l.target }));
+    let mut record = json!({
+        "version": &manifest.version,
+        "object_layout": manifest.object_layout,
+        "files": files.chain(links).collect::<Vec<_>>(),
+    });
+    if let Some(sequence) = manifest.sequence {
+        record["sequence"] = json!(sequence);
+    }
+    if let Some(at) = &manifest.generated_at {
+        record["generated_at"] = json!(at);
+    }
+    if let Some(prefix) = &manifest.prefix {
+        record["prefix"] = json!(prefix);
+    }
+    if !manifest.filters.is_empty() {
+        record["filters"] = json!(&manifest.filters);
+    }
+    record
+}
+
+/// Logs the counts (and with `show_all` every path) of what moving from
+/// `previous` to `manifest` changes. Returns `None` when there is nothing
+/// recorded to compare against.
+pub fn report(
+    log: &Logger,
+    manifests_dir: &Path,
+    previous: Option<&str>,
+    manifest: &Manifest,
+    show_all: bool,
+) -> Option<DiffCounts> {
+    let Some(previous) = previous else {
+        log.info(
+            "diff_skipped",
+            json!({ "reason": "no_previous_version" }),
+            "no previous deploy on this root; skipping change summary",
+        );
+        return None;
+    };
+    let old = match load(manifests_dir, previous) {
+        Ok(Some(old)) => old,
+        Ok(None) => {
+            log.info(
+                "diff_skipped",
+                json!({ "reason": "no_recorded_manifest", "previous_version": previous }),
+                format_args!(
+                    "no manifest recorded for previous version {previous}; skipping change summary"
+                ),
+            );
+            return None;
+        }
+        Err(err) => {
+            log.warn(
+                "diff_skipped",
+                json!({ "reason": "unreadable_manifest", "error": format!("{err:#}") }),
+                format_args!("skipping change summary: {err:#}"),
+            );
+            return None;
+        }
+    };
+
+    let diff = ManifestDiff::between(&old, manifest);
+    let counts = diff.counts();
+    log.info(
+        "diff",
+        json!({ "from": previous, "to": &manifest.version, "changes": counts }),
+        format_args!(
+            "changes {previous} -> {}: {} added, {} removed, {} modified",
+            manifest.version, counts.added, counts.removed, counts.modified
+        ),
+    );
+    if show_all {
+        for (change, sign, paths) in [
+            ("added", '+', &diff.added),
+            ("removed", '-', &diff.removed),
+            ("modified", '~', &diff.modified),
+        ] {
+            for path in paths {
+                log.info(
+                    "diff_path",
+                    json!({ "change": change, "path": path }),
+                    format_args!("  {sign} {path}"),
+                );
+            }
+        }
+    }
+    Some(counts)
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct DiffArgs {
+    /// Version to compare from, under snapshots/.
+    #[arg(value_name = "VERSION_A")]
+    pub from: String,
+    /// Version to compare to.
+    #[arg(value_name = "VERSION_B")]
+    pub to: String,
+    /// Print only the counts and the change in size, not every path.
+    #[arg(long)]
+    pub stat: bool,
+}
+
+/// Where one side of a `diff` came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Source {
+    /// `manifests/<version>.json`.
+    Record,
+    /// Hashing `snapshots/<version>`.
+    Snapshot,
+}
+
+#[derive(Debug, Serialize)]
+struct Sized {
+    path: String,
+    /// `None` for a symlink.
+    size: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct Modified {
+    path: String,
+    old_size: Option<u64>,
+    new_size: Option<u64>,
+}
+
+/// The `--output json` document. With `--stat` the path lists are left out.
+#[derive(Debug, Serialize)]
+struct Comparison {
+    from: String,
+    to: String,
+    from_source: Source,
+    to_source: Source,
+    counts: DiffCounts,
+    /// Size of `to` minus size of `from`, over the paths that changed.
+    bytes_delta: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    added: Option<Vec<Sized>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    removed: Option<Vec<Sized>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    modified: Option<Vec<Modified>>,
+}
+
+/// Runs `diff` and returns the exit code: 0 once the comparison is printed,
+/// whatever it says.
+pub fn main(args: &Args, diff: &DiffArgs, log: &Logger) -> i32 {
+    let comparison = match compare(args.root(), diff, log) {
+        Ok(comparison) => comparison,
+        Err(err) => {
+            log.error(
+                "diff_failed",
+                json!({ "error": format!("{err:#}") }),
+                format_args!("diff failed: {err:#}"),
+            );
+            return EXIT_FAILURE;
+        }
+    };
+    match args.output {
+        OutputFormat::Json => match serde_json::to_string(&comparison) {
+            Ok(doc) => println!("{doc}"),
+            Err(err) => {
+                log.error(
+                    "summary_failed",
+                    json!({ "error": err.to_string() }),
+                    format_args!("render summary: {err}"),
+                );
+                return EXIT_FAILURE;
+            }
+        },
+        OutputFormat::Text => print!("{}", render(&comparison)),
+    }
+    0
+}
+
+fn compare(root: &Path, diff: &DiffArgs, log: &Logger) -> Result<Comparison> {
+    let (old, from_source) = side(root, &diff.from, log)?;
+    let (new, to_source) = side(root, &diff.to, log)?;
+    let changes = ManifestDiff::between(&old, &new);
+    let (old_sizes, new_sizes) = (sizes(&old), sizes(&new));
+    let size = |sizes: &BTreeMap<String, Option<u64>>, path: &String| sizes[path];
+    let added: Vec<Sized> = changes
+        .added
+        .iter()
+        .map(|path| Sized {
+            path: path.clone(),
+            size: size(&new_sizes, path),
+        })
+        .collect();
+    let removed: Vec<Sized> = changes
+        .removed
+        .iter()
+        .map(|path| Sized {
+            path: path.clone(),
+            size: size(&old_sizes, path),
+        })
+        .collect();
+    let modified: Vec<Modified> = changes
+        .modified
+        .iter()
+        .map(|path| Modified {
+            path: path.clone(),
+            old_size: size(&old_sizes, path),
+            new_size: size(&new_sizes, path),
+        })
+        .collect();
+    let bytes = |size: Option<u64>| size.unwrap_or(0) as i64;
+    let bytes_delta = added.iter().map(|a| bytes(a.size)).sum::<i64>()
+        - removed.iter().map(|r| bytes(r.size)).sum::<i64>()
+        + modified
+            .iter()
+            .map(|m| bytes(m.new_size) - bytes(m.old_size))
+            .sum::<i64>();
+    let full = !diff.stat;
+    Ok(Comparison {
+        from: diff.from.clone(),
+        to: diff.to.clone(),
+        from_source,
+        to_source,
+        counts: changes.counts(),
+        bytes_delta,
+        added: full.then_some(added),
+        removed: full.then_some(removed),
+        modified: full.then_some(modified),
+    })
+}
+
+/// `version` as recorded, or failing that as its snapshot holds it now.
+fn side(root: &Path, version: &str, log: &Logger) -> Result<(Manifest, Source)> {
+    validate_version(version).with_context(|| format!("invalid version {version:?}"))?;
+    if let Some(manifest) = load(&root.join("manifests"), version)? {
+        return Ok((manifest, Source::Record));
+    }
+    let snapshot = root.join("snapshots").join(version);
+    if !snapshot.is_dir() {
+        bail!("no recorded manifest or snapshot for {version}");
+    }
+    log.info(
+        "diff_hashing",
+        json!({ "version": version }),
+        format_args!(
+            "no manifest recorded for {version}; hashing {}",
+            snapshot.display()
+        ),
+    );
+    let manifest = from_snapshot(&snapshot, version)
+        .with_context(|| format!("read snapshot {}", snapshot.display()))?;
+    Ok((manifest, Source::Snapshot))
+}
+
+/// Path -> size, `None` for symlinks; keyed like `entries`.
+fn sizes(manifest: &Manifest) -> BTreeMap<String, Option<u64>> {
+    let files = manifest.files.iter().map(|f| (key(&f.path), Some(f.size)));
+    let links = manifest.symlinks.iter().map(|l| (key(&l.path), None));
+    files.chain(links).collect()
+}
+
+fn render(comparison: &Comparison) -> String {
+    let shown = |size: Option<u64>| match size {
+        Some(size) => format!("{size} bytes"),
+        None => "symlink".to_string(),
+    };
+    let mut out = String::new();
+    for added in comparison.added.iter().flatten() {
+        out.push_str(&format!("+ {} ({})\n", added.path, shown(added.size)));
+    }
+    for removed in comparison.removed.iter().flatten() {
+        out.push_str(&format!("- {} ({})\n", removed.path, shown(removed.size)));
+    }
+    for modified in comparison.modified.iter().flatten() {
+        out.push_str(&format!(
+            "~ {} ({} -> {})\n",
+            modified.path,
+            shown(modified.old_size),
+            shown(modified.new_size)
+        ));
+    }
+    let counts = comparison.counts;
+    out.push_str(&format!(
+        "{} -> {}: {} added, {} removed, {} modified, {:+} bytes\n",
+        comparison.from,
+        comparison.to,
+        counts.added,
+        counts.removed,
+        counts.modified,
+        comparison.bytes_delta
+    ));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(version: &str, files: &str) -> Manifest {
+        serde_json::from_str(&format!(
+            r#"{{"version": "{version}", "files": [{files}]}}"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn diff_reports_added_removed_and_modified_paths() {
+        let (a, b) = ("a".repeat(64), "b".repeat(64));
+        let old = manifest(
+            "v1",
+            &format!(
+                r#"{{ "path": "same.html", "hash": "{a}", "size": 1 }},
+                   {{ "path": "gone.html", "hash": "{a}", "size": 1 }},
+                   {{ "path": "d/./edit.html", "hash": "{a}", "size": 1 }},
+                   {{ "path": "link", "symlink": "same.html" }}"#
+            ),
+        );
+        let new = manifest(
+            "v2",
+            &format!(
+                r#"{{ "path": "same.html", "hash": "{a}", "size": 1 }},
+                   {{ "path": "d/edit.html", "hash": "{b}", "size": 1 }},
+                   {{ "path": "new.html", "hash": "{b}", "size": 1 }},
+                   {{ "path": "link", "symlink": "new.html" }}"#
+            ),
+        );
+        let diff = ManifestDiff::between(&old, &new);
+        assert_eq!(diff.added, ["new.html"]);
+        assert_eq!(diff.removed, ["gone.html"]);
+        assert_eq!(diff.modified, ["d/edit.html", "link"]);
+    }
+
+    #[test]
+    fn recorded_manifest_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = "a".repeat(64);
+        let original = manifest(
+            "v1",
+            &format!(
+                r#"{{ "path": "x.sh", "hash": "{a}", "size": 3, "mode": "0755" }},
+                   {{ "path": "y", "symlink": "x.sh" }}"#
+            ),
+        );
+        record(dir.path(), &original).unwrap();
+        let loaded = load(dir.path(), "v1").unwrap().unwrap();
+        assert_eq!(loaded.files[0].mode, Some(0o755));
+        assert_eq!(
+            ManifestDiff::between(&original, &loaded).counts(),
+            DiffCounts::default()
+        );
+        assert!(load(dir.path(), "v0").unwrap().is_none());
+    }
+}
@@ -0,0 +1,565 @@
+//! `cityfeed-puller doctor`: the checklist for a new edge box, run before
+//! its first deploy. Each check passes, warns or fails, and most problems
+//! come with a hint on what to change:
+//!
+//! - `root`: the root is a directory, or can be created.
+//! - `write`: a file can be written and fsynced there.
+//! - `symlinks`: `current` can be switched there the way a deploy does it.
+//! - `lock`: no other instance holds the root lock (a warning: the timer's
+//!   run may simply be in progress).
+//! - `clock`: the system clock is plausible, or certificates can't be checked.
+//! - `tls`: which root certificates HTTPS origins are checked against.
+//! - `origin`: each origin answers a manifest request with a valid manifest.
+//! - `disk_space`: the root's filesystem has room for the largest manifest.
+//!
+//! Nothing is deployed and the root is left as it was found, apart from the
+//! directories leading to it.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::http::Http;
+use crate::logger::Logger;
+use crate::switch::{read_current, switch_symlink_atomically};
+use crate::{
+    fetch_manifest, fsync_dir, manifest_url, network_hint, normalize_origins,
+    tls_name_mismatch_hint, Args, Fetcher, HttpStatusError, Manifest, NetFailure, OutputFormat,
+    EXIT_FAILURE, LOCK_FILE,
+};
+
+/// Earliest plausible system time, 2025-01-01. A box without a battery-backed
+/// clock boots in 1970 until NTP catches up.
+const CLOCK_FLOOR: Duration = Duration::from_secs(1_735_689_600);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl Status {
+    fn label(self) -> &'static str {
+        match self {
+            Status::Pass => "PASS",
+            Status::Warn => "WARN",
+            Status::Fail => "FAIL",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Check {
+    name: &'static str,
+    /// The origin an `origin` check is about.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+    status: Status,
+    detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hint: Option<String>,
+}
+
+impl Check {
+    fn new(name: &'static str, status: Status, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            target: None,
+            status,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn hint(self, hint: impl Into<String>) -> Self {
+        Self {
+            hint: Some(hint.into()),
+            ..self
+        }
+    }
+}
+
+/// The `--output json` document.
+#[derive(Debug, Serialize)]
+struct Report {
+    root: PathBuf,
+    /// No check failed.
+    ok: bool,
+    checks: Vec<Check>,
+}
+
+/// Runs the checks against `args.root()` and `args.origins` and returns the
+/// exit code: 0 unless a check failed.
+pub fn main(args: &Args, log: &Logger) -> i32 {
+    let root = args.root();
+    let checks = checks(args, log, root);
+    let report = Report {
+        root: root.to_path_buf(),
+        ok: !checks.iter().any(|check| check.status == Status::Fail),
+        checks,
+    };
+    match args.output {
+        OutputFormat::Json => match serde_json::to_string(&report) {
+            Ok(doc) => println!("{doc}"),
+            Err(err) => {
+                log.error(
+                    "summary_failed",
+                    json!({ "error": err.to_string() }),
+                    format_args!("render summary: {err}"),
+                );
+                return EXIT_FAILURE;
+            }
+        },
+        OutputFormat::Text => print!("{}", render(&report)),
+    }
+    if report.ok {
+        0
+    } else {
+        EXIT_FAILURE
+    }
+}
+
+fn checks(args: &Args, log: &Logger, root: &Path) -> Vec<Check> {
+    let (dir, root_check) = check_root(root);
+    let mut checks = vec![root_check];
+    if let Some(dir) = &dir {
+        checks.push(check_write(dir));
+        checks.push(check_symlinks(dir));
+    }
+    checks.push(check_lock(root));
+    checks.push(check_clock());
+    checks.push(check_tls());
+    let mut largest: Option<Manifest> = None;
+    checks.extend(check_origins(args, log, &mut largest));
+    if let Some(dir) = &dir {
+        checks.push(check_disk_space(dir, largest.as_ref(), args.min_free_bytes));
+    }
+    checks
+}
+
+/// The root, or the nearest directory above it that exists, which is where
+/// the first deploy will create it. `None` if there is no such directory.
+fn check_root(root: &Path) -> (Option<PathBuf>, Check) {
+    match fs::metadata(root) {
+        Ok(meta) if meta.is_dir() => (
+            Some(root.to_path_buf()),
+            Check::new("root", Status::Pass, format!("{} exists", root.display())),
+        ),
+        Ok(_) => (
+            None,
+            Check::new(
+                "root",
+                Status::Fail,
+                format!("{} is not a directory", root.display()),
+            )
+            .hint("point --root at a directory, or remove what is in the way"),
+        ),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            let parent = root.ancestors().skip(1).find(|dir| dir.is_dir());
+            match parent {
+                Some(parent) => (
+                    Some(parent.to_path_buf()),
+                    Check::new(
+                        "root",
+                        Status::Pass,
+                        format!(
+                            "{} does not exist yet; the first deploy creates it in {}",
+                            root.display(),
+                            parent.display()
+                        ),
+                    ),
+                ),
+                None => (
+                    None,
+                    Check::new(
+                        "root",
+                        Status::Fail,
+                        format!("no directory above {} exists", root.display()),
+                    ),
+                ),
+            }
+        }
+        Err(err) => (
+            None,
+            Check::new(
+                "root",
+                Status::Fail,
+                format!("stat {}: {err}", root.display()),
+            )
+            .hint("run the puller as a user that can reach the root"),
+        ),
+    }
+}
+
+fn check_write(dir: &Path) -> Check {
+    let probe = || -> Result<()> {
+        let mut tmp = tempfile::Builder::new()
+            .prefix(".doctor-")
+            .tempfile_in(dir)
+            .with_context(|| format!("create a file in {}", dir.display()))?;
+        tmp.write_all(&[0; 64 << 10]).context("write")?;
+        tmp.as_file().sync_all().context("fsync")?;
+        fsync_dir(dir)
+    };
+    match probe() {
+        Ok(()) => Check::new(
+            "write",
+            Status::Pass,
+            format!("wrote and fsynced a file in {}", dir.display()),
+        ),
+        Err(err) => Check::new("write", Status::Fail, format!("{err:#}")).hint(format!(
+            "run the puller as the user that owns {}, or chown it to that user",
+            dir.display()
+        )),
+    }
+}
+
+/// Switches a scratch `current` twice, as two deploys would.
+fn check_symlinks(dir: &Path) -> Check {
+    let probe = || -> Result<Option<PathBuf>> {
+        let scratch = tempfile::Builder::new()
+            .prefix(".doctor-")
+            .tempdir_in(dir)
+            .with_context(|| format!("create a directory in {}", dir.display()))?;
+        let current = scratch.path().join("current");
+        for version in ["snapshots/a", "snapshots/b"] {
+            switch_symlink_atomically(&current, Path::new(version), scratch.path())?;
+        }
+        let points_at = read_current(&current)?;
+        #[cfg(windows)]
+        if scratch.path().join(crate::CURRENT_POINTER).exists() {
+            anyhow::bail!("no privilege to create symlinks; current would be a pointer file");
+        }
+        Ok(points_at)
+    };
+    let hint = "keep the root on a filesystem with symlinks and atomic rename, such as ext4, \
+                xfs or btrfs; FAT, exFAT and some network shares have neither";
+    match probe() {
+        Ok(Some(target)) if target == Path::new("snapshots/b") => Check::new(
+            "symlinks",
+            Status::Pass,
+            "current can be switched atomically",
+        ),
+        Ok(target) => Check::new(
+            "symlinks",
+            Status::Fail,
+            format!("current points at {target:?} after switching it to snapshots/b"),
+        )
+        .hint(hint),
+        Err(err) => Check::new("symlinks", Status::Fail, format!("{err:#}")).hint(hint),
+    }
+}
+
+/// Looks for a live holder of the root lock without creating the lock file.
+fn check_lock(root: &Path) -> Check {
+    let path = root.join(LOCK_FILE);
+    let file = match OpenOptions::new().read(true).write(true).open(&path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Check::new("lock", Status::Pass, "the root is not locked");
+        }
+        Err(err) => {
+            return Check::new(
+                "lock",
+                Status::Warn,
+                format!("open {}: {err}", path.display()),
+            )
+            .hint("run the puller as the user that owns the root");
+        }
+    };
+    match file.try_lock() {
+        Ok(()) => Check::new("lock", Status::Pass, "the root is not locked"),
+        Err(fs::TryLockError::WouldBlock) => {
+            let holder = fs::read_to_string(&path).unwrap_or_default();
+            Check::new(
+                "lock",
+                Status::Warn,
+                format!("locked by another cityfeed-puller (pid {})", holder.trim()),
+            )
+            .hint("a deploy is running; if it is stuck, stop that process before deploying by hand")
+        }
+        Err(fs::TryLockError::Error(err)) => Check::new(
+            "lock",
+            Status::Warn,
+            format!("lock {}: {err}", path.display()),
+        ),
+    }
+}
+
+fn check_clock() -> Check {
+    let now = SystemTime::now();
+    let shown = httpdate::fmt_http_date(now);
+    match now.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(since) if since >= CLOCK_FLOOR => {
+            Check::new("clock", Status::Pass, format!("system time is {shown}"))
+        }
+        _ => Check::new(
+            "clock",
+            Status::Fail,
+            format!("system time is {shown}, too early to check any certificate against"),
+        )
+        .hint("set the time, and keep it set with NTP (timedatectl set-ntp true)"),
+    }
+}
+
+/// HTTPS origins are checked against the Mozilla roots built into the
+/// binary, so the system trust store and its variables play no part.
+fn check_tls() -> Check {
+    let detail = "certificates are checked against the Mozilla root certificates built into \
+                  cityfeed-puller";
+    let set: Vec<&str> = ["SSL_CERT_FILE", "SSL_CERT_DIR"]
+        .into_iter()
+        .filter(|name| std::env::var_os(name).is_some_and(|v| !v.is_empty()))
+        .collect();
+    if set.is_empty() {
+        return Check::new("tls", Status::Pass, detail);
+    }
+    Check::new(
+        "tls",
+        Status::Warn,
+        format!(
+            "{detail}; {} {} set but not used",
+            set.join(" and "),
+            if set.len() == 1 { "is" } else { "are" }
+        ),
+    )
+    .hint(
+        "a TLS-inspecting proxy's own CA is not trusted; have the venue exempt the origins \
+         from inspection",
+    )
+}
+
+/// One check per origin. `largest` is left holding the biggest manifest
+/// served, for the disk space check.
+fn check_origins(args: &Args, log: &Logger, largest: &mut Option<Manifest>) -> Vec<Check> {
+    let origins = match normalize_origins(&args.origins) {
+        Ok(origins) => origins,
+        Err(err) => {
+            return vec![Check::new("origin", Status::Fail, format!("{err:#}"))
+                .hint("pass --origin, or set origins in --config")];
+        }
+    };
+    let fetcher = match Http::new(args)
+        .and_then(|http| Fetcher::new(args, &http, Arc::new(AtomicBool::new(false))))
+    {
+        Ok(fetcher) => fetcher,
+        Err(err) => return vec![Check::new("origin", Status::Fail, format!("{err:#}"))],
+    };
+    let mut checks = Vec::new();
+    for origin in origins {
+        let started = Instant::now();
+        let check = match fetch_manifest(
+            &fetcher,
+            log,
+            &manifest_url(&origin, &fetcher.channel),
+            &origin,
+        ) {
+            Ok(manifest) => {
+                let check = Check::new(
+                    "origin",
+                    Status::Pass,
+                    format!(
+                        "manifest {} ({} files) in {} ms",
+                        manifest.version,
+                        manifest.files.len(),
+                        started.elapsed().as_millis()
+                    ),
+                );
+                if largest
+                    .as_ref()
+                    .is_none_or(|l| total_bytes(l) < total_bytes(&manifest))
+                {
+                    *largest = Some(manifest);
+                }
+                check
+            }
+            Err(err) => {
+                let hint = origin_hint(&err, &origin, &fetcher);
+                let detail: Vec<String> = err
+                    .chain()
+                    .map(|cause| cause.to_string())
+                    .filter(|cause| Some(cause) != hint.as_ref())
+                    .collect();
+                Check {
+                    hint,
+                    ..Check::new("origin", Status::Fail, detail.join(": "))
+                }
+            }
+        };
+        checks.push(Check {
+            target: Some(origin),
+            ..check
+        });
+    }
+    checks
+}
+
+/// What to change when `origin` failed with `err`: the hints deploys attach
+/// to network errors, plus a few for answers that came back wrong.
+fn origin_hint(err: &anyhow::Error, origin: &str, fetcher: &Fetcher) -> Option<String> {
+    let text = format!("{err:#}");
+    if text.contains("certificate not valid for name") {
+        if let Some(hint) = tls_name_mismatch_hint(origin) {
+            return Some(hint);
+        }
+    }
+    if text.contains("UnknownIssuer") {
+        return Some(format!(
+            "{origin}'s certificate is not signed by a trusted root; if the venue inspects TLS, \
+             have it exempt this origin"
+        ));
+    }
+    if text.contains("Expired") || text.contains("NotValidYet") {
+        return Some(
+            "the certificate is outside its validity period; check the system clock".to_string(),
+        );
+    }
+    for cause in err.chain() {
+        if let Some(status) = cause.downcast_ref::<HttpStatusError>() {
+            return match status.status.as_u16() {
+                401 | 403 => Some(format!(
+                    "{origin} refused the request; check --auth-token, --auth-basic or \
+                     --query-auth for it"
+                )),
+                404 => Some(format!(
+                    "{origin} has no manifests/{}.json; the origin is the URL the publisher \
+                     uploads manifests/ and objects/ under, and --channel names a file there",
+                    fetcher.channel
+                )),
+                _ => None,
+            };
+        }
+        if let Some(err) = cause.downcast_ref::<reqwest::Error>() {
+            return NetFailure::of(err)
+                .map(|failure| network_hint(failure, origin, &fetcher.net.for_origin(origin)));
+        }
+    }
+    None
+}
+
+fn total_bytes(manifest: &Manifest) -> u64 {
+    manifest
+        .files
+        .iter()
+        .fold(0u64, |sum, file| sum.saturating_add(file.size))
+}
+
+/// A fresh root holds each file twice: once in `objects/`, once staged.
+fn check_disk_space(dir: &Path, largest: Option<&Manifest>, min_free: u64) -> Check {
+    let available = match fs4::available_space(dir) {
+        Ok(available) => available,
+        Err(err) => {
+            return Check::new(
+                "disk_space",
+                Status::Warn,
+                format!("query free space on {}: {err}", dir.display()),
+            );
+        }
+    };
+    let Some(manifest) = largest else {
+        let status = if available < min_free {
+            Status::Fail
+        } else {
+            Status::Pass
+        };
+        return Check::new(
+            "disk_space",
+            status,
+            format!("{available} bytes free, {min_free} reserved by --min-free-bytes"),
+        );
+    };
+    let bytes = total_bytes(manifest);
+    let detail = format!(
+        "{available} bytes free; manifest {} needs {bytes} bytes for its snapshot and as much \
+         again for objects, plus {min_free} reserved by --min-free-bytes",
+        manifest.version
+    );
+    let hint = "free space on the root's filesystem, or move --root to a larger one";
+    if available < bytes.saturating_add(min_free) {
+        Check::new("disk_space", Status::Fail, detail).hint(hint)
+    } else if available < bytes.saturating_mul(2).saturating_add(min_free) {
+        Check::new("disk_space", Status::Warn, detail).hint(hint)
+    } else {
+        Check::new("disk_space", Status::Pass, detail)
+    }
+}
+
+fn render(report: &Report) -> String {
+    let mut out = String::new();
+    for check in &report.checks {
+        let name = match &check.target {
+            Some(target) => format!("{} {target}", check.name),
+            None => check.name.to_string(),
+        };
+        out.push_str(&format!(
+            "{} {name:<10} {}\n",
+            check.status.label(),
+            check.detail
+        ));
+        if let Some(hint) = &check.hint {
+            out.push_str(&format!("     hint: {hint}\n"));
+        }
+    }
+    let failed = report
+        .checks
+        .iter()
+        .filter(|check| check.status == Status::Fail)
+        .count();
+    match failed {
+        0 => out.push_str("ready to deploy\n"),
+        n => out.push_str(&format!("{n} check(s) failed\n")),
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_puts_hints_under_their_check() {
+        let report = Report {
+            root: PathBuf::from("/var/www/mspmetro"),
+            ok: false,
+            checks: vec![
+                Check::new("root", Status::Pass, "/var/www/mspmetro exists"),
+                Check {
+                    target: Some("https://cdn.example".into()),
+                    ..Check::new("origin", Status::Fail, "tcp connect error")
+                        .hint("is the server listening?")
+                },
+            ],
+        };
+        assert_eq!(
+            render(&report),
+            "PASS root       /var/www/mspmetro exists\n\
+             FAIL origin https://cdn.example tcp connect error\n     \
+             hint: is the server listening?\n\
+             1 check(s) failed\n"
+        );
+    }
+
+    #[test]
+    fn missing_root_is_checked_where_it_will_be_created() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("srv/site");
+        let (probe, check) = check_root(&root);
+        assert_eq!(check.status, Status::Pass);
+        assert_eq!(probe.as_deref(), Some(dir.path()));
+
+        let file = dir.path().join("file");
+        fs::write(&file, "x").unwrap();
+        let (probe, check) = check_root(&file);
+        assert_eq!(check.status, Status::Fail);
+        assert!(probe.is_none());
+    }
+}
@@ -0,0 +1,133 @@
+//! Refusing to switch back to an older publish. A mirror that still serves
+//! last week's `latest.json` would otherwise win whenever the primary origin
+//! is briefly down.
+//!
+//! The offered manifest is compared with the record of the live version in
+//! `manifests/` by `sequence`, then `generated_at`. Manifests without either
+//! fall back to local history: a version this root deployed before the live
+//! one (its record is older) is a downgrade. A version never seen here and
+//! without an ordering key can't be placed and is allowed.
+
+use std::cmp::Ordering;
+use std::fs;
+use std::path::Path;
+
+use crate::{diff, Manifest};
+
+/// Why `offered` is older than the live `current` version, or `None` if it
+/// isn't (or can't be told).
+pub fn older(manifests_dir: &Path, current: &str, offered: &Manifest) -> Option<String> {
+    if offered.version == current {
+        return None;
+    }
+    let live = diff::load(manifests_dir, current).ok().flatten();
+    if let Some(live) = &live {
+        match offered.publish_order(live) {
+            Some(Ordering::Less) => return Some(key_reason(offered, live)),
+            Some(_) => return None,
+            None => {}
+        }
+    }
+    let deployed_at = |version: &str| {
+        fs::metadata(diff::record_path(manifests_dir, version))
+            .and_then(|meta| meta.modified())
+            .ok()
+    };
+    match (deployed_at(&offered.version), deployed_at(current)) {
+        (Some(offered_at), Some(current_at)) if offered_at < current_at => Some(format!(
+            "{} was deployed here before {current}",
+            offered.version
+        )),
+        _ => None,
+    }
+}
+
+fn key_reason(offered: &Manifest, live: &Manifest) -> String {
+    match (offered.sequence, live.sequence) {
+        (Some(a), Some(b)) => format!("sequence {a} < {b}"),
+        _ => format!(
+            "generated_at {} < {}",
+            offered.generated_at.as_deref().unwrap_or_default(),
+            live.generated_at.as_deref().unwrap_or_default()
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::time::{Duration, SystemTime};
+
+    fn manifest(version: &str, sequence: Option<u64>, generated_at: Option<&str>) -> Manifest {
+        Manifest {
+            version: version.to_string(),
+            files: Vec::new(),
+            symlinks: Vec::new(),
+            object_layout: None,
+            sequence,
+            generated_at: generated_at.map(str::to_string),
+            prefix: None,
+            filters: Vec::new(),
+        }
+    }
+
+    /// Records `manifest` as deployed `ago` seconds back.
+    fn record(dir: &Path, manifest: &Manifest, ago: u64) {
+        diff::record(dir, manifest).unwrap();
+        let at = SystemTime::now() - Duration::from_secs(ago);
+        File::options()
+            .write(true)
+            .open(diff::record_path(dir, &manifest.version))
+            .unwrap()
+            .set_modified(at)
+            .unwrap();
+    }
+
+    #[test]
+    fn compares_ordering_keys_with_the_live_record() {
+        let dir = tempfile::tempdir().unwrap();
+        record(dir.path(), &manifest("v5", Some(5), None), 0);
+
+        let stale = manifest("v4", Some(4), None);
+        assert_eq!(
+            older(dir.path(), "v5", &stale).as_deref(),
+            Some("sequence 4 < 5")
+        );
+        assert_eq!(
+            older(dir.path(), "v5", &manifest("v6", Some(6), None)),
+            None
+        );
+        assert_eq!(
+            older(dir.path(), "v5", &manifest("v5", Some(1), None)),
+            None
+        );
+
+        record(
+            dir.path(),
+            &manifest("b", None, Some("2026-10-16T08:00:00Z")),
+            0,
+        );
+        let earlier = manifest("a", None, Some("2026-10-15T08:00:00Z"));
+        assert!(older(dir.path(), "b", &earlier)
+            .unwrap()
+            .starts_with("generated_at 2026-10-15"));
+    }
+
+    #[test]
+    fn falls_back_to_when_versions_were_deployed_here() {
+        let dir = tempfile::tempdir().unwrap();
+        record(dir.path(), &manifest("old", None, None), 600);
+        record(dir.path(), &manifest("new", None, None), 60);
+
+        let offered = manifest("old", None, None);
+        assert_eq!(
+            older(dir.path(), "new", &offered).as_deref(),
+            Some("old was deployed here before new")
+        );
+        // Never deployed here, and nothing to order it by.
+        assert_eq!(older(dir.path(), "new", &manifest("x", None, None)), None);
+        // Live version has no record at all.
+        assert_eq!(older(dir.path(), "gone", &offered), None);
+    }
+}
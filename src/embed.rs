@@ -0,0 +1,163 @@
+//! Deploying from another program. `deploy(root, origins, &options)` runs
+//! the same pull as `cityfeed-puller --root ROOT --origin ...`: root lock,
+//! stale cleanup, manifest, objects, snapshot, switch, hooks, pruning,
+//! metrics and notifications. It installs no signal handlers and prints
+//! nothing to stdout; logs still go to stderr.
+//!
+//! Options become command-line flags and are parsed by the binary's own
+//! parser, so every default and check is the same as on the command line.
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use clap::CommandFactory;
+
+use crate::diff::DiffCounts;
+use crate::http::Http;
+use crate::logger::Logger;
+use crate::{
+    args_with_config, pull, record_error_report, report_run, settle, Args, RunError, Summary,
+};
+
+/// Settings for `deploy`. The defaults match the binary's.
+#[derive(Clone, Debug, Default)]
+pub struct DeployOptions {
+    /// TOML file read like `--config`; `root` and `origins` given to
+    /// `deploy` win over the file's.
+    pub config: Option<PathBuf>,
+    /// Same as `--manifest-url`.
+    pub manifest_url: Option<String>,
+    /// Same as `--keep-days`.
+    pub keep_days: Option<u64>,
+    /// Same as `--on-switch`, one command per entry.
+    pub on_switch: Vec<String>,
+    /// Same as `--quiet`: log errors only.
+    pub quiet: bool,
+    /// Any other flags, exactly as the binary takes them (no subcommands).
+    pub extra_args: Vec<OsString>,
+    /// Set this to stop the deploy; it unwinds as on SIGTERM and fails with
+    /// `Failure::Interrupted`.
+    pub cancel: Option<Arc<AtomicBool>>,
+}
+
+/// What a successful `deploy` did.
+#[derive(Clone, Debug)]
+pub struct DeployOutcome {
+    /// The manifest's version, now live.
+    pub version: String,
+    /// Version `current` pointed at before, if any.
+    pub previous_version: Option<String>,
+    /// `current` moved; false when it already pointed at `version`.
+    pub switched: bool,
+    /// Paths added, removed and modified since `previous_version`, when its
+    /// manifest was recorded.
+    pub changes: Option<DiffCounts>,
+    pub objects_downloaded: u64,
+    pub bytes_downloaded: u64,
+    /// `<root>/snapshots/<version>`.
+    pub snapshot: PathBuf,
+}
+
+/// Brings `root` to the version the origins publish. Errors carry the same
+/// failure class the binary turns into its exit code.
+pub fn deploy(
+    root: &Path,
+    origins: &[String],
+    options: &DeployOptions,
+) -> Result<DeployOutcome, RunError> {
+    let args = options.args(root, origins)?;
+    let log = Logger::new(args.log_format, args.verbosity());
+    let http = Http::new(&args)?;
+    let cancel = options.cancel.clone().unwrap_or_default();
+    let mut summary = Summary::default();
+    let (puller, result) = pull(&args, &log, &http, cancel, &mut summary);
+    let report = record_error_report(&args, &log, &summary, &result);
+    let code = settle(&log, &mut summary, &result, report.as_deref());
+    report_run(&args, &log, puller.as_ref(), &summary, code);
+    result?;
+    Ok(DeployOutcome {
+        version: summary.version.unwrap_or_default(),
+        previous_version: summary.previous_version,
+        switched: summary.switched,
+        changes: summary.changes,
+        objects_downloaded: summary.objects_downloaded,
+        bytes_downloaded: summary.bytes_downloaded,
+        snapshot: summary.snapshot.unwrap_or_default(),
+    })
+}
+
+impl DeployOptions {
+    /// The command line these options stand for, parsed and checked.
+    fn args(&self, root: &Path, origins: &[String]) -> Result<Args> {
+        let mut argv: Vec<OsString> = vec!["cityfeed-puller".into(), "--root".into(), root.into()];
+        for origin in origins {
+            argv.extend(["--origin".into(), origin.into()]);
+        }
+        if let Some(config) = &self.config {
+            argv.extend(["--config".into(), config.into()]);
+        }
+        if let Some(url) = &self.manifest_url {
+            argv.extend(["--manifest-url".into(), url.into()]);
+        }
+        if let Some(days) = self.keep_days {
+            argv.extend(["--keep-days".into(), days.to_string().into()]);
+        }
+        for command in &self.on_switch {
+            argv.extend(["--on-switch".into(), command.into()]);
+        }
+        if self.quiet {
+            argv.push("--quiet".into());
+        }
+        argv.extend(self.extra_args.iter().cloned());
+
+        let matches = Args::command().try_get_matches_from(&argv)?;
+        let args = args_with_config(argv, &matches)?;
+        if args.command.is_some() {
+            bail!("deploy options can't name a subcommand");
+        }
+        if args.watch {
+            bail!("deploy runs once; --watch is not supported");
+        }
+        if !args.sites.is_empty() {
+            bail!("deploy takes one root; [[site]] blocks are not supported");
+        }
+        Ok(args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_parse_like_the_command_line() {
+        let options = DeployOptions {
+            keep_days: Some(14),
+            on_switch: vec!["systemctl reload nginx".into()],
+            extra_args: vec!["--max-file-count".into(), "500".into()],
+            ..DeployOptions::default()
+        };
+        let args = options
+            .args(Path::new("/srv/site"), &["https://a.example".into()])
+            .unwrap();
+        assert_eq!(args.root(), Path::new("/srv/site"));
+        assert_eq!(args.origins, ["https://a.example"]);
+        assert_eq!(args.keep_days, Some(14));
+        assert_eq!(args.on_switch, ["systemctl reload nginx"]);
+        assert_eq!(args.max_file_count, 500);
+
+        let watch = DeployOptions {
+            extra_args: vec!["--watch".into()],
+            ..DeployOptions::default()
+        };
+        assert!(watch
+            .args(Path::new("/srv/site"), &["https://a.example".into()])
+            .is_err());
+        assert!(DeployOptions::default()
+            .args(Path::new("/srv/site"), &[])
+            .is_err());
+    }
+}
@@ -0,0 +1,155 @@
+//! `Content-Encoding` handling for origin responses.
+//!
+//! We ask for gzip and decode it ourselves, so a CDN that compresses JSON (or
+//! anything else) behaves the same as one that doesn't. Manifest `size` and
+//! `hash` always describe the decoded bytes; what went over the wire is only
+//! logged.
+
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use flate2::read::MultiGzDecoder;
+use serde_json::json;
+
+use crate::logger::Logger;
+
+/// Sent as `Accept-Encoding` on every HTTP request.
+pub const ACCEPTED: &str = "gzip";
+
+/// Wraps `body` so reads yield decoded content for the given
+/// `Content-Encoding` header. Unknown encodings are an error rather than
+/// bytes that would fail the size check further down.
+pub fn decode(
+    log: &Logger,
+    url: &str,
+    encoding: Option<&str>,
+    body: Box<dyn Read>,
+) -> Result<Box<dyn Read>> {
+    let encoding = encoding.map(|e| e.trim().to_ascii_lowercase());
+    match encoding.as_deref() {
+        None | Some("") | Some("identity") => Ok(body),
+        Some("gzip") | Some("x-gzip") => {
+            let wire = Arc::new(AtomicU64::new(0));
+            let counted = Counted {
+                inner: body,
+                count: Arc::clone(&wire),
+            };
+            Ok(Box::new(Gunzip {
+                inner: MultiGzDecoder::new(counted),
+                wire,
+                decoded: 0,
+                log: log.clone(),
+                url: url.to_string(),
+                reported: false,
+            }))
+        }
+        Some(other) => bail!("unsupported Content-Encoding {other:?} from {url}"),
+    }
+}
+
+/// Counts the compressed bytes read from the wire.
+struct Counted {
+    inner: Box<dyn Read>,
+    count: Arc<AtomicU64>,
+}
+
+impl Read for Counted {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(out)?;
+        self.count.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+/// Decodes a gzip body and logs how much it shrank once it ends.
+struct Gunzip {
+    inner: MultiGzDecoder<Counted>,
+    wire: Arc<AtomicU64>,
+    decoded: u64,
+    log: Logger,
+    url: String,
+    reported: bool,
+}
+
+impl Read for Gunzip {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let n = self
+            .inner
+            .read(out)
+            .map_err(|err| io::Error::new(err.kind(), format!("gunzip {}: {err}", self.url)))?;
+        self.decoded += n as u64;
+        if n == 0 && !out.is_empty() && !self.reported {
+            self.reported = true;
+            let wire = self.wire.load(Ordering::Relaxed);
+            let saved = if self.decoded > 0 {
+                100.0 * (1.0 - wire as f64 / self.decoded as f64)
+            } else {
+                0.0
+            };
+            self.log.debug(
+                "decoded",
+                json!({
+                    "url": &self.url,
+                    "encoding": "gzip",
+                    "wire_bytes": wire,
+                    "decoded_bytes": self.decoded,
+                }),
+                format_args!(
+                    "GET {} arrived gzip: {wire} -> {} bytes ({saved:.0}% smaller)",
+                    self.url, self.decoded
+                ),
+            );
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    use crate::logger::LogFormat;
+
+    fn logger() -> Logger {
+        Logger::new(LogFormat::Text, -1)
+    }
+
+    fn gzip(bytes: &[u8]) -> Vec<u8> {
+        let mut enc = GzEncoder::new(Vec::new(), Compression::default());
+        enc.write_all(bytes).unwrap();
+        enc.finish().unwrap()
+    }
+
+    fn read_all(encoding: Option<&str>, body: Vec<u8>) -> Result<Vec<u8>> {
+        let mut reader = decode(
+            &logger(),
+            "http://o/x",
+            encoding,
+            Box::new(io::Cursor::new(body)),
+        )?;
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn gzip_bodies_are_decoded_and_others_pass_through() {
+        let text = b"hello hello hello hello".to_vec();
+        assert_eq!(read_all(Some("gzip"), gzip(&text)).unwrap(), text);
+        assert_eq!(read_all(Some(" X-Gzip "), gzip(&text)).unwrap(), text);
+        assert_eq!(read_all(None, text.clone()).unwrap(), text);
+        assert_eq!(read_all(Some("identity"), text.clone()).unwrap(), text);
+    }
+
+    #[test]
+    fn unknown_or_corrupt_encodings_are_errors() {
+        let err = read_all(Some("br"), b"x".to_vec()).unwrap_err();
+        assert!(format!("{err:#}").contains("unsupported Content-Encoding \"br\""));
+        assert!(read_all(Some("gzip"), b"not gzip".to_vec()).is_err());
+    }
+}
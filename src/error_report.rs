@@ -0,0 +1,158 @@
+//! `<root>/state/last-error.json`: a structured report of the last failed
+//! run, for whatever wraps the puller to attach to an incident instead of
+//! scraping stderr. Replaced atomically on every failure and removed by the
+//! next successful run.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{fsync_dir, state, FailedObject, Summary};
+
+const REPORT_DIR: &str = "state";
+const REPORT_FILE: &str = "last-error.json";
+
+/// How far a run got before it failed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    /// Arguments, the root lock, the HTTP client.
+    #[default]
+    Setup,
+    Manifest,
+    Download,
+    Staging,
+    /// Moving `current`, including the canary checks after it.
+    Switch,
+    /// `--on-switch` commands, with `--hook-failure fail`.
+    Hooks,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Report {
+    pub phase: Phase,
+    pub exit_code: i32,
+    /// The error and its context chain, outermost first.
+    pub error: Vec<String>,
+    /// Origin of the last failed request, for manifest and download failures.
+    pub origin: Option<String>,
+    /// Object being downloaded when the run failed.
+    pub hash: Option<String>,
+    /// Objects no origin could deliver, each with every origin's error;
+    /// with `--keep-going`, all of them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failed_objects: Vec<FailedObject>,
+    /// Version of the manifest the run was deploying, once it had one.
+    pub version: Option<String>,
+    pub previous_version: Option<String>,
+    pub started_at: String,
+    pub failed_at: String,
+    pub puller_version: String,
+}
+
+impl Report {
+    pub fn new(summary: &Summary, err: &anyhow::Error, exit_code: i32) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let started = now.saturating_sub(Duration::from_secs_f64(summary.elapsed_secs));
+        let fetching = matches!(summary.phase, Phase::Manifest | Phase::Download);
+        Self {
+            phase: summary.phase,
+            exit_code,
+            error: err.chain().map(|e| e.to_string()).collect(),
+            origin: summary.failed_origin.clone().filter(|_| fetching),
+            hash: summary.object.clone(),
+            failed_objects: summary.failed_objects.clone(),
+            version: summary.version.clone(),
+            previous_version: summary.previous_version.clone(),
+            started_at: state::rfc3339(started.as_secs()),
+            failed_at: state::rfc3339(now.as_secs()),
+            puller_version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+}
+
+pub fn path(root: &Path) -> PathBuf {
+    root.join(REPORT_DIR).join(REPORT_FILE)
+}
+
+/// Replaces the report in `root` and returns its path.
+pub fn write(root: &Path, report: &Report) -> Result<PathBuf> {
+    let dir = root.join(REPORT_DIR);
+    fs::create_dir_all(&dir).with_context(|| format!("create {}", dir.display()))?;
+    let path = path(root);
+    let mut tmp = tempfile::NamedTempFile::new_in(&dir)
+        .with_context(|| format!("create temp file in {}", dir.display()))?;
+    serde_json::to_writer_pretty(&mut tmp, report).context("write error report")?;
+    tmp.write_all(b"\n").context("write error report")?;
+    tmp.as_file().sync_all().context("fsync error report")?;
+    tmp.persist(&path)
+        .with_context(|| format!("persist {}", path.display()))?;
+    fsync_dir(&dir).context("fsync state dir")?;
+    Ok(path)
+}
+
+/// Removes the report after a successful run; a missing one is fine.
+pub fn clear(root: &Path) -> Result<()> {
+    let path = path(root);
+    match fs::remove_file(&path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            Err(err).with_context(|| format!("remove {}", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn write_replaces_and_clear_removes() {
+        let root = tempfile::tempdir().unwrap();
+        let summary = Summary {
+            phase: Phase::Download,
+            object: Some("ab12".into()),
+            failed_origin: Some("https://a.example".into()),
+            version: Some("v2".into()),
+            elapsed_secs: 90.0,
+            ..Summary::default()
+        };
+        let err = anyhow!("HTTP 404").context("download object ab12");
+        let report = Report::new(&summary, &err, 5);
+        assert_eq!(report.error, ["download object ab12", "HTTP 404"]);
+        assert_eq!(report.origin.as_deref(), Some("https://a.example"));
+        assert!(report.started_at < report.failed_at);
+
+        write(root.path(), &report).unwrap();
+        let second = Report::new(
+            &Summary {
+                phase: Phase::Staging,
+                ..summary
+            },
+            &err,
+            1,
+        );
+        // The origin only matters while fetching.
+        assert_eq!(second.origin, None);
+        let written = write(root.path(), &second).unwrap();
+        let text = fs::read_to_string(&written).unwrap();
+        let read: Report = serde_json::from_str(&text).unwrap();
+        assert_eq!(read, second);
+        assert!(text.contains(r#""phase": "staging""#), "{text}");
+        assert_eq!(
+            fs::read_dir(root.path().join(REPORT_DIR)).unwrap().count(),
+            1
+        );
+
+        clear(root.path()).unwrap();
+        assert!(!written.exists());
+        clear(root.path()).unwrap();
+    }
+}
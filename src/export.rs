@@ -0,0 +1,237 @@
+//! `cityfeed-puller export`: a snapshot plus its recorded manifest as one
+//! `.tar.zst`, for audits and for seeding air-gapped boxes.
+//!
+//! The archive holds `manifest.json` (the record from `manifests/`, byte for
+//! byte) followed by `snapshot/<path>` for every directory, file and symlink,
+//! in path order. Every entry has mtime 0 and owner 0:0 and keeps its mode
+//! from disk, so exporting the same snapshot twice gives identical bytes.
+//!
+//! Files are hashed as they stream into the archive and checked against the
+//! manifest. A mismatch aborts the export, and the output is only renamed
+//! into place once everything verified.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::json;
+use tar::{EntryType, Header};
+
+use crate::hashing::Hashing;
+use crate::logger::Logger;
+use crate::{
+    current_version, diff, validate_rel_path, validate_symlink_target, validate_version, Manifest,
+    EXIT_FAILURE,
+};
+
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct ExportArgs {
+    /// Snapshot to export; the one `current` points at if omitted.
+    #[arg(long, value_name = "VERSION")]
+    pub version: Option<String>,
+
+    /// Write the .tar.zst here (replaced atomically).
+    #[arg(long, value_name = "FILE")]
+    pub out: PathBuf,
+}
+
+enum Item<'a> {
+    Dir,
+    File { hash: &'a str, size: u64 },
+    Link(&'a str),
+}
+
+/// Runs `export` against `root` and returns the exit code.
+pub fn main(root: &Path, args: &ExportArgs, log: &Logger) -> i32 {
+    match export(root, args) {
+        Ok((version, entries)) => {
+            log.outcome(
+                "exported",
+                json!({ "version": &version, "out": &args.out, "entries": entries }),
+                format_args!(
+                    "exported {version} ({entries} entries) to {}",
+                    args.out.display()
+                ),
+            );
+            0
+        }
+        Err(err) => {
+            log.error(
+                "export_failed",
+                json!({ "error": format!("{err:#}") }),
+                format_args!("export failed: {err:#}"),
+            );
+            EXIT_FAILURE
+        }
+    }
+}
+
+/// Writes the archive; returns the version and how many snapshot entries it
+/// holds.
+fn export(root: &Path, args: &ExportArgs) -> Result<(String, usize)> {
+    let version = match &args.version {
+        Some(version) => version.clone(),
+        None => current_version(&root.join("current"))
+            .ok_or_else(|| anyhow!("{} has no current snapshot; pass --version", root.display()))?,
+    };
+    validate_version(&version).with_context(|| format!("invalid version {version:?}"))?;
+    let snapshot = root.join("snapshots").join(&version);
+    if !snapshot.is_dir() {
+        bail!("no snapshot {}", snapshot.display());
+    }
+    let record = diff::record_path(&root.join("manifests"), &version);
+    let manifest_bytes = fs::read(&record)
+        .with_context(|| format!("read recorded manifest {}", record.display()))?;
+    let manifest: Manifest = serde_json::from_slice(&manifest_bytes)
+        .with_context(|| format!("parse {}", record.display()))?;
+    let items = items(&manifest)?;
+
+    let dir = match args.out.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let tmp = tempfile::NamedTempFile::new_in(dir)
+        .with_context(|| format!("create temp file in {}", dir.display()))?;
+    let encoder = zstd::Encoder::new(BufWriter::new(tmp.as_file()), ZSTD_LEVEL)
+        .context("start zstd stream")?;
+    let mut tar = tar::Builder::new(encoder);
+
+    let mut header = header(EntryType::Regular, 0o644, manifest_bytes.len() as u64);
+    tar.append_data(&mut header, "manifest.json", manifest_bytes.as_slice())
+        .context("write manifest.json")?;
+    for (rel, item) in &items {
+        append(&mut tar, &snapshot, rel, item)
+            .with_context(|| format!("export {}", rel.display()))?;
+    }
+
+    tar.into_inner()
+        .and_then(|encoder| encoder.finish())
+        .and_then(|mut out| out.flush())
+        .context("finish archive")?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(tmp.path(), fs::Permissions::from_mode(0o644))
+            .context("chmod archive")?;
+    }
+    tmp.as_file().sync_all().context("fsync archive")?;
+    tmp.persist(&args.out)
+        .with_context(|| format!("replace {}", args.out.display()))?;
+    Ok((version, items.len()))
+}
+
+/// Every path the snapshot should hold, with the directories implied by the
+/// manifest, sorted so parents come before their children.
+fn items(manifest: &Manifest) -> Result<BTreeMap<PathBuf, Item<'_>>> {
+    let mut items = BTreeMap::new();
+    let files = manifest.files.iter().map(|f| {
+        let item = Item::File {
+            hash: &f.hash,
+            size: f.size,
+        };
+        (f.path.as_str(), item)
+    });
+    let links = manifest
+        .symlinks
+        .iter()
+        .map(|l| (l.path.as_str(), Item::Link(&l.target)));
+    for (path, item) in files.chain(links) {
+        let rel = validate_rel_path(path).with_context(|| format!("manifest path {path:?}"))?;
+        for dir in rel.ancestors().skip(1) {
+            if !dir.as_os_str().is_empty() {
+                items.insert(dir.to_path_buf(), Item::Dir);
+            }
+        }
+        items.insert(rel, item);
+    }
+    Ok(items)
+}
+
+fn append<W: Write>(
+    tar: &mut tar::Builder<W>,
+    snapshot: &Path,
+    rel: &Path,
+    item: &Item<'_>,
+) -> Result<()> {
+    let path = snapshot.join(rel);
+    let name = Path::new("snapshot").join(rel);
+    let meta = fs::symlink_metadata(&path).with_context(|| format!("stat {}", path.display()))?;
+    match *item {
+        Item::Dir => {
+            if !meta.is_dir() {
+                bail!("expected a directory");
+            }
+            let mut header = header(EntryType::Directory, mode(&meta), 0);
+            tar.append_data(&mut header, &name, std::io::empty())?;
+        }
+        Item::File { hash, size } => {
+            if !meta.is_file() || meta.len() != size {
+                bail!("expected a {size} byte file, found {}", describe(&meta));
+            }
+            let file = File::open(&path).with_context(|| format!("open {}", path.display()))?;
+            let mut reader = Hashing::new(file);
+            let mut header = header(EntryType::Regular, mode(&meta), size);
+            tar.append_data(&mut header, &name, &mut reader)?;
+            let (got, len, _) = reader.finish();
+            if len != size || got != hash {
+                bail!("content does not match the manifest (sha256 {got}, expected {hash})");
+            }
+        }
+        Item::Link(target) => {
+            // Compared as the deploy wrote it, normalized.
+            let want = validate_symlink_target(rel, target)?;
+            let found = fs::read_link(&path)
+                .with_context(|| format!("expected a symlink, found {}", describe(&meta)))?;
+            if found != want {
+                bail!(
+                    "symlink points to {}, expected {}",
+                    found.display(),
+                    want.display()
+                );
+            }
+            let mut header = header(EntryType::Symlink, 0o777, 0);
+            tar.append_link(&mut header, &name, &want)?;
+        }
+    }
+    Ok(())
+}
+
+fn header(kind: EntryType, mode: u32, size: u64) -> Header {
+    let mut header = Header::new_gnu();
+    header.set_entry_type(kind);
+    header.set_mode(mode);
+    header.set_size(size);
+    header.set_mtime(0);
+    header.set_uid(0);
+    header.set_gid(0);
+    header
+}
+
+#[cfg(unix)]
+fn mode(meta: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn mode(meta: &fs::Metadata) -> u32 {
+    if meta.is_dir() {
+        0o755
+    } else {
+        0o644
+    }
+}
+
+fn describe(meta: &fs::Metadata) -> String {
+    if meta.is_dir() {
+        "a directory".into()
+    } else if meta.is_symlink() {
+        "a symlink".into()
+    } else {
+        format!("{} bytes", meta.len())
+    }
+}
@@ -0,0 +1,258 @@
+//! `--prefix`, `--only` and `--exclude`: deploy the part of a manifest whose
+//! paths match.
+//!
+//! `--prefix <dir>` comes first: it keeps the entries under `dir` and strips
+//! `dir/` from their paths, so the snapshot holds that directory's contents.
+//! The other rules then see the stripped paths.
+//!
+//! Rules are tried in command-line order and the first whose glob matches a
+//! path decides it. A path no rule matches is kept, unless there is any
+//! `--only` rule. Globs match whole normalized paths: `*` and `?` stay within
+//! one path component, `**` crosses them, and `**/` also matches no
+//! directories at all. A leading `/` is ignored, so `/kiosk/**` and
+//! `kiosk/**` are the same rule.
+
+use anyhow::{bail, Result};
+use clap::ArgMatches;
+
+use crate::manifest::validate_symlink_target;
+use crate::{validate_rel_path, Manifest};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Rule {
+    Only(String),
+    Exclude(String),
+}
+
+impl Rule {
+    /// How the rule is written in logs and the manifest record.
+    fn describe(&self) -> String {
+        match self {
+            Rule::Only(glob) => format!("only {glob}"),
+            Rule::Exclude(glob) => format!("exclude {glob}"),
+        }
+    }
+}
+
+/// Parses one `--only`/`--exclude` value into the form rules match on.
+pub fn parse_glob(s: &str) -> Result<String> {
+    let glob = s.trim().trim_start_matches('/');
+    if glob.is_empty() {
+        bail!("empty glob");
+    }
+    Ok(glob.to_string())
+}
+
+/// Parses `--prefix` into a normalized relative directory, `daily/2024`. As
+/// with globs, a leading `/` is ignored.
+pub fn parse_prefix(s: &str) -> Result<String> {
+    let dir = validate_rel_path(s.trim().trim_matches('/'))?;
+    Ok(dir.to_string_lossy().into_owned())
+}
+
+/// The `--only` and `--exclude` rules in the order they were given.
+pub fn rules(matches: &ArgMatches) -> Vec<Rule> {
+    let mut rules: Vec<(usize, Rule)> = Vec::new();
+    for (id, rule) in [
+        ("only", Rule::Only as fn(String) -> Rule),
+        ("exclude", Rule::Exclude),
+    ] {
+        let (Some(indices), Some(globs)) = (matches.indices_of(id), matches.get_many::<String>(id))
+        else {
+            continue;
+        };
+        rules.extend(indices.zip(globs.map(|glob| rule(glob.clone()))));
+    }
+    rules.sort_by_key(|(index, _)| *index);
+    rules.into_iter().map(|(_, rule)| rule).collect()
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PathFilter {
+    prefix: Option<String>,
+    rules: Vec<Rule>,
+}
+
+impl PathFilter {
+    pub fn new(prefix: Option<String>, rules: Vec<Rule>) -> Self {
+        Self { prefix, rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.prefix.is_none() && self.rules.is_empty()
+    }
+
+    /// Whether `manifest` was cut down by exactly these settings, so a record
+    /// of it stands for what this filter makes of the same version.
+    pub fn made(&self, manifest: &Manifest) -> bool {
+        manifest.prefix == self.prefix && manifest.filters == self.describe()
+    }
+
+    /// The rules as recorded with a manifest filtered by them.
+    pub fn describe(&self) -> Vec<String> {
+        self.rules.iter().map(Rule::describe).collect()
+    }
+
+    pub fn keeps(&self, path: &str) -> bool {
+        let path = validate_rel_path(path)
+            .map(|p| p.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| path.to_string());
+        for rule in &self.rules {
+            match rule {
+                Rule::Only(glob) if glob_match(glob.as_bytes(), path.as_bytes()) => return true,
+                Rule::Exclude(glob) if glob_match(glob.as_bytes(), path.as_bytes()) => {
+                    return false
+                }
+                _ => {}
+            }
+        }
+        !self.rules.iter().any(|rule| matches!(rule, Rule::Only(_)))
+    }
+
+    /// Cuts `manifest` down to the prefix and drops the files and symlinks
+    /// the rules leave out, noting both in `manifest.prefix` and
+    /// `manifest.filters`. Returns how many entries were dropped, and the
+    /// symlinks among them that were under the prefix but point out of it.
+    pub fn apply(&self, manifest: &mut Manifest) -> (usize, Vec<String>) {
+        manifest.prefix = self.prefix.clone();
+        manifest.filters = self.describe();
+        let before = manifest.files.len() + manifest.symlinks.len();
+        let mut escaping = Vec::new();
+        if let Some(prefix) = &self.prefix {
+            manifest
+                .files
+                .retain_mut(|file| match strip(prefix, &file.path) {
+                    Some(rest) => {
+                        file.path = rest;
+                        true
+                    }
+                    None => false,
+                });
+            manifest.symlinks.retain_mut(|link| {
+                let Some(rest) = strip(prefix, &link.path) else {
+                    return false;
+                };
+                if validate_symlink_target(std::path::Path::new(&rest), &link.target).is_err() {
+                    escaping.push(link.path.clone());
+                    return false;
+                }
+                link.path = rest;
+                true
+            });
+        }
+        if !self.rules.is_empty() {
+            manifest.files.retain(|file| self.keeps(&file.path));
+            manifest.symlinks.retain(|link| self.keeps(&link.path));
+        }
+        (
+            before - manifest.files.len() - manifest.symlinks.len(),
+            escaping,
+        )
+    }
+}
+
+/// `path` relative to `prefix`, if it is inside it.
+fn strip(prefix: &str, path: &str) -> Option<String> {
+    let path = validate_rel_path(path).ok()?;
+    let rest = path.strip_prefix(prefix).ok()?;
+    (!rest.as_os_str().is_empty()).then(|| rest.to_string_lossy().into_owned())
+}
+
+fn glob_match(glob: &[u8], path: &[u8]) -> bool {
+    match glob {
+        [] => path.is_empty(),
+        [b'*', b'*', b'/', rest @ ..] => {
+            // No directories, or one or more whole ones.
+            glob_match(rest, path)
+                || path
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, &b)| b == b'/')
+                    .any(|(i, _)| glob_match(rest, &path[i + 1..]))
+        }
+        [b'*', b'*', rest @ ..] => (0..=path.len()).any(|i| glob_match(rest, &path[i..])),
+        [b'*', rest @ ..] => {
+            let component = path.iter().position(|&b| b == b'/').unwrap_or(path.len());
+            (0..=component).any(|i| glob_match(rest, &path[i..]))
+        }
+        [b'?', rest @ ..] => match path {
+            [first, tail @ ..] if *first != b'/' => glob_match(rest, tail),
+            _ => false,
+        },
+        [c, rest @ ..] => match path {
+            [first, tail @ ..] if first == c => glob_match(rest, tail),
+            _ => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs_respect_path_components() {
+        let yes = |glob: &str, path: &str| glob_match(glob.as_bytes(), path.as_bytes());
+        assert!(yes("kiosk/**", "kiosk/index.html"));
+        assert!(yes("kiosk/**", "kiosk/a/b/c.js"));
+        assert!(!yes("kiosk/**", "kiosks/index.html"));
+        assert!(yes("*.html", "index.html"));
+        assert!(!yes("*.html", "kiosk/index.html"));
+        assert!(yes("**/*.mp4", "intro.mp4"));
+        assert!(yes("**/*.mp4", "archive/2024/intro.mp4"));
+        assert!(yes("assets/?.css", "assets/a.css"));
+        assert!(!yes("assets/?.css", "assets/ab.css"));
+        assert!(yes("archive/**/big.bin", "archive/big.bin"));
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let filter = PathFilter::new(
+            None,
+            vec![
+                Rule::Exclude("kiosk/video/**".into()),
+                Rule::Only("kiosk/**".into()),
+                Rule::Only("assets/**".into()),
+            ],
+        );
+        assert!(filter.keeps("kiosk/index.html"));
+        assert!(filter.keeps("assets//app.css"));
+        assert!(!filter.keeps("kiosk/video/loop.mp4"));
+        assert!(!filter.keeps("archive/2024.tar"));
+
+        let exclude_only = PathFilter::new(None, vec![Rule::Exclude("archive/**".into())]);
+        assert!(exclude_only.keeps("index.html"));
+        assert!(!exclude_only.keeps("archive/2024.tar"));
+        assert!(PathFilter::default().keeps("anything"));
+    }
+
+    #[test]
+    fn prefix_strips_its_directory_before_the_rules() {
+        let mut manifest: Manifest = serde_json::from_str(&format!(
+            r#"{{"version": "v", "files": [
+                {{"path": "daily/index.html", "hash": "{h}", "size": 1}},
+                {{"path": "daily/2024/a.html", "hash": "{h}", "size": 1}},
+                {{"path": "daily/2024/old.pdf", "hash": "{h}", "size": 1}},
+                {{"path": "dailyish/x.html", "hash": "{h}", "size": 1}},
+                {{"path": "metro/index.html", "hash": "{h}", "size": 1}},
+                {{"path": "daily/today", "symlink": "2024/a.html"}},
+                {{"path": "daily/metro", "symlink": "../metro/index.html"}}
+            ]}}"#,
+            h = "a".repeat(64)
+        ))
+        .unwrap();
+        let filter = PathFilter::new(
+            Some(parse_prefix("/daily/").unwrap()),
+            vec![Rule::Exclude("**/*.pdf".into())],
+        );
+        let (dropped, escaping) = filter.apply(&mut manifest);
+        let paths: Vec<&str> = manifest.files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, ["index.html", "2024/a.html"]);
+        assert_eq!(manifest.symlinks.len(), 1);
+        assert_eq!(manifest.symlinks[0].path, "today");
+        assert_eq!(escaping, ["daily/metro"]);
+        assert_eq!(dropped, 4);
+        assert_eq!(manifest.prefix.as_deref(), Some("daily"));
+        assert!(filter.made(&manifest));
+    }
+}
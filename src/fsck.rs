@@ -0,0 +1,480 @@
+//! `cityfeed-puller fsck`: re-hashes everything under `objects/` and reports
+//! what a deploy would trip over later:
+//!
+//! - `corrupt`: the content no longer matches the hash in the file name
+//!   (bitrot, a torn write).
+//! - `misnamed`: not a sha256 name, or stored outside its layout location.
+//! - `unreadable`: can't be opened, or isn't world-readable, so the web
+//!   server couldn't serve a copy made from it.
+//! - `orphaned-temp`: a partial download no live run owns. The next deploy
+//!   removes these.
+//! - `bad-signature`: with `--manifest-pubkey`, a record under `manifests/`
+//!   with a per-file signature that doesn't verify, or with
+//!   `--require-file-signatures` an unsigned file.
+//!
+//! Hashing runs on `--hash-jobs` threads, one per CPU by default, and the
+//! report comes out the same with any number. `--delete-corrupt` moves corrupt
+//! objects to `objects/.quarantine/` so the next deploy fetches them again.
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::hashing::{map_in_order, Hashing};
+use crate::logger::Logger;
+use crate::progress;
+use crate::signatures::Policy;
+use crate::store::{ObjectStore, MAX_SHARD_DEPTH};
+use crate::{
+    diff, ensure_dir, fsync_dir, lock_root, stale, validate_hash, Args, OutputFormat, EXIT_FAILURE,
+    EXIT_OBJECT_FAILED,
+};
+
+/// Where `--delete-corrupt` moves objects, inside `objects/`.
+pub const QUARANTINE_DIR: &str = ".quarantine";
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct FsckArgs {
+    /// Move corrupt objects into objects/.quarantine/ so they are fetched again.
+    #[arg(long)]
+    pub delete_corrupt: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Kind {
+    Corrupt,
+    Misnamed,
+    Unreadable,
+    OrphanedTemp,
+    BadSignature,
+}
+
+impl Kind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Kind::Corrupt => "corrupt",
+            Kind::Misnamed => "misnamed",
+            Kind::Unreadable => "unreadable",
+            Kind::OrphanedTemp => "orphaned-temp",
+            Kind::BadSignature => "bad-signature",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Problem {
+    kind: Kind,
+    path: PathBuf,
+    detail: String,
+}
+
+/// The `--output json` document.
+#[derive(Debug, Default, Serialize)]
+struct Report {
+    objects_checked: u64,
+    bytes_checked: u64,
+    corrupt: usize,
+    misnamed: usize,
+    unreadable: usize,
+    orphaned_temp: usize,
+    /// Manifest records checked against `--manifest-pubkey`.
+    manifests_checked: u64,
+    bad_signatures: usize,
+    quarantined: usize,
+    problems: Vec<Problem>,
+    elapsed_secs: f64,
+}
+
+impl Report {
+    fn count(&mut self) {
+        let count = |kind| self.problems.iter().filter(|p| p.kind == kind).count();
+        let corrupt = count(Kind::Corrupt);
+        let misnamed = count(Kind::Misnamed);
+        let unreadable = count(Kind::Unreadable);
+        let orphaned_temp = count(Kind::OrphanedTemp);
+        let bad_signatures = count(Kind::BadSignature);
+        self.corrupt = corrupt;
+        self.misnamed = misnamed;
+        self.unreadable = unreadable;
+        self.orphaned_temp = orphaned_temp;
+        self.bad_signatures = bad_signatures;
+    }
+
+    fn damaged(&self) -> bool {
+        self.corrupt + self.misnamed + self.unreadable + self.bad_signatures > 0
+    }
+}
+
+/// An object to hash.
+struct Object {
+    path: PathBuf,
+    hash: String,
+    size: u64,
+}
+
+/// Runs `fsck` on `args.root()` and returns the exit code: 0 when clean,
+/// `EXIT_OBJECT_FAILED` when anything but orphaned temps was found.
+pub fn main(args: &Args, fsck: &FsckArgs, log: &Logger) -> i32 {
+    let started = Instant::now();
+    let signing = Policy {
+        key: args.manifest_pubkey.clone(),
+        require: args.require_file_signatures,
+    };
+    let report = match check(args.root(), fsck, args.hash_jobs(), &signing, log) {
+        Ok(report) => report,
+        Err(err) => {
+            log.error(
+                "fsck_failed",
+                json!({ "error": format!("{err:#}") }),
+                format_args!("fsck failed: {err:#}"),
+            );
+            return EXIT_FAILURE;
+        }
+    };
+    let report = Report {
+        elapsed_secs: started.elapsed().as_secs_f64(),
+        ..report
+    };
+    for problem in &report.problems {
+        log.warn(
+            "fsck_problem",
+            json!({ "kind": problem.kind, "path": &problem.path, "detail": &problem.detail }),
+            format_args!(
+                "{} {}: {}",
+                problem.kind.as_str(),
+                problem.path.display(),
+                problem.detail
+            ),
+        );
+    }
+    log.outcome(
+        "fsck",
+        json!({
+            "objects_checked": report.objects_checked,
+            "bytes_checked": report.bytes_checked,
+            "corrupt": report.corrupt,
+            "misnamed": report.misnamed,
+            "unreadable": report.unreadable,
+            "orphaned_temp": report.orphaned_temp,
+            "manifests_checked": report.manifests_checked,
+            "bad_signatures": report.bad_signatures,
+            "quarantined": report.quarantined,
+        }),
+        format_args!(
+            "checked {} objects ({} bytes): {} corrupt, {} misnamed, {} unreadable, {} orphaned temp{}{}",
+            report.objects_checked,
+            report.bytes_checked,
+            report.corrupt,
+            report.misnamed,
+            report.unreadable,
+            report.orphaned_temp,
+            match report.manifests_checked {
+                0 => String::new(),
+                n => format!("; {} of {n} manifests with bad signatures", report.bad_signatures),
+            },
+            match report.quarantined {
+                0 => String::new(),
+                n => format!("; quarantined {n}"),
+            }
+        ),
+    );
+    if args.output == OutputFormat::Json {
+        match serde_json::to_string(&report) {
+            Ok(doc) => println!("{doc}"),
+            Err(err) => log.error(
+                "summary_failed",
+                json!({ "error": err.to_string() }),
+                format_args!("render summary: {err}"),
+            ),
+        }
+    }
+    if report.damaged() {
+        EXIT_OBJECT_FAILED
+    } else {
+        0
+    }
+}
+
+fn check(
+    root: &Path,
+    fsck: &FsckArgs,
+    jobs: usize,
+    signing: &Policy,
+    log: &Logger,
+) -> Result<Report> {
+    let objects_dir = root.join("objects");
+    if !objects_dir.is_dir() {
+        bail!("no objects dir at {}", objects_dir.display());
+    }
+    // Keeps a deploy from writing objects (or building from them) meanwhile.
+    let _lock = lock_root(root)?;
+    let store = ObjectStore::open(objects_dir).context("read object layout")?;
+
+    let mut report = Report::default();
+    let mut objects = Vec::new();
+    scan(&store, store.dir(), 0, &mut objects, &mut report.problems)?;
+    report.objects_checked = objects.len() as u64;
+    report.bytes_checked = objects.iter().map(|o| o.size).sum();
+
+    let bar = progress::bytes_bar(log, "fsck", report.bytes_checked);
+    let found = map_in_order(&objects, jobs, |object| verify(object, &bar));
+    bar.finish_and_clear();
+    report.problems.extend(found.into_iter().flatten());
+    if signing.key.is_some() {
+        report.manifests_checked =
+            check_signatures(&root.join("manifests"), signing, &mut report.problems)?;
+    }
+    report.problems.sort_by(|a, b| a.path.cmp(&b.path));
+
+    if fsck.delete_corrupt {
+        for problem in report.problems.iter().filter(|p| p.kind == Kind::Corrupt) {
+            quarantine(&store, &problem.path)
+                .with_context(|| format!("quarantine {}", problem.path.display()))?;
+            log.info(
+                "quarantined",
+                json!({ "path": &problem.path }),
+                format_args!("quarantined {}", problem.path.display()),
+            );
+            report.quarantined += 1;
+        }
+    }
+    report.count();
+    Ok(report)
+}
+
+/// Collects the objects to hash under `dir`, reporting anything that isn't
+/// one. `depth` is 1 inside a shard directory.
+fn scan(
+    store: &ObjectStore,
+    dir: &Path,
+    depth: usize,
+    objects: &mut Vec<Object>,
+    problems: &mut Vec<Problem>,
+) -> Result<()> {
+    let mut entries = fs::read_dir(dir)
+        .with_context(|| format!("list {}", dir.display()))?
+        .collect::<io::Result<Vec<_>>>()
+        .with_context(|| format!("list {}", dir.display()))?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let file_type = entry
+            .file_type()
+            .with_context(|| format!("stat {}", path.display()))?;
+        let mut problem = |kind, detail: String| {
+            problems.push(Problem {
+                kind,
+                path: path.clone(),
+                detail,
+            })
+        };
+        if name.starts_with('.') {
+            if stale::is_orphaned_object_temp(&name) {
+                problem(
+                    Kind::OrphanedTemp,
+                    "partial download from a run that is gone".into(),
+                );
+            }
+            // `.layout`, `.quarantine` and live downloads.
+            continue;
+        }
+        if file_type.is_dir() {
+            if depth < MAX_SHARD_DEPTH {
+                scan(store, &path, depth + 1, objects, problems)?;
+            } else {
+                problem(Kind::Misnamed, "unexpected directory".into());
+            }
+            continue;
+        }
+        if !file_type.is_file() {
+            problem(Kind::Misnamed, "not a regular file".into());
+            continue;
+        }
+        if let Err(err) = validate_hash(&name) {
+            problem(Kind::Misnamed, format!("not an object name: {err}"));
+            continue;
+        }
+        if store.path(&name) != path {
+            problem(
+                Kind::Misnamed,
+                format!("belongs at {}", store.path(&name).display()),
+            );
+            continue;
+        }
+        let size = entry
+            .metadata()
+            .with_context(|| format!("stat {}", path.display()))?
+            .len();
+        objects.push(Object {
+            path,
+            hash: name,
+            size,
+        });
+    }
+    Ok(())
+}
+
+/// Checks every record in `manifests_dir` against `signing`; returns how
+/// many there were.
+fn check_signatures(
+    manifests_dir: &Path,
+    signing: &Policy,
+    problems: &mut Vec<Problem>,
+) -> Result<u64> {
+    let entries = match fs::read_dir(manifests_dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err).with_context(|| format!("list {}", manifests_dir.display())),
+    };
+    let mut checked = 0;
+    for entry in entries {
+        let path = entry
+            .with_context(|| format!("list {}", manifests_dir.display()))?
+            .path();
+        let Some(version) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(".json"))
+            .filter(|version| !version.starts_with('.'))
+        else {
+            continue;
+        };
+        checked += 1;
+        let checked = diff::load(manifests_dir, version)
+            .and_then(|manifest| signing.check(&manifest.context("record vanished")?));
+        if let Err(err) = checked {
+            problems.push(Problem {
+                kind: Kind::BadSignature,
+                path,
+                detail: format!("{err:#}"),
+            });
+        }
+    }
+    Ok(checked)
+}
+
+fn verify(object: &Object, bar: &indicatif::ProgressBar) -> Vec<Problem> {
+    let problem = |kind, detail: String| Problem {
+        kind,
+        path: object.path.clone(),
+        detail,
+    };
+    let mut problems = Vec::new();
+    #[cfg(unix)]
+    if let Ok(meta) = fs::metadata(&object.path) {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = meta.permissions().mode() & 0o7777;
+        if mode & 0o004 == 0 {
+            problems.push(problem(
+                Kind::Unreadable,
+                format!("not world-readable (mode {mode:04o})"),
+            ));
+        }
+    }
+    let hashed = File::open(&object.path).and_then(|file| {
+        let mut reader = Hashing::new(bar.wrap_read(file));
+        io::copy(&mut reader, &mut io::sink())?;
+        Ok(reader.finish().0)
+    });
+    match hashed {
+        Ok(hash) if hash == object.hash => {}
+        Ok(hash) => problems.push(problem(Kind::Corrupt, format!("content hashes to {hash}"))),
+        Err(err) => problems.push(problem(Kind::Unreadable, err.to_string())),
+    }
+    problems
+}
+
+/// Moves an object into `objects/.quarantine/`, replacing any earlier copy.
+pub fn quarantine(store: &ObjectStore, path: &Path) -> Result<()> {
+    let dir = store.dir().join(QUARANTINE_DIR);
+    ensure_dir(&dir)?;
+    let name = path.file_name().context("object has no file name")?;
+    fs::rename(path, dir.join(name))?;
+    fsync_dir(&dir)?;
+    if let Some(parent) = path.parent() {
+        fsync_dir(parent)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hashing::sha256;
+    use crate::store::ObjectLayout;
+
+    #[test]
+    fn one_thread_and_many_report_the_same_problems() {
+        let root = tempfile::tempdir().unwrap();
+        let objects = root.path().join("objects");
+        fs::create_dir(&objects).unwrap();
+        for i in 0..40u32 {
+            let body = format!("object {i}").repeat(1000);
+            let name = sha256(body.as_bytes());
+            // Every seventh one rotted after it was stored.
+            let stored = if i % 7 == 3 {
+                body.replace('o', "0")
+            } else {
+                body
+            };
+            fs::write(objects.join(name), stored).unwrap();
+        }
+        fs::write(objects.join("stray"), b"?").unwrap();
+        let log = Logger::new(crate::logger::LogFormat::Text, -1);
+        let fsck = FsckArgs {
+            delete_corrupt: false,
+        };
+
+        let serial = check(root.path(), &fsck, 1, &Policy::default(), &log).unwrap();
+        assert_eq!(serial.objects_checked, 40);
+        assert_eq!(serial.corrupt, 6);
+        assert_eq!(serial.misnamed, 1);
+        let serial = serde_json::to_string(&serial).unwrap();
+        for jobs in [2, 8, 64] {
+            let parallel = check(root.path(), &fsck, jobs, &Policy::default(), &log).unwrap();
+            assert_eq!(serde_json::to_string(&parallel).unwrap(), serial, "{jobs}");
+        }
+    }
+
+    #[test]
+    fn scan_sorts_objects_from_strays() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ObjectStore::new(dir.path().to_path_buf(), ObjectLayout::Flat);
+        let good = sha256(b"good");
+        fs::write(dir.path().join(&good), b"good").unwrap();
+        fs::write(dir.path().join("README"), b"?").unwrap();
+        fs::create_dir(dir.path().join("ab")).unwrap();
+        fs::write(dir.path().join("ab").join(sha256(b"x")), b"x").unwrap();
+        fs::write(dir.path().join(".tmp-999999999-abc"), b"").unwrap();
+        fs::write(dir.path().join(".layout"), b"flat\n").unwrap();
+
+        let (mut objects, mut problems) = (Vec::new(), Vec::new());
+        scan(&store, dir.path(), 0, &mut objects, &mut problems).unwrap();
+        assert_eq!(objects.len(), 1);
+        assert_eq!(objects[0].hash, good);
+        let kinds: Vec<_> = problems
+            .iter()
+            .map(|p| {
+                (
+                    p.kind,
+                    p.path.file_name().unwrap().to_string_lossy().into_owned(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                (Kind::OrphanedTemp, ".tmp-999999999-abc".to_string()),
+                (Kind::Misnamed, "README".to_string()),
+                (Kind::Misnamed, sha256(b"x")),
+            ]
+        );
+    }
+}
@@ -0,0 +1,110 @@
+//! sha256 over bytes as they stream past, for checks that can't wait for a
+//! second read of what was written.
+
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use sha2::{Digest, Sha256};
+
+/// sha256 of everything read through it, as lowercase hex.
+pub struct Hashing<R> {
+    inner: R,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl<R> Hashing<R> {
+    pub fn new(inner: R) -> Self {
+        Self::resume(inner, Sha256::new())
+    }
+
+    /// Continues a digest that already covers earlier bytes.
+    pub fn resume(inner: R, hasher: Sha256) -> Self {
+        Self {
+            inner,
+            hasher,
+            len: 0,
+        }
+    }
+
+    /// Digest state so far, to `resume` from later.
+    pub fn state(&self) -> Sha256 {
+        self.hasher.clone()
+    }
+
+    /// Hex digest and byte count, plus the wrapped reader.
+    pub fn finish(self) -> (String, u64, R) {
+        (hex(self.hasher), self.len, self.inner)
+    }
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(out)?;
+        self.hasher.update(&out[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+}
+
+pub fn hex(hasher: Sha256) -> String {
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// `f` over `items` on up to `jobs` threads, with the results in `items`
+/// order whatever finished first, so reports don't depend on scheduling.
+pub fn map_in_order<T: Sync, R: Send>(
+    items: &[T],
+    jobs: usize,
+    f: impl Fn(&T) -> R + Sync,
+) -> Vec<R> {
+    let next = AtomicUsize::new(0);
+    let done = Mutex::new(Vec::with_capacity(items.len()));
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, items.len().max(1)) {
+            scope.spawn(|| {
+                let mut mine = Vec::new();
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(item) = items.get(i) else {
+                        break;
+                    };
+                    mine.push((i, f(item)));
+                }
+                done.lock().unwrap_or_else(|e| e.into_inner()).extend(mine);
+            });
+        }
+    });
+    let mut done = done.into_inner().unwrap_or_else(|e| e.into_inner());
+    done.sort_by_key(|(i, _)| *i);
+    done.into_iter().map(|(_, r)| r).collect()
+}
+
+#[cfg(test)]
+pub fn sha256(bytes: &[u8]) -> String {
+    hex(Sha256::new_with_prefix(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digest_matches_known_value_and_can_resume() {
+        let abc = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert_eq!(sha256(b"abc"), abc);
+
+        let mut first = Hashing::new(&b"a"[..]);
+        io::copy(&mut first, &mut io::sink()).unwrap();
+        let mut rest = Hashing::resume(&b"bc"[..], first.state());
+        io::copy(&mut rest, &mut io::sink()).unwrap();
+        let (hex, len, _) = rest.finish();
+        assert_eq!((hex.as_str(), len), (abc, 2));
+    }
+}
@@ -0,0 +1,194 @@
+//! Extra request headers: repeatable `--header "Name: value"` for every
+//! origin, and `[origin_headers."<origin>"]` tables in the config file that
+//! replace same-named headers for one origin.
+//!
+//! The `--header` set is installed as default headers on the origin client
+//! only; webhook receivers and canary checks never see it. Values of headers
+//! whose names look secret (authorization, tokens, cookies, keys) are shown
+//! as `<redacted>` in logs and `Debug` output.
+
+use std::fmt;
+
+use anyhow::{bail, Result};
+use reqwest::blocking::RequestBuilder;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+
+/// Name fragments that mark a header value as a secret.
+const SENSITIVE: &[&str] = &["auth", "token", "cookie", "secret", "key", "password"];
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Header {
+    pub name: HeaderName,
+    pub value: HeaderValue,
+}
+
+impl fmt::Debug for Header {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.name, self.shown())
+    }
+}
+
+impl Header {
+    pub fn new(name: &str, value: &str) -> Result<Self> {
+        let name = name.trim();
+        let value = value.trim();
+        let Ok(header_name) = HeaderName::from_bytes(name.as_bytes()) else {
+            bail!("invalid header name {name:?}");
+        };
+        let Ok(header_value) = HeaderValue::from_str(value) else {
+            if is_sensitive(name) {
+                bail!("invalid value for header {name}");
+            }
+            bail!("invalid value {value:?} for header {name}");
+        };
+        Ok(Self {
+            name: header_name,
+            value: header_value,
+        })
+    }
+
+    /// The value as it may appear in logs.
+    fn shown(&self) -> &str {
+        if is_sensitive(self.name.as_str()) {
+            "<redacted>"
+        } else {
+            self.value.to_str().unwrap_or("<binary>")
+        }
+    }
+}
+
+/// Parses `--header "Name: value"`.
+pub fn parse(s: &str) -> Result<Header> {
+    let Some((name, value)) = s.split_once(':') else {
+        bail!("expected \"Name: value\", got {s:?}");
+    };
+    Header::new(name, value)
+}
+
+pub fn is_sensitive(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    SENSITIVE.iter().any(|part| name.contains(part))
+}
+
+/// `headers` as a client's default headers; a repeated name keeps its last value.
+pub fn default_map(headers: &[Header]) -> HeaderMap {
+    let mut map = HeaderMap::new();
+    for header in headers {
+        map.insert(header.name.clone(), header.value.clone());
+    }
+    map
+}
+
+/// Which headers go to which origin.
+#[derive(Clone, Debug, Default)]
+pub struct Headers {
+    /// From `--header`; already on every request as the client's defaults.
+    pub default: Vec<Header>,
+    /// Normalized origin -> headers that replace same-named defaults.
+    pub per_origin: Vec<(String, Vec<Header>)>,
+}
+
+impl Headers {
+    fn for_origin(&self, origin: &str) -> &[Header] {
+        self.per_origin
+            .iter()
+            .find(|(o, _)| o == origin)
+            .map_or(&[], |(_, headers)| headers)
+    }
+
+    /// Adds `origin`'s own headers to a request. The client only fills in
+    /// defaults whose names the request doesn't already carry.
+    pub fn apply(&self, origin: &str, req: RequestBuilder) -> RequestBuilder {
+        self.for_origin(origin).iter().fold(req, |req, header| {
+            req.header(header.name.clone(), header.value.clone())
+        })
+    }
+
+    /// Every extra header a request to `origin` carries, secrets redacted,
+    /// for `-v` logs; `None` without any.
+    pub fn describe(&self, origin: &str) -> Option<String> {
+        let own = self.for_origin(origin);
+        let defaults = default_map(&self.default);
+        let shown: Vec<String> = defaults
+            .iter()
+            .filter(|(name, _)| !own.iter().any(|h| h.name == **name))
+            .map(|(name, value)| Header {
+                name: name.clone(),
+                value: value.clone(),
+            })
+            .chain(own.iter().cloned())
+            .map(|header| format!("{header:?}"))
+            .collect();
+        (!shown.is_empty()).then(|| shown.join(", "))
+    }
+
+    /// `text` with every secret header value replaced, for text that came
+    /// back from an origin.
+    pub fn redact(&self, text: &str) -> String {
+        self.default
+            .iter()
+            .chain(self.per_origin.iter().flat_map(|(_, headers)| headers))
+            .filter(|header| is_sensitive(header.name.as_str()))
+            .filter_map(|header| header.value.to_str().ok())
+            .filter(|value| !value.is_empty())
+            .fold(text.to_string(), |text, secret| {
+                text.replace(secret, "<redacted>")
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rejects_bad_names_and_values_quoting_them() {
+        let header = parse("X-Org-Token:  abc123 ").unwrap();
+        assert_eq!(header.name, "x-org-token");
+        assert_eq!(header.value, "abc123");
+
+        let err = parse("no colon here").unwrap_err().to_string();
+        assert_eq!(err, r#"expected "Name: value", got "no colon here""#);
+        let err = parse("Bad Name: x").unwrap_err().to_string();
+        assert_eq!(err, r#"invalid header name "Bad Name""#);
+        let err = parse("Accept: a\u{7f}b").unwrap_err().to_string();
+        assert_eq!(err, r#"invalid value "a\u{7f}b" for header Accept"#);
+        // A secret's value is never repeated, even when it's the bad part.
+        let err = parse("X-Api-Key: s3cret\u{7f}").unwrap_err().to_string();
+        assert_eq!(err, "invalid value for header X-Api-Key");
+    }
+
+    #[test]
+    fn per_origin_headers_replace_defaults_and_secrets_are_redacted() {
+        let headers = Headers {
+            default: vec![
+                parse("X-Org-Token: org-s3cret").unwrap(),
+                parse("Accept: application/json").unwrap(),
+            ],
+            per_origin: vec![(
+                "https://b.example".into(),
+                vec![parse("X-Org-Token: b-s3cret").unwrap()],
+            )],
+        };
+        assert_eq!(
+            headers.describe("https://a.example").unwrap(),
+            "x-org-token: <redacted>, accept: application/json"
+        );
+        assert_eq!(
+            headers.describe("https://b.example").unwrap(),
+            "accept: application/json, x-org-token: <redacted>"
+        );
+        assert!(Headers::default().describe("https://a.example").is_none());
+        let debug = format!("{headers:?}");
+        assert!(!debug.contains("s3cret"), "{debug}");
+        assert_eq!(
+            headers.redact("rejected org-s3cret and b-s3cret for application/json"),
+            "rejected <redacted> and <redacted> for application/json"
+        );
+
+        for name in ["Authorization", "Cookie", "X-Api-Key", "X-Auth-Token"] {
+            assert!(is_sensitive(name), "{name}");
+        }
+        assert!(!is_sensitive("Accept"));
+    }
+}
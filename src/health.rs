@@ -0,0 +1,430 @@
+//! Per-origin success/failure bookkeeping so a dead or flaky mirror stops
+//! being tried first for every object.
+//!
+//! After `DEMOTE_AFTER` consecutive failures an origin is demoted for
+//! `COOLDOWN`: it is still tried, but only after every healthy origin. Once
+//! the cooldown expires it gets its normal place back for one probe; a
+//! success clears the streak, another failure demotes it again.
+//!
+//! Each origin also has an error budget per run: `--origin-error-budget`
+//! failed requests, or `--origin-time-budget` spent in failed requests,
+//! whichever runs out first. Then its circuit opens and it is left out of
+//! every request for `--circuit-cooldown`. After that the next request to
+//! ask for an order takes the one probe (half-open), and concurrent requests
+//! keep skipping the origin until it is answered: a success closes the
+//! circuit with a fresh budget, a failure opens it for another cooldown. A
+//! probe that never gets answered is handed out again after a cooldown.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::logger::Logger;
+
+const DEMOTE_AFTER: u32 = 3;
+const COOLDOWN: Duration = Duration::from_secs(30);
+
+/// `--origin-error-budget`, `--origin-time-budget` and `--circuit-cooldown`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Budget {
+    pub failures: u32,
+    pub wasted: Duration,
+    pub cooldown: Duration,
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Self {
+            failures: 10,
+            wasted: Duration::from_secs(60),
+            cooldown: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Circuit {
+    /// Tried as usual.
+    #[default]
+    Closed,
+    /// Out of budget; skipped until the cooldown ends.
+    Open,
+    /// Cooldown over; one request is probing it and decides.
+    HalfOpen,
+}
+
+impl Circuit {
+    fn as_str(self) -> &'static str {
+        match self {
+            Circuit::Closed => "closed",
+            Circuit::Open => "open",
+            Circuit::HalfOpen => "half-open",
+        }
+    }
+}
+
+/// One change of an origin's circuit, for the run's stats.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Transition {
+    pub origin: String,
+    pub from: Circuit,
+    pub to: Circuit,
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct OriginStats {
+    pub origin: String,
+    pub requests: u64,
+    pub failures: u64,
+    /// Seconds spent in requests that failed.
+    pub wasted_secs: f64,
+    pub circuit: Circuit,
+    #[serde(skip)]
+    consecutive_failures: u32,
+    #[serde(skip)]
+    demoted_until: Option<Instant>,
+    /// Failures and wasted time counted against the budget since the
+    /// circuit last closed.
+    #[serde(skip)]
+    spent: (u32, Duration),
+    /// While open, the end of the cooldown; while half-open, when the
+    /// probe is handed out again if nobody has answered it.
+    #[serde(skip)]
+    open_until: Option<Instant>,
+}
+
+#[derive(Default)]
+pub struct OriginHealth {
+    budget: Budget,
+    stats: Mutex<HashMap<String, OriginStats>>,
+    last_failed: Mutex<Option<String>>,
+    failovers: AtomicU64,
+    transitions: Mutex<Vec<Transition>>,
+}
+
+impl OriginHealth {
+    pub fn new(budget: Budget) -> Self {
+        Self {
+            budget,
+            ..Self::default()
+        }
+    }
+
+    /// `origins` reordered so demoted ones come last; order is otherwise
+    /// kept. Origins whose circuit is open, or half-open with the probe
+    /// handed to another request, are left out. An origin whose cooldown is
+    /// over goes half-open here, under the lock, so only this caller gets it.
+    pub fn order(&self, log: &Logger, origins: &[String]) -> Vec<String> {
+        let mut stats = self.stats.lock().unwrap();
+        let now = Instant::now();
+        let mut probing = Vec::new();
+        for origin in origins {
+            let Some(entry) = stats.get_mut(origin) else {
+                continue;
+            };
+            let cooled = entry.open_until.is_some_and(|until| until <= now);
+            if entry.circuit == Circuit::Closed || !cooled {
+                continue;
+            }
+            entry.open_until = Some(now + self.budget.cooldown);
+            probing.push(origin);
+            if entry.circuit == Circuit::Open {
+                self.transition(
+                    log,
+                    entry,
+                    Circuit::HalfOpen,
+                    "cooldown over; probing".into(),
+                );
+            }
+        }
+        let demoted = |origin: &String| {
+            stats
+                .get(origin)
+                .and_then(|s| s.demoted_until)
+                .is_some_and(|until| until > now)
+        };
+        let open = |origin: &String| {
+            stats
+                .get(origin)
+                .and_then(|s| s.open_until)
+                .is_some_and(|until| until > now)
+        };
+        let (mut healthy, sick): (Vec<String>, Vec<String>) = origins
+            .iter()
+            .filter(|o| !open(o) || probing.contains(o))
+            .cloned()
+            .partition(|o| !demoted(o));
+        healthy.extend(sick);
+        healthy
+    }
+
+    /// Why no origin of `origins` is left to try: every circuit is open, or
+    /// there were none to begin with.
+    pub fn none_left(&self, origins: &[String]) -> anyhow::Error {
+        if origins.is_empty() {
+            return anyhow!("no origins configured");
+        }
+        self.all_open(origins)
+            .unwrap_or_else(|| anyhow!("no origin left to try"))
+    }
+
+    /// With every circuit among `origins` open, an error that says so and
+    /// what each origin used up.
+    pub fn all_open(&self, origins: &[String]) -> Option<anyhow::Error> {
+        let stats = self.stats.lock().unwrap();
+        let now = Instant::now();
+        let spent: Option<Vec<String>> = origins
+            .iter()
+            .map(|origin| {
+                let s = stats.get(origin)?;
+                s.open_until.filter(|until| *until > now)?;
+                Some(format!(
+                    "{origin} ({} failures, {:.1}s wasted)",
+                    s.failures, s.wasted_secs
+                ))
+            })
+            .collect();
+        let spent = spent.filter(|spent| !spent.is_empty())?;
+        Some(anyhow!(
+            "every origin's circuit breaker is open: {}",
+            spent.join(", ")
+        ))
+    }
+
+    /// Records one request to `origin` that took `took`.
+    pub fn record(&self, log: &Logger, origin: &str, ok: bool, took: Duration) {
+        let mut stats = self.stats.lock().unwrap();
+        let entry = stats
+            .entry(origin.to_string())
+            .or_insert_with(|| OriginStats {
+                origin: origin.to_string(),
+                ..OriginStats::default()
+            });
+        entry.requests += 1;
+        if ok {
+            entry.consecutive_failures = 0;
+            entry.demoted_until = None;
+            if entry.circuit == Circuit::HalfOpen {
+                entry.spent = (0, Duration::ZERO);
+                entry.open_until = None;
+                self.transition(log, entry, Circuit::Closed, "probe succeeded".into());
+            }
+            return;
+        }
+        entry.failures += 1;
+        entry.wasted_secs += took.as_secs_f64();
+        entry.consecutive_failures += 1;
+        entry.spent.0 += 1;
+        entry.spent.1 += took;
+        *self.last_failed.lock().unwrap() = Some(origin.to_string());
+        let reason = match entry.circuit {
+            Circuit::Open => None,
+            Circuit::HalfOpen => Some("probe failed".to_string()),
+            _ if entry.spent.0 >= self.budget.failures => {
+                Some(format!("{} failed requests", entry.spent.0))
+            }
+            _ if entry.spent.1 >= self.budget.wasted => Some(format!(
+                "{:.1}s spent in failed requests",
+                entry.spent.1.as_secs_f64()
+            )),
+            _ => None,
+        };
+        if let Some(reason) = reason {
+            entry.open_until = Some(Instant::now() + self.budget.cooldown);
+            self.transition(log, entry, Circuit::Open, reason);
+            return;
+        }
+        if entry.consecutive_failures >= DEMOTE_AFTER {
+            entry.demoted_until = Some(Instant::now() + COOLDOWN);
+            log.warn(
+                "origin_demoted",
+                json!({
+                    "origin": origin,
+                    "consecutive_failures": entry.consecutive_failures,
+                    "cooldown_secs": COOLDOWN.as_secs(),
+                }),
+                format_args!(
+                    "origin {origin} demoted for {COOLDOWN:?} after {} consecutive failures",
+                    entry.consecutive_failures
+                ),
+            );
+        }
+    }
+
+    fn transition(&self, log: &Logger, entry: &mut OriginStats, to: Circuit, reason: String) {
+        let transition = Transition {
+            origin: entry.origin.clone(),
+            from: entry.circuit,
+            to,
+            reason,
+        };
+        entry.circuit = to;
+        let text = format_args!(
+            "origin {} circuit {} -> {}: {}{}",
+            transition.origin,
+            transition.from.as_str(),
+            to.as_str(),
+            transition.reason,
+            match to {
+                Circuit::Open => format!("; skipping it for {:?}", self.budget.cooldown),
+                _ => String::new(),
+            }
+        );
+        match to {
+            Circuit::Open => log.warn("origin_circuit", json!(&transition), text),
+            _ => log.info("origin_circuit", json!(&transition), text),
+        }
+        self.transitions.lock().unwrap().push(transition);
+    }
+
+    /// Takes the run's circuit transitions, and gives every origin whose
+    /// circuit is closed a fresh budget for the next run.
+    pub fn end_run(&self) -> Vec<Transition> {
+        for entry in self.stats.lock().unwrap().values_mut() {
+            if entry.circuit == Circuit::Closed {
+                entry.spent = (0, Duration::ZERO);
+            }
+        }
+        std::mem::take(&mut self.transitions.lock().unwrap())
+    }
+
+    /// The origin whose request failed most recently.
+    pub fn last_failed(&self) -> Option<String> {
+        self.last_failed.lock().unwrap().clone()
+    }
+
+    /// Counts a request that succeeded on another origin after one failed.
+    pub fn failed_over(&self) {
+        self.failovers.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Failovers since the last call, so each watch cycle reports its own.
+    pub fn take_failovers(&self) -> u64 {
+        self.failovers.swap(0, Ordering::Relaxed)
+    }
+
+    /// Counters for every origin that has been contacted, in `origins` order.
+    pub fn snapshot(&self, origins: &[String]) -> Vec<OriginStats> {
+        let stats = self.stats.lock().unwrap();
+        origins
+            .iter()
+            .filter_map(|o| stats.get(o).cloned())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logger::LogFormat;
+
+    #[test]
+    fn demotes_after_consecutive_failures_and_recovers_on_success() {
+        let log = Logger::new(LogFormat::Text, -1);
+        let health = OriginHealth::default();
+        let origins = vec!["a".to_string(), "b".to_string()];
+
+        for _ in 0..DEMOTE_AFTER - 1 {
+            health.record(&log, "a", false, Duration::ZERO);
+        }
+        assert_eq!(health.order(&log, &origins), origins);
+        health.record(&log, "a", false, Duration::ZERO);
+        assert_eq!(health.order(&log, &origins), vec!["b", "a"]);
+
+        health.record(&log, "a", true, Duration::ZERO);
+        assert_eq!(health.order(&log, &origins), origins);
+
+        let snap = health.snapshot(&origins);
+        assert_eq!(snap.len(), 1);
+        assert_eq!(snap[0].requests, u64::from(DEMOTE_AFTER) + 1);
+        assert_eq!(snap[0].failures, u64::from(DEMOTE_AFTER));
+    }
+
+    #[test]
+    fn circuit_opens_on_either_budget_and_probes_after_the_cooldown() {
+        let log = Logger::new(LogFormat::Text, -1);
+        let health = OriginHealth::new(Budget {
+            failures: 4,
+            wasted: Duration::from_secs(10),
+            cooldown: Duration::from_millis(50),
+        });
+        let origins = vec!["slow".to_string(), "dead".to_string(), "ok".to_string()];
+        // Two timeouts use up the time, four refusals the count.
+        health.record(&log, "slow", false, Duration::from_secs(5));
+        health.record(&log, "slow", false, Duration::from_secs(5));
+        for _ in 0..4 {
+            health.record(&log, "dead", false, Duration::from_millis(1));
+        }
+        health.record(&log, "ok", false, Duration::from_secs(1));
+        assert_eq!(health.order(&log, &origins), vec!["ok"]);
+        assert!(health.all_open(&origins).is_none());
+        let err = health.all_open(&origins[..2]).unwrap().to_string();
+        assert!(
+            err.starts_with("every origin's circuit breaker is open: slow (2 failures, 10.0s"),
+            "{err}"
+        );
+
+        std::thread::sleep(Duration::from_millis(60));
+        // Back for a probe; dead is still demoted behind the rest. Only the
+        // first request gets the probes; the next one skips both until they
+        // are answered.
+        assert_eq!(health.order(&log, &origins), vec!["slow", "ok", "dead"]);
+        assert_eq!(health.order(&log, &origins), vec!["ok"]);
+        health.record(&log, "slow", true, Duration::ZERO);
+        health.record(&log, "dead", false, Duration::ZERO);
+        assert_eq!(health.order(&log, &origins), vec!["slow", "ok"]);
+
+        let steps: Vec<(String, Circuit, Circuit)> = health
+            .end_run()
+            .into_iter()
+            .map(|t| (t.origin, t.from, t.to))
+            .collect();
+        use Circuit::*;
+        assert_eq!(
+            steps,
+            [
+                ("slow".to_string(), Closed, Open),
+                ("dead".to_string(), Closed, Open),
+                ("slow".to_string(), Open, HalfOpen),
+                ("dead".to_string(), Open, HalfOpen),
+                ("slow".to_string(), HalfOpen, Closed),
+                ("dead".to_string(), HalfOpen, Open),
+            ]
+        );
+        assert!(health.end_run().is_empty());
+    }
+
+    #[test]
+    fn unanswered_probe_is_handed_out_again_after_a_cooldown() {
+        let log = Logger::new(LogFormat::Text, -1);
+        let health = OriginHealth::new(Budget {
+            failures: 1,
+            wasted: Duration::from_secs(10),
+            cooldown: Duration::from_millis(50),
+        });
+        let origins = vec!["a".to_string()];
+        health.record(&log, "a", false, Duration::ZERO);
+        assert!(health.order(&log, &origins).is_empty());
+
+        std::thread::sleep(Duration::from_millis(60));
+        // Taken by a request that was served elsewhere and never tried it.
+        assert_eq!(health.order(&log, &origins), origins);
+        assert!(health.order(&log, &origins).is_empty());
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(health.order(&log, &origins), origins);
+        assert!(health.order(&log, &origins).is_empty());
+
+        health.record(&log, "a", true, Duration::ZERO);
+        assert_eq!(health.order(&log, &origins), origins);
+        assert_eq!(health.order(&log, &origins), origins);
+        let steps: Vec<Circuit> = health.end_run().into_iter().map(|t| t.to).collect();
+        assert_eq!(steps, [Circuit::Open, Circuit::HalfOpen, Circuit::Closed]);
+    }
+}
@@ -0,0 +1,158 @@
+//! `<root>/state/history.jsonl`: one JSON line per deploy attempt, so "what
+//! did this box run last Tuesday" can be answered on the box itself.
+//!
+//! Lines are appended and fsynced under the root lock. Once the file holds
+//! `--history-max-entries` lines it is renamed to `history.jsonl.1`
+//! (replacing the previous one) and a fresh file is started, so at most
+//! twice that many entries are kept.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{fsync_dir, state, Summary};
+
+const HISTORY_DIR: &str = "state";
+const HISTORY_FILE: &str = "history.jsonl";
+
+/// One deploy attempt.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    /// When the attempt finished, UTC.
+    pub at: String,
+    /// As in the `--output json` summary: updated, already-current or error.
+    pub outcome: String,
+    pub previous_version: Option<String>,
+    pub version: Option<String>,
+    pub origin: Option<String>,
+    /// `--channel` the manifest was asked for, if it came from one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+    pub objects_downloaded: u64,
+    pub bytes_downloaded: u64,
+    /// `--preserve` paths carried over from the previous snapshot.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub preserved: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Entry {
+    pub fn from_summary(summary: &Summary) -> Self {
+        Self {
+            at: state::utc_now(),
+            outcome: serde_json::to_value(summary.outcome)
+                .ok()
+                .and_then(|v| v.as_str().map(str::to_string))
+                .unwrap_or_default(),
+            previous_version: summary.previous_version.clone(),
+            version: summary.version.clone(),
+            origin: summary.origin.clone(),
+            channel: summary.channel.clone(),
+            objects_downloaded: summary.objects_downloaded,
+            bytes_downloaded: summary.bytes_downloaded,
+            preserved: summary.preserved.clone(),
+            error: (!summary.error.is_empty()).then(|| summary.error.join(": ")),
+        }
+    }
+}
+
+fn path(root: &Path) -> PathBuf {
+    root.join(HISTORY_DIR).join(HISTORY_FILE)
+}
+
+fn rotated(root: &Path) -> PathBuf {
+    root.join(HISTORY_DIR).join(format!("{HISTORY_FILE}.1"))
+}
+
+/// Appends `entry`, first rotating a file that already holds `max_entries`
+/// lines; 0 never rotates.
+pub fn append(root: &Path, entry: &Entry, max_entries: u64) -> Result<()> {
+    let dir = root.join(HISTORY_DIR);
+    fs::create_dir_all(&dir).with_context(|| format!("create {}", dir.display()))?;
+    let path = path(root);
+    let lines = match fs::read(&path) {
+        Ok(bytes) => bytes.iter().filter(|&&b| b == b'\n').count() as u64,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+        Err(err) => return Err(err).with_context(|| format!("read {}", path.display())),
+    };
+    if max_entries > 0 && lines >= max_entries {
+        fs::rename(&path, rotated(root)).with_context(|| format!("rotate {}", path.display()))?;
+    }
+
+    let mut line = serde_json::to_vec(entry).context("encode history entry")?;
+    line.push(b'\n');
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("open {}", path.display()))?;
+    file.write_all(&line)
+        .with_context(|| format!("append to {}", path.display()))?;
+    file.sync_all()
+        .with_context(|| format!("fsync {}", path.display()))?;
+    fsync_dir(&dir).context("fsync history dir")
+}
+
+/// The last `n` entries, oldest first, across the rotated file and the live
+/// one. Lines that don't parse (a torn write) are skipped.
+pub fn recent(root: &Path, n: usize) -> Vec<Entry> {
+    let mut entries: Vec<Entry> = [rotated(root), path(root)]
+        .iter()
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .flat_map(|text| {
+            text.lines()
+                .filter_map(|line| serde_json::from_str(line).ok())
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    let skip = entries.len().saturating_sub(n);
+    entries.drain(..skip);
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(version: &str) -> Entry {
+        Entry {
+            at: "2026-10-16T08:30:00Z".to_string(),
+            outcome: "updated".to_string(),
+            previous_version: None,
+            version: Some(version.to_string()),
+            origin: Some("https://a.example".to_string()),
+            channel: None,
+            objects_downloaded: 1,
+            bytes_downloaded: 10,
+            preserved: Vec::new(),
+            error: None,
+        }
+    }
+
+    #[test]
+    fn rotates_after_max_entries_and_reads_across_both_files() {
+        let root = tempfile::tempdir().unwrap();
+        for version in ["v1", "v2", "v3", "v4", "v5"] {
+            append(root.path(), &entry(version), 2).unwrap();
+        }
+        let live = fs::read_to_string(path(root.path())).unwrap();
+        assert_eq!(live.lines().count(), 1);
+        let old = fs::read_to_string(rotated(root.path())).unwrap();
+        assert_eq!(old.lines().count(), 2);
+
+        let versions: Vec<_> = recent(root.path(), 10)
+            .into_iter()
+            .map(|e| e.version.unwrap())
+            .collect();
+        assert_eq!(versions, ["v3", "v4", "v5"]);
+        assert_eq!(recent(root.path(), 1), [entry("v5")]);
+
+        fs::write(path(root.path()), "{\"at\": tor\n").unwrap();
+        assert_eq!(recent(root.path(), 10).len(), 2);
+        assert!(recent(&root.path().join("missing"), 10).is_empty());
+    }
+}
@@ -0,0 +1,111 @@
+//! `--on-switch` commands, run through the shell after `current` has moved to
+//! a new snapshot (e.g. `systemctl reload nginx`).
+
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use serde_json::json;
+
+use crate::logger::Logger;
+
+/// What a failing `--on-switch` command does to the run's exit code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum HookFailure {
+    /// Log a warning; the run still counts as a successful switch.
+    Warn,
+    /// Exit with the hook-failure code (the switch is not rolled back).
+    Fail,
+}
+
+/// The switch a hook is told about, as `CITYFEED_*` environment variables.
+pub struct Switch<'a> {
+    pub root: &'a Path,
+    /// `None` on a first deploy.
+    pub old_version: Option<&'a str>,
+    pub new_version: &'a str,
+    pub snapshot: &'a Path,
+    /// Written to the file named by `CITYFEED_CHANGED_PATHS_FILE`, one per
+    /// line; environment size is limited.
+    pub changed_paths: &'a [String],
+}
+
+/// Runs every command in order, even after one fails, and returns an error
+/// naming the first failure. Hook stdout goes to our stderr so it can't
+/// corrupt `--output json`. The changed-paths file is removed once the last
+/// hook exits.
+pub fn run_on_switch(cmds: &[String], log: &Logger, switch: &Switch) -> Result<()> {
+    let mut changed = tempfile::Builder::new()
+        .prefix("cityfeed-changed-")
+        .tempfile()
+        .context("create changed-paths file for on-switch hooks")?;
+    for path in switch.changed_paths {
+        writeln!(changed, "{path}").context("write changed-paths file")?;
+    }
+    changed.flush().context("write changed-paths file")?;
+
+    let mut first_err: Option<anyhow::Error> = None;
+    let mut failed = 0;
+    for cmd in cmds {
+        log.info(
+            "hook_start",
+            json!({ "command": cmd }),
+            format_args!("on-switch: {cmd}"),
+        );
+        let result = shell(cmd)
+            .env("CITYFEED_ROOT", switch.root)
+            .env("CITYFEED_OLD_VERSION", switch.old_version.unwrap_or(""))
+            .env("CITYFEED_NEW_VERSION", switch.new_version)
+            .env("CITYFEED_SNAPSHOT_PATH", switch.snapshot)
+            .env("CITYFEED_CHANGED_PATHS_FILE", changed.path())
+            .env(
+                "CITYFEED_FIRST_DEPLOY",
+                if switch.old_version.is_none() {
+                    "1"
+                } else {
+                    "0"
+                },
+            )
+            .stdin(Stdio::null())
+            .stdout(Stdio::from(std::io::stderr()))
+            .status()
+            .with_context(|| format!("spawn on-switch hook `{cmd}`"))
+            .and_then(|status| {
+                if status.success() {
+                    Ok(())
+                } else {
+                    Err(anyhow!("on-switch hook `{cmd}` exited with {status}"))
+                }
+            });
+        if let Err(err) = result {
+            failed += 1;
+            log.warn(
+                "hook_failed",
+                json!({ "command": cmd, "error": format!("{err:#}") }),
+                format_args!("{err:#}"),
+            );
+            first_err.get_or_insert(err);
+        }
+    }
+    match first_err {
+        Some(err) => Err(err.context(format!("{failed} of {} on-switch hooks failed", cmds.len()))),
+        None => Ok(()),
+    }
+}
+
+/// `cmd` run through `sh -c` (`cmd /C` on Windows).
+#[cfg(not(windows))]
+pub fn shell(cmd: &str) -> Command {
+    let mut c = Command::new("sh");
+    c.arg("-c").arg(cmd);
+    c
+}
+
+#[cfg(windows)]
+pub fn shell(cmd: &str) -> Command {
+    let mut c = Command::new("cmd");
+    c.arg("/C").arg(cmd);
+    c
+}
@@ -0,0 +1,136 @@
+//! The HTTP client a process fetches through. It is built once and shared by
+//! every site `--site-jobs` runs, so sites on the same origins also share its
+//! connection pool, and it counts the connections it opens so reuse can be
+//! checked with `-v`.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+
+use anyhow::{Context, Result};
+use reqwest::blocking::{Client, ClientBuilder};
+use serde_json::json;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::logger::Logger;
+use crate::{headers, Args, IpFamily};
+
+#[derive(Clone)]
+pub struct Http {
+    pub client: Client,
+    /// For `--notify-url`: webhook receivers are not origins and may not
+    /// speak HTTP/2, so they never get `--http2-prior-knowledge` or `--header`.
+    pub notify_client: Client,
+    connections: Arc<AtomicUsize>,
+    requests: Arc<AtomicUsize>,
+}
+
+impl Http {
+    pub fn new(args: &Args) -> Result<Self> {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let mut origins = builder(args)
+            .default_headers(headers::default_map(&args.headers))
+            .connector_layer(CountConnections(Arc::clone(&connections)));
+        if args.http2_prior_knowledge {
+            origins = origins.http2_prior_knowledge();
+        }
+        let client = origins.build().context("build http client")?;
+        let notify_client = if args.http2_prior_knowledge || !args.headers.is_empty() {
+            builder(args).build().context("build http client")?
+        } else {
+            client.clone()
+        };
+        Ok(Self {
+            client,
+            notify_client,
+            connections,
+            requests: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// Counts one request sent through `client`.
+    pub fn sent(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Logs (at -v) how many connections the requests so far needed.
+    pub fn report(&self, log: &Logger) {
+        let connections = self.connections.load(Ordering::Relaxed);
+        let requests = self.requests.load(Ordering::Relaxed);
+        log.debug(
+            "http_connections",
+            json!({ "connections": connections, "requests": requests }),
+            format_args!("opened {connections} connections for {requests} requests"),
+        );
+    }
+}
+
+/// Settings shared by both clients.
+fn builder(args: &Args) -> ClientBuilder {
+    let ip_family = IpFamily::from_flags(args.ipv4_only, args.ipv6_only);
+    // reqwest keeps using the URL's host for SNI and certificate checks,
+    // so a pinned address still has to present a certificate for HOST.
+    let mut builder = args
+        .resolve
+        .iter()
+        .fold(Client::builder(), |builder, pin| {
+            builder.resolve(&pin.host, pin.addr)
+        })
+        .local_address(ip_family.map(IpFamily::local_address))
+        .connect_timeout(args.connect_timeout)
+        .timeout(args.request_timeout)
+        .tcp_keepalive(args.tcp_keepalive)
+        .user_agent(concat!("cityfeed-puller/", env!("CARGO_PKG_VERSION")));
+    if let Some(max) = args.pool_max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(max);
+    }
+    builder
+}
+
+/// Connector layer that counts connections once they are established.
+#[derive(Clone)]
+struct CountConnections(Arc<AtomicUsize>);
+
+impl<S> Layer<S> for CountConnections {
+    type Service = Counted<S>;
+
+    fn layer(&self, inner: S) -> Counted<S> {
+        Counted {
+            inner,
+            opened: Arc::clone(&self.0),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Counted<S> {
+    inner: S,
+    opened: Arc<AtomicUsize>,
+}
+
+impl<S, R> Service<R> for Counted<S>
+where
+    S: Service<R>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: R) -> Self::Future {
+        let connecting = self.inner.call(req);
+        let opened = Arc::clone(&self.opened);
+        Box::pin(async move {
+            let conn = connecting.await?;
+            opened.fetch_add(1, Ordering::Relaxed);
+            Ok(conn)
+        })
+    }
+}
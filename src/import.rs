@@ -0,0 +1,243 @@
+//! `cityfeed-puller import FILE`: seeds a root from an `export` tarball, for
+//! boxes that can't reach any origin.
+//!
+//! The manifest is validated, and its signatures checked under
+//! `--manifest-pubkey`, the way a fetched one is. Files from the archive go
+//! into `objects/` under their manifest hash, each one hashed on the way in,
+//! so a tampered archive is rejected before the snapshot exists. The snapshot
+//! is then staged and renamed into place like a network deploy. `--switch`
+//! hands the rest to the normal deploy path: diff, switch, deploy-state.json
+//! and `--on-switch` hooks.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::json;
+use tar::EntryType;
+
+use crate::hashing::Hashing;
+use crate::http::Http;
+use crate::logger::Logger;
+use crate::object_temp::ObjectTemp;
+use crate::store::ObjectStore;
+use crate::{
+    current_version, finish, install_stop_flag, store_object, validate_hash, validate_manifest,
+    validate_rel_path, validate_symlink_target, validate_version, Args, FailAs, Failure, Manifest,
+    Outcome, Puller, RunError, Summary,
+};
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct ImportArgs {
+    /// Tarball written by `cityfeed-puller export`.
+    #[arg(value_name = "FILE")]
+    pub file: PathBuf,
+
+    /// Also point `current` at the imported snapshot (runs --on-switch hooks).
+    #[arg(long)]
+    pub switch: bool,
+}
+
+/// What the manifest says a `snapshot/` entry must be.
+enum Expect<'a> {
+    File { hash: &'a str, size: u64 },
+    Link(PathBuf),
+}
+
+/// Runs `import` and returns the exit code.
+pub fn main(args: &Args, import: &ImportArgs, log: &Logger) -> i32 {
+    let started = Instant::now();
+    let mut summary = Summary::default();
+    let puller = install_stop_flag().and_then(|stop| Puller::new(args, &Http::new(args)?, stop));
+    let (puller, result) = match puller {
+        Ok(puller) => {
+            let result = run(&puller, log, &mut summary, import);
+            (Some(puller), result)
+        }
+        Err(err) => (None, Err(err.into())),
+    };
+    summary.elapsed_secs = started.elapsed().as_secs_f64();
+    let code = finish(args, log, &mut summary, result);
+    if let Some(puller) = puller {
+        puller.record_history(args, log, &summary);
+    }
+    code
+}
+
+fn run(
+    puller: &Puller,
+    log: &Logger,
+    summary: &mut Summary,
+    import: &ImportArgs,
+) -> Result<Outcome, RunError> {
+    puller.reclaim_stale(log);
+    summary.previous_version = current_version(&puller.current_link);
+    let origin = import.file.display().to_string();
+    let file = File::open(&import.file).with_context(|| format!("open {origin}"))?;
+    let mut archive = tar::Archive::new(zstd::Decoder::new(file).context("start zstd stream")?);
+    let mut entries = archive
+        .entries()
+        .with_context(|| format!("read {origin}"))?;
+
+    let manifest = match entries.next() {
+        Some(entry) => read_manifest(entry.with_context(|| format!("read {origin}"))?),
+        None => Err(anyhow!("archive is empty")),
+    }
+    .with_context(|| format!("read manifest.json from {origin}"))
+    .fail_as(Failure::Manifest)?;
+    // The same checks a fetched manifest gets, signatures included.
+    validate_manifest(&manifest)
+        .and_then(|()| puller.fetcher.signing.check(&manifest))
+        .with_context(|| format!("manifest.json from {origin}"))
+        .fail_as(Failure::Manifest)?;
+    summary.version = Some(manifest.version.clone());
+    summary.origin = Some(origin.clone());
+    summary.file_count = Some(manifest.files.len() as u64);
+    summary.snapshot = Some(puller.snapshots_dir.join(&manifest.version));
+
+    if puller.snapshots_dir.join(&manifest.version).exists() {
+        log.outcome(
+            "already_imported",
+            json!({ "version": &manifest.version }),
+            format_args!(
+                "snapshot {} already exists; nothing to import",
+                manifest.version
+            ),
+        );
+        if import.switch {
+            return puller.deploy(log, summary, &manifest, &origin);
+        }
+        return Ok(Outcome::AlreadyCurrent);
+    }
+
+    let store = puller.store_for(&manifest);
+    store.migrate(log).context("migrate object layout")?;
+    let mut expected = expected(&manifest).fail_as(Failure::Manifest)?;
+    for entry in entries {
+        puller.fetcher.check_cancelled()?;
+        let entry = entry.with_context(|| format!("read {origin}"))?;
+        let name = entry
+            .path()
+            .with_context(|| format!("read {origin}"))?
+            .into_owned();
+        import_entry(log, &store, &mut expected, entry, &name)
+            .with_context(|| format!("import {}", name.display()))
+            .fail_as(Failure::Object)?;
+    }
+    if let Some(path) = expected.keys().next() {
+        return Err(anyhow!("archive has no entry for {}", path.display()))
+            .fail_as(Failure::Object);
+    }
+
+    if import.switch {
+        return puller.deploy(log, summary, &manifest, &origin);
+    }
+    puller.record_manifest(log, &manifest);
+    puller.stage(log, &store, &manifest)?;
+    log.outcome(
+        "imported",
+        json!({ "version": &manifest.version, "origin": &origin }),
+        format_args!(
+            "imported snapshots/{} from {origin}; current not switched",
+            manifest.version
+        ),
+    );
+    Ok(Outcome::Updated)
+}
+
+fn read_manifest<R: Read>(mut entry: tar::Entry<'_, R>) -> Result<Manifest> {
+    if *entry.path()? != *Path::new("manifest.json") {
+        bail!(
+            "archive starts with {}, not manifest.json",
+            entry.path()?.display()
+        );
+    }
+    let mut bytes = Vec::new();
+    entry.read_to_end(&mut bytes)?;
+    let manifest: Manifest = serde_json::from_slice(&bytes).context("parse manifest.json")?;
+    validate_version(&manifest.version)
+        .with_context(|| format!("invalid version {:?}", manifest.version))?;
+    Ok(manifest)
+}
+
+/// Every file and symlink the archive must provide, by snapshot path.
+fn expected(manifest: &Manifest) -> Result<BTreeMap<PathBuf, Expect<'_>>> {
+    let mut expected = BTreeMap::new();
+    for file in &manifest.files {
+        let rel = validate_rel_path(&file.path)
+            .with_context(|| format!("invalid manifest path: {}", file.path))?;
+        validate_hash(&file.hash)
+            .with_context(|| format!("invalid object hash for {}", file.path))?;
+        let want = Expect::File {
+            hash: &file.hash,
+            size: file.size,
+        };
+        expected.insert(rel, want);
+    }
+    for link in &manifest.symlinks {
+        let rel = validate_rel_path(&link.path)
+            .with_context(|| format!("invalid manifest path: {}", link.path))?;
+        let target = validate_symlink_target(&rel, &link.target)
+            .with_context(|| format!("invalid symlink target for {}", link.path))?;
+        expected.insert(rel, Expect::Link(target));
+    }
+    Ok(expected)
+}
+
+fn import_entry<R: Read>(
+    log: &Logger,
+    store: &ObjectStore,
+    expected: &mut BTreeMap<PathBuf, Expect<'_>>,
+    entry: tar::Entry<'_, R>,
+    name: &Path,
+) -> Result<()> {
+    let kind = entry.header().entry_type();
+    let rel = name
+        .strip_prefix("snapshot")
+        .ok()
+        .and_then(|rel| validate_rel_path(&rel.to_string_lossy()).ok())
+        .ok_or_else(|| anyhow!("unexpected entry outside snapshot/"))?;
+    // Staging creates directories as it needs them.
+    if kind == EntryType::Directory {
+        return Ok(());
+    }
+    match (expected.remove(&rel), kind) {
+        (Some(Expect::File { hash, size }), EntryType::Regular) => {
+            log.debug(
+                "import_object",
+                json!({ "hash": hash, "bytes": size, "path": &rel }),
+                format_args!("import object hash={hash} size={size}"),
+            );
+            let mut tmp = ObjectTemp::new_in(store.dir())?;
+            tmp.preallocate(size)?;
+            let mut body = Hashing::new(entry.take(size.saturating_add(1)));
+            io::copy(&mut body, &mut tmp).context("write object")?;
+            let (got, len, _) = body.finish();
+            if len != size {
+                bail!("size mismatch: expected {size} got {len}");
+            }
+            if got != hash {
+                bail!("content does not match the manifest (sha256 {got}, expected {hash})");
+            }
+            store_object(tmp, store, hash)
+        }
+        (Some(Expect::Link(target)), EntryType::Symlink) => {
+            let found = entry
+                .link_name()?
+                .ok_or_else(|| anyhow!("symlink entry has no target"))?;
+            if *found != *target {
+                bail!(
+                    "symlink points to {}, expected {}",
+                    found.display(),
+                    target.display()
+                );
+            }
+            Ok(())
+        }
+        (Some(_), kind) => bail!("{kind:?} entry does not match the manifest"),
+        (None, _) => bail!("not in the manifest"),
+    }
+}
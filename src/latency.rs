@@ -0,0 +1,151 @@
+//! `--origin-strategy latency`: at the start of each run every origin is asked
+//! for its manifest at once, and the time to the response headers decides
+//! the order origins are tried in for the rest of the run. Origins that fail
+//! the probe are left out, unless every one did.
+//!
+//! Probes that haven't answered within `PROBE_WAIT` count as failed; their
+//! threads finish (or time out) on their own.
+
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use serde_json::json;
+
+use crate::logger::Logger;
+use crate::{manifest_url, Fetcher};
+
+/// Longest a run waits for the probes before it starts.
+const PROBE_WAIT: Duration = Duration::from_secs(5);
+
+/// One origin's probe, as logged and put in the `--output json` summary.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Probe {
+    pub origin: String,
+    /// Milliseconds to the response headers; absent if the probe failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Probes every origin concurrently. The result lists answering origins
+/// fastest first, then the failed ones in `origins` order.
+pub fn probe(fetcher: &Fetcher, log: &Logger, origins: &[String]) -> Vec<Probe> {
+    let (tx, rx) = mpsc::channel();
+    for origin in origins {
+        let (fetcher, log, origin, tx) = (fetcher.clone(), log.clone(), origin.clone(), tx.clone());
+        thread::spawn(move || {
+            let started = Instant::now();
+            // Headers are all we time; the body is dropped unread.
+            let result = fetcher.send(
+                &log,
+                &manifest_url(&origin, &fetcher.channel),
+                &origin,
+                "latency probe",
+                None,
+            );
+            let probe = match result {
+                Ok(_) => Probe {
+                    origin,
+                    ms: Some(started.elapsed().as_millis() as u64),
+                    error: None,
+                },
+                Err(err) => Probe {
+                    origin,
+                    ms: None,
+                    error: Some(format!("{err:#}")),
+                },
+            };
+            let _ = tx.send(probe);
+        });
+    }
+    drop(tx);
+
+    let deadline = Instant::now() + PROBE_WAIT;
+    let mut probes = Vec::new();
+    while let Ok(probe) = rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        probes.push(probe);
+    }
+    for origin in origins {
+        if !probes.iter().any(|p| &p.origin == origin) {
+            probes.push(Probe {
+                origin: origin.clone(),
+                ms: None,
+                error: Some(format!("no answer within {}s", PROBE_WAIT.as_secs())),
+            });
+        }
+    }
+    probes.sort_by_key(|p| {
+        let rank = origins.iter().position(|o| o == &p.origin);
+        (p.ms.is_none(), p.ms, rank)
+    });
+
+    for p in &probes {
+        match (p.ms, &p.error) {
+            (Some(ms), _) => log.info(
+                "latency_probe",
+                json!({ "origin": &p.origin, "ms": ms }),
+                format_args!("latency probe origin={} {ms} ms", p.origin),
+            ),
+            (None, error) => log.warn(
+                "latency_probe_failed",
+                json!({ "origin": &p.origin, "error": error }),
+                format_args!(
+                    "latency probe origin={} failed: {}",
+                    p.origin,
+                    error.as_deref().unwrap_or_default()
+                ),
+            ),
+        }
+    }
+    probes
+}
+
+/// `origins` in probe order, without the ones whose probe failed. Origins
+/// never probed go last; if nothing answered, `origins` is returned as is so
+/// the real requests can report why.
+pub fn order(probes: &[Probe], origins: &[String]) -> Vec<String> {
+    let mut ordered: Vec<String> = probes
+        .iter()
+        .filter(|p| p.ms.is_some() && origins.contains(&p.origin))
+        .map(|p| p.origin.clone())
+        .collect();
+    if ordered.is_empty() {
+        return origins.to_vec();
+    }
+    ordered.extend(
+        origins
+            .iter()
+            .filter(|o| !probes.iter().any(|p| &p.origin == *o))
+            .cloned(),
+    );
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn probe(origin: &str, ms: Option<u64>) -> Probe {
+        Probe {
+            origin: origin.to_string(),
+            ms,
+            error: ms.is_none().then(|| "refused".to_string()),
+        }
+    }
+
+    #[test]
+    fn fastest_first_and_failed_probes_dropped() {
+        let origins: Vec<String> = ["a", "b", "c", "d"].map(String::from).to_vec();
+        let probes = [probe("c", Some(8)), probe("a", Some(40)), probe("b", None)];
+        assert_eq!(order(&probes, &origins), ["c", "a", "d"]);
+        // Origins that weren't asked about are left out.
+        assert_eq!(order(&probes, &origins[..2]), ["a"]);
+
+        let all_failed = [probe("a", None), probe("b", None)];
+        assert_eq!(order(&all_failed, &origins[..2]), ["a", "b"]);
+        assert_eq!(order(&[], &origins[..2]), ["a", "b"]);
+    }
+}
@@ -0,0 +1,5110 @@
+//! Manifest-based static site puller. A deploy downloads the
+//! content-addressed objects an origin's manifest lists into
+//! `<root>/objects/`, builds `<root>/snapshots/<version>/` from them and
+//! switches `<root>/current` to it atomically.
+//!
+//! `deploy` runs one pull from another program; `manifest`, `store` and
+//! `switch` are the pieces it is built from. The `cityfeed-puller` binary is
+//! `parse_args` followed by `run_cli`.
+
+use std::cmp;
+use std::collections::{hash_map, HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+mod auth;
+mod canary;
+mod completions;
+mod config;
+mod copy_strategy;
+mod diff;
+mod doctor;
+mod downgrade;
+mod embed;
+mod encoding;
+mod error_report;
+mod export;
+mod filter;
+mod fsck;
+mod hashing;
+mod headers;
+mod health;
+mod history;
+mod hooks;
+mod http;
+mod import;
+mod latency;
+mod logger;
+pub mod manifest;
+mod metrics;
+mod migrate_objects;
+mod object_temp;
+mod parts;
+mod perms;
+mod portability;
+mod progress;
+mod promote;
+mod prune;
+mod query_auth;
+mod sd_notify;
+mod serve;
+mod signatures;
+mod stale;
+mod state;
+mod stats;
+mod status;
+pub mod store;
+pub mod switch;
+mod trace;
+mod transfer;
+mod validators;
+mod webhook;
+
+use auth::{Auth, Credentials};
+use canary::Canary;
+use copy_strategy::{Copier, CopyStrategy};
+pub use diff::DiffCounts;
+pub use embed::{deploy, DeployOptions, DeployOutcome};
+use error_report::Phase;
+use filter::PathFilter;
+use hashing::Hashing;
+use headers::{Header, Headers};
+use health::{Budget, OriginHealth, OriginStats};
+use hooks::HookFailure;
+use http::Http;
+use logger::{LogFormat, Logger};
+use manifest::{
+    validate_hash, validate_manifest, validate_rel_path, validate_symlink_target, validate_version,
+    Manifest, ManifestFile, ManifestPart, ManifestSymlink, ManifestTransfer,
+};
+use object_temp::ObjectTemp;
+use perms::Perms;
+use portability::{PathPortability, Portability};
+use progress::{Progress, ProgressMode};
+use query_auth::{QueryAuth, QueryParam};
+use sd_notify::Notifier;
+use stats::Stats;
+use store::{ObjectLayout, ObjectStore};
+#[cfg(windows)]
+use switch::CURRENT_POINTER;
+use switch::{
+    current_points_to, current_version, read_current, set_previous, switch_symlink_atomically,
+    PREVIOUS_LINK,
+};
+use trace::Trace;
+use validators::{ManifestValidators, Validators};
+use webhook::NotifyOn;
+
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+
+#[derive(Parser, Debug, Clone)]
+#[command(
+    name = "cityfeed-puller",
+    version,
+    about = "Manifest-based static site puller",
+    subcommand_negates_reqs = true
+)]
+pub struct Args {
+    /// TOML file with defaults for any of these flags; flags given here win.
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    #[arg(long = "origin", required_unless_present_any = ["config", "offline"], num_args = 1..)]
+    origins: Vec<String>,
+
+    /// Send `Authorization: Bearer <TOKEN>` to every origin.
+    #[arg(long, value_name = "TOKEN", value_parser = auth::parse_token, conflicts_with = "auth_basic")]
+    auth_token: Option<Auth>,
+
+    /// Send HTTP basic auth to every origin.
+    #[arg(long, value_name = "USER:PASS", value_parser = auth::parse_basic)]
+    auth_basic: Option<Auth>,
+
+    /// Append KEY=VALUE to every request's query string (repeatable).
+    #[arg(long, value_name = "KEY=VALUE", value_parser = query_auth::parse_param)]
+    query_auth: Vec<QueryParam>,
+
+    /// Run this before every request and append the query string it prints.
+    #[arg(long, value_name = "CMD")]
+    query_auth_command: Option<String>,
+
+    /// Send this header to every origin, e.g. "X-Org-Token: abc" (repeatable).
+    #[arg(long = "header", value_name = "NAME: VALUE", value_parser = headers::parse)]
+    headers: Vec<Header>,
+
+    /// Release channel to follow: fetch <origin>/manifests/<NAME>.json.
+    #[arg(long, value_name = "NAME", default_value = DEFAULT_CHANNEL, value_parser = parse_channel)]
+    channel: String,
+
+    /// Fetch the manifest from this URL instead of <origin>/manifests/<channel>.json;
+    /// objects still come from --origin.
+    #[arg(long, value_name = "URL", conflicts_with = "race_manifest")]
+    manifest_url: Option<String>,
+
+    /// Accept only a manifest whose raw bytes have this sha256; any other is a failed fetch.
+    #[arg(long, value_name = "HEX", value_parser = parse_sha256)]
+    manifest_sha256: Option<String>,
+
+    /// Check the manifest's per-file signatures against this ed25519 public key (64 hex digits).
+    #[arg(long, value_name = "HEX", value_parser = signatures::parse_pubkey, global = true)]
+    manifest_pubkey: Option<signatures::PublicKey>,
+
+    /// Refuse a manifest with any unsigned file; needs --manifest-pubkey.
+    #[arg(long, requires = "manifest_pubkey", global = true)]
+    require_file_signatures: bool,
+
+    /// Deploy from --manifest-file and objects already in the store, without any network access.
+    #[arg(
+        long,
+        requires = "manifest_file",
+        conflicts_with_all = ["watch", "manifest_url", "race_manifest"]
+    )]
+    offline: bool,
+
+    /// Manifest to deploy with --offline, e.g. a saved <root>/manifests/<version>.json.
+    #[arg(long, value_name = "PATH", requires = "offline")]
+    manifest_file: Option<PathBuf>,
+
+    /// Deploy root; repeat to keep several roots on the same version.
+    #[arg(
+        id = "root",
+        long = "root",
+        value_name = "DIR",
+        default_value = "/var/www/mspmetro",
+        global = true
+    )]
+    roots: Vec<PathBuf>,
+
+    /// Give up connecting to an origin after this long and fail over (e.g. 10s, 500ms).
+    #[arg(long, default_value = "10s", value_parser = parse_duration)]
+    connect_timeout: Duration,
+
+    /// Overall limit for a single request, body included. Unlimited by default.
+    #[arg(long, value_parser = parse_duration)]
+    request_timeout: Option<Duration>,
+
+    /// Abort a transfer when no body bytes arrive for this long.
+    #[arg(long, value_parser = parse_duration)]
+    stall_timeout: Option<Duration>,
+
+    /// Connect to ADDRESS for HOST on any port, like curl's --resolve (repeatable).
+    #[arg(long, value_name = "HOST:PORT:ADDRESS", value_parser = parse_resolve)]
+    resolve: Vec<Resolve>,
+
+    /// Connect to origins over IPv4 only, for sites where IPv6 resolves but hangs.
+    #[arg(long, conflicts_with = "ipv6_only")]
+    ipv4_only: bool,
+
+    /// Connect to origins over IPv6 only.
+    #[arg(long)]
+    ipv6_only: bool,
+
+    /// Speak HTTP/2 to origins without negotiating it first (h2c for http:// origins).
+    #[arg(long)]
+    http2_prior_knowledge: bool,
+
+    /// Idle connections to keep open per origin host for reuse (reqwest's default: unlimited).
+    #[arg(long, value_name = "N")]
+    pool_max_idle_per_host: Option<usize>,
+
+    /// Send TCP keepalive probes on idle connections at this interval (e.g. 30s).
+    #[arg(long, value_parser = parse_duration)]
+    tcp_keepalive: Option<Duration>,
+
+    /// Object layout on origins and in objects/; overrides the manifest's `object_layout`.
+    #[arg(long, value_enum)]
+    object_layout: Option<ObjectLayout>,
+
+    /// How the starting origin is chosen for the manifest and each object.
+    #[arg(long, value_enum, default_value_t = OriginStrategy::Ordered)]
+    origin_strategy: OriginStrategy,
+
+    /// Ask every origin for the manifest at once and use the first good answer.
+    #[arg(long)]
+    race_manifest: bool,
+
+    /// Which origin's manifest to deploy when they serve different versions.
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ManifestPolicy::FirstSuccess,
+        conflicts_with_all = ["race_manifest", "manifest_url"]
+    )]
+    manifest_policy: ManifestPolicy,
+
+    /// Longest Retry-After honored on a 429/503 before retrying the same origin.
+    #[arg(long, default_value = "60s", value_parser = parse_duration)]
+    max_retry_after: Duration,
+
+    /// Extra tries on the same origin for an object whose body had the wrong size or hash.
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    mismatch_retries: u32,
+
+    /// Failed requests an origin may have in a run before its circuit opens and it is skipped.
+    #[arg(long, value_name = "N", default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+    origin_error_budget: u32,
+
+    /// Time an origin may spend in failed requests in a run before its circuit opens.
+    #[arg(long, value_name = "DURATION", default_value = "60s", value_parser = parse_duration)]
+    origin_time_budget: Duration,
+
+    /// How long an origin with an open circuit is skipped before one probe request.
+    #[arg(long, value_name = "DURATION", default_value = "60s", value_parser = parse_duration)]
+    circuit_cooldown: Duration,
+
+    /// Try every missing object even after one fails on all origins, then fail with all of them.
+    #[arg(long)]
+    keep_going: bool,
+
+    /// Cap total object download throughput in bytes/sec (suffixes K, M, G).
+    #[arg(long, value_parser = parse_bytes)]
+    max_rate: Option<u64>,
+
+    /// `json` prints a run summary document on stdout; logs stay on stderr.
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text, global = true)]
+    output: OutputFormat,
+
+    /// `json` writes one JSON object per stderr line instead of plain text.
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Print only the final outcome and errors.
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// More detail: -v adds per-request URLs, statuses and timings; -vv also reused objects.
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Keep running, re-check the manifest every --interval and deploy when it changes.
+    #[arg(long)]
+    watch: bool,
+
+    /// Poll interval for --watch.
+    #[arg(long, default_value = "60s", value_parser = parse_duration)]
+    interval: Duration,
+
+    /// Download and verify the manifest's objects, then exit without staging or switching.
+    #[arg(long, conflicts_with_all = ["watch", "offline"])]
+    prefetch_only: bool,
+
+    /// Shell command to run after `current` moves to a new snapshot (repeatable).
+    #[arg(long = "on-switch", value_name = "CMD")]
+    on_switch: Vec<String>,
+
+    /// Whether a failing --on-switch command fails the run.
+    #[arg(long, value_enum, default_value_t = HookFailure::Warn)]
+    hook_failure: HookFailure,
+
+    /// After switching, GET this URL from the local web server; any non-2xx rolls back (repeatable).
+    #[arg(long = "canary-url", value_name = "URL")]
+    canary_urls: Vec<String>,
+
+    /// Limit for each --canary-url request (e.g. 10s).
+    #[arg(long, default_value = "10s", value_parser = parse_duration)]
+    canary_timeout: Duration,
+
+    /// Wait this long after switching before the --canary-url checks (e.g. 2s).
+    #[arg(long, default_value = "0s", value_parser = parse_duration)]
+    canary_grace: Duration,
+
+    /// Webhook URL to POST a JSON run summary to (repeatable).
+    #[arg(long = "notify-url", value_name = "URL")]
+    notify_urls: Vec<String>,
+
+    /// Which runs trigger --notify-url.
+    #[arg(long, value_enum, default_value_t = NotifyOn::Change)]
+    notify_on: NotifyOn,
+
+    /// Write Prometheus metrics for node_exporter's textfile collector here after every run.
+    #[arg(long, value_name = "PATH")]
+    metrics_textfile: Option<PathBuf>,
+
+    /// Export a trace of each deploy to this OTLP/HTTP collector (e.g. http://localhost:4318).
+    #[cfg(feature = "otlp")]
+    #[arg(long, value_name = "URL")]
+    otlp_endpoint: Option<String>,
+
+    /// Free space that must remain on the root's filesystem after a deploy (e.g. 500M).
+    #[arg(long, default_value = "0", value_parser = parse_bytes)]
+    min_free_bytes: u64,
+
+    /// Refuse a deploy needing more than this many bytes of new objects (e.g. 20G); 0 = no limit.
+    #[arg(long, default_value = "0", value_parser = parse_bytes)]
+    max_total_bytes: u64,
+
+    /// Refuse a manifest listing more than this many files; 0 = no limit.
+    #[arg(long, default_value_t = 0)]
+    max_file_count: u64,
+
+    /// Refuse a manifest listing fewer than this many files.
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    min_files: u64,
+
+    /// Refuse a manifest whose files add up to fewer bytes than this (e.g. 1M).
+    #[arg(long, default_value = "0", value_parser = parse_bytes)]
+    min_total_bytes: u64,
+
+    /// Deploy a manifest below --min-files or --min-total-bytes, even an empty one (for teardowns).
+    #[arg(long)]
+    allow_small_manifest: bool,
+
+    /// Move aside a regular file or directory sitting where the `current` symlink belongs.
+    #[arg(long)]
+    force_current: bool,
+
+    /// Switch to a manifest published before the live one (by sequence, generated_at or local history).
+    #[arg(long)]
+    allow_downgrade: bool,
+
+    /// Fail, instead of warning, when a deployed version comes back with different files.
+    #[arg(long)]
+    strict_version: bool,
+
+    /// Deploy manifests with paths that differ only in case (they clash on macOS and SMB copies).
+    #[arg(long)]
+    allow_case_collisions: bool,
+
+    /// Re-hash each object while copying it into the snapshot and fail on content that no longer matches.
+    #[arg(long)]
+    verify_on_stage: bool,
+
+    /// How staging puts objects into a snapshot; auto tries reflink, then hardlink, then copy.
+    #[arg(long, value_enum, default_value_t = CopyStrategy::Auto)]
+    copy_strategy: CopyStrategy,
+
+    /// Carry this file over from the live snapshot into each new one, over the manifest's copy (repeatable).
+    #[arg(long, value_name = "PATH", value_parser = parse_preserve)]
+    preserve: Vec<PathBuf>,
+
+    /// Deploy only the manifest entries under this directory, with DIR/ stripped from their paths.
+    #[arg(long, value_name = "DIR", value_parser = filter::parse_prefix)]
+    prefix: Option<String>,
+
+    /// Deploy only manifest paths matching this glob, e.g. "kiosk/**" (repeatable, in order with --exclude).
+    #[arg(long, value_name = "GLOB", value_parser = filter::parse_glob)]
+    only: Vec<String>,
+
+    /// Leave out manifest paths matching this glob (repeatable, in order with --only).
+    #[arg(long, value_name = "GLOB", value_parser = filter::parse_glob)]
+    exclude: Vec<String>,
+
+    /// Which filesystems manifest paths must be valid on; windows-safe refuses names NTFS can't hold.
+    #[arg(long, value_enum, default_value_t = PathPortability::Posix)]
+    path_portability: PathPortability,
+
+    /// Longest file or directory name allowed with --path-portability windows-safe.
+    #[arg(long, value_name = "CHARS", default_value_t = 255)]
+    max_path_component_len: usize,
+
+    /// Longest path inside a snapshot allowed with --path-portability windows-safe.
+    #[arg(long, value_name = "CHARS", default_value_t = 240)]
+    max_path_len: usize,
+
+    /// Octal mode for snapshot files whose manifest entry sets none (default 0644).
+    #[arg(long, value_name = "MODE", value_parser = perms::parse_mode)]
+    file_mode: Option<u32>,
+
+    /// Octal mode for snapshot directories (default 0755).
+    #[arg(long, value_name = "MODE", value_parser = perms::parse_mode)]
+    dir_mode: Option<u32>,
+
+    /// Owner of snapshot files and directories, as user:group (names or ids); needs root.
+    #[arg(long, value_name = "USER:GROUP", value_parser = perms::parse_owner)]
+    owner: Option<perms::Owner>,
+
+    /// After deploying, remove snapshots last deployed more than this many days ago.
+    #[arg(long, value_name = "DAYS")]
+    keep_days: Option<u64>,
+
+    /// Rotate <root>/state/history.jsonl to history.jsonl.1 once it holds this many entries; 0 = never.
+    #[arg(long, value_name = "N", default_value_t = 10_000)]
+    history_max_entries: u64,
+
+    /// Download progress bars: drawn when stderr is a terminal (never with --log-format json).
+    #[arg(long, value_enum, default_value_t = ProgressMode::Auto)]
+    progress: ProgressMode,
+
+    /// List every added, removed and modified path, not just the counts.
+    #[arg(long)]
+    show_diff: bool,
+
+    /// How many files to copy into a new snapshot at once.
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u64).range(1..))]
+    stage_jobs: u64,
+
+    /// How many files to hash at once in fsck, --verify-on-stage and --offline/--prefetch-only checks (default: one per CPU).
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..), global = true)]
+    hash_jobs: Option<u64>,
+
+    /// How many [[site]] blocks from --config to deploy at once.
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    site_jobs: u64,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// `[[site]]` blocks from --config; empty for a single-site run.
+    #[arg(skip)]
+    sites: Vec<config::Site>,
+
+    /// `[auth."<origin>"]` tables from --config.
+    #[arg(skip)]
+    origin_auth: Vec<(String, Auth)>,
+
+    /// `[origin_headers."<origin>"]` tables from --config.
+    #[arg(skip)]
+    origin_headers: Vec<(String, Vec<Header>)>,
+
+    /// --only and --exclude in the order they were given.
+    #[arg(skip)]
+    filters: Vec<filter::Rule>,
+}
+
+impl Args {
+    /// The first `--root`: the one subcommands work on, and whose `objects/`
+    /// a multi-root deploy downloads into.
+    fn root(&self) -> &Path {
+        &self.roots[0]
+    }
+
+    /// `--hash-jobs`, or one thread per CPU.
+    fn hash_jobs(&self) -> usize {
+        match self.hash_jobs {
+            Some(jobs) => usize::try_from(jobs).unwrap_or(usize::MAX),
+            None => thread::available_parallelism().map_or(1, |n| n.get()),
+        }
+    }
+
+    /// -1 for `--quiet`, otherwise the number of `-v`s up to 2.
+    fn verbosity(&self) -> i8 {
+        if self.quiet {
+            -1
+        } else {
+            self.verbose.min(2) as i8
+        }
+    }
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Package a deployed snapshot and its manifest as a deterministic .tar.zst.
+    Export(export::ExportArgs),
+    /// Seed the root from an exported tarball, without contacting any origin.
+    Import(import::ImportArgs),
+    /// Point current at a snapshot already on disk, without contacting any origin.
+    Promote(promote::PromoteArgs),
+    /// Serve the current snapshot over plain HTTP, for smoke tests.
+    Serve(serve::ServeArgs),
+    /// Re-hash every stored object and report corrupt or stray entries.
+    Fsck(fsck::FsckArgs),
+    /// Move the object store to objects/ab/cd/<hash>, for very large sites.
+    MigrateObjects,
+    /// Report the live version, disk usage, leftover temp files and free space.
+    Status,
+    /// Check the root, the clock and the origins before a box's first deploy.
+    Doctor,
+    /// List the paths that differ between two snapshots on this box.
+    Diff(diff::DiffArgs),
+    /// Print a shell completion script to stdout.
+    Completions(completions::CompletionsArgs),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+/// Which origin a request tries first; the rest follow as fallbacks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum OriginStrategy {
+    /// Always start from the first --origin.
+    Ordered,
+    /// Rotate the starting origin on every request.
+    RoundRobin,
+    /// Pick a random starting origin per request.
+    Random,
+    /// Probe every origin at the start of a run and try the fastest first.
+    Latency,
+}
+
+/// Which manifest a run deploys when origins disagree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum ManifestPolicy {
+    /// The first origin, in attempt order, that serves a valid manifest.
+    FirstSuccess,
+    /// Ask every origin and deploy the newest by `sequence`, then `generated_at`.
+    Newest,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Outcome {
+    /// `current` now points at a different snapshot.
+    Updated,
+    /// `current` already pointed at the manifest's version.
+    AlreadyCurrent,
+    /// `--prefetch-only`: the objects are stored, `current` is untouched.
+    Prefetched,
+    Error,
+}
+
+impl Outcome {
+    fn exit_code(self) -> i32 {
+        match self {
+            Outcome::Updated | Outcome::Prefetched => EXIT_UPDATED,
+            Outcome::AlreadyCurrent => EXIT_ALREADY_CURRENT,
+            Outcome::Error => EXIT_FAILURE,
+        }
+    }
+}
+
+// Exit-code contract (documented in DEPLOY.md). 2 is left to clap for usage errors.
+const EXIT_UPDATED: i32 = 0;
+const EXIT_FAILURE: i32 = 1;
+const EXIT_ALREADY_CURRENT: i32 = 3;
+const EXIT_MANIFEST_FAILED: i32 = 4;
+const EXIT_OBJECT_FAILED: i32 = 5;
+const EXIT_INTERRUPTED: i32 = 6;
+const EXIT_HOOK_FAILED: i32 = 7;
+const EXIT_CANARY_FAILED: i32 = 8;
+
+/// Failure classes callers can tell apart by exit code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// No origin produced a usable manifest.
+    Manifest,
+    /// An object could not be downloaded or failed verification.
+    Object,
+    /// SIGTERM/SIGINT arrived; temp files were removed and `current` left alone.
+    Interrupted,
+    /// `current` was switched but an `--on-switch` hook failed (`--hook-failure fail`).
+    Hook,
+    /// A `--canary-url` failed after the switch, and `current` was rolled back.
+    Canary,
+    Other,
+}
+
+impl Failure {
+    /// The binary's exit code for this failure.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Failure::Manifest => EXIT_MANIFEST_FAILED,
+            Failure::Object => EXIT_OBJECT_FAILED,
+            Failure::Interrupted => EXIT_INTERRUPTED,
+            Failure::Hook => EXIT_HOOK_FAILED,
+            Failure::Canary => EXIT_CANARY_FAILED,
+            Failure::Other => EXIT_FAILURE,
+        }
+    }
+}
+
+/// A failed run: what kind of failure, and the error chain behind it.
+#[derive(Debug)]
+pub struct RunError {
+    pub failure: Failure,
+    pub err: anyhow::Error,
+}
+
+impl std::fmt::Display for RunError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#}", self.err)
+    }
+}
+
+impl std::error::Error for RunError {}
+
+impl From<anyhow::Error> for RunError {
+    fn from(err: anyhow::Error) -> Self {
+        RunError {
+            failure: Failure::Other,
+            err,
+        }
+    }
+}
+
+trait FailAs<T> {
+    fn fail_as(self, failure: Failure) -> Result<T, RunError>;
+}
+
+impl<T> FailAs<T> for Result<T> {
+    fn fail_as(self, failure: Failure) -> Result<T, RunError> {
+        self.map_err(|err| RunError { failure, err })
+    }
+}
+
+/// What a run did, filled in as the pipeline progresses so a failed run
+/// still reports how far it got.
+#[derive(Debug, Serialize)]
+struct Summary {
+    /// Which `[[site]]` this run was for, in a multi-site run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    site: Option<String>,
+    outcome: Outcome,
+    version: Option<String>,
+    /// Version `current` pointed at before the run, if any.
+    previous_version: Option<String>,
+    origin: Option<String>,
+    /// `--channel` the manifest was fetched from; none with --manifest-url
+    /// or --offline.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    channel: Option<String>,
+    objects_downloaded: u64,
+    bytes_downloaded: u64,
+    objects_reused: u64,
+    bytes_reused: u64,
+    /// Stored objects of the wrong size that were quarantined and fetched again.
+    objects_repaired: u64,
+    /// Objects no origin could deliver; with --keep-going, all of them.
+    objects_failed: u64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    failed_objects: Vec<FailedObject>,
+    /// Snapshots removed by --keep-days, and the bytes that freed.
+    snapshots_pruned: Vec<String>,
+    bytes_pruned: u64,
+    /// Paths changed relative to `previous_version`, when its manifest was recorded.
+    changes: Option<DiffCounts>,
+    /// The manifest reused a version already deployed here, with other files.
+    version_content_mismatch: bool,
+    /// `--preserve` paths carried over from the live snapshot.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    preserved: Vec<String>,
+    /// Files in the manifest, as checked against --max-file-count.
+    file_count: Option<u64>,
+    /// Bytes of those files, as checked against --min-total-bytes.
+    total_bytes: Option<u64>,
+    /// Bytes of objects the deploy had to fetch, as checked against --max-total-bytes.
+    bytes_needed: Option<u64>,
+    snapshot: Option<PathBuf>,
+    switched: bool,
+    elapsed_secs: f64,
+    /// Request and failure counts per origin contacted.
+    origins: Vec<OriginStats>,
+    /// `--origin-strategy latency` probe results, fastest first.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    latency: Vec<latency::Probe>,
+    /// Phase timings, download throughput and origin failovers.
+    stats: Stats,
+    error: Vec<String>,
+    /// How far the run got, for the error report.
+    #[serde(skip)]
+    phase: Phase,
+    /// Object being downloaded, until it is stored.
+    #[serde(skip)]
+    object: Option<String>,
+    /// Origin of the run's most recent failed request.
+    #[serde(skip)]
+    failed_origin: Option<String>,
+}
+
+impl Default for Summary {
+    fn default() -> Self {
+        Self {
+            site: None,
+            outcome: Outcome::Error,
+            version: None,
+            previous_version: None,
+            origin: None,
+            channel: None,
+            objects_downloaded: 0,
+            bytes_downloaded: 0,
+            objects_reused: 0,
+            bytes_reused: 0,
+            objects_repaired: 0,
+            objects_failed: 0,
+            failed_objects: Vec::new(),
+            snapshots_pruned: Vec::new(),
+            bytes_pruned: 0,
+            changes: None,
+            version_content_mismatch: false,
+            preserved: Vec::new(),
+            file_count: None,
+            total_bytes: None,
+            bytes_needed: None,
+            snapshot: None,
+            switched: false,
+            elapsed_secs: 0.0,
+            origins: Vec::new(),
+            latency: Vec::new(),
+            stats: Stats::default(),
+            error: Vec::new(),
+            phase: Phase::Setup,
+            object: None,
+            failed_origin: None,
+        }
+    }
+}
+
+impl Summary {
+    /// Moves the run on to `phase`, for the error report and the timings.
+    fn enter(&mut self, phase: Phase) {
+        self.phase = phase;
+        self.stats.enter(phase);
+    }
+}
+
+/// Only permission bits an edge should ever apply: no setuid/setgid/sticky
+/// and nothing group- or world-writable.
+const MODE_MASK: u32 = 0o755;
+
+/// Downloads per object before a stored copy that keeps coming out the wrong
+/// size fails the deploy.
+const OBJECT_FETCH_ATTEMPTS: u32 = 3;
+
+/// Runs the command line `args` came from and returns the process exit code.
+pub fn run_cli(args: Args) -> i32 {
+    let mut log = Logger::new(args.log_format, args.verbosity());
+    if progress::wanted(args.progress, args.log_format, args.quiet) {
+        log = log.with_progress(indicatif::MultiProgress::new());
+    }
+    match &args.command {
+        Some(Command::Export(export)) => return export::main(args.root(), export, &log),
+        Some(Command::Import(import)) => return import::main(&args, import, &log),
+        Some(Command::Promote(promote)) => return promote::main(&args, promote, &log),
+        Some(Command::Serve(serve)) => return serve::main(args.root(), serve, &log),
+        Some(Command::Fsck(fsck)) => return fsck::main(&args, fsck, &log),
+        Some(Command::MigrateObjects) => return migrate_objects::main(&args, &log),
+        Some(Command::Status) => return status::main(&args, &log),
+        Some(Command::Doctor) => return doctor::main(&args, &log),
+        Some(Command::Diff(diff)) => return diff::main(&args, diff, &log),
+        Some(Command::Completions(shell)) => return completions::main(shell),
+        None => {}
+    }
+    if args.watch {
+        return watch(&args, &log);
+    }
+
+    match install_stop_flag() {
+        Ok(stop) => match Http::new(&args) {
+            Ok(http) => {
+                let code = if args.sites.is_empty() {
+                    run_once(&args, &log, &http, stop, Summary::default())
+                } else {
+                    run_sites(&args, &log, &http, stop)
+                };
+                http.report(&log);
+                code
+            }
+            Err(err) => finish(&args, &log, &mut Summary::default(), Err(err.into())),
+        },
+        Err(err) => finish(&args, &log, &mut Summary::default(), Err(err.into())),
+    }
+}
+
+/// One full pull for `args.roots`, from taking the lock to notifications.
+fn run_once(
+    args: &Args,
+    log: &Logger,
+    http: &Http,
+    stop: Arc<AtomicBool>,
+    mut summary: Summary,
+) -> i32 {
+    let (puller, result) = pull(args, log, http, stop, &mut summary);
+    let code = finish(args, log, &mut summary, result);
+    report_run(args, log, puller.as_ref(), &summary, code);
+    code
+}
+
+/// Takes the root lock and deploys. The lock is held until the returned
+/// `Puller` is dropped.
+fn pull(
+    args: &Args,
+    log: &Logger,
+    http: &Http,
+    stop: Arc<AtomicBool>,
+    summary: &mut Summary,
+) -> (Option<Puller>, Result<Outcome, RunError>) {
+    let started = Instant::now();
+    let (puller, result) = match Puller::new(args, http, stop) {
+        Ok(puller) => {
+            summary.channel = puller.channel.clone();
+            let result = run(&puller, log, summary);
+            (Some(puller), result)
+        }
+        Err(err) => (None, Err(err.into())),
+    };
+    summary.elapsed_secs = started.elapsed().as_secs_f64();
+    if let Some(puller) = &puller {
+        puller.report_origins(log, summary);
+        puller.report_stats(log, summary);
+    }
+    (puller, result)
+}
+
+/// Metrics, deploy history, trace export and notifications, once the run's
+/// exit code is known.
+fn report_run(args: &Args, log: &Logger, puller: Option<&Puller>, summary: &Summary, code: i32) {
+    write_metrics(args, log, summary, code);
+    if let Some(puller) = puller {
+        puller.record_history(args, log, summary);
+        puller.export_trace(log, summary);
+        puller.notify(args, log, summary);
+    }
+}
+
+/// Runs every `[[site]]`, `--site-jobs` at a time. Each site takes its own
+/// root lock, so a single-site run on one of the roots is still excluded.
+/// All sites share `http`, and with it the pooled connections.
+fn run_sites(args: &Args, log: &Logger, http: &Http, stop: Arc<AtomicBool>) -> i32 {
+    let sites: Vec<(String, Args)> = args
+        .sites
+        .iter()
+        .map(|site| {
+            let mut site_args = args.clone();
+            site_args.roots = vec![site.root.clone()];
+            if !site.origins.is_empty() {
+                site_args.origins = site.origins.clone();
+            }
+            (site.label(), site_args)
+        })
+        .collect();
+
+    let next = AtomicUsize::new(0);
+    let codes = Mutex::new(vec![0; sites.len()]);
+    let jobs = usize::try_from(args.site_jobs)
+        .unwrap_or(usize::MAX)
+        .min(sites.len());
+    thread::scope(|scope| {
+        for _ in 0..jobs {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                let Some((name, site_args)) = sites.get(i) else {
+                    break;
+                };
+                let log = log.with_site(name);
+                let summary = Summary {
+                    site: Some(name.clone()),
+                    ..Summary::default()
+                };
+                let code = run_once(site_args, &log, http, stop.clone(), summary);
+                log.outcome(
+                    "site_finished",
+                    json!({ "root": site_args.root(), "exit_code": code }),
+                    format_args!("site {name} finished with exit code {code}"),
+                );
+                codes.lock().unwrap()[i] = code;
+            });
+        }
+    });
+    aggregate_exit_code(&codes.into_inner().unwrap())
+}
+
+/// First failing site's code, in config order; otherwise 0 if any site
+/// updated, or 3 if every site was already current.
+fn aggregate_exit_code(codes: &[i32]) -> i32 {
+    let ok = [EXIT_UPDATED, EXIT_ALREADY_CURRENT];
+    if let Some(&failed) = codes.iter().find(|code| !ok.contains(code)) {
+        return failed;
+    }
+    if codes.contains(&EXIT_UPDATED) {
+        EXIT_UPDATED
+    } else {
+        EXIT_ALREADY_CURRENT
+    }
+}
+
+/// Command line merged with `--config`. Usage errors exit 2 like clap's own.
+pub fn parse_args() -> Args {
+    let argv: Vec<OsString> = std::env::args_os().collect();
+    let matches = Args::command().get_matches_from(&argv);
+    args_with_config(argv, &matches).unwrap_or_else(|err| {
+        eprintln!("error: {err:#}");
+        std::process::exit(2);
+    })
+}
+
+fn args_with_config(mut argv: Vec<OsString>, matches: &ArgMatches) -> Result<Args> {
+    let args = match matches.get_one::<PathBuf>("config") {
+        Some(path) => {
+            let loaded = config::load(path, &Args::command(), matches)?;
+            // Before any subcommand, so they still parse as top-level flags.
+            argv.splice(1..1, loaded.flags);
+            let merged = Args::command().try_get_matches_from(argv).map_err(|err| {
+                // Keep clap's "invalid value ... for '--flag'" line, minus usage hints.
+                let msg = err.to_string();
+                let line = msg.lines().next().unwrap_or_default();
+                anyhow!(
+                    "config {}: {}",
+                    path.display(),
+                    line.trim_start_matches("error: ")
+                )
+            })?;
+            let mut args = Args::from_arg_matches(&merged)?;
+            args.sites = loaded.sites;
+            args.origin_auth = loaded.auth;
+            args.origin_headers = loaded.headers;
+            args.filters = filter::rules(&merged);
+            args
+        }
+        None => {
+            let mut args = Args::from_arg_matches(matches)?;
+            args.filters = filter::rules(matches);
+            args
+        }
+    };
+    let mut seen = HashSet::new();
+    if let Some(root) = args.roots.iter().find(|root| !seen.insert(*root)) {
+        bail!("--root {} is given more than once", root.display());
+    }
+    if args.owner.is_some() && perms::is_root() == Some(false) {
+        bail!("--owner needs root");
+    }
+    if args.roots.len() > 1 {
+        if args.command.is_some() {
+            bail!("subcommands take a single --root");
+        }
+        if args.watch {
+            bail!("--watch does not support more than one --root; run one watcher per root");
+        }
+        if !args.sites.is_empty() {
+            bail!("[[site]] blocks can't be combined with more than one --root");
+        }
+    }
+    if args.command.is_some() {
+        return Ok(args);
+    }
+    if args.sites.is_empty() {
+        if args.origins.is_empty() && !args.offline {
+            bail!("no origins: pass --origin or set `origins` in --config");
+        }
+        return Ok(args);
+    }
+
+    if args.watch {
+        bail!("--watch does not support [[site]] blocks; run one watcher per site");
+    }
+    let mut roots = HashSet::new();
+    for site in &args.sites {
+        if site.origins.is_empty() && args.origins.is_empty() {
+            bail!(
+                "site {} has no origins and no top-level ones to fall back on",
+                site.label()
+            );
+        }
+        if !roots.insert(&site.root) {
+            bail!("more than one [[site]] uses root {}", site.root.display());
+        }
+    }
+    Ok(args)
+}
+
+/// Records the result in `summary`, reports failures, prints the JSON summary
+/// when asked for, and returns the exit code.
+fn finish(
+    args: &Args,
+    log: &Logger,
+    summary: &mut Summary,
+    result: Result<Outcome, RunError>,
+) -> i32 {
+    let report = record_error_report(args, log, summary, &result);
+    let code = settle(log, summary, &result, report.as_deref());
+    if args.output == OutputFormat::Json {
+        match serde_json::to_string(&summary) {
+            Ok(doc) => println!("{doc}"),
+            Err(err) => log.error(
+                "summary_failed",
+                json!({ "error": err.to_string() }),
+                format_args!("render summary: {err}"),
+            ),
+        }
+    }
+    code
+}
+
+/// Writes `state/last-error.json` into every root after a failure, or
+/// removes it after a success. Returns the first root's report, if written.
+fn record_error_report(
+    args: &Args,
+    log: &Logger,
+    summary: &Summary,
+    result: &Result<Outcome, RunError>,
+) -> Option<PathBuf> {
+    let report = match result {
+        Ok(_) => None,
+        Err(RunError { failure, err }) => {
+            Some(error_report::Report::new(summary, err, failure.exit_code()))
+        }
+    };
+    let mut written = None;
+    // A root that doesn't exist yet failed before anything happened in it.
+    for root in args.roots.iter().filter(|root| root.is_dir()) {
+        let result = match &report {
+            Some(report) => error_report::write(root, report).map(|path| {
+                written.get_or_insert(path);
+            }),
+            None => error_report::clear(root),
+        };
+        if let Err(err) = result {
+            log.warn(
+                "error_report_failed",
+                json!({ "root": root, "error": format!("{err:#}") }),
+                format_args!("error report for {}: {err:#}", root.display()),
+            );
+        }
+    }
+    written
+}
+
+/// Records the result in `summary`, logs a failure (pointing at its
+/// `report`, if one was written) and returns the exit code.
+fn settle(
+    log: &Logger,
+    summary: &mut Summary,
+    result: &Result<Outcome, RunError>,
+    report: Option<&Path>,
+) -> i32 {
+    match result {
+        Ok(outcome) => {
+            summary.outcome = *outcome;
+            outcome.exit_code()
+        }
+        Err(RunError { failure, err }) => {
+            summary.outcome = Outcome::Error;
+            summary.error = err.chain().map(|e| e.to_string()).collect();
+            match report {
+                Some(report) => log.error(
+                    "run_failed",
+                    json!({
+                        "error": &summary.error,
+                        "exit_code": failure.exit_code(),
+                        "report": report,
+                    }),
+                    format_args!("{err:#} (report: {})", report.display()),
+                ),
+                None => log.error(
+                    "run_failed",
+                    json!({ "error": &summary.error, "exit_code": failure.exit_code() }),
+                    format_args!("{err:#}"),
+                ),
+            }
+            failure.exit_code()
+        }
+    }
+}
+
+/// Polls the origins every `--interval` and runs a deploy whenever the
+/// manifest version differs from what `current` points at. A failed cycle is
+/// reported and retried with exponential backoff (up to 16x the interval).
+/// SIGTERM/SIGINT end the loop between cycles. Under a `Type=notify` unit it
+/// reports readiness after the first good cycle and pings the watchdog.
+fn watch(args: &Args, log: &Logger) -> i32 {
+    let stop = match install_stop_flag() {
+        Ok(stop) => stop,
+        Err(err) => {
+            log.error(
+                "run_failed",
+                json!({ "error": format!("{err:#}") }),
+                format_args!("{err:#}"),
+            );
+            return EXIT_FAILURE;
+        }
+    };
+    let puller = match Http::new(args).and_then(|http| Puller::new(args, &http, Arc::clone(&stop)))
+    {
+        Ok(puller) => puller,
+        Err(err) => {
+            log.error(
+                "run_failed",
+                json!({ "error": format!("{err:#}") }),
+                format_args!("{err:#}"),
+            );
+            return EXIT_FAILURE;
+        }
+    };
+    puller.reclaim_stale(log);
+
+    let notifier = Notifier::from_env();
+    let mut ready = false;
+    let mut deployed: Option<String> = None;
+    let mut failures: u32 = 0;
+    while !stop.load(Ordering::SeqCst) {
+        notifier.watchdog();
+        let started = Instant::now();
+        let mut summary = Summary {
+            channel: puller.channel.clone(),
+            ..Summary::default()
+        };
+        puller.trace.begin("deploy");
+        summary.enter(Phase::Manifest);
+        let result = match puller.latest_manifest(log) {
+            Ok((manifest, origin)) if puller.is_current(&manifest.version) => {
+                log.debug(
+                    "watch_unchanged",
+                    json!({ "version": &manifest.version }),
+                    format_args!("watch: version {} unchanged", manifest.version),
+                );
+                puller
+                    .check_version_content(log, &mut summary, &manifest, &origin)
+                    .and_then(|()| {
+                        state::checked(&puller.root, &manifest, &origin, puller.channel.as_deref())
+                            .context("write deploy-state.json")
+                            .map_err(RunError::from)
+                    })
+                    .map(|()| (manifest.version, None))
+            }
+            Ok((manifest, origin)) => {
+                log_manifest(log, &manifest, &origin);
+                puller
+                    .check_downgrade(log, &manifest, &origin)
+                    .and_then(|()| puller.deploy(log, &mut summary, &manifest, &origin))
+                    .map(|outcome| (manifest.version, Some(outcome)))
+            }
+            Err(err) => Err(puller.classify(RunError {
+                failure: Failure::Manifest,
+                err,
+            })),
+        };
+
+        let delay = match result {
+            Ok((version, outcome)) => {
+                failures = 0;
+                if let Some(outcome) = outcome {
+                    summary.elapsed_secs = started.elapsed().as_secs_f64();
+                    puller.report_origins(log, &mut summary);
+                    puller.report_stats(log, &mut summary);
+                    puller.fetcher.http.report(log);
+                    let code = finish(args, log, &mut summary, Ok(outcome));
+                    write_metrics(args, log, &summary, code);
+                    puller.record_history(args, log, &summary);
+                    puller.export_trace(log, &summary);
+                    puller.notify(args, log, &summary);
+                } else {
+                    puller.trace.discard();
+                    summary.outcome = Outcome::AlreadyCurrent;
+                    summary.version = Some(version.clone());
+                    summary.elapsed_secs = started.elapsed().as_secs_f64();
+                    write_metrics(args, log, &summary, EXIT_ALREADY_CURRENT);
+                    puller.record_history(args, log, &summary);
+                }
+                let status = format!("deployed {version}");
+                if !ready {
+                    notifier.ready(&status);
+                    ready = true;
+                } else if deployed.as_deref() != Some(version.as_str()) {
+                    notifier.status(&status);
+                }
+                deployed = Some(version);
+                args.interval
+            }
+            // Stopped mid-cycle: the deploy already cleaned up after itself.
+            Err(err) if err.failure == Failure::Interrupted => break,
+            Err(err) => {
+                failures = failures.saturating_add(1);
+                summary.elapsed_secs = started.elapsed().as_secs_f64();
+                puller.report_origins(log, &mut summary);
+                puller.report_stats(log, &mut summary);
+                puller.fetcher.http.report(log);
+                let code = finish(args, log, &mut summary, Err(err));
+                write_metrics(args, log, &summary, code);
+                puller.record_history(args, log, &summary);
+                puller.export_trace(log, &summary);
+                // One webhook per outage, not one per retry.
+                if failures == 1 {
+                    puller.notify(args, log, &summary);
+                }
+                let delay = args.interval * 2u32.pow(failures.min(4));
+                log.warn(
+                    "watch_retry",
+                    json!({ "failures": failures, "retry_in_secs": delay.as_secs_f64() }),
+                    format_args!(
+                        "watch: cycle failed ({failures} in a row); retrying in {delay:?}"
+                    ),
+                );
+                notifier.status(&format!(
+                    "deployed {}; last {failures} check(s) failed",
+                    deployed.as_deref().unwrap_or("nothing")
+                ));
+                delay
+            }
+        };
+        sleep_unless_stopped(delay, &stop, &notifier);
+    }
+
+    notifier.stopping();
+    log.outcome("watch_stopped", json!({}), "watch: stop requested, exiting");
+    EXIT_UPDATED
+}
+
+#[cfg(feature = "otlp")]
+fn otlp_endpoint(args: &Args) -> Option<&str> {
+    args.otlp_endpoint.as_deref()
+}
+
+#[cfg(not(feature = "otlp"))]
+fn otlp_endpoint(_args: &Args) -> Option<&str> {
+    None
+}
+
+/// Rewrites `--metrics-textfile`, if set. Failing to is only a warning: the
+/// run's own outcome stands.
+fn write_metrics(args: &Args, log: &Logger, summary: &Summary, code: i32) {
+    let Some(path) = &args.metrics_textfile else {
+        return;
+    };
+    let path = metrics::path_for(path, summary.site.as_deref());
+    if let Err(err) = metrics::write(&path, summary, code) {
+        log.warn(
+            "metrics_failed",
+            json!({ "path": &path, "error": format!("{err:#}") }),
+            format_args!("metrics textfile {}: {err:#}", path.display()),
+        );
+    }
+}
+
+/// Returns a flag set by the first SIGTERM/SIGINT; a second signal while the
+/// flag is already set terminates the process immediately.
+fn install_stop_flag() -> Result<Arc<AtomicBool>> {
+    use signal_hook::consts::{SIGINT, SIGTERM};
+
+    let stop = Arc::new(AtomicBool::new(false));
+    for sig in [SIGTERM, SIGINT] {
+        signal_hook::flag::register_conditional_shutdown(sig, EXIT_FAILURE, Arc::clone(&stop))
+            .context("install signal handler")?;
+        signal_hook::flag::register(sig, Arc::clone(&stop)).context("install signal handler")?;
+    }
+    Ok(stop)
+}
+
+fn sleep_unless_stopped(total: Duration, stop: &AtomicBool, notifier: &Notifier) {
+    let deadline = Instant::now() + total;
+    while !stop.load(Ordering::SeqCst) {
+        notifier.keepalive();
+        let now = Instant::now();
+        if now >= deadline {
+            return;
+        }
+        thread::sleep((deadline - now).min(Duration::from_millis(100)));
+    }
+}
+
+fn run(puller: &Puller, log: &Logger, summary: &mut Summary) -> Result<Outcome, RunError> {
+    puller.trace.begin("deploy");
+    puller.reclaim_stale(log);
+    summary.previous_version = current_version(&puller.current_link);
+    summary.enter(Phase::Manifest);
+    let (manifest, manifest_origin) = puller.fetch_manifest(log)?;
+    if puller.prefetch_only {
+        return puller.prefetch(log, summary, &manifest, &manifest_origin);
+    }
+    puller.check_downgrade(log, &manifest, &manifest_origin)?;
+    puller.deploy(log, summary, &manifest, &manifest_origin)
+}
+
+/// A switched root with where its `current` and `previous` links pointed
+/// before, for `restore_current`.
+type Undo<'a> = (&'a Puller, Option<PathBuf>, Option<PathBuf>);
+
+/// Root layout, origins and HTTP setup shared by every deploy in a process.
+struct Puller {
+    root: PathBuf,
+    objects_dir: PathBuf,
+    snapshots_dir: PathBuf,
+    /// Applied manifests, one per version; see `diff`.
+    manifests_dir: PathBuf,
+    current_link: PathBuf,
+    origins: Vec<String>,
+    fetcher: Fetcher,
+    on_switch: Vec<String>,
+    hook_failure: HookFailure,
+    canary: Canary,
+    min_free_bytes: u64,
+    max_total_bytes: u64,
+    max_file_count: u64,
+    min_files: u64,
+    min_total_bytes: u64,
+    allow_small_manifest: bool,
+    force_current: bool,
+    allow_downgrade: bool,
+    strict_version: bool,
+    allow_case_collisions: bool,
+    verify_on_stage: bool,
+    /// `--keep-going`: download every object before failing on any.
+    keep_going: bool,
+    copier: Copier,
+    /// `--preserve` paths, already validated.
+    preserve: Vec<PathBuf>,
+    filter: PathFilter,
+    stage_jobs: usize,
+    hash_jobs: usize,
+    portability: Portability,
+    keep_days: Option<u64>,
+    perms: Perms,
+    race_manifest: bool,
+    manifest_policy: ManifestPolicy,
+    manifest_url: Option<String>,
+    /// `--channel`, unless --manifest-url or --offline names the manifest.
+    channel: Option<String>,
+    offline: bool,
+    manifest_file: Option<PathBuf>,
+    prefetch_only: bool,
+    object_layout: Option<ObjectLayout>,
+    /// `migrate-objects` moved this root to `objects/ab/cd/<hash>`.
+    nested_objects: bool,
+    show_diff: bool,
+    trace: Trace,
+    /// The other `--root`s. They are staged from this root's object store and
+    /// switched together with it; see `deploy_inner`.
+    mirrors: Vec<Puller>,
+    /// Held for the life of the process; see `lock_root`.
+    _lock: File,
+}
+
+impl Puller {
+    /// `cancel` is polled between objects and inside every body read; once set,
+    /// the deploy unwinds (dropping its temp files) before touching `current`.
+    fn new(args: &Args, http: &Http, cancel: Arc<AtomicBool>) -> Result<Self> {
+        // Subcommands work on the root alone and may have no origins.
+        let origins = match args.command {
+            Some(_) if args.origins.is_empty() => Vec::new(),
+            None if args.offline && args.origins.is_empty() => Vec::new(),
+            _ => normalize_origins(&args.origins)?,
+        };
+        let root = args.root().to_path_buf();
+
+        let dir_mode = args.dir_mode.unwrap_or(perms::DEFAULT_DIR_MODE);
+        ensure_served_dir(&root, dir_mode)
+            .with_context(|| format!("create root dir {}", root.display()))?;
+
+        let objects_dir = root.join("objects");
+        let snapshots_dir = root.join("snapshots");
+        let manifests_dir = root.join("manifests");
+        let current_link = root.join("current");
+
+        ensure_served_dir(&objects_dir, dir_mode).context("create objects dir")?;
+        ensure_served_dir(&snapshots_dir, dir_mode).context("create snapshots dir")?;
+        ensure_dir(&manifests_dir).context("create manifests dir")?;
+        let lock = lock_root(&root)?;
+        let nested_objects = store::is_nested(&objects_dir).context("read object layout")?;
+        let mirrors = args.roots[1..]
+            .iter()
+            .map(|mirror| {
+                let mut args = args.clone();
+                args.roots = vec![mirror.clone()];
+                Puller::new(&args, http, Arc::clone(&cancel))
+                    .with_context(|| format!("root {}", mirror.display()))
+            })
+            .collect::<Result<_>>()?;
+
+        let mut fetcher = Fetcher::new(args, http, cancel)?;
+        fetcher.validators = args.manifest_sha256.is_none().then(|| {
+            Arc::new(ManifestValidators::load(
+                &root,
+                &manifests_dir,
+                PathFilter::new(args.prefix.clone(), args.filters.clone()),
+                &args.channel,
+            ))
+        });
+
+        Ok(Self {
+            root,
+            objects_dir,
+            snapshots_dir,
+            manifests_dir,
+            current_link,
+            origins,
+            fetcher,
+            on_switch: args.on_switch.clone(),
+            hook_failure: args.hook_failure,
+            canary: Canary {
+                urls: args.canary_urls.clone(),
+                timeout: args.canary_timeout,
+                grace: args.canary_grace,
+            },
+            min_free_bytes: args.min_free_bytes,
+            max_total_bytes: args.max_total_bytes,
+            max_file_count: args.max_file_count,
+            min_files: args.min_files,
+            min_total_bytes: args.min_total_bytes,
+            allow_small_manifest: args.allow_small_manifest,
+            force_current: args.force_current,
+            allow_downgrade: args.allow_downgrade,
+            strict_version: args.strict_version,
+            allow_case_collisions: args.allow_case_collisions,
+            verify_on_stage: args.verify_on_stage,
+            keep_going: args.keep_going,
+            copier: Copier::new(args.root(), args.copy_strategy),
+            preserve: args.preserve.clone(),
+            filter: PathFilter::new(args.prefix.clone(), args.filters.clone()),
+            stage_jobs: usize::try_from(args.stage_jobs).unwrap_or(usize::MAX),
+            hash_jobs: args.hash_jobs(),
+            portability: Portability {
+                mode: args.path_portability,
+                max_component_len: args.max_path_component_len,
+                max_path_len: args.max_path_len,
+            },
+            keep_days: args.keep_days,
+            perms: Perms {
+                file_mode: args.file_mode.unwrap_or(perms::DEFAULT_FILE_MODE),
+                dir_mode,
+                owner: args.owner,
+            },
+            race_manifest: args.race_manifest,
+            manifest_policy: args.manifest_policy,
+            manifest_url: args
+                .manifest_url
+                .as_deref()
+                .map(normalize_manifest_url)
+                .transpose()?,
+            channel: (args.manifest_url.is_none() && !args.offline).then(|| args.channel.clone()),
+            offline: args.offline,
+            manifest_file: args.manifest_file.clone(),
+            prefetch_only: args.prefetch_only,
+            object_layout: args.object_layout,
+            nested_objects,
+            show_diff: args.show_diff,
+            trace: Trace::new(otlp_endpoint(args)),
+            mirrors,
+            _lock: lock,
+        })
+    }
+
+    /// Latest manifest and the origin that served it, raced across origins
+    /// with `--race-manifest`, otherwise tried in order.
+    fn latest_manifest(&self, log: &Logger) -> Result<(Manifest, String)> {
+        if self.fetcher.strategy == OriginStrategy::Latency && !self.offline {
+            let probes = latency::probe(&self.fetcher, log, &self.origins);
+            *self.fetcher.probes.lock().unwrap() = probes;
+        }
+        let mut span = self.trace.span("manifest.fetch");
+        let (mut manifest, origin) = self.find_manifest(log)?;
+        span.attr("origin", &origin);
+        span.attr("cityfeed.version", &manifest.version);
+        span.ok();
+        let entries = manifest.files.len() + manifest.symlinks.len();
+        let (dropped, escaping) = self.filter.apply(&mut manifest);
+        if !escaping.is_empty() {
+            log.warn(
+                "prefix_symlinks_dropped",
+                json!({ "prefix": &manifest.prefix, "paths": &escaping }),
+                format_args!(
+                    "left out symlinks that point outside --prefix: {}",
+                    escaping.join(", ")
+                ),
+            );
+        }
+        if !self.filter.is_empty() {
+            let settings: Vec<String> = manifest
+                .prefix
+                .iter()
+                .map(|prefix| format!("prefix {prefix}"))
+                .chain(manifest.filters.iter().cloned())
+                .collect();
+            log.info(
+                "filtered",
+                json!({
+                    "prefix": &manifest.prefix,
+                    "filters": &manifest.filters,
+                    "kept": entries - dropped,
+                    "dropped": dropped,
+                }),
+                format_args!(
+                    "kept {} of {entries} manifest entries ({})",
+                    entries - dropped,
+                    settings.join(", ")
+                ),
+            );
+        }
+        if !self.allow_case_collisions {
+            manifest::check_case_collisions(&manifest)
+                .with_context(|| format!("manifest {} from {origin}", manifest.version))?;
+        }
+        self.portability
+            .check(&manifest)
+            .with_context(|| format!("manifest {} from {origin}", manifest.version))?;
+        Ok((manifest, origin))
+    }
+
+    fn find_manifest(&self, log: &Logger) -> Result<(Manifest, String)> {
+        if let Some(path) = &self.manifest_file {
+            // A file:// URL goes through the same parsing and validation as
+            // a fetched manifest, without touching the network.
+            let path = fs::canonicalize(path)
+                .with_context(|| format!("read --manifest-file {}", path.display()))?;
+            let url = Url::from_file_path(&path)
+                .map_err(|()| anyhow!("--manifest-file {} is not a file path", path.display()))?;
+            let label = path.display().to_string();
+            let manifest = fetch_manifest(&self.fetcher, log, url.as_str(), &label)
+                .context("read --manifest-file")?;
+            return Ok((manifest, label));
+        }
+        if let Some(url) = &self.manifest_url {
+            // Not one of the origins: it only serves this manifest, so it
+            // stays out of origin health and object ordering.
+            self.fetcher.check_cancelled()?;
+            let started = Instant::now();
+            let result = fetch_manifest(&self.fetcher, log, url, url);
+            let manifest = log_manifest_attempt(log, url, started, result)
+                .context("fetch manifest from --manifest-url")?;
+            return Ok((manifest, url.clone()));
+        }
+        if self.race_manifest {
+            race_manifest(&self.fetcher, log, &self.origins)
+        } else if self.manifest_policy == ManifestPolicy::Newest {
+            newest_manifest(&self.fetcher, log, &self.origins)
+        } else {
+            fetch_manifest_any(&self.fetcher, log, &self.origins)
+        }
+    }
+
+    /// Origins to download objects from. A raced manifest's winner goes first:
+    /// it just proved to be the fastest to answer. Under `--manifest-policy
+    /// newest` the winner is the one origin sure to have every object.
+    fn object_origins(&self, manifest_origin: &str) -> Vec<String> {
+        let mut origins = self.origins.clone();
+        if self.race_manifest || self.manifest_policy == ManifestPolicy::Newest {
+            if let Some(pos) = origins.iter().position(|o| o == manifest_origin) {
+                let winner = origins.remove(pos);
+                origins.insert(0, winner);
+            }
+        }
+        origins
+    }
+
+    /// Object store for `manifest`: `--object-layout` wins over the manifest.
+    /// A nested root keeps its own layout locally.
+    fn store_for(&self, manifest: &Manifest) -> ObjectStore {
+        let layout = self
+            .object_layout
+            .or(manifest.object_layout)
+            .unwrap_or_default();
+        let store = ObjectStore::new(self.objects_dir.clone(), layout);
+        if self.nested_objects {
+            store.nested()
+        } else {
+            store
+        }
+    }
+
+    fn fetch_manifest(&self, log: &Logger) -> Result<(Manifest, String), RunError> {
+        let (manifest, manifest_origin) = self
+            .latest_manifest(log)
+            .fail_as(Failure::Manifest)
+            .map_err(|err| self.classify(err))?;
+        log_manifest(log, &manifest, &manifest_origin);
+        Ok((manifest, manifest_origin))
+    }
+
+    /// Makes sure `current` is either absent or a symlink before deploying. A
+    /// dangling link is only reported: the normal path rebuilds the missing
+    /// snapshot or repoints the link. Anything else in the way is an error,
+    /// or is moved aside with `--force-current`.
+    fn check_current(&self, log: &Logger) -> Result<()> {
+        let current = &self.current_link;
+        let meta = match fs::symlink_metadata(current) {
+            Ok(meta) => meta,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err).with_context(|| format!("stat {}", current.display())),
+        };
+        if meta.file_type().is_symlink() {
+            if !current.exists() {
+                let target = fs::read_link(current).unwrap_or_default();
+                log.warn(
+                    "current_dangling",
+                    json!({ "target": &target }),
+                    format_args!(
+                        "current -> {} is dangling; it will be repointed",
+                        target.display()
+                    ),
+                );
+            }
+            return Ok(());
+        }
+
+        let kind = if meta.is_dir() {
+            "directory"
+        } else {
+            "regular file"
+        };
+        if !self.force_current {
+            bail!(
+                "{} is a {kind}, not a symlink; move it away or pass --force-current",
+                current.display()
+            );
+        }
+        let secs = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let aside = self.root.join(format!("current.replaced-{secs}"));
+        fs::rename(current, &aside)
+            .with_context(|| format!("move {} aside to {}", current.display(), aside.display()))?;
+        log.warn(
+            "current_replaced",
+            json!({ "kind": kind, "moved_to": &aside }),
+            format_args!(
+                "current was a {kind}; moved it to {} (--force-current)",
+                aside.display()
+            ),
+        );
+        Ok(())
+    }
+
+    /// Deletes temp artifacts from earlier runs that died mid-deploy. Failing to
+    /// list a directory is only a warning; the deploy itself doesn't need it.
+    fn reclaim_stale(&self, log: &Logger) {
+        if let Err(err) = stale::reclaim(&self.root, &self.objects_dir, &self.snapshots_dir, log) {
+            log.warn(
+                "reclaim_failed",
+                json!({ "error": format!("{err:#}") }),
+                format_args!("stale temp cleanup failed: {err:#}"),
+            );
+        }
+        for mirror in &self.mirrors {
+            mirror.reclaim_stale(log);
+        }
+    }
+
+    /// Copies per-origin counters into the summary and logs them: at `-v`
+    /// normally, by default when some origin failed.
+    fn report_origins(&self, log: &Logger, summary: &mut Summary) {
+        summary.origins = self.fetcher.health.snapshot(&self.origins);
+        summary.latency = self.fetcher.probes.lock().unwrap().clone();
+        summary.failed_origin = self.fetcher.health.last_failed();
+        for stats in &summary.origins {
+            let fields = json!({
+                "origin": &stats.origin,
+                "requests": stats.requests,
+                "failures": stats.failures,
+            });
+            let text = format_args!(
+                "origin {}: {} requests, {} failed",
+                stats.origin, stats.requests, stats.failures
+            );
+            if stats.failures > 0 {
+                log.info("origin_stats", fields, text);
+            } else {
+                log.debug("origin_stats", fields, text);
+            }
+        }
+    }
+
+    /// Closes the run's timings, takes its failover count and logs the lot
+    /// as one line.
+    fn report_stats(&self, log: &Logger, summary: &mut Summary) {
+        let stats = &mut summary.stats;
+        stats.failovers = self.fetcher.health.take_failovers();
+        stats.circuit_transitions = self.fetcher.health.end_run();
+        stats.finish();
+        if stats.phase_secs.contains_key(&Phase::Staging) {
+            stats.copy_strategy = Some(self.copier.current());
+            stats.copy_downgrades = std::iter::once(self)
+                .chain(&self.mirrors)
+                .flat_map(|target| target.copier.downgrades())
+                .collect();
+        }
+        let staged = match stats.copy_strategy {
+            Some(strategy) if stats.copy_downgrades.is_empty() => {
+                format!("; staged with {}", copy_strategy::name(strategy))
+            }
+            Some(strategy) => format!(
+                "; staged with {} (downgraded {})",
+                copy_strategy::name(strategy),
+                stats
+                    .copy_downgrades
+                    .iter()
+                    .map(|d| format!(
+                        "{} -> {}",
+                        copy_strategy::name(d.from),
+                        copy_strategy::name(d.to)
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            None => String::new(),
+        };
+        let opened: Vec<&str> = stats
+            .circuit_transitions
+            .iter()
+            .filter(|t| t.to == health::Circuit::Open)
+            .map(|t| t.origin.as_str())
+            .collect();
+        let circuits = match opened.len() {
+            0 => String::new(),
+            n => format!("; {n} circuits opened ({})", opened.join(", ")),
+        };
+        let phases = stats
+            .phase_secs
+            .iter()
+            .map(|(phase, secs)| format!("{} {secs:.2}s", json!(phase).as_str().unwrap_or("?")))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let rate = |r: Option<f64>| r.map_or_else(|| "-".to_string(), |r| format!("{r:.0}"));
+        log.info(
+            "run_stats",
+            json!({
+                "objects_downloaded": summary.objects_downloaded,
+                "bytes_downloaded": summary.bytes_downloaded,
+                "objects_reused": summary.objects_reused,
+                "bytes_reused": summary.bytes_reused,
+                "stats": &summary.stats,
+            }),
+            format_args!(
+                "stats: {} objects ({} bytes) downloaded, {} ({} bytes) reused; {phases}; \
+                 {} avg / {} peak bytes/s; {} failovers{circuits}{staged}",
+                summary.objects_downloaded,
+                summary.bytes_downloaded,
+                summary.objects_reused,
+                summary.bytes_reused,
+                rate(summary.stats.avg_bytes_per_sec),
+                rate(summary.stats.peak_bytes_per_sec),
+                summary.stats.failovers
+            ),
+        );
+    }
+
+    /// Posts this run's trace to --otlp-endpoint, if set.
+    fn export_trace(&self, log: &Logger, summary: &Summary) {
+        if let Some(outcome) = json!(summary.outcome).as_str() {
+            self.trace.root_attr("cityfeed.outcome", outcome);
+        }
+        let error = (summary.outcome == Outcome::Error).then(|| summary.error.join(": "));
+        self.trace
+            .export(&self.fetcher.http.notify_client, log, error);
+    }
+
+    /// Appends the attempt to each root's `state/history.jsonl`. A failed
+    /// write is a warning; the deploy's outcome stands.
+    fn record_history(&self, args: &Args, log: &Logger, summary: &Summary) {
+        let entry = history::Entry::from_summary(summary);
+        for target in std::iter::once(self).chain(&self.mirrors) {
+            if let Err(err) = history::append(&target.root, &entry, args.history_max_entries) {
+                log.warn(
+                    "history_failed",
+                    json!({ "root": &target.root, "error": format!("{err:#}") }),
+                    format_args!("deploy history for {}: {err:#}", target.root.display()),
+                );
+            }
+        }
+    }
+
+    fn notify(&self, args: &Args, log: &Logger, summary: &Summary) {
+        webhook::notify(
+            &self.fetcher.http.notify_client,
+            log,
+            &args.notify_urls,
+            args.notify_on,
+            &self.root,
+            summary,
+        );
+    }
+
+    /// True when `current` already points at the snapshot for `version`.
+    fn is_current(&self, version: &str) -> bool {
+        let target_rel = PathBuf::from("snapshots").join(version);
+        self.snapshots_dir.join(version).exists()
+            && current_points_to(&self.current_link, &target_rel).unwrap_or(false)
+    }
+
+    /// Reports any failure after a stop signal as an interruption, whatever
+    /// error the aborted read or request happened to surface.
+    fn classify(&self, err: RunError) -> RunError {
+        if self.fetcher.cancelled() {
+            RunError {
+                failure: Failure::Interrupted,
+                err: err.err,
+            }
+        } else {
+            err
+        }
+    }
+
+    /// Refuses a manifest published before the live version, unless
+    /// --allow-downgrade. `promote` and `import` are explicit and skip this.
+    fn check_downgrade(
+        &self,
+        log: &Logger,
+        manifest: &Manifest,
+        manifest_origin: &str,
+    ) -> Result<(), RunError> {
+        if self.allow_downgrade {
+            return Ok(());
+        }
+        let Some(current) = current_version(&self.current_link) else {
+            return Ok(());
+        };
+        let Some(reason) = downgrade::older(&self.manifests_dir, &current, manifest) else {
+            return Ok(());
+        };
+        log.warn(
+            "stale_manifest",
+            json!({
+                "origin": manifest_origin,
+                "version": &manifest.version,
+                "current": &current,
+                "reason": &reason,
+            }),
+            format_args!(
+                "{manifest_origin} offered {}, older than live {current} ({reason})",
+                manifest.version
+            ),
+        );
+        Err(anyhow!(
+            "manifest {} from {manifest_origin} is older than live {current} ({reason}); --allow-downgrade to switch anyway",
+            manifest.version
+        ))
+        .fail_as(Failure::Manifest)
+    }
+
+    /// Compares `manifest` with what was applied when its version was
+    /// deployed here before: the recorded manifest, or failing that the
+    /// counts in `deploy-state.json`. Describes the difference, if any.
+    fn version_content_mismatch(&self, log: &Logger, manifest: &Manifest) -> Option<String> {
+        if !self.snapshots_dir.join(&manifest.version).exists() {
+            return None;
+        }
+        if let Ok(Some(recorded)) = diff::load(&self.manifests_dir, &manifest.version) {
+            if recorded.prefix != manifest.prefix || recorded.filters != manifest.filters {
+                // Not comparable: the snapshot holds another part of the site.
+                log.warn(
+                    "filters_changed",
+                    json!({
+                        "version": &manifest.version,
+                        "recorded": { "prefix": &recorded.prefix, "filters": &recorded.filters },
+                        "now": { "prefix": &manifest.prefix, "filters": &manifest.filters },
+                    }),
+                    format_args!(
+                        "snapshots/{} was built with other --prefix/--only/--exclude settings; they apply from the next version",
+                        manifest.version
+                    ),
+                );
+                return None;
+            }
+            let counts = diff::ManifestDiff::between(&recorded, manifest).counts();
+            let total = counts.added + counts.removed + counts.modified;
+            return (total > 0).then(|| {
+                format!(
+                    "{total} paths differ ({} added, {} removed, {} modified)",
+                    counts.added, counts.removed, counts.modified
+                )
+            });
+        }
+        let applied = state::read(&self.root).filter(|s| s.version == manifest.version)?;
+        let files = manifest.files.len() as u64;
+        let bytes: u64 = manifest.files.iter().map(|f| f.size).sum();
+        (files != applied.file_count || bytes != applied.total_bytes).then(|| {
+            format!(
+                "{files} files ({bytes} bytes), {} ({} bytes) when it was deployed",
+                applied.file_count, applied.total_bytes
+            )
+        })
+    }
+
+    /// Warns when `manifest` reuses a deployed version with other files,
+    /// which would otherwise pass as already current. --strict-version
+    /// refuses it instead.
+    fn check_version_content(
+        &self,
+        log: &Logger,
+        summary: &mut Summary,
+        manifest: &Manifest,
+        manifest_origin: &str,
+    ) -> Result<(), RunError> {
+        let Some(mismatch) = self.version_content_mismatch(log, manifest) else {
+            return Ok(());
+        };
+        summary.version_content_mismatch = true;
+        let version = &manifest.version;
+        log.warn(
+            "version_content_mismatch",
+            json!({ "origin": manifest_origin, "version": version, "mismatch": &mismatch }),
+            format_args!(
+                "manifest {version} from {manifest_origin} does not match the {version} \
+                 already deployed here: {mismatch}; the publisher changed files without a new \
+                 version, so they are not deployed"
+            ),
+        );
+        if self.strict_version {
+            return Err(anyhow!(
+                "manifest {version} from {manifest_origin} differs from the deployed {version} ({mismatch}); refusing it under --strict-version"
+            ))
+            .fail_as(Failure::Manifest);
+        }
+        Ok(())
+    }
+
+    /// Keeps `manifest` for the change summary of the next deploy. Losing it
+    /// only costs that summary, so failures are warnings.
+    fn record_manifest(&self, log: &Logger, manifest: &Manifest) {
+        if let Err(err) = diff::record(&self.manifests_dir, manifest) {
+            log.warn(
+                "manifest_record_failed",
+                json!({ "version": &manifest.version, "error": format!("{err:#}") }),
+                format_args!("could not record manifest {}: {err:#}", manifest.version),
+            );
+        }
+    }
+
+    /// `--prefetch-only`: downloads and verifies every object `manifest`
+    /// needs. Snapshots, `current` and the manifest records are left alone,
+    /// so a later deploy of the same version only has to stage and switch.
+    fn prefetch(
+        &self,
+        log: &Logger,
+        summary: &mut Summary,
+        manifest: &Manifest,
+        manifest_origin: &str,
+    ) -> Result<Outcome, RunError> {
+        summary.version = Some(manifest.version.clone());
+        summary.origin = Some(manifest_origin.to_string());
+        self.trace.root_attr("cityfeed.version", &manifest.version);
+        let store = self.store_for(manifest);
+        summary.enter(Phase::Download);
+        self.download(
+            log,
+            summary,
+            manifest,
+            &store,
+            &self.object_origins(manifest_origin),
+        )
+        .and_then(|()| {
+            check_local_objects(&store, manifest, self.hash_jobs)
+                .context("verify prefetched objects")
+                .fail_as(Failure::Object)
+        })
+        .map_err(|err| self.classify(err))?;
+        log.outcome(
+            "prefetched",
+            json!({
+                "version": &manifest.version,
+                "objects_downloaded": summary.objects_downloaded,
+                "bytes_downloaded": summary.bytes_downloaded,
+                "objects_reused": summary.objects_reused,
+            }),
+            format_args!(
+                "prefetched {}: {} objects ({} bytes) downloaded, {} already stored",
+                manifest.version,
+                summary.objects_downloaded,
+                summary.bytes_downloaded,
+                summary.objects_reused
+            ),
+        );
+        Ok(Outcome::Prefetched)
+    }
+
+    /// Downloads missing objects, builds the snapshot and switches `current`,
+    /// then runs the `--on-switch` hooks if it actually moved.
+    fn deploy(
+        &self,
+        log: &Logger,
+        summary: &mut Summary,
+        manifest: &Manifest,
+        manifest_origin: &str,
+    ) -> Result<Outcome, RunError> {
+        summary.previous_version = current_version(&self.current_link);
+        let outcome = self
+            .deploy_inner(log, summary, manifest, manifest_origin)
+            .map_err(|err| self.classify(err))?;
+        if summary.switched && !self.on_switch.is_empty() {
+            summary.enter(Phase::Hooks);
+            let span = self.trace.span("on_switch");
+            let previous = summary.previous_version.as_deref();
+            let switch = hooks::Switch {
+                root: &self.root,
+                old_version: previous,
+                new_version: &manifest.version,
+                snapshot: &self.snapshots_dir.join(&manifest.version),
+                changed_paths: &diff::changed_paths(&self.manifests_dir, previous, manifest),
+            };
+            let hooks = hooks::run_on_switch(&self.on_switch, log, &switch);
+            if hooks.is_ok() {
+                span.ok();
+            }
+            if let Err(err) = hooks {
+                if self.hook_failure == HookFailure::Fail {
+                    return Err(err).fail_as(Failure::Hook);
+                }
+            }
+        }
+        for target in std::iter::once(self).chain(&self.mirrors) {
+            target.prune(log, summary);
+        }
+        Ok(outcome)
+    }
+
+    /// Applies --keep-days. Failures are warnings: the deploy already
+    /// succeeded.
+    fn prune(&self, log: &Logger, summary: &mut Summary) {
+        let Some(days) = self.keep_days else {
+            return;
+        };
+        let current = current_version(&self.current_link);
+        let previous = current_version(&self.root.join(PREVIOUS_LINK));
+        let pruned = prune::prune(
+            &self.snapshots_dir,
+            &self.manifests_dir,
+            current.as_deref(),
+            previous.as_deref(),
+            Duration::from_secs(days.saturating_mul(24 * 60 * 60)),
+            log,
+        );
+        match pruned {
+            Ok(pruned) if !pruned.versions.is_empty() => {
+                log.info(
+                    "pruned",
+                    json!({ "root": &self.root, "versions": &pruned.versions, "bytes": pruned.bytes }),
+                    format_args!(
+                        "pruned {} snapshots older than {days} days, freed {} bytes",
+                        pruned.versions.len(),
+                        pruned.bytes
+                    ),
+                );
+                summary.snapshots_pruned.extend(pruned.versions);
+                summary.bytes_pruned += pruned.bytes;
+            }
+            Ok(_) => {}
+            Err(err) => log.warn(
+                "prune_failed",
+                json!({ "error": format!("{err:#}") }),
+                format_args!("snapshot pruning failed: {err:#}"),
+            ),
+        }
+    }
+
+    /// Brings this root and every mirror to `manifest.version`. Objects are
+    /// downloaded once, into this root's store. Every root that lacks the
+    /// snapshot is staged from that store before any `current` moves, so a
+    /// download or staging failure leaves all roots as they were.
+    fn deploy_inner(
+        &self,
+        log: &Logger,
+        summary: &mut Summary,
+        manifest: &Manifest,
+        manifest_origin: &str,
+    ) -> Result<Outcome, RunError> {
+        let origins = &self.object_origins(manifest_origin);
+        summary.version = Some(manifest.version.clone());
+        summary.origin = Some(manifest_origin.to_string());
+        self.trace.root_attr("cityfeed.version", &manifest.version);
+        let targets: Vec<&Puller> = std::iter::once(self).chain(&self.mirrors).collect();
+        for target in &targets {
+            target
+                .check_current(log)
+                .map_err(|err| self.in_root(target, err.into()))?;
+        }
+        self.check_version_content(log, summary, manifest, manifest_origin)?;
+
+        summary.snapshot = Some(self.snapshots_dir.join(&manifest.version));
+        let target_rel = PathBuf::from("snapshots").join(&manifest.version);
+        let pending: Vec<&Puller> = targets
+            .iter()
+            .copied()
+            .filter(|target| !target.is_current(&manifest.version))
+            .collect();
+        if pending.is_empty() {
+            log.outcome(
+                "already_current",
+                json!({ "version": &manifest.version }),
+                "snapshot already present and current already points to it",
+            );
+            for target in &targets {
+                state::checked(
+                    &target.root,
+                    manifest,
+                    manifest_origin,
+                    self.channel.as_deref(),
+                )
+                .context("write deploy-state.json")
+                .map_err(|err| self.in_root(target, err.into()))?;
+            }
+            return Ok(Outcome::AlreadyCurrent);
+        }
+
+        summary.changes = diff::report(
+            log,
+            &self.manifests_dir,
+            summary.previous_version.as_deref(),
+            manifest,
+            self.show_diff,
+        );
+        // The existing snapshot still holds the recorded files, so the
+        // record stays theirs.
+        if !summary.version_content_mismatch {
+            for target in &pending {
+                target.record_manifest(log, manifest);
+            }
+        }
+
+        let unstaged: Vec<&Puller> = pending
+            .iter()
+            .copied()
+            .filter(|target| !target.snapshots_dir.join(&manifest.version).exists())
+            .collect();
+        let rebuilt = !unstaged.is_empty();
+        if rebuilt {
+            let store = self.store_for(manifest);
+            summary.enter(Phase::Download);
+            self.download(log, summary, manifest, &store, origins)?;
+            summary.enter(Phase::Staging);
+            for target in &unstaged {
+                let preserved = target
+                    .stage(log, &store, manifest)
+                    .map_err(|err| self.in_root(target, err))?;
+                for path in preserved {
+                    if !summary.preserved.contains(&path) {
+                        summary.preserved.push(path);
+                    }
+                }
+            }
+        }
+
+        summary.enter(Phase::Switch);
+        let undo = self.switch_all(log, &pending, &target_rel)?;
+        if let Err(err) = self.canary.check(log) {
+            for (target, previous, previous_link) in undo.iter().rev() {
+                target.restore_current(log, previous.as_deref(), previous_link.as_deref());
+            }
+            return Err(err.context(format!(
+                "canary failed after switching to {}; current rolled back",
+                manifest.version
+            )))
+            .fail_as(Failure::Canary);
+        }
+        summary.switched = true;
+        for target in &pending {
+            state::switched(
+                &target.root,
+                manifest,
+                manifest_origin,
+                self.channel.as_deref(),
+            )
+            .context("write deploy-state.json")
+            .map_err(|err| self.in_root(target, err.into()))?;
+        }
+
+        let fields =
+            json!({ "version": &manifest.version, "target": &target_rel, "rebuilt": rebuilt });
+        if rebuilt {
+            log.outcome(
+                "switched",
+                fields,
+                format_args!("switched current -> {}", target_rel.display()),
+            );
+        } else {
+            log.outcome(
+                "switched",
+                fields,
+                format_args!(
+                    "snapshot already present; switched current -> {}",
+                    target_rel.display()
+                ),
+            );
+        }
+        Ok(Outcome::Updated)
+    }
+
+    /// Fetches every object of `manifest` that `store` lacks, after the
+    /// size and free-space checks.
+    fn download(
+        &self,
+        log: &Logger,
+        summary: &mut Summary,
+        manifest: &Manifest,
+        store: &ObjectStore,
+        origins: &[String],
+    ) -> Result<(), RunError> {
+        let fetcher = &self.fetcher;
+        store.migrate(log).context("migrate object layout")?;
+        let needed = missing_object_bytes(store, manifest);
+        let total = manifest
+            .files
+            .iter()
+            .fold(0u64, |sum, file| sum.saturating_add(file.size));
+        summary.file_count = Some(manifest.files.len() as u64);
+        summary.total_bytes = Some(total);
+        summary.bytes_needed = Some(needed);
+        if !self.allow_small_manifest {
+            check_minimums(
+                manifest.files.len() as u64,
+                total,
+                self.min_files,
+                self.min_total_bytes,
+            )
+            .fail_as(Failure::Manifest)?;
+        }
+        check_limits(
+            manifest.files.len() as u64,
+            needed,
+            self.max_file_count,
+            self.max_total_bytes,
+        )
+        .fail_as(Failure::Manifest)?;
+        if self.offline {
+            return check_local_objects(store, manifest, self.hash_jobs).fail_as(Failure::Object);
+        }
+        self.copier.probe(log, &self.root);
+        check_free_space(
+            &self.root,
+            store,
+            manifest,
+            &self.copier,
+            self.min_free_bytes,
+        )?;
+
+        let progress = fetcher.progress.start(log, download_bytes(store, manifest));
+        let mut seen: HashSet<&str> = HashSet::new();
+        let mut failed: Vec<FailedObject> = Vec::new();
+        for file in &manifest.files {
+            let _ = validate_rel_path(&file.path)
+                .with_context(|| format!("invalid manifest path: {}", file.path))?;
+
+            let first_sighting = seen.insert(&file.hash);
+            let stored = stored_size(store, &file.hash);
+            if stored == Some(file.size) {
+                if first_sighting {
+                    summary.objects_reused += 1;
+                    summary.bytes_reused += file.size;
+                    log.trace(
+                        "reuse_object",
+                        json!({ "hash": &file.hash, "bytes": file.size }),
+                        format_args!("reuse object hash={} size={}", file.hash, file.size),
+                    );
+                }
+                continue;
+            }
+            if let Some(failure) = failed.iter_mut().find(|f| f.hash == file.hash) {
+                failure.paths.push(file.path.clone());
+                continue;
+            }
+            summary.object = Some(file.hash.clone());
+            if let Some(size) = stored {
+                repair_object(log, store, file, size).fail_as(Failure::Object)?;
+                summary.objects_repaired += 1;
+            }
+
+            fetcher.check_cancelled()?;
+            let transfer = file.transfer.as_ref();
+            match transfer {
+                Some(t) => log.info(
+                    "download_object",
+                    json!({
+                        "hash": &file.hash,
+                        "bytes": file.size,
+                        "transfer": { "encoding": &t.encoding, "hash": &t.hash, "bytes": t.size },
+                    }),
+                    format_args!(
+                        "download object hash={} size={} as {} size={}",
+                        file.hash, file.size, t.encoding, t.size
+                    ),
+                ),
+                None if !file.parts.is_empty() => log.info(
+                    "download_object",
+                    json!({ "hash": &file.hash, "bytes": file.size, "parts": file.parts.len() }),
+                    format_args!(
+                        "download object hash={} size={} in {} parts",
+                        file.hash,
+                        file.size,
+                        file.parts.len()
+                    ),
+                ),
+                None => log.info(
+                    "download_object",
+                    json!({ "hash": &file.hash, "bytes": file.size }),
+                    format_args!("download object hash={} size={}", file.hash, file.size),
+                ),
+            }
+            fetcher
+                .progress
+                .start_object(&file.path, transfer.map_or(file.size, |t| t.size));
+            let mut span = self.trace.span("object.download");
+            span.attr("hash", &file.hash);
+            span.attr("path", &file.path);
+            span.attr("bytes", transfer.map_or(file.size, |t| t.size));
+            let started = Instant::now();
+            let served = match fetch_object(fetcher, log, origins, file, store)
+                .with_context(|| format!("download object {}", file.hash))
+            {
+                Ok(served) => served,
+                Err(err) => {
+                    let Some(all) = err
+                        .chain()
+                        .find_map(|e| e.downcast_ref::<AllOriginsFailed>())
+                    else {
+                        return Err(err).fail_as(Failure::Object);
+                    };
+                    failed.push(FailedObject {
+                        hash: file.hash.clone(),
+                        paths: vec![file.path.clone()],
+                        error: format!("{err:#}"),
+                        origins: all.errors.clone(),
+                    });
+                    summary.objects_failed = failed.len() as u64;
+                    if !self.keep_going {
+                        summary.failed_objects = failed;
+                        return Err(err).fail_as(Failure::Object);
+                    }
+                    log.warn(
+                        "object_failed",
+                        json!({ "hash": &file.hash, "path": &file.path, "error": format!("{err:#}") }),
+                        format_args!(
+                            "no origin could deliver {} ({}); carrying on (--keep-going)",
+                            file.path, file.hash
+                        ),
+                    );
+                    fetcher.progress.finish_object();
+                    summary.object = None;
+                    continue;
+                }
+            };
+            let wire = transfer.map_or(file.size, |t| t.size);
+            summary.stats.downloaded(wire, started.elapsed());
+            span.attr("origin", &served.origin);
+            span.attr("retries", served.retries);
+            span.ok();
+            fetcher.progress.finish_object();
+            summary.objects_downloaded += 1;
+            // What went over the wire, which is what metered links care about.
+            summary.bytes_downloaded += wire;
+            summary.object = None;
+        }
+        drop(progress);
+        if failed.is_empty() {
+            return Ok(());
+        }
+        let listed: Vec<String> = failed
+            .iter()
+            .take(FAILED_OBJECTS_LISTED)
+            .map(|f| format!("{} ({})", f.paths[0], f.hash))
+            .collect();
+        let more = match failed.len().saturating_sub(FAILED_OBJECTS_LISTED) {
+            0 => String::new(),
+            n => format!(" and {n} more"),
+        };
+        let err = anyhow!(
+            "{} objects could not be downloaded from any origin: {}{more}",
+            failed.len(),
+            listed.join(", ")
+        );
+        summary.failed_objects = failed;
+        Err(err).fail_as(Failure::Object)
+    }
+
+    /// Points `current` at `target_rel` in each of `targets`, in order. If
+    /// one switch fails, the roots already switched are pointed back at
+    /// their old snapshot.
+    fn switch_all<'a>(
+        &self,
+        log: &Logger,
+        targets: &[&'a Puller],
+        target_rel: &Path,
+    ) -> Result<Vec<Undo<'a>>, RunError> {
+        let span = self.trace.span("switch");
+        let mut switched: Vec<Undo> = Vec::new();
+        for target in targets {
+            let previous_link = fs::read_link(target.root.join(PREVIOUS_LINK)).ok();
+            let result = read_current(&target.current_link).and_then(|previous| {
+                switch_symlink_atomically(&target.current_link, target_rel, &target.root)
+                    .context("switch current symlink")?;
+                Ok(previous)
+            });
+            match result {
+                Ok(previous) => switched.push((target, previous, previous_link)),
+                Err(err) => {
+                    for (done, previous, previous_link) in switched.iter().rev() {
+                        done.restore_current(log, previous.as_deref(), previous_link.as_deref());
+                    }
+                    return Err(self.in_root(target, err.into()));
+                }
+            }
+        }
+        span.ok();
+        Ok(switched)
+    }
+
+    /// Undoes a switch during a multi-root rollback, `previous` link
+    /// included. Failing to is logged: the switch error that caused the
+    /// rollback is the one reported.
+    fn restore_current(&self, log: &Logger, previous: Option<&Path>, previous_link: Option<&Path>) {
+        let restored = match previous {
+            Some(previous) => switch_symlink_atomically(&self.current_link, previous, &self.root),
+            None => fs::remove_file(&self.current_link)
+                .or_else(|_| fs::remove_dir(&self.current_link))
+                .with_context(|| format!("remove {}", self.current_link.display())),
+        }
+        .and_then(|()| set_previous(&self.root, previous_link));
+        match restored {
+            Ok(()) => log.warn(
+                "switch_rolled_back",
+                json!({ "root": &self.root, "target": previous }),
+                format_args!(
+                    "rolled back {} -> {}",
+                    self.current_link.display(),
+                    previous.map_or("nothing".into(), |p| p.display().to_string())
+                ),
+            ),
+            Err(err) => log.error(
+                "rollback_failed",
+                json!({ "root": &self.root, "error": format!("{err:#}") }),
+                format_args!(
+                    "could not roll back {}: {err:#}",
+                    self.current_link.display()
+                ),
+            ),
+        }
+    }
+
+    /// Names `target`'s root in `err` when the deploy spans more than one.
+    fn in_root(&self, target: &Puller, err: RunError) -> RunError {
+        if self.mirrors.is_empty() {
+            return err;
+        }
+        RunError {
+            failure: err.failure,
+            err: err.err.context(format!("root {}", target.root.display())),
+        }
+    }
+
+    /// Copies each object to its staging path on `--stage-jobs` threads, or
+    /// at least `--hash-jobs` with `--verify-on-stage`, since every copy is
+    /// then hashed too. The first error stops the workers from starting new
+    /// files and is returned once they are done, so the staging dir is never
+    /// promoted.
+    fn copy_files(
+        &self,
+        log: &Logger,
+        store: &ObjectStore,
+        copies: &[(&ManifestFile, PathBuf)],
+    ) -> Result<(), RunError> {
+        let next = AtomicUsize::new(0);
+        let failed: Mutex<Option<RunError>> = Mutex::new(None);
+        let jobs = match self.verify_on_stage {
+            true => self.stage_jobs.max(self.hash_jobs),
+            false => self.stage_jobs,
+        };
+        let jobs = jobs.clamp(1, copies.len().max(1));
+        thread::scope(|scope| {
+            for _ in 0..jobs {
+                scope.spawn(|| loop {
+                    if failed.lock().unwrap().is_some() {
+                        break;
+                    }
+                    let Some((file, dst)) = copies.get(next.fetch_add(1, Ordering::SeqCst)) else {
+                        break;
+                    };
+                    if let Err(err) = self.stage_file(log, store, file, dst) {
+                        failed.lock().unwrap().get_or_insert(err);
+                        break;
+                    }
+                });
+            }
+        });
+        match failed.into_inner().unwrap() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Hard-links each repeated file to the copy of the same content made by
+    /// `copy_files`. Where linking fails (a filesystem without hard links),
+    /// or with `--copy-strategy copy`, the file is copied instead.
+    fn link_files(
+        &self,
+        log: &Logger,
+        store: &ObjectStore,
+        copies: &[(&ManifestFile, PathBuf)],
+        links: &[(&ManifestFile, PathBuf, usize)],
+    ) -> Result<(), RunError> {
+        let mut linked = 0;
+        for (file, dst, first) in links {
+            self.fetcher.check_cancelled()?;
+            let src = &copies[*first].1;
+            if self.copier.requested() == CopyStrategy::Copy {
+                self.stage_file(log, store, file, dst)?;
+                continue;
+            }
+            match fs::hard_link(src, dst) {
+                Ok(()) => {
+                    let parent = dst.parent().unwrap_or(dst);
+                    fsync_dir(parent).with_context(|| format!("fsync dir {}", parent.display()))?;
+                    linked += 1;
+                }
+                Err(err) => {
+                    log.debug(
+                        "link_failed",
+                        json!({ "path": &file.path, "error": err.to_string() }),
+                        format_args!(
+                            "can't hard-link {} to {}: {err}; copying it",
+                            file.path,
+                            src.display()
+                        ),
+                    );
+                    self.stage_file(log, store, file, dst)?;
+                }
+            }
+        }
+        if linked > 0 {
+            log.debug(
+                "files_linked",
+                json!({ "linked": linked }),
+                format_args!("staged {linked} repeated files as hard links"),
+            );
+        }
+        Ok(())
+    }
+
+    /// Snapshot permission bits for `file`.
+    fn file_mode(&self, file: &ManifestFile) -> u32 {
+        file.mode
+            .map(|m| m & MODE_MASK)
+            .unwrap_or(self.perms.file_mode)
+    }
+
+    /// Copies `file`'s object to `dst`, whose parent already exists.
+    fn stage_file(
+        &self,
+        log: &Logger,
+        store: &ObjectStore,
+        file: &ManifestFile,
+        dst: &Path,
+    ) -> Result<(), RunError> {
+        self.fetcher.check_cancelled()?;
+        let src_obj = check_stored_object(store, file).fail_as(Failure::Object)?;
+        if dst.exists() {
+            return Err(anyhow!("snapshot destination already exists: {}", dst.display()).into());
+        }
+        let verify = self.verify_on_stage.then_some(file.hash.as_str());
+        let mode = self.file_mode(file);
+        let copied = self
+            .copier
+            .place(log, &src_obj, dst, mode, &self.perms, verify);
+        match copied {
+            Ok(()) => Ok(()),
+            Err(err) if err.is::<BodyMismatch>() => {
+                let err = quarantine_corrupt(log, store, file, err);
+                Err(err).fail_as(Failure::Object)
+            }
+            Err(err) => Err(err
+                .context(format!("copy {} -> {}", src_obj.display(), dst.display()))
+                .into()),
+        }
+    }
+
+    /// Copies each `--preserve` path from the live snapshot into `staging`,
+    /// replacing the manifest's file there, and returns the paths it copied.
+    /// Only regular files are carried over, with their own permission bits.
+    fn preserve_files(
+        &self,
+        log: &Logger,
+        staging: &Path,
+        manifest_paths: &HashSet<PathBuf>,
+    ) -> Result<Vec<String>, RunError> {
+        if self.preserve.is_empty() {
+            return Ok(Vec::new());
+        }
+        let live = current_version(&self.current_link).map(|v| self.snapshots_dir.join(v));
+        let mut preserved = Vec::new();
+        for rel_path in &self.preserve {
+            let src = live.as_ref().map(|live| live.join(rel_path));
+            let meta = src.as_ref().and_then(|src| fs::symlink_metadata(src).ok());
+            let Some((src, meta)) = src.zip(meta) else {
+                if !manifest_paths.contains(rel_path) {
+                    log.warn(
+                        "preserve_missing",
+                        json!({ "path": rel_path }),
+                        format_args!(
+                            "--preserve {}: in neither the live snapshot nor the manifest; nothing to keep",
+                            rel_path.display()
+                        ),
+                    );
+                }
+                continue;
+            };
+            if !meta.is_file() {
+                log.warn(
+                    "preserve_skipped",
+                    json!({ "path": rel_path }),
+                    format_args!(
+                        "--preserve {}: not a regular file in the live snapshot; skipped",
+                        rel_path.display()
+                    ),
+                );
+                continue;
+            }
+            let dst = staging.join(rel_path);
+            let parent = dst
+                .parent()
+                .ok_or_else(|| anyhow!("destination has no parent: {}", dst.display()))?;
+            self.perms
+                .create_dir_all(staging, parent)
+                .with_context(|| format!("create dir {}", parent.display()))?;
+            match fs::symlink_metadata(&dst) {
+                Ok(existing) if existing.is_dir() => {
+                    return Err(anyhow!(
+                        "--preserve {}: the manifest has a directory there",
+                        rel_path.display()
+                    )
+                    .into());
+                }
+                Ok(_) => {
+                    fs::remove_file(&dst).with_context(|| format!("remove {}", dst.display()))?
+                }
+                Err(_) => {}
+            }
+            copy_file_atomic(&src, &dst, self.preserved_mode(&meta), &self.perms, None)
+                .with_context(|| format!("preserve {}", rel_path.display()))?;
+            preserved.push(rel_path.to_string_lossy().into_owned());
+        }
+        if !preserved.is_empty() {
+            log.info(
+                "preserved",
+                json!({ "root": &self.root, "paths": &preserved }),
+                format_args!("preserved from the live snapshot: {}", preserved.join(", ")),
+            );
+        }
+        Ok(preserved)
+    }
+
+    /// A preserved file keeps its own permission bits, within `MODE_MASK`.
+    #[cfg(unix)]
+    fn preserved_mode(&self, meta: &fs::Metadata) -> u32 {
+        meta.permissions().mode() & MODE_MASK
+    }
+
+    #[cfg(not(unix))]
+    fn preserved_mode(&self, _meta: &fs::Metadata) -> u32 {
+        self.perms.file_mode
+    }
+
+    /// Builds `snapshots/<version>` from objects already in `store`: copied
+    /// into a staging dir that is renamed into place once complete. Returns
+    /// the `--preserve` paths carried over.
+    fn stage(
+        &self,
+        log: &Logger,
+        store: &ObjectStore,
+        manifest: &Manifest,
+    ) -> Result<Vec<String>, RunError> {
+        let snapshots_dir = &self.snapshots_dir;
+        let snapshot_final = snapshots_dir.join(&manifest.version);
+        let span = self.trace.span("stage");
+        let staging = tempfile::Builder::new()
+            .prefix(&stale::staging_prefix(&sanitize_prefix(&manifest.version)))
+            .tempdir_in(snapshots_dir)
+            .context("create staging snapshot dir")?;
+        let perms = &self.perms;
+        perms
+            .apply_dir(staging.path())
+            .context("set up staging snapshot dir")?;
+
+        // Directories first, on this thread, so the copy workers never race
+        // to create (and chmod) the same parent. Content is copied once per
+        // hash and mode; the other paths with it become hard links.
+        let mut copies = Vec::with_capacity(manifest.files.len());
+        let mut links = Vec::new();
+        let mut first_copy: HashMap<(&str, u32), usize> = HashMap::new();
+        for file in &manifest.files {
+            let rel_path = validate_rel_path(&file.path)
+                .with_context(|| format!("invalid manifest path: {}", file.path))?;
+            let dst = staging.path().join(&rel_path);
+            if let Some(parent) = dst.parent() {
+                perms
+                    .create_dir_all(staging.path(), parent)
+                    .with_context(|| format!("create dir {}", parent.display()))?;
+            }
+            match first_copy.entry((&file.hash, self.file_mode(file))) {
+                hash_map::Entry::Occupied(first) => links.push((file, dst, *first.get())),
+                hash_map::Entry::Vacant(slot) => {
+                    slot.insert(copies.len());
+                    copies.push((file, dst));
+                }
+            }
+        }
+        self.copy_files(log, store, &copies)?;
+        self.link_files(log, store, &copies, &links)?;
+        let manifest_paths: HashSet<PathBuf> = copies
+            .iter()
+            .map(|(_, dst)| dst)
+            .chain(links.iter().map(|(_, dst, _)| dst))
+            .filter_map(|dst| dst.strip_prefix(staging.path()).ok())
+            .map(Path::to_path_buf)
+            .collect();
+
+        for link in &manifest.symlinks {
+            let rel_path = validate_rel_path(&link.path)
+                .with_context(|| format!("invalid manifest path: {}", link.path))?;
+            let target = validate_symlink_target(&rel_path, &link.target)
+                .with_context(|| format!("invalid symlink target for {}", link.path))?;
+
+            let dst = staging.path().join(&rel_path);
+            let parent = dst
+                .parent()
+                .ok_or_else(|| anyhow!("destination has no parent: {}", dst.display()))?;
+            perms
+                .create_dir_all(staging.path(), parent)
+                .with_context(|| format!("create dir {}", parent.display()))?;
+            if fs::symlink_metadata(&dst).is_ok() {
+                return Err(
+                    anyhow!("snapshot destination already exists: {}", dst.display()).into(),
+                );
+            }
+            create_symlink(&target, &dst)?;
+            perms.chown(&dst)?;
+            fsync_dir(parent).with_context(|| format!("fsync dir {}", parent.display()))?;
+        }
+
+        let preserved = self.preserve_files(log, staging.path(), &manifest_paths)?;
+
+        // Last point at which a stop request still leaves the root untouched.
+        self.fetcher.check_cancelled()?;
+        let staging_path = staging.keep();
+        fs::rename(&staging_path, &snapshot_final).with_context(|| {
+            format!(
+                "promote snapshot {} -> {}",
+                staging_path.display(),
+                snapshot_final.display()
+            )
+        })?;
+        fsync_dir(snapshots_dir).context("fsync snapshots dir")?;
+        span.ok();
+        Ok(preserved)
+    }
+}
+
+fn log_manifest(log: &Logger, manifest: &Manifest, origin: &str) {
+    log.info(
+        "manifest",
+        json!({
+            "version": &manifest.version,
+            "files": manifest.files.len(),
+            "symlinks": manifest.symlinks.len(),
+        }),
+        format_args!(
+            "manifest version={} files={}",
+            manifest.version,
+            manifest.files.len()
+        ),
+    );
+    log.info(
+        "manifest_origin",
+        json!({ "origin": origin }),
+        format_args!("manifest origin={origin}"),
+    );
+}
+
+/// Total size of the manifest's objects not yet in the store, each counted once.
+fn missing_object_bytes(store: &ObjectStore, manifest: &Manifest) -> u64 {
+    let mut missing: HashSet<&str> = HashSet::new();
+    let mut bytes: u64 = 0;
+    for file in &manifest.files {
+        if stored_size(store, &file.hash) != Some(file.size) && missing.insert(&file.hash) {
+            bytes = bytes.saturating_add(file.size);
+        }
+    }
+    bytes
+}
+
+/// Bytes the deploy will actually transfer for missing objects: the
+/// compressed size for `transfer` entries, the file size otherwise.
+fn download_bytes(store: &ObjectStore, manifest: &Manifest) -> u64 {
+    let mut missing: HashSet<&str> = HashSet::new();
+    let mut bytes: u64 = 0;
+    for file in &manifest.files {
+        if stored_size(store, &file.hash) != Some(file.size) && missing.insert(&file.hash) {
+            let size = file.transfer.as_ref().map_or(file.size, |t| t.size);
+            bytes = bytes.saturating_add(size);
+        }
+    }
+    bytes
+}
+
+/// Guards against a runaway publish before anything is downloaded. A limit
+/// of 0 means unlimited.
+fn check_limits(files: u64, bytes: u64, max_files: u64, max_bytes: u64) -> Result<()> {
+    if max_files > 0 && files > max_files {
+        bail!("manifest lists {files} files, over --max-file-count {max_files}");
+    }
+    if max_bytes > 0 && bytes > max_bytes {
+        bail!("deploy needs {bytes} bytes of new objects, over --max-total-bytes {max_bytes}");
+    }
+    Ok(())
+}
+
+/// Guards against a publisher emitting an empty or truncated site. An empty
+/// manifest fails whatever the thresholds.
+fn check_minimums(files: u64, bytes: u64, min_files: u64, min_bytes: u64) -> Result<()> {
+    const OVERRIDE: &str = "--allow-small-manifest to deploy it anyway";
+    if files == 0 || files < min_files {
+        bail!("manifest lists {files} files, under --min-files {min_files} ({OVERRIDE})");
+    }
+    if bytes < min_bytes {
+        bail!("manifest files add up to {bytes} bytes, under --min-total-bytes {min_bytes} ({OVERRIDE})");
+    }
+    Ok(())
+}
+
+/// Fails before any download if the root's filesystem can't hold the missing
+/// objects plus what staging the snapshot writes, and still keep `min_free`
+/// bytes spare.
+fn check_free_space(
+    root: &Path,
+    store: &ObjectStore,
+    manifest: &Manifest,
+    copier: &Copier,
+    min_free: u64,
+) -> Result<()> {
+    let objects_bytes = missing_object_bytes(store, manifest);
+    let staging_bytes = staging_bytes(manifest, copier);
+    let required = objects_bytes.saturating_add(staging_bytes);
+    let available = fs4::available_space(root)
+        .with_context(|| format!("query free space on {}", root.display()))?;
+    if required.saturating_add(min_free) > available {
+        bail!(
+            "insufficient disk space on {}: need {required} bytes ({objects_bytes} objects + \
+             {staging_bytes} snapshot) plus {min_free} reserved, {available} available",
+            root.display()
+        );
+    }
+    Ok(())
+}
+
+/// Bytes staging `manifest` writes besides its objects. Reflinked and
+/// hard-linked files take next to none. Copying writes each distinct content
+/// once, since repeats are linked to the first copy, except under `--copy-strategy
+/// copy`, which links nothing.
+fn staging_bytes(manifest: &Manifest, copier: &Copier) -> u64 {
+    if copier.current() != CopyStrategy::Copy {
+        return 0;
+    }
+    let mut seen: HashSet<&str> = HashSet::new();
+    manifest
+        .files
+        .iter()
+        .filter(|file| copier.requested() == CopyStrategy::Copy || seen.insert(&file.hash))
+        .fold(0u64, |sum, file| sum.saturating_add(file.size))
+}
+
+/// `--offline`: every object `manifest` needs must already be in `store`
+/// with the right size and content. Lists each one that isn't.
+fn check_local_objects(store: &ObjectStore, manifest: &Manifest, jobs: usize) -> Result<()> {
+    let mut seen = HashSet::new();
+    let unique: Vec<&ManifestFile> = manifest
+        .files
+        .iter()
+        .filter(|file| seen.insert(&file.hash))
+        .collect();
+    let problems: Vec<String> = hashing::map_in_order(&unique, jobs, |file| {
+        let problem = match File::open(store.path(&file.hash)) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => "missing".to_string(),
+            Err(err) => format!("unreadable: {err}"),
+            Ok(f) => {
+                let mut reader = Hashing::new(f);
+                match io::copy(&mut reader, &mut io::sink()) {
+                    Err(err) => format!("unreadable: {err}"),
+                    Ok(_) => match reader.finish() {
+                        (_, len, _) if len != file.size => {
+                            format!("{len} bytes, expected {}", file.size)
+                        }
+                        (hash, _, _) if hash != file.hash => format!("content hashes to {hash}"),
+                        _ => return None,
+                    },
+                }
+            }
+        };
+        Some(format!("{} ({}): {problem}", file.path, file.hash))
+    })
+    .into_iter()
+    .flatten()
+    .collect();
+    if !problems.is_empty() {
+        bail!(
+            "{} of {} objects not available offline: {}",
+            problems.len(),
+            unique.len(),
+            problems.join("; ")
+        );
+    }
+    Ok(())
+}
+
+/// Size of the stored object for `hash`, if there is one.
+fn stored_size(store: &ObjectStore, hash: &str) -> Option<u64> {
+    fs::metadata(store.path(hash)).ok().map(|meta| meta.len())
+}
+
+/// Downloads `file` into the store. A stored copy that still has the wrong
+/// size afterwards (one that raced in ahead of ours is kept as is) is
+/// quarantined and fetched again, up to `OBJECT_FETCH_ATTEMPTS` times.
+fn fetch_object(
+    fetcher: &Fetcher,
+    log: &Logger,
+    origins: &[String],
+    file: &ManifestFile,
+    store: &ObjectStore,
+) -> Result<Served> {
+    let mut attempt = 1;
+    loop {
+        let served = if file.parts.is_empty() {
+            let transfer = file.transfer.as_ref();
+            download_object_any(
+                fetcher, log, origins, &file.hash, file.size, transfer, store,
+            )?
+        } else {
+            parts::assemble(fetcher, log, origins, file, store)?
+        };
+        match stored_size(store, &file.hash) {
+            Some(size) if size == file.size => return Ok(served),
+            Some(size) if attempt < OBJECT_FETCH_ATTEMPTS => {
+                repair_object(log, store, file, size)?;
+                attempt += 1;
+            }
+            Some(size) => bail!(
+                "stored object is {size} bytes after {attempt} downloads, expected {}",
+                file.size
+            ),
+            None => bail!("object missing from the store after download"),
+        }
+    }
+}
+
+/// Moves a stored object of the wrong size into `objects/.quarantine/` so
+/// the caller can fetch it again.
+fn repair_object(log: &Logger, store: &ObjectStore, file: &ManifestFile, size: u64) -> Result<()> {
+    log.warn(
+        "repair_object",
+        json!({ "hash": &file.hash, "bytes": file.size, "found_bytes": size }),
+        format_args!(
+            "stored object {} is {size} bytes, expected {}; quarantining and fetching it again",
+            file.hash, file.size
+        ),
+    );
+    fsck::quarantine(store, &store.path(&file.hash))
+        .with_context(|| format!("quarantine object {}", file.hash))
+}
+
+/// Moves a stored object that failed `--verify-on-stage` into the
+/// quarantine, so the next run downloads it again, and returns the error
+/// to fail the deploy with.
+fn quarantine_corrupt(
+    log: &Logger,
+    store: &ObjectStore,
+    file: &ManifestFile,
+    err: anyhow::Error,
+) -> anyhow::Error {
+    log.warn(
+        "corrupt_object",
+        json!({ "hash": &file.hash, "path": &file.path, "error": format!("{err:#}") }),
+        format_args!(
+            "stored object {} for {} is corrupt ({err:#}); quarantining it",
+            file.hash, file.path
+        ),
+    );
+    let err = match fsck::quarantine(store, &store.path(&file.hash)) {
+        Ok(()) => err,
+        Err(quarantine) => err.context(format!("quarantine failed too: {quarantine:#}")),
+    };
+    err.context(format!(
+        "stage {}: stored object {} is corrupt",
+        file.path, file.hash
+    ))
+}
+
+/// Returns the CAS path for `file` after checking it exists with the declared size.
+fn check_stored_object(store: &ObjectStore, file: &ManifestFile) -> Result<PathBuf> {
+    let src_obj = store.path(&file.hash);
+    if !src_obj.exists() {
+        bail!(
+            "missing required object after download: {}",
+            src_obj.display()
+        );
+    }
+    let actual_size = fs::metadata(&src_obj)
+        .with_context(|| format!("stat {}", src_obj.display()))?
+        .len();
+    if actual_size != file.size {
+        bail!(
+            "object {} size mismatch on disk: expected {} got {}",
+            file.hash,
+            file.size,
+            actual_size
+        );
+    }
+    Ok(src_obj)
+}
+
+/// HTTP client plus the transfer knobs that apply to every request.
+/// `file://` URLs bypass the client and read straight from disk.
+#[derive(Clone)]
+struct Fetcher {
+    http: Http,
+    stall_timeout: Option<Duration>,
+    max_retry_after: Duration,
+    /// Same-origin retries after a `BodyMismatch`.
+    mismatch_retries: u32,
+    /// `--manifest-sha256`: the only manifest bytes accepted.
+    manifest_sha256: Option<String>,
+    /// `--manifest-pubkey` and `--require-file-signatures`.
+    signing: signatures::Policy,
+    limiter: Option<Arc<RateLimiter>>,
+    cancel: Arc<AtomicBool>,
+    health: Arc<OriginHealth>,
+    strategy: OriginStrategy,
+    /// Round-robin position, shared by every request in the process.
+    next_origin: Arc<AtomicUsize>,
+    /// This run's `--origin-strategy latency` probes, fastest first.
+    probes: Arc<Mutex<Vec<latency::Probe>>>,
+    progress: Arc<Progress>,
+    ip_family: Option<IpFamily>,
+    /// For the hints on connection errors.
+    net: NetSettings,
+    auth: Credentials,
+    headers: Headers,
+    query_auth: QueryAuth,
+    /// Conditional manifest requests; off with `--manifest-sha256`, which
+    /// has to see the bytes.
+    validators: Option<Arc<ManifestValidators>>,
+    /// `--channel`: which `manifests/<channel>.json` origins are asked for.
+    channel: String,
+}
+
+enum Source {
+    File(File),
+    Http(reqwest::blocking::Response),
+    /// A conditional request's `304`.
+    NotModified,
+}
+
+/// A manifest body from `Fetcher::open`.
+struct Opened {
+    body: Box<dyn Read>,
+    validators: Validators,
+}
+
+impl Fetcher {
+    /// A fetcher for `args`' origins, without manifest validators.
+    fn new(args: &Args, http: &Http, cancel: Arc<AtomicBool>) -> Result<Self> {
+        Ok(Self {
+            http: http.clone(),
+            stall_timeout: args.stall_timeout,
+            max_retry_after: args.max_retry_after,
+            mismatch_retries: args.mismatch_retries,
+            manifest_sha256: args.manifest_sha256.clone(),
+            signing: signatures::Policy {
+                key: args.manifest_pubkey.clone(),
+                require: args.require_file_signatures,
+            },
+            limiter: args.max_rate.map(|rate| Arc::new(RateLimiter::new(rate))),
+            cancel,
+            health: Arc::new(OriginHealth::new(Budget {
+                failures: args.origin_error_budget,
+                wasted: args.origin_time_budget,
+                cooldown: args.circuit_cooldown,
+            })),
+            strategy: args.origin_strategy,
+            next_origin: Arc::new(AtomicUsize::new(0)),
+            probes: Arc::default(),
+            progress: Arc::new(Progress::default()),
+            ip_family: IpFamily::from_flags(args.ipv4_only, args.ipv6_only),
+            channel: args.channel.clone(),
+            net: NetSettings::new(args),
+            auth: Credentials {
+                default: args.auth_token.clone().or_else(|| args.auth_basic.clone()),
+                per_origin: args
+                    .origin_auth
+                    .iter()
+                    .map(|(origin, auth)| Ok((normalize_origin(origin)?, auth.clone())))
+                    .collect::<Result<_>>()
+                    .context("[auth] table in --config")?,
+            },
+            headers: Headers {
+                default: args.headers.clone(),
+                per_origin: args
+                    .origin_headers
+                    .iter()
+                    .map(|(origin, headers)| Ok((normalize_origin(origin)?, headers.clone())))
+                    .collect::<Result<_>>()
+                    .context("[origin_headers] table in --config")?,
+            },
+            query_auth: QueryAuth {
+                params: args.query_auth.clone(),
+                command: args.query_auth_command.clone(),
+            },
+            validators: None,
+        })
+    }
+
+    fn cancelled(&self) -> bool {
+        self.cancel.load(Ordering::SeqCst)
+    }
+
+    /// The order to try `origins` in for one request: rotated to the
+    /// `--origin-strategy` starting point, then with demoted origins moved last.
+    fn attempt_order(&self, log: &Logger, origins: &[String]) -> Vec<String> {
+        let start = match self.strategy {
+            OriginStrategy::Ordered | OriginStrategy::Latency => 0,
+            OriginStrategy::RoundRobin => self.next_origin.fetch_add(1, Ordering::Relaxed),
+            OriginStrategy::Random => {
+                use std::hash::{BuildHasher, Hasher};
+                std::collections::hash_map::RandomState::new()
+                    .build_hasher()
+                    .finish() as usize
+            }
+        };
+        let mut rotated = match self.strategy {
+            OriginStrategy::Latency => latency::order(&self.probes.lock().unwrap(), origins),
+            _ => origins.to_vec(),
+        };
+        if !rotated.is_empty() {
+            let len = rotated.len();
+            rotated.rotate_left(start % len);
+        }
+        self.health.order(log, &rotated)
+    }
+
+    /// Feeds an attempt's result into the origin health scores. Attempts cut
+    /// short by a stop signal say nothing about the origin.
+    fn record(&self, log: &Logger, origin: &str, ok: bool, took: Duration) {
+        if !self.cancelled() {
+            self.health.record(log, origin, ok, took);
+        }
+    }
+
+    /// The error for a request no origin served: the last origin's, saying
+    /// so when that left every origin's circuit open.
+    fn exhausted(&self, origins: &[String], last_err: Option<anyhow::Error>) -> anyhow::Error {
+        match (last_err, self.health.all_open(origins)) {
+            (Some(err), Some(open)) => err.context(open.to_string()),
+            (Some(err), None) => err,
+            (None, _) => self.health.none_left(origins),
+        }
+    }
+
+    /// The manifest recorded for `version`, for a `304` that stands for it.
+    fn recorded_manifest(&self, version: &str) -> Result<Manifest> {
+        let cache = self.validators.as_ref().context("no validators")?;
+        let manifest = diff::load(cache.manifests_dir(), version)?
+            .with_context(|| format!("no record of {version}"))?;
+        if !cache.filter().made(&manifest) {
+            // The record is cut down by other rules than this run's.
+            bail!("recorded with other --prefix/--only/--exclude settings");
+        }
+        Ok(manifest)
+    }
+
+    fn check_cancelled(&self) -> Result<()> {
+        if self.cancelled() {
+            bail!("interrupted by signal");
+        }
+        Ok(())
+    }
+
+    /// Opens `url` and checks the status; `what` names the resource in error
+    /// context. With `conditional`, a `304` is `Source::NotModified`.
+    fn send(
+        &self,
+        log: &Logger,
+        url: &str,
+        origin: &str,
+        what: &str,
+        conditional: Option<&Validators>,
+    ) -> Result<Source> {
+        self.check_cancelled()?;
+        if let Some(path) = local_path(url)? {
+            log.debug(
+                "file_open",
+                json!({ "url": url, "path": &path }),
+                format_args!("open {}", path.display()),
+            );
+            let file =
+                File::open(&path).with_context(|| format!("open {what} {}", path.display()))?;
+            return Ok(Source::File(file));
+        }
+        let mut throttled = 0;
+        loop {
+            let signed = self
+                .query_auth
+                .sign(url)
+                .with_context(|| format!("sign {what} url"))?;
+            let target = signed.as_ref().map_or(url, |signed| signed.url.as_str());
+            if let Some(headers) = self.headers.describe(origin) {
+                log.debug(
+                    "request_headers",
+                    json!({ "url": url, "headers": &headers }),
+                    format_args!("GET {url} with {headers}"),
+                );
+            }
+            let started = Instant::now();
+            self.http.sent();
+            let req = self
+                .http
+                .client
+                .get(target)
+                .header(reqwest::header::ACCEPT_ENCODING, encoding::ACCEPTED);
+            let req = self.headers.apply(origin, req);
+            let req = match conditional {
+                Some(validators) => validators.apply(req),
+                None => req,
+            };
+            let resp = match self.auth.apply(origin, req).send() {
+                Ok(resp) => resp,
+                Err(err) => {
+                    let err = match &signed {
+                        Some(signed) => signed.redact_error(err),
+                        None => err,
+                    };
+                    let ms = started.elapsed().as_millis() as u64;
+                    log.debug(
+                        "http_error",
+                        json!({ "url": url, "ms": ms, "error": err.to_string() }),
+                        format_args!("GET {url} failed after {ms} ms: {err}"),
+                    );
+                    return Err(augment_reqwest_error(
+                        err,
+                        origin,
+                        self.ip_family,
+                        &self.net,
+                    ))
+                    .with_context(|| format!("request {what}"));
+                }
+            };
+            let ms = started.elapsed().as_millis() as u64;
+            log.debug(
+                "http_response",
+                json!({ "url": url, "status": resp.status().as_u16(), "ms": ms }),
+                format_args!("GET {url} -> {} in {ms} ms", resp.status()),
+            );
+            if conditional.is_some() && resp.status() == reqwest::StatusCode::NOT_MODIFIED {
+                return Ok(Source::NotModified);
+            }
+            let err = match ensure_success(resp) {
+                Ok(resp) => return Ok(Source::Http(resp)),
+                Err(err) => match err.downcast::<HttpStatusError>() {
+                    Ok(mut status_err) => {
+                        status_err.body = self.auth.redact(&status_err.body);
+                        status_err.body = self.headers.redact(&status_err.body);
+                        if let Some(signed) = &signed {
+                            status_err.url = signed.redact(&status_err.url);
+                            status_err.body = signed.redact(&status_err.body);
+                        }
+                        status_err.into()
+                    }
+                    Err(err) => err,
+                },
+            };
+
+            // 429/503 mean "this origin is busy", not "this origin is broken":
+            // wait and ask the same origin again rather than piling onto the next.
+            let wait = match err.downcast_ref::<HttpStatusError>() {
+                Some(status_err) if status_err.is_throttle() && throttled < THROTTLE_RETRIES => {
+                    match status_err.retry_after {
+                        Some(after) => after.min(self.max_retry_after),
+                        None => THROTTLE_BACKOFF * 2u32.pow(throttled),
+                    }
+                }
+                _ => return Err(err).with_context(|| format!("{what} http status")),
+            };
+            throttled += 1;
+            log.warn(
+                "throttled",
+                json!({ "url": url, "retry_in_secs": wait.as_secs_f64(), "attempt": throttled }),
+                format_args!("{err}; retrying {url} in {wait:?}"),
+            );
+            self.pause(wait);
+            self.check_cancelled()?;
+        }
+    }
+
+    /// Sleeps, waking early if a stop signal arrives.
+    fn pause(&self, total: Duration) {
+        let deadline = Instant::now() + total;
+        while !self.cancelled() {
+            let now = Instant::now();
+            if now >= deadline {
+                return;
+            }
+            thread::sleep((deadline - now).min(Duration::from_millis(100)));
+        }
+    }
+
+    /// Opens a body, decoded, along with the response's validators. `None`
+    /// when `conditional` was given and the origin answered `304`.
+    fn open(
+        &self,
+        log: &Logger,
+        url: &str,
+        origin: &str,
+        what: &str,
+        conditional: Option<&Validators>,
+    ) -> Result<Option<Opened>> {
+        let (body, validators): (Box<dyn Read>, _) =
+            match self.send(log, url, origin, what, conditional)? {
+                Source::File(file) => (Box::new(file), Validators::default()),
+                Source::Http(resp) => {
+                    let encoding = content_encoding(&resp);
+                    let validators = Validators::of(&resp);
+                    let body = encoding::decode(log, url, encoding.as_deref(), self.body(resp))?;
+                    (body, validators)
+                }
+                Source::NotModified => return Ok(None),
+            };
+        Ok(Some(Opened {
+            body: self.cancellable(body),
+            validators,
+        }))
+    }
+
+    /// Like `open`, for an object body of `size` bytes: network bodies are
+    /// also subject to `--max-rate`, and a response whose Content-Length says
+    /// otherwise (an error page cached under the object URL) fails before any
+    /// of the body is read.
+    fn open_object(
+        &self,
+        log: &Logger,
+        url: &str,
+        origin: &str,
+        what: &str,
+        size: u64,
+    ) -> Result<Box<dyn Read>> {
+        let body: Box<dyn Read> = match self.send(log, url, origin, what, None)? {
+            Source::File(file) => Box::new(file),
+            Source::NotModified => unreachable!("object requests are unconditional"),
+            Source::Http(resp) => {
+                let encoding = content_encoding(&resp);
+                // An encoded body's length says nothing about the object's.
+                let identity = encoding.as_deref().is_none_or(|e| {
+                    e.trim().is_empty() || e.trim().eq_ignore_ascii_case("identity")
+                });
+                if let Some(len) = resp.content_length().filter(|_| identity) {
+                    log.debug(
+                        "content_length",
+                        json!({ "url": url, "content_length": len, "expected": size }),
+                        format_args!("{what} from {url}: Content-Length {len}, expected {size}"),
+                    );
+                    if len != size {
+                        bail!("{what} from {url} has Content-Length {len}, expected {size}");
+                    }
+                }
+                encoding::decode(log, url, encoding.as_deref(), self.object_body(resp))?
+            }
+        };
+        Ok(self.cancellable(self.progress.reader(body)))
+    }
+
+    fn cancellable(&self, inner: Box<dyn Read>) -> Box<dyn Read> {
+        Box::new(Cancellable {
+            inner,
+            cancel: Arc::clone(&self.cancel),
+        })
+    }
+
+    /// Wraps a response body so reads fail once the stall timeout elapses without data.
+    fn body(&self, resp: reqwest::blocking::Response) -> Box<dyn Read> {
+        match self.stall_timeout {
+            Some(stall) => Box::new(StallGuard::new(resp, stall)),
+            None => Box::new(resp),
+        }
+    }
+
+    fn object_body(&self, resp: reqwest::blocking::Response) -> Box<dyn Read> {
+        let body = self.body(resp);
+        match &self.limiter {
+            Some(limiter) => Box::new(Throttled {
+                inner: body,
+                limiter: Arc::clone(limiter),
+            }),
+            None => body,
+        }
+    }
+}
+
+fn content_encoding(resp: &reqwest::blocking::Response) -> Option<String> {
+    resp.headers()
+        .get(reqwest::header::CONTENT_ENCODING)
+        .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+}
+
+/// Returns the filesystem path for `file://` URLs and `None` for anything else.
+fn local_path(url: &str) -> Result<Option<PathBuf>> {
+    let parsed = Url::parse(url).with_context(|| format!("parse url {url}"))?;
+    if parsed.scheme() != "file" {
+        return Ok(None);
+    }
+    let path = parsed
+        .to_file_path()
+        .map_err(|()| anyhow!("file url has no local path: {url}"))?;
+    Ok(Some(path))
+}
+
+fn normalize_origin(origin: &str) -> Result<String> {
+    let trimmed = origin.trim();
+    if trimmed.is_empty() {
+        bail!("--origin must not be empty");
+    }
+    let normalized = trimmed.trim_end_matches('/');
+    check_url_scheme("--origin", normalized)?;
+    Ok(normalized.to_string())
+}
+
+fn normalize_manifest_url(url: &str) -> Result<String> {
+    let trimmed = url.trim();
+    if trimmed.is_empty() {
+        bail!("--manifest-url must not be empty");
+    }
+    check_url_scheme("--manifest-url", trimmed)?;
+    Ok(trimmed.to_string())
+}
+
+fn check_url_scheme(flag: &str, url: &str) -> Result<()> {
+    let parsed = Url::parse(url)
+        .with_context(|| format!("parse {flag} as URL (include http://, https:// or file://)"))?;
+    if !parsed.username().is_empty() || parsed.password().is_some() {
+        bail!("{flag} must not contain credentials; use --auth-basic or --auth-token");
+    }
+    match parsed.scheme() {
+        "http" | "https" | "file" => Ok(()),
+        other => bail!("unsupported {flag} scheme: {other}"),
+    }
+}
+
+fn normalize_origins(origins: &[String]) -> Result<Vec<String>> {
+    if origins.is_empty() {
+        bail!("at least one --origin is required");
+    }
+    let mut out: Vec<String> = Vec::new();
+    for origin in origins {
+        let normalized = normalize_origin(origin)?;
+        if !out.iter().any(|x| x == &normalized) {
+            out.push(normalized);
+        }
+    }
+    Ok(out)
+}
+
+/// Channel followed without `--channel`.
+const DEFAULT_CHANNEL: &str = "latest";
+
+fn manifest_url(origin: &str, channel: &str) -> String {
+    format!("{origin}/manifests/{channel}.json")
+}
+
+/// Fetches and validates the manifest at `url`, served by `origin`.
+fn fetch_manifest(fetcher: &Fetcher, log: &Logger, url: &str, origin: &str) -> Result<Manifest> {
+    let cached = fetcher.validators.as_ref().and_then(|v| v.get(origin));
+    let conditional = cached.as_ref().map(|entry| &entry.validators);
+    let opened = match fetcher.open(log, url, origin, "latest manifest", conditional)? {
+        Some(opened) => opened,
+        None => {
+            let version = cached.map(|entry| entry.version).unwrap_or_default();
+            match fetcher.recorded_manifest(&version) {
+                Ok(manifest) => {
+                    log.debug(
+                        "manifest_not_modified",
+                        json!({ "url": url, "origin": origin, "version": &version }),
+                        format_args!("{url} not modified; using the recorded {version}"),
+                    );
+                    fetcher.signing.check(&manifest)?;
+                    return Ok(manifest);
+                }
+                Err(err) => {
+                    log.warn(
+                        "manifest_record_unreadable",
+                        json!({ "origin": origin, "version": &version, "error": format!("{err:#}") }),
+                        format_args!(
+                            "{url} not modified, but the record of {version} is unusable ({err:#}); fetching it again"
+                        ),
+                    );
+                    fetcher
+                        .open(log, url, origin, "latest manifest", None)?
+                        .context("unconditional request answered 304")?
+                }
+            }
+        }
+    };
+    let Opened { body, validators } = opened;
+    let mut body = Hashing::new(body);
+    let mut bytes = Vec::new();
+    body.read_to_end(&mut bytes).context("read latest.json")?;
+    if let Some(expected) = &fetcher.manifest_sha256 {
+        let (digest, _, _) = body.finish();
+        if digest != *expected {
+            bail!(
+                "manifest from {origin} has sha256 {digest}, but --manifest-sha256 is {expected}"
+            );
+        }
+    }
+    let mut manifest: Manifest = serde_json::from_slice(&bytes).context("parse latest.json")?;
+    validate_manifest(&manifest)?;
+    fetcher.signing.check(&manifest)?;
+    for path in manifest::dedup(&mut manifest) {
+        log.warn(
+            "duplicate_manifest_entry",
+            json!({ "path": &path, "origin": origin }),
+            format_args!(
+                "manifest lists {path} more than once with the same content; using one copy"
+            ),
+        );
+    }
+    if let Some(cache) = &fetcher.validators {
+        // Only costs the next request its condition.
+        if let Err(err) = cache.remember(origin, &manifest.version, validators) {
+            log.warn(
+                "manifest_validators_failed",
+                json!({ "origin": origin, "error": format!("{err:#}") }),
+                format_args!("could not keep the validators from {origin}: {err:#}"),
+            );
+        }
+    }
+    Ok(manifest)
+}
+
+fn fetch_manifest_any(
+    fetcher: &Fetcher,
+    log: &Logger,
+    origins: &[String],
+) -> Result<(Manifest, String)> {
+    let mut last_err: Option<anyhow::Error> = None;
+    for origin in fetcher.attempt_order(log, origins) {
+        fetcher.check_cancelled()?;
+        let started = Instant::now();
+        let result = fetch_manifest(
+            fetcher,
+            log,
+            &manifest_url(&origin, &fetcher.channel),
+            &origin,
+        );
+        fetcher.record(log, &origin, result.is_ok(), started.elapsed());
+        match log_manifest_attempt(log, &origin, started, result) {
+            Ok(manifest) => {
+                if last_err.is_some() {
+                    fetcher.health.failed_over();
+                }
+                return Ok((manifest, origin));
+            }
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(fetcher.exhausted(origins, last_err)).context("fetch latest manifest from all origins")
+}
+
+/// Requests the manifest from every origin concurrently and returns the first
+/// well-formed one. Losing requests are left to finish (or time out) on their
+/// own threads; their results are dropped.
+fn race_manifest(
+    fetcher: &Fetcher,
+    log: &Logger,
+    origins: &[String],
+) -> Result<(Manifest, String)> {
+    fetcher.check_cancelled()?;
+    let (tx, rx) = mpsc::channel();
+    for origin in origins {
+        let (fetcher, log, origin, tx) = (fetcher.clone(), log.clone(), origin.clone(), tx.clone());
+        thread::spawn(move || {
+            let started = Instant::now();
+            let result = fetch_manifest(
+                &fetcher,
+                &log,
+                &manifest_url(&origin, &fetcher.channel),
+                &origin,
+            );
+            let _ = tx.send((origin, started, result));
+        });
+    }
+    drop(tx);
+
+    let mut last_err: Option<anyhow::Error> = None;
+    for (origin, started, result) in rx {
+        fetcher.record(log, &origin, result.is_ok(), started.elapsed());
+        match log_manifest_attempt(log, &origin, started, result) {
+            Ok(manifest) => return Ok((manifest, origin)),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(fetcher.exhausted(origins, last_err)).context("fetch latest manifest from all origins")
+}
+
+/// Requests the manifest from every origin concurrently and returns the
+/// newest, by `sequence` and then `generated_at`. Origins that fail are
+/// skipped with a warning as long as one answers, and each one serving an
+/// older version is logged with how far behind it is. When versions can't
+/// be ordered, the earlier origin in `--origin` order wins.
+fn newest_manifest(
+    fetcher: &Fetcher,
+    log: &Logger,
+    origins: &[String],
+) -> Result<(Manifest, String)> {
+    fetcher.check_cancelled()?;
+    let live = fetcher.attempt_order(log, origins);
+    let results: Vec<(String, Result<Manifest>)> = thread::scope(|scope| {
+        let attempts: Vec<_> = origins
+            .iter()
+            .filter(|origin| live.contains(origin))
+            .map(|origin| {
+                scope.spawn(move || {
+                    let started = Instant::now();
+                    let result = fetch_manifest(
+                        fetcher,
+                        log,
+                        &manifest_url(origin, &fetcher.channel),
+                        origin,
+                    );
+                    fetcher.record(log, origin, result.is_ok(), started.elapsed());
+                    (
+                        origin.clone(),
+                        log_manifest_attempt(log, origin, started, result),
+                    )
+                })
+            })
+            .collect();
+        attempts
+            .into_iter()
+            .map(|attempt| attempt.join().expect("manifest fetch thread panicked"))
+            .collect()
+    });
+
+    let mut served: Vec<(String, Manifest)> = Vec::new();
+    let mut last_err: Option<anyhow::Error> = None;
+    for (origin, result) in results {
+        match result {
+            Ok(manifest) => served.push((origin, manifest)),
+            Err(err) => {
+                log.warn(
+                    "manifest_origin_skipped",
+                    json!({ "origin": origin, "error": format!("{err:#}") }),
+                    format_args!("skipping {origin} in the newest-manifest check: {err:#}"),
+                );
+                last_err = Some(err);
+            }
+        }
+    }
+    let Some(newest) =
+        (0..served.len()).reduce(|best, i| match served[i].1.publish_order(&served[best].1) {
+            Some(cmp::Ordering::Greater) => i,
+            _ => best,
+        })
+    else {
+        return Err(fetcher.exhausted(origins, last_err))
+            .context("fetch latest manifest from all origins");
+    };
+    let (newest_origin, newest_manifest) = &served[newest];
+    for (origin, manifest) in &served {
+        if manifest.version == newest_manifest.version {
+            continue;
+        }
+        match manifest.publish_order(newest_manifest) {
+            Some(cmp::Ordering::Less) => {
+                let behind = manifest.lag_behind(newest_manifest);
+                log.info(
+                    "origin_behind",
+                    json!({
+                        "origin": origin,
+                        "version": manifest.version,
+                        "newest_origin": newest_origin,
+                        "newest_version": newest_manifest.version,
+                        "behind": behind,
+                    }),
+                    format_args!(
+                        "{origin} is behind: serves {}, {behind} older than {} from {newest_origin}",
+                        manifest.version, newest_manifest.version
+                    ),
+                );
+            }
+            _ => log.warn(
+                "origin_unordered",
+                json!({
+                    "origin": origin,
+                    "version": manifest.version,
+                    "newest_origin": newest_origin,
+                    "newest_version": newest_manifest.version,
+                }),
+                format_args!(
+                    "can't tell whether {} from {origin} is older than {} from {newest_origin} \
+                     (no sequence or generated_at to compare); using {newest_origin}",
+                    manifest.version, newest_manifest.version
+                ),
+            ),
+        }
+    }
+    let (origin, manifest) = served.swap_remove(newest);
+    Ok((manifest, origin))
+}
+
+fn log_manifest_attempt(
+    log: &Logger,
+    origin: &str,
+    started: Instant,
+    result: Result<Manifest>,
+) -> Result<Manifest> {
+    let ms = started.elapsed().as_millis() as u64;
+    log.debug(
+        "manifest_attempt",
+        json!({ "origin": origin, "ok": result.is_ok(), "ms": ms }),
+        format_args!(
+            "manifest attempt origin={origin} {} in {ms} ms",
+            if result.is_ok() { "ok" } else { "failed" }
+        ),
+    );
+    if let Err(err) = &result {
+        log.warn(
+            "manifest_fetch_failed",
+            json!({ "origin": origin, "error": format!("{err:#}") }),
+            format_args!("frontpage fetch failed from {origin}: {err:#}"),
+        );
+    }
+    result
+}
+
+fn download_object(
+    fetcher: &Fetcher,
+    log: &Logger,
+    origin: &str,
+    hash: &str,
+    expected_size: u64,
+    transfer: Option<&ManifestTransfer>,
+    store: &ObjectStore,
+) -> Result<()> {
+    validate_hash(hash).with_context(|| format!("invalid object hash: {hash:?}"))?;
+
+    let mut tmp = ObjectTemp::new_in(store.dir())?;
+    tmp.preallocate(expected_size)?;
+
+    match transfer {
+        Some(transfer) => {
+            let url = store.url(origin, &transfer.hash);
+            let what = format!("object {hash}");
+            let body = fetcher.open_object(log, &url, origin, &what, transfer.size)?;
+            // One byte past the declared size is enough to see it's too long.
+            let body = body.take(transfer.size.saturating_add(1));
+            transfer::decode_into(body, &mut tmp, transfer, hash, expected_size)?;
+        }
+        None => {
+            let url = store.url(origin, hash);
+            let what = format!("object {hash}");
+            let body = fetcher.open_object(log, &url, origin, &what, expected_size)?;
+            // Stop one byte past the declared size instead of letting a
+            // runaway body fill the disk.
+            let mut body = Hashing::new(body.take(expected_size.saturating_add(1)));
+            let written = io::copy(&mut body, &mut tmp).context("write object body")?;
+            if written > expected_size {
+                bail!(BodyMismatch(format!(
+                    "object {hash} body exceeds declared size {expected_size}"
+                )));
+            }
+            if expected_size != written {
+                bail!(BodyMismatch(format!(
+                    "object {hash} size mismatch: expected {expected_size} got {written}"
+                )));
+            }
+            let (digest, _, _) = body.finish();
+            if digest != hash {
+                bail!(BodyMismatch(format!(
+                    "object {hash} hash mismatch: got {digest}"
+                )));
+            }
+        }
+    }
+    store_object(tmp, store, hash)
+}
+
+/// An object body that didn't match the manifest's size or hash. From an
+/// origin that is usually a response cut short on the way rather than a bad
+/// origin, so the same origin is asked again (`--mismatch-retries`) before
+/// failing over. At staging (`--verify-on-stage`) it means the stored copy
+/// went bad.
+#[derive(Debug)]
+pub(crate) struct BodyMismatch(pub(crate) String);
+
+impl std::fmt::Display for BodyMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for BodyMismatch {}
+
+/// Moves a fully written and checked temp file into the store as `hash`.
+fn store_object(mut tmp: ObjectTemp, store: &ObjectStore, hash: &str) -> Result<()> {
+    tmp.as_file_mut()
+        .sync_all()
+        .context("fsync object temp file")?;
+    let final_path = store.path(hash);
+    let final_dir = final_path.parent().unwrap_or(store.dir());
+    ensure_dir(final_dir).with_context(|| format!("create dir {}", final_dir.display()))?;
+
+    match tmp.persist_noclobber(&final_path) {
+        Ok(()) => {}
+        Err(err) => {
+            if err.kind() == io::ErrorKind::AlreadyExists {
+                return Ok(());
+            }
+            return Err(err).with_context(|| format!("persist object {}", final_path.display()));
+        }
+    }
+
+    set_world_readable(&final_path).context("chmod object")?;
+    fsync_dir(final_dir).context("fsync objects dir")?;
+    Ok(())
+}
+
+/// How many failed objects the `--keep-going` error names; the summary and
+/// the error report have them all.
+const FAILED_OBJECTS_LISTED: usize = 10;
+
+/// An object no origin could deliver, for the summary and the error report.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct FailedObject {
+    hash: String,
+    /// Every manifest path with this content.
+    paths: Vec<String>,
+    error: String,
+    /// Each origin's last error for it.
+    origins: Vec<OriginError>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct OriginError {
+    origin: String,
+    error: String,
+}
+
+/// Every origin failed to deliver an object. The chain goes on with the
+/// last origin's error; `errors` has each origin's.
+#[derive(Debug)]
+struct AllOriginsFailed {
+    hash: String,
+    errors: Vec<OriginError>,
+    last: Box<dyn std::error::Error + Send + Sync>,
+}
+
+impl std::fmt::Display for AllOriginsFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "download object {} from all origins", self.hash)
+    }
+}
+
+impl std::error::Error for AllOriginsFailed {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&*self.last)
+    }
+}
+
+/// Where a downloaded object came from, for its trace span.
+struct Served {
+    /// The origin that delivered it (the last part's, for multi-part objects).
+    origin: String,
+    /// Failed attempts before that.
+    retries: u32,
+}
+
+fn download_object_any(
+    fetcher: &Fetcher,
+    log: &Logger,
+    origins: &[String],
+    hash: &str,
+    expected_size: u64,
+    transfer: Option<&ManifestTransfer>,
+    store: &ObjectStore,
+) -> Result<Served> {
+    let order = fetcher.attempt_order(log, origins);
+    if let Some(first) = order.first() {
+        log.debug(
+            "object_origin",
+            json!({ "hash": hash, "origin": first }),
+            format_args!("object {hash} starts at origin={first}"),
+        );
+    }
+    let mut last_err: Option<anyhow::Error> = None;
+    let mut errors: Vec<OriginError> = Vec::new();
+    let mut retries = 0;
+    for origin in &order {
+        // A mismatched body gets `--mismatch-retries` more tries here; any
+        // other failure moves on to the next origin straight away.
+        for attempt in 1..=fetcher.mismatch_retries.saturating_add(1) {
+            fetcher.check_cancelled()?;
+            let started = Instant::now();
+            let result =
+                download_object(fetcher, log, origin, hash, expected_size, transfer, store);
+            fetcher.record(log, origin, result.is_ok(), started.elapsed());
+            let ms = started.elapsed().as_millis() as u64;
+            log.debug(
+                "object_attempt",
+                json!({ "origin": origin, "hash": hash, "ok": result.is_ok(), "ms": ms }),
+                format_args!(
+                    "object attempt origin={origin} hash={hash} {} in {ms} ms",
+                    if result.is_ok() { "ok" } else { "failed" }
+                ),
+            );
+            let err = match result {
+                Ok(()) => {
+                    if Some(origin) != order.first() {
+                        fetcher.health.failed_over();
+                    }
+                    return Ok(Served {
+                        origin: origin.clone(),
+                        retries,
+                    });
+                }
+                Err(err) => err,
+            };
+            retries += 1;
+            fetcher.progress.rewind_object(0);
+            log.warn(
+                "object_download_failed",
+                json!({
+                    "origin": origin,
+                    "hash": hash,
+                    "attempt": attempt,
+                    "error": format!("{err:#}"),
+                }),
+                format_args!(
+                    "object download failed from {origin} hash={hash} (attempt {attempt}): {err:#}"
+                ),
+            );
+            let mismatch = err.is::<BodyMismatch>();
+            errors.retain(|e| e.origin != *origin);
+            errors.push(OriginError {
+                origin: origin.clone(),
+                error: format!("{err:#}"),
+            });
+            last_err = Some(err);
+            if !mismatch {
+                break;
+            }
+        }
+    }
+    Err(anyhow::Error::new(AllOriginsFailed {
+        hash: hash.to_string(),
+        errors,
+        last: fetcher.exhausted(origins, last_err).into(),
+    }))
+}
+
+/// Copies `src` to `dst` through a temp file. With `verify`, the bytes are
+/// hashed on the way and a digest other than `verify` fails with a
+/// `BodyMismatch` before `dst` appears.
+fn copy_file_atomic(
+    src: &Path,
+    dst: &Path,
+    mode: u32,
+    perms: &Perms,
+    verify: Option<&str>,
+) -> Result<()> {
+    let parent = dst
+        .parent()
+        .ok_or_else(|| anyhow!("destination has no parent: {}", dst.display()))?;
+
+    let mut tmp = tempfile::NamedTempFile::new_in(parent).context("create temp snapshot file")?;
+    let src_f = File::open(src).with_context(|| format!("open {}", src.display()))?;
+    let mut reader = Hashing::new(src_f);
+    io::copy(&mut reader, &mut tmp).context("copy bytes")?;
+    if let Some(expected) = verify {
+        check_digest(reader, src, expected)?;
+    }
+    persist_staged(tmp, dst, mode, perms)
+}
+
+/// Fails with a `BodyMismatch` when what `reader` read from `src` does not
+/// hash to `expected`.
+fn check_digest<R>(reader: Hashing<R>, src: &Path, expected: &str) -> Result<()> {
+    let (got, _, _) = reader.finish();
+    if got != expected {
+        bail!(BodyMismatch(format!(
+            "content of {} hashes to {got}, expected {expected}",
+            src.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Moves a filled snapshot temp file to `dst`, which must not exist yet,
+/// and gives it `mode` and the configured owner.
+fn persist_staged(
+    mut tmp: tempfile::NamedTempFile,
+    dst: &Path,
+    mode: u32,
+    perms: &Perms,
+) -> Result<()> {
+    let parent = dst
+        .parent()
+        .ok_or_else(|| anyhow!("destination has no parent: {}", dst.display()))?;
+    tmp.as_file_mut()
+        .sync_all()
+        .context("fsync snapshot temp file")?;
+
+    match tmp.persist_noclobber(dst) {
+        Ok(_file) => {}
+        Err(err) => return Err(err.error).with_context(|| format!("persist {}", dst.display())),
+    }
+
+    set_mode(dst, mode).context("chmod snapshot file")?;
+    perms.chown(dst)?;
+    fsync_dir(parent).with_context(|| format!("fsync dir {}", parent.display()))?;
+    Ok(())
+}
+
+fn set_world_readable(path: &Path) -> Result<()> {
+    // Readable by everyone, writable only by owner.
+    set_mode(path, 0o644)
+}
+
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    #[cfg(unix)]
+    {
+        let mut perms = fs::metadata(path)
+            .with_context(|| format!("stat {}", path.display()))?
+            .permissions();
+        perms.set_mode(mode);
+        fs::set_permissions(path, perms).with_context(|| format!("chmod {}", path.display()))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (path, mode);
+        Ok(())
+    }
+}
+
+fn create_symlink(target: &Path, link: &Path) -> Result<()> {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(target, link)
+            .with_context(|| format!("create symlink {} -> {}", link.display(), target.display()))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (target, link);
+        bail!("symlink entries are only supported on unix");
+    }
+}
+
+const LOCK_FILE: &str = ".cityfeed-puller.lock";
+
+/// Takes an exclusive lock on `root/.cityfeed-puller.lock` so two pullers
+/// (say the timer and a manual run) never deploy into one root at once. The OS
+/// drops the lock when the process exits, however it exits.
+fn lock_root(root: &Path) -> Result<File> {
+    let path = root.join(LOCK_FILE);
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("open lock file {}", path.display()))?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(fs::TryLockError::WouldBlock) => {
+            let holder = fs::read_to_string(&path).unwrap_or_default();
+            bail!(
+                "{} is locked by another cityfeed-puller (pid {})",
+                root.display(),
+                holder.trim()
+            );
+        }
+        Err(fs::TryLockError::Error(err)) => {
+            return Err(err).with_context(|| format!("lock {}", path.display()));
+        }
+    }
+    file.set_len(0).context("truncate lock file")?;
+    writeln!(file, "{}", std::process::id()).context("write lock file")?;
+    Ok(file)
+}
+
+fn ensure_dir(path: &Path) -> Result<()> {
+    fs::create_dir_all(path).with_context(|| format!("create_dir_all {}", path.display()))
+}
+
+/// `ensure_dir` for a directory the web server has to get through. If it has
+/// to be created, it gets `mode` rather than whatever the umask leaves;
+/// an existing one is left as the operator set it up.
+fn ensure_served_dir(path: &Path, mode: u32) -> Result<()> {
+    if path.is_dir() {
+        return Ok(());
+    }
+    ensure_dir(path)?;
+    set_mode(path, mode)
+}
+
+fn fsync_dir(dir: &Path) -> Result<()> {
+    // Directories can't be opened (or fsynced) through std on Windows; NTFS
+    // journals the metadata updates we care about.
+    #[cfg(windows)]
+    {
+        let _ = dir;
+        Ok(())
+    }
+
+    #[cfg(not(windows))]
+    {
+        let file = File::open(dir).with_context(|| format!("open dir {}", dir.display()))?;
+        file.sync_all()
+            .with_context(|| format!("fsync dir {}", dir.display()))
+    }
+}
+
+/// Reads the inner body on a helper thread so a silent connection can be
+/// abandoned after `stall` instead of blocking until the request timeout.
+struct StallGuard {
+    rx: mpsc::Receiver<io::Result<Vec<u8>>>,
+    stall: Duration,
+    buf: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl StallGuard {
+    fn new<R: Read + Send + 'static>(mut inner: R, stall: Duration) -> Self {
+        let (tx, rx) = mpsc::sync_channel(4);
+        thread::spawn(move || {
+            let mut chunk = vec![0u8; 64 * 1024];
+            loop {
+                match inner.read(&mut chunk) {
+                    Ok(0) => {
+                        let _ = tx.send(Ok(Vec::new()));
+                        return;
+                    }
+                    Ok(n) => {
+                        if tx.send(Ok(chunk[..n].to_vec())).is_err() {
+                            return;
+                        }
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                    Err(err) => {
+                        let _ = tx.send(Err(err));
+                        return;
+                    }
+                }
+            }
+        });
+        Self {
+            rx,
+            stall,
+            buf: Vec::new(),
+            pos: 0,
+            done: false,
+        }
+    }
+}
+
+impl Read for StallGuard {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos >= self.buf.len() {
+            if self.done {
+                return Ok(0);
+            }
+            match self.rx.recv_timeout(self.stall) {
+                Ok(Ok(chunk)) => {
+                    self.done = chunk.is_empty();
+                    self.buf = chunk;
+                    self.pos = 0;
+                }
+                Ok(Err(err)) => return Err(err),
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("no data received for {:?} (--stall-timeout)", self.stall),
+                    ));
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "body reader thread exited",
+                    ));
+                }
+            }
+        }
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Token bucket shared by every object download so the cap applies to the
+/// total rate rather than per connection. Starts empty; holds at most one
+/// second worth of tokens.
+struct RateLimiter {
+    rate: u64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate: rate.max(1),
+            bucket: Mutex::new(Bucket {
+                tokens: 0.0,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    /// Blocks until some tokens are available and returns how many bytes
+    /// (at most `want`) the caller may read now.
+    fn take(&self, want: usize) -> usize {
+        let rate = self.rate as f64;
+        // Wait for a reasonably sized slice rather than trickling single bytes.
+        let chunk = (want as f64).min((rate / 20.0).max(1.0));
+        loop {
+            let wait = {
+                let mut b = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+                let now = Instant::now();
+                b.tokens = (b.tokens + now.duration_since(b.last).as_secs_f64() * rate).min(rate);
+                b.last = now;
+                if b.tokens >= chunk {
+                    let n = (b.tokens.floor() as usize).min(want).max(1);
+                    b.tokens -= n as f64;
+                    return n;
+                }
+                (chunk - b.tokens) / rate
+            };
+            thread::sleep(Duration::from_secs_f64(wait));
+        }
+    }
+
+    fn refund(&self, n: usize) {
+        let mut b = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+        b.tokens = (b.tokens + n as f64).min(self.rate as f64);
+    }
+}
+
+struct Throttled {
+    inner: Box<dyn Read>,
+    limiter: Arc<RateLimiter>,
+}
+
+impl Read for Throttled {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if out.is_empty() {
+            return Ok(0);
+        }
+        let allowed = self.limiter.take(out.len());
+        let result = self.inner.read(&mut out[..allowed]);
+        let used = *result.as_ref().unwrap_or(&0);
+        self.limiter.refund(allowed - used);
+        result
+    }
+}
+
+/// Fails reads once the stop flag is set so a large body doesn't delay shutdown.
+struct Cancellable {
+    inner: Box<dyn Read>,
+    cancel: Arc<AtomicBool>,
+}
+
+impl Read for Cancellable {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.cancel.load(Ordering::SeqCst) {
+            return Err(io::Error::other("interrupted by signal"));
+        }
+        self.inner.read(out)
+    }
+}
+
+/// Parses a byte count with an optional binary suffix: `512`, `64K`, `10M`, `2G`.
+fn parse_bytes(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let n: u64 = num
+        .parse()
+        .with_context(|| format!("invalid byte count {s:?} (expected e.g. 500K, 10M)"))?;
+    let mult: u64 = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        other => bail!("invalid byte unit {other:?} in {s:?} (use K, M, G or T)"),
+    };
+    n.checked_mul(mult)
+        .ok_or_else(|| anyhow!("byte count {s:?} overflows"))
+}
+
+/// Parses a sha256 digest: 64 hex digits, either case.
+fn parse_sha256(s: &str) -> Result<String> {
+    let s = s.trim();
+    if s.len() != 64 || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("invalid sha256 {s:?} (expected 64 hex digits)");
+    }
+    Ok(s.to_ascii_lowercase())
+}
+
+/// A channel names a file under the origin's `manifests/` (and the local
+/// validators file), so it is kept to one plain, URL-safe path component.
+fn parse_channel(s: &str) -> Result<String> {
+    if s.is_empty() || s.len() > 64 {
+        bail!("channel must be 1 to 64 characters");
+    }
+    if !s
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+    {
+        bail!("channel {s:?} may only contain letters, digits, '-', '_' and '.'");
+    }
+    if s.starts_with(['.', '-']) {
+        bail!("channel {s:?} must not start with '.' or '-'");
+    }
+    Ok(s.to_string())
+}
+
+fn parse_preserve(s: &str) -> Result<PathBuf> {
+    validate_rel_path(s).with_context(|| format!("invalid --preserve path {s:?}"))
+}
+
+/// Parses `500ms`, `10s`, `2m`, `1h`; a bare number is taken as seconds.
+fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let n: u64 = num
+        .parse()
+        .with_context(|| format!("invalid duration {s:?} (expected e.g. 10s, 500ms, 2m)"))?;
+    let d = match unit {
+        "ms" => Duration::from_millis(n),
+        "" | "s" => Duration::from_secs(n),
+        "m" => Duration::from_secs(n.checked_mul(60).context("duration too large")?),
+        "h" => Duration::from_secs(n.checked_mul(3600).context("duration too large")?),
+        other => bail!("invalid duration unit {other:?} in {s:?} (use ms, s, m or h)"),
+    };
+    Ok(d)
+}
+
+/// One `--resolve` pin.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Resolve {
+    host: String,
+    addr: SocketAddr,
+}
+
+/// `--ipv4-only` / `--ipv6-only`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum IpFamily {
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    fn from_flags(ipv4_only: bool, ipv6_only: bool) -> Option<Self> {
+        match (ipv4_only, ipv6_only) {
+            (true, _) => Some(Self::V4),
+            (_, true) => Some(Self::V6),
+            _ => None,
+        }
+    }
+
+    /// Binding to one family's unspecified address makes the connector skip
+    /// the other family's addresses altogether instead of trying them first.
+    fn local_address(self) -> IpAddr {
+        match self {
+            Self::V4 => Ipv4Addr::UNSPECIFIED.into(),
+            Self::V6 => Ipv6Addr::UNSPECIFIED.into(),
+        }
+    }
+
+    fn connect_hint(self) -> &'static str {
+        match self {
+            Self::V4 => "only IPv4 addresses were tried (--ipv4-only)",
+            Self::V6 => "only IPv6 addresses were tried (--ipv6-only)",
+        }
+    }
+}
+
+/// Parses curl-style `HOST:PORT:ADDRESS`; IPv6 addresses may be bracketed.
+fn parse_resolve(s: &str) -> Result<Resolve> {
+    let usage = "expected HOST:PORT:ADDRESS, e.g. cdn.example.com:443:203.0.113.7";
+    let mut fields = s.trim().splitn(3, ':');
+    let (Some(host), Some(port), Some(ip)) = (fields.next(), fields.next(), fields.next()) else {
+        bail!("{usage}");
+    };
+    if host.is_empty() {
+        bail!("missing host in {s:?} ({usage})");
+    }
+    let port: u16 = port
+        .parse()
+        .with_context(|| format!("invalid port {port:?} in {s:?} ({usage})"))?;
+    let ip: IpAddr = ip
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .with_context(|| format!("invalid IP address {ip:?} in {s:?} ({usage})"))?;
+    Ok(Resolve {
+        host: host.to_ascii_lowercase(),
+        addr: SocketAddr::new(ip, port),
+    })
+}
+
+fn sanitize_prefix(s: &str) -> String {
+    s.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// Retries of one request on the same origin after 429/503.
+const THROTTLE_RETRIES: u32 = 3;
+/// First wait after a 429/503 without Retry-After; doubles each time.
+const THROTTLE_BACKOFF: Duration = Duration::from_secs(1);
+
+/// A non-2xx response. Kept typed so the retry logic can see the status and
+/// any Retry-After the server sent.
+#[derive(Debug)]
+struct HttpStatusError {
+    status: reqwest::StatusCode,
+    retry_after: Option<Duration>,
+    url: String,
+    body: String,
+}
+
+impl HttpStatusError {
+    fn is_throttle(&self) -> bool {
+        self.status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            || self.status == reqwest::StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+impl std::fmt::Display for HttpStatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HTTP {} for {}: {}", self.status, self.url, self.body)
+    }
+}
+
+impl std::error::Error for HttpStatusError {}
+
+fn ensure_success(resp: reqwest::blocking::Response) -> Result<reqwest::blocking::Response> {
+    let status = resp.status();
+    let url = resp.url().to_string();
+    if status.is_success() {
+        return Ok(resp);
+    }
+    let retry_after = resp
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_retry_after);
+    let mut body = resp.text().unwrap_or_default();
+    body = body.replace(['\n', '\r'], " ");
+    if body.len() > 2000 {
+        body.truncate(2000);
+        body.push('…');
+    }
+    Err(HttpStatusError {
+        status,
+        retry_after,
+        url,
+        body,
+    }
+    .into())
+}
+
+/// Retry-After is either delay-seconds or an HTTP-date.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = httpdate::parse_http_date(value).ok()?;
+    Some(
+        at.duration_since(std::time::SystemTime::now())
+            .unwrap_or_default(),
+    )
+}
+
+/// What went wrong below HTTP, for the hint an operator gets with it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum NetFailure {
+    Dns,
+    Refused,
+    ConnectTimeout,
+    Timeout,
+}
+
+impl NetFailure {
+    fn of(err: &reqwest::Error) -> Option<Self> {
+        let chain = || std::iter::successors(std::error::Error::source(err), |e| e.source());
+        let refused = chain().any(|e| {
+            e.downcast_ref::<io::Error>()
+                .is_some_and(|io| io.kind() == io::ErrorKind::ConnectionRefused)
+        });
+        if err.is_timeout() {
+            Some(if err.is_connect() {
+                Self::ConnectTimeout
+            } else {
+                Self::Timeout
+            })
+        } else if !err.is_connect() {
+            None
+        } else if refused {
+            Some(Self::Refused)
+        } else if chain().any(|e| e.to_string().starts_with("dns error")) {
+            Some(Self::Dns)
+        } else {
+            None
+        }
+    }
+}
+
+/// Timeouts and proxy a request went out with, for `network_hint`.
+#[derive(Clone, Debug, Default)]
+struct NetSettings {
+    connect_timeout: Duration,
+    request_timeout: Option<Duration>,
+    /// `HTTPS_PROXY` or similar, credentials removed, with the variable's name.
+    proxy: Option<(String, String)>,
+}
+
+impl NetSettings {
+    fn new(args: &Args) -> Self {
+        Self {
+            connect_timeout: args.connect_timeout,
+            request_timeout: args.request_timeout,
+            proxy: None,
+        }
+    }
+
+    /// These settings with the proxy reqwest picks up from the environment
+    /// for `origin`'s scheme, if any.
+    fn for_origin(&self, origin: &str) -> Self {
+        let https = origin.starts_with("https:");
+        let names: &[&str] = if https {
+            &["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"]
+        } else {
+            &["HTTP_PROXY", "http_proxy", "ALL_PROXY", "all_proxy"]
+        };
+        let proxy = names.iter().find_map(|name| {
+            let value = std::env::var(name).ok().filter(|v| !v.is_empty())?;
+            let shown = match Url::parse(&value) {
+                Ok(mut url) => {
+                    let _ = url.set_username("");
+                    let _ = url.set_password(None);
+                    url.to_string()
+                }
+                Err(_) => value,
+            };
+            Some((name.to_string(), shown))
+        });
+        Self {
+            proxy,
+            ..self.clone()
+        }
+    }
+}
+
+/// One line on what a `NetFailure` for `origin` usually means and what to
+/// change, naming the proxy when one was in the way.
+fn network_hint(failure: NetFailure, origin: &str, net: &NetSettings) -> String {
+    let parsed = Url::parse(origin).ok();
+    let host = parsed
+        .as_ref()
+        .and_then(|u| u.host_str().map(str::to_string))
+        .unwrap_or_else(|| origin.to_string());
+    let port = parsed
+        .as_ref()
+        .and_then(Url::port_or_known_default)
+        .unwrap_or(443);
+    let mut hint = match failure {
+        NetFailure::Dns => format!(
+            "DNS resolution failed for {host}; check /etc/resolv.conf or pin an address \
+             with --resolve {host}:{port}:<address>"
+        ),
+        NetFailure::Refused => format!(
+            "connection refused on {host} port {port}; is the port in origin {origin} correct, \
+             and is the server listening?"
+        ),
+        NetFailure::ConnectTimeout => format!(
+            "connecting to {host} port {port} timed out after {:?}; raise --connect-timeout \
+             or check the venue firewall",
+            net.connect_timeout
+        ),
+        NetFailure::Timeout => match net.request_timeout {
+            Some(limit) => format!(
+                "request to {origin} timed out after {limit:?}; raise --request-timeout \
+                 or check the venue firewall"
+            ),
+            None => format!("request to {origin} timed out; check the venue firewall"),
+        },
+    };
+    if let Some((name, proxy)) = &net.proxy {
+        hint.push_str(&format!(" (requests go through proxy {proxy} from {name})"));
+    }
+    hint
+}
+
+fn augment_reqwest_error(
+    err: reqwest::Error,
+    origin: &str,
+    ip_family: Option<IpFamily>,
+    net: &NetSettings,
+) -> anyhow::Error {
+    let msg = err.to_string();
+    if err.is_connect() && msg.contains("certificate not valid for name") {
+        if let Some(hint) = tls_name_mismatch_hint(origin) {
+            return anyhow!(err).context(hint);
+        }
+    }
+    let connect = err.is_connect();
+    let failure = NetFailure::of(&err);
+    let mut err = anyhow!(err);
+    if let Some(failure) = failure {
+        err = err.context(network_hint(failure, origin, &net.for_origin(origin)));
+    }
+    match ip_family {
+        Some(family) if connect => err.context(family.connect_hint()),
+        _ => err,
+    }
+}
+
+/// Where the endpoint starts in a virtual-hosted bucket hostname.
+#[derive(Clone, Copy)]
+enum BucketEndpoint {
+    /// The last `n` labels; the bucket is everything before them.
+    Last(usize),
+    /// The `s3` (or `s3-website`) label and everything after it.
+    S3Label,
+}
+
+/// Object-storage hosts that put the bucket name in front of the endpoint,
+/// by host suffix; the first match wins. Each endpoint also serves the
+/// bucket path-style, as `https://<endpoint>/<bucket>`. `None` marks hosts
+/// that have no such endpoint.
+const OBJECT_STORAGE_HOSTS: &[(&str, Option<BucketEndpoint>)] = &[
+    // Google Cloud Storage: <bucket>.storage.googleapis.com
+    ("storage.googleapis.com", Some(BucketEndpoint::Last(3))),
+    // Spaces CDN hosts only answer for the bucket in the hostname.
+    ("cdn.digitaloceanspaces.com", None),
+    // DigitalOcean Spaces: <bucket>.<region>.digitaloceanspaces.com
+    ("digitaloceanspaces.com", Some(BucketEndpoint::Last(3))),
+    // Hetzner Object Storage: <bucket>.<location>.your-objectstorage.com
+    ("your-objectstorage.com", Some(BucketEndpoint::Last(3))),
+    // Backblaze B2: <bucket>.s3.<region>.backblazeb2.com
+    ("backblazeb2.com", Some(BucketEndpoint::S3Label)),
+    // Wasabi: <bucket>.s3.wasabisys.com, <bucket>.s3.<region>.wasabisys.com
+    ("wasabisys.com", Some(BucketEndpoint::S3Label)),
+    // AWS, Scaleway and other S3-compatible stores.
+    ("", Some(BucketEndpoint::S3Label)),
+];
+
+fn tls_name_mismatch_hint(origin: &str) -> Option<String> {
+    let url = Url::parse(origin).ok()?;
+    if url.scheme() != "https" {
+        return None;
+    }
+    let host = url.host_str()?;
+    let parts: Vec<&str> = host.split('.').collect();
+    let endpoint = OBJECT_STORAGE_HOSTS
+        .iter()
+        .find(|(suffix, _)| {
+            suffix.is_empty() || host == *suffix || host.ends_with(&format!(".{suffix}"))
+        })
+        .and_then(|(_, endpoint)| *endpoint)?;
+    let (idx, endpoint_head) = match endpoint {
+        BucketEndpoint::Last(n) => (parts.len().checked_sub(n)?, parts[parts.len() - n]),
+        BucketEndpoint::S3Label => {
+            if let Some(i) = parts.iter().position(|p| *p == "s3") {
+                (i, "s3")
+            } else if let Some(i) = parts.iter().position(|p| *p == "s3-website") {
+                (i, "s3")
+            } else {
+                return None;
+            }
+        }
+    };
+    if idx <= 1 {
+        return None;
+    }
+    let bucket = parts[..idx].join(".");
+    let endpoint = std::iter::once(endpoint_head)
+        .chain(parts.iter().skip(idx + 1).copied())
+        .collect::<Vec<_>>()
+        .join(".");
+    Some(format!(
+        "Try path-style origin for dotted bucket names, e.g. `https://{endpoint}/{bucket}`"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_file_fills_in_flags_the_command_line_left_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("puller.toml");
+        fs::write(
+            &path,
+            r#"
+origins = ["https://cfg.example"]
+root = "/from/config"
+connect_timeout = "3s"
+on_switch = ["true"]
+race_manifest = true
+"#,
+        )
+        .unwrap();
+        let parse = |extra: &[&str]| {
+            let mut argv: Vec<OsString> = vec!["cityfeed-puller".into(), "--config".into()];
+            argv.push(path.clone().into());
+            argv.extend(extra.iter().map(OsString::from));
+            let matches = Args::command().try_get_matches_from(&argv).unwrap();
+            args_with_config(argv, &matches)
+        };
+
+        let args = parse(&[]).unwrap();
+        assert_eq!(args.origins, ["https://cfg.example"]);
+        assert_eq!(args.root(), Path::new("/from/config"));
+        assert_eq!(args.connect_timeout, Duration::from_secs(3));
+        assert_eq!(args.on_switch, ["true"]);
+        assert!(args.race_manifest);
+        assert_eq!(args.interval, Duration::from_secs(60));
+
+        let args = parse(&["--root", "/from/flag", "--origin", "https://flag.example"]).unwrap();
+        assert_eq!(args.root(), Path::new("/from/flag"));
+        assert_eq!(args.origins, ["https://flag.example"]);
+        assert_eq!(args.connect_timeout, Duration::from_secs(3));
+
+        fs::write(&path, "connect_timeout = \"soon\"\n").unwrap();
+        let err = parse(&["--origin", "https://flag.example"]).unwrap_err();
+        assert!(err.to_string().contains("--connect-timeout"), "{err:#}");
+        fs::write(&path, "root = \"/x\"\n").unwrap();
+        let err = parse(&[]).unwrap_err();
+        assert!(err.to_string().contains("no origins"), "{err:#}");
+
+        // `export` needs no origins and still picks up the configured root.
+        let args = parse(&["export", "--out", "site.tar.zst"]).unwrap();
+        assert_eq!(args.root(), Path::new("/x"));
+        let Some(Command::Export(export)) = args.command else {
+            panic!("expected export, got {:?}", args.command);
+        };
+        assert_eq!(export.out, PathBuf::from("site.tar.zst"));
+        assert_eq!(export.version, None);
+
+        fs::write(
+            &path,
+            "root = [\"/a\", \"/b\"]\norigins = [\"https://o\"]\n",
+        )
+        .unwrap();
+        assert_eq!(
+            parse(&[]).unwrap().roots,
+            [Path::new("/a"), Path::new("/b")]
+        );
+        let err = parse(&["--root", "/c", "--root", "/c"]).unwrap_err();
+        assert!(err.to_string().contains("more than once"), "{err:#}");
+        let err = parse(&["--watch"]).unwrap_err();
+        assert!(err.to_string().contains("--watch"), "{err:#}");
+        let err = parse(&["export", "--out", "x"]).unwrap_err();
+        assert!(err.to_string().contains("single --root"), "{err:#}");
+    }
+
+    #[test]
+    fn aggregate_exit_code_reports_the_first_failed_site() {
+        assert_eq!(aggregate_exit_code(&[0, 3]), 0);
+        assert_eq!(aggregate_exit_code(&[3, 3]), 3);
+        assert_eq!(aggregate_exit_code(&[0, 5, 4]), 5);
+        assert_eq!(aggregate_exit_code(&[3, 1]), 1);
+    }
+
+    #[test]
+    fn normalize_origin_trims_and_rejects_bad_schemes() {
+        assert_eq!(
+            normalize_origin(" https://example.com/ ").unwrap(),
+            "https://example.com"
+        );
+        assert!(normalize_origin("").is_err());
+        assert!(normalize_origin("ftp://example.com").is_err());
+        assert_eq!(
+            normalize_origin("file:///mnt/usb/site/").unwrap(),
+            "file:///mnt/usb/site"
+        );
+        assert!(normalize_origin("not a url").is_err());
+    }
+
+    #[test]
+    fn parse_retry_after_accepts_seconds_and_dates() {
+        assert_eq!(parse_retry_after(" 7 "), Some(Duration::from_secs(7)));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        let later = std::time::SystemTime::now() + Duration::from_secs(120);
+        let wait = parse_retry_after(&httpdate::fmt_http_date(later)).unwrap();
+        assert!(wait > Duration::from_secs(100) && wait <= Duration::from_secs(120));
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+
+    #[test]
+    fn parse_sha256_wants_64_hex_digits() {
+        let digest = "AB".repeat(32);
+        assert_eq!(parse_sha256(&digest).unwrap(), "ab".repeat(32));
+        assert!(parse_sha256("abc").is_err());
+        assert!(parse_sha256(&"g".repeat(64)).is_err());
+    }
+
+    #[test]
+    fn channels_are_single_url_safe_components() {
+        assert_eq!(parse_channel("beta").unwrap(), "beta");
+        assert_eq!(parse_channel("kiosk_2.0-rc").unwrap(), "kiosk_2.0-rc");
+        for bad in ["", "../latest", "a/b", ".hidden", "-x", "beta json", "béta"] {
+            assert!(parse_channel(bad).is_err(), "{bad:?}");
+        }
+        assert_eq!(
+            manifest_url("https://cdn.example", "staging"),
+            "https://cdn.example/manifests/staging.json"
+        );
+    }
+
+    #[test]
+    fn parse_duration_accepts_units() {
+        assert_eq!(parse_duration("10").unwrap(), Duration::from_secs(10));
+        assert_eq!(parse_duration("10s").unwrap(), Duration::from_secs(10));
+        assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+        assert_eq!(parse_duration("2m").unwrap(), Duration::from_secs(120));
+        assert_eq!(parse_duration("1h").unwrap(), Duration::from_secs(3600));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("5d").is_err());
+        assert!(parse_duration("fast").is_err());
+
+        let err = parse_duration(&format!("{}h", u64::MAX / 3600 + 1)).unwrap_err();
+        assert!(err.to_string().contains("too large"), "{err:#}");
+        assert!(parse_duration(&format!("{}m", u64::MAX)).is_err());
+    }
+
+    #[test]
+    fn check_limits_treats_zero_as_unlimited() {
+        check_limits(1_000_000, u64::MAX, 0, 0).unwrap();
+        check_limits(10, 500, 10, 500).unwrap();
+
+        let err = check_limits(11, 500, 10, 500).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "manifest lists 11 files, over --max-file-count 10"
+        );
+        let err = check_limits(10, 501, 10, 500).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "deploy needs 501 bytes of new objects, over --max-total-bytes 500"
+        );
+    }
+
+    #[test]
+    fn check_minimums_refuses_empty_and_small_manifests() {
+        check_minimums(1, 0, 1, 0).unwrap();
+        check_minimums(500, 2_000_000, 100, 1_000_000).unwrap();
+
+        let err = check_minimums(0, 0, 0, 0).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "manifest lists 0 files, under --min-files 0 (--allow-small-manifest to deploy it anyway)"
+        );
+        let err = check_minimums(99, 2_000_000, 100, 1_000_000).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("manifest lists 99 files, under --min-files 100"));
+        let err = check_minimums(500, 999_999, 100, 1_000_000).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("manifest files add up to 999999 bytes, under --min-total-bytes 1000000"));
+    }
+
+    #[test]
+    fn parse_resolve_accepts_curl_triples() {
+        assert_eq!(
+            parse_resolve("CDN.example.com:443:203.0.113.7").unwrap(),
+            Resolve {
+                host: "cdn.example.com".into(),
+                addr: "203.0.113.7:443".parse().unwrap(),
+            }
+        );
+        assert_eq!(
+            parse_resolve("cdn.example.com:8443:[2001:db8::1]")
+                .unwrap()
+                .addr,
+            "[2001:db8::1]:8443".parse().unwrap()
+        );
+        for (bad, want) in [
+            ("cdn.example.com:443", "expected HOST:PORT:ADDRESS"),
+            (":443:203.0.113.7", "missing host"),
+            (
+                "cdn.example.com:https:203.0.113.7",
+                "invalid port \"https\"",
+            ),
+            (
+                "cdn.example.com:443:edge-7",
+                "invalid IP address \"edge-7\"",
+            ),
+        ] {
+            let err = format!("{:#}", parse_resolve(bad).unwrap_err());
+            assert!(err.contains(want), "{bad}: {err}");
+        }
+    }
+
+    #[test]
+    fn parse_bytes_accepts_suffixes() {
+        assert_eq!(parse_bytes("512").unwrap(), 512);
+        assert_eq!(parse_bytes("64K").unwrap(), 64 * 1024);
+        assert_eq!(parse_bytes("10m").unwrap(), 10 * 1024 * 1024);
+        assert_eq!(parse_bytes("2GiB").unwrap(), 2 * 1024 * 1024 * 1024);
+        assert!(parse_bytes("").is_err());
+        assert!(parse_bytes("12X").is_err());
+        assert!(parse_bytes("99999999999T").is_err());
+    }
+
+    #[test]
+    fn rate_limiter_paces_reads() {
+        let limiter = Arc::new(RateLimiter::new(20_000));
+        let mut reader = Throttled {
+            inner: Box::new(io::repeat(7).take(10_000)),
+            limiter,
+        };
+        let started = Instant::now();
+        let copied = io::copy(&mut reader, &mut io::sink()).unwrap();
+        assert_eq!(copied, 10_000);
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(400), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(3), "{elapsed:?}");
+    }
+
+    #[test]
+    fn stall_guard_times_out_on_silent_reader() {
+        struct Silent;
+        impl Read for Silent {
+            fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+                thread::sleep(Duration::from_secs(5));
+                Ok(0)
+            }
+        }
+        let mut guard = StallGuard::new(Silent, Duration::from_millis(50));
+        let err = guard.read(&mut [0u8; 8]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        let mut guard = StallGuard::new(&b"hello"[..], Duration::from_secs(1));
+        let mut out = String::new();
+        guard.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "hello");
+    }
+
+    #[test]
+    fn tls_name_mismatch_hint_for_dotted_bucket() {
+        let hint = tls_name_mismatch_hint("https://foo.bar.s3.fr-par.scw.cloud").unwrap();
+        assert!(hint.contains("path-style origin"));
+        assert!(hint.contains("https://s3.fr-par.scw.cloud/foo.bar"));
+        assert!(tls_name_mismatch_hint("http://foo.bar.s3.fr-par.scw.cloud").is_none());
+        assert!(tls_name_mismatch_hint("https://puller.s3.fr-par.scw.cloud").is_none());
+    }
+
+    #[test]
+    fn connection_errors_are_classified_and_explained() {
+        let client = reqwest::blocking::Client::builder()
+            .no_proxy()
+            .timeout(Duration::from_millis(200))
+            .build()
+            .unwrap();
+        let get = |url: &str| client.get(url).send().unwrap_err();
+
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let refused_url = format!("http://{}/", closed.local_addr().unwrap());
+        drop(closed);
+        let err = get(&refused_url);
+        assert_eq!(NetFailure::of(&err), Some(NetFailure::Refused));
+        let err = augment_reqwest_error(err, &refused_url, None, &NetSettings::default());
+        let text = format!("{err:#}");
+        assert!(
+            text.contains("connection refused on 127.0.0.1 port"),
+            "{text}"
+        );
+        // The raw error stays in the chain.
+        assert!(text.contains("error sending request"), "{text}");
+
+        // Accepted by the kernel, never answered.
+        let silent = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let err = get(&format!("http://{}/", silent.local_addr().unwrap()));
+        assert_eq!(NetFailure::of(&err), Some(NetFailure::Timeout));
+
+        let err = get("http://cityfeed-puller.invalid/");
+        assert_eq!(NetFailure::of(&err), Some(NetFailure::Dns));
+    }
+
+    #[test]
+    fn network_hints_name_the_flag_to_change() {
+        let origin = "https://cdn.example";
+        let mut net = NetSettings {
+            connect_timeout: Duration::from_secs(10),
+            request_timeout: Some(Duration::from_secs(300)),
+            proxy: None,
+        };
+        assert_eq!(
+            network_hint(NetFailure::Dns, origin, &net),
+            "DNS resolution failed for cdn.example; check /etc/resolv.conf or pin an address \
+             with --resolve cdn.example:443:<address>"
+        );
+        assert_eq!(
+            network_hint(NetFailure::Refused, "http://cdn.example:8080", &net),
+            "connection refused on cdn.example port 8080; is the port in origin \
+             http://cdn.example:8080 correct, and is the server listening?"
+        );
+        assert_eq!(
+            network_hint(NetFailure::ConnectTimeout, origin, &net),
+            "connecting to cdn.example port 443 timed out after 10s; raise --connect-timeout \
+             or check the venue firewall"
+        );
+        assert!(network_hint(NetFailure::Timeout, origin, &net).starts_with(
+            "request to https://cdn.example timed out after 300s; raise --request-timeout"
+        ));
+
+        net.proxy = Some(("HTTPS_PROXY".into(), "http://proxy.venue:3128/".into()));
+        assert!(network_hint(NetFailure::Refused, origin, &net)
+            .ends_with(" (requests go through proxy http://proxy.venue:3128/ from HTTPS_PROXY)"));
+    }
+
+    #[test]
+    fn tls_name_mismatch_hint_for_other_object_stores() {
+        for (origin, path_style) in [
+            (
+                "https://brief.mspmetro.nyc3.digitaloceanspaces.com",
+                "https://nyc3.digitaloceanspaces.com/brief.mspmetro",
+            ),
+            (
+                "https://brief.mspmetro.nbg1.your-objectstorage.com",
+                "https://nbg1.your-objectstorage.com/brief.mspmetro",
+            ),
+            (
+                "https://brief.mspmetro.storage.googleapis.com",
+                "https://storage.googleapis.com/brief.mspmetro",
+            ),
+            (
+                "https://brief.mspmetro.s3.us-west-004.backblazeb2.com",
+                "https://s3.us-west-004.backblazeb2.com/brief.mspmetro",
+            ),
+            (
+                "https://brief.mspmetro.s3.eu-central-1.wasabisys.com",
+                "https://s3.eu-central-1.wasabisys.com/brief.mspmetro",
+            ),
+            (
+                "https://brief.mspmetro.s3.wasabisys.com",
+                "https://s3.wasabisys.com/brief.mspmetro",
+            ),
+            (
+                "https://brief.mspmetro.s3-website.us-east-2.amazonaws.com",
+                "https://s3.us-east-2.amazonaws.com/brief.mspmetro",
+            ),
+        ] {
+            let hint = tls_name_mismatch_hint(origin).unwrap();
+            assert!(hint.contains(path_style), "{origin}: {hint}");
+        }
+        for origin in [
+            "https://brief.nyc3.digitaloceanspaces.com",
+            "https://brief.storage.googleapis.com",
+            "https://brief.s3.us-west-004.backblazeb2.com",
+            "https://brief.s3.wasabisys.com",
+            "https://brief.mspmetro.nyc3.cdn.digitaloceanspaces.com",
+            "https://storage.googleapis.com",
+            "http://brief.mspmetro.storage.googleapis.com",
+        ] {
+            assert!(tls_name_mismatch_hint(origin).is_none(), "{origin}");
+        }
+    }
+}
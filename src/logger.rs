@@ -0,0 +1,184 @@
+//! Progress and warning output on stderr, either as the historical
+//! human-readable lines or as one JSON object per line for log shippers.
+
+use std::fmt::Display;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+use indicatif::MultiProgress;
+use serde_json::{Map, Value};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Level {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    fn as_str(self) -> &'static str {
+        match self {
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
+    }
+}
+
+/// Every event has a stable `event` name plus structured `fields`; `text` is
+/// the line printed in text mode and must not change, since people grep it.
+///
+/// Verbosity: -1 (`--quiet`) keeps only the final outcome and errors, 0 is the
+/// default output, 1 (`-v`) adds per-request detail, 2 (`-vv`) adds per-object
+/// bookkeeping.
+#[derive(Clone, Debug)]
+pub struct Logger {
+    format: LogFormat,
+    verbosity: i8,
+    /// Set when one run deploys several sites: text lines get a `[site]`
+    /// prefix and JSON objects a `site` field.
+    site: Option<String>,
+    /// Progress bars being drawn on stderr; lines are printed around them.
+    progress: Option<MultiProgress>,
+}
+
+impl Logger {
+    pub fn new(format: LogFormat, verbosity: i8) -> Self {
+        Self {
+            format,
+            verbosity,
+            site: None,
+            progress: None,
+        }
+    }
+
+    pub fn with_site(&self, site: &str) -> Self {
+        Self {
+            site: Some(site.to_string()),
+            ..self.clone()
+        }
+    }
+
+    pub fn with_progress(self, progress: MultiProgress) -> Self {
+        Self {
+            progress: Some(progress),
+            ..self
+        }
+    }
+
+    pub fn progress(&self) -> Option<&MultiProgress> {
+        self.progress.as_ref()
+    }
+
+    /// The line summarizing what the run did; printed even with `--quiet`.
+    pub fn outcome(&self, event: &str, fields: Value, text: impl Display) {
+        self.emit(Level::Info, event, fields, text);
+    }
+
+    pub fn info(&self, event: &str, fields: Value, text: impl Display) {
+        if self.verbosity >= 0 {
+            self.emit(Level::Info, event, fields, text);
+        }
+    }
+
+    pub fn warn(&self, event: &str, fields: Value, text: impl Display) {
+        if self.verbosity >= 0 {
+            self.emit(Level::Warn, event, fields, text);
+        }
+    }
+
+    pub fn error(&self, event: &str, fields: Value, text: impl Display) {
+        self.emit(Level::Error, event, fields, text);
+    }
+
+    /// Detail shown from `-v` on.
+    pub fn debug(&self, event: &str, fields: Value, text: impl Display) {
+        if self.verbosity >= 1 {
+            self.emit(Level::Debug, event, fields, text);
+        }
+    }
+
+    /// Detail shown from `-vv` on.
+    pub fn trace(&self, event: &str, fields: Value, text: impl Display) {
+        if self.verbosity >= 2 {
+            self.emit(Level::Debug, event, fields, text);
+        }
+    }
+
+    fn emit(&self, level: Level, event: &str, mut fields: Value, text: impl Display) {
+        match self.format {
+            LogFormat::Text => {
+                let site = self
+                    .site
+                    .as_deref()
+                    .map(|s| format!("[{s}] "))
+                    .unwrap_or_default();
+                let line = match level {
+                    Level::Debug | Level::Info => format!("{site}{text}"),
+                    Level::Warn => format!("{site}warn: {text}"),
+                    Level::Error => format!("{site}error: {text}"),
+                };
+                match &self.progress {
+                    Some(bars) => bars.suspend(|| eprintln!("{line}")),
+                    None => eprintln!("{line}"),
+                }
+            }
+            LogFormat::Json => {
+                if let (Some(site), Value::Object(map)) = (&self.site, &mut fields) {
+                    map.insert("site".into(), site.as_str().into());
+                }
+                eprintln!("{}", render_json(level, event, fields, &text.to_string()));
+            }
+        }
+    }
+}
+
+fn render_json(level: Level, event: &str, fields: Value, msg: &str) -> String {
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default();
+    let mut obj = Map::new();
+    obj.insert("ts".into(), ts.into());
+    obj.insert("level".into(), level.as_str().into());
+    obj.insert("event".into(), event.into());
+    if let Value::Object(fields) = fields {
+        for (k, v) in fields {
+            obj.entry(k).or_insert(v);
+        }
+    }
+    obj.insert("msg".into(), msg.into());
+    Value::Object(obj).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn render_json_carries_event_and_fields() {
+        let line = render_json(
+            Level::Warn,
+            "object_download_failed",
+            json!({ "origin": "https://a", "hash": "abc", "event": "ignored" }),
+            "object download failed",
+        );
+        let v: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(v["level"], "warn");
+        assert_eq!(v["event"], "object_download_failed");
+        assert_eq!(v["origin"], "https://a");
+        assert_eq!(v["hash"], "abc");
+        assert_eq!(v["msg"], "object download failed");
+        assert!(v["ts"].as_f64().unwrap() > 0.0);
+        assert!(!line.contains('\n'));
+    }
+}
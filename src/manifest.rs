@@ -0,0 +1,1126 @@
+//! The manifest an origin publishes as `manifests/latest.json`, and the
+//! checks every entry passes before a deploy touches the disk.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::OsStr;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+
+use crate::store::ObjectLayout;
+
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "RawManifest")]
+pub struct Manifest {
+    pub version: String,
+    pub files: Vec<ManifestFile>,
+    pub symlinks: Vec<ManifestSymlink>,
+    /// How objects are laid out under `objects/` on the origin; flat if absent.
+    pub object_layout: Option<ObjectLayout>,
+    /// Publish counter; a higher one is newer.
+    pub sequence: Option<u64>,
+    /// When the publisher built the manifest, `YYYY-MM-DDTHH:MM:SS[.f]Z`.
+    pub generated_at: Option<String>,
+    /// The `--prefix` directory this manifest was cut down to and stripped
+    /// from its paths, as kept in its record.
+    pub prefix: Option<String>,
+    /// The `--only`/`--exclude` rules that cut this manifest down, as kept in
+    /// its record; empty for a whole manifest.
+    pub filters: Vec<String>,
+}
+
+impl Manifest {
+    /// How this manifest's publication compares with `other`'s: by
+    /// `sequence` when both have one, else by `generated_at`. `None` when
+    /// neither pair is available.
+    pub fn publish_order(&self, other: &Manifest) -> Option<Ordering> {
+        if let (Some(a), Some(b)) = (self.sequence, other.sequence) {
+            return Some(a.cmp(&b));
+        }
+        let a = timestamp_key(self.generated_at.as_deref()?)?;
+        let b = timestamp_key(other.generated_at.as_deref()?)?;
+        Some(a.cmp(&b))
+    }
+
+    /// How far this manifest's publication trails `newer`'s, on the same
+    /// scale `publish_order` compared them by: `3 publishes` or `840s`.
+    pub fn lag_behind(&self, newer: &Manifest) -> String {
+        if let (Some(a), Some(b)) = (self.sequence, newer.sequence) {
+            let n = b.saturating_sub(a);
+            return format!("{n} publish{}", if n == 1 { "" } else { "es" });
+        }
+        let secs = |m: &Manifest| timestamp_secs(timestamp_key(m.generated_at.as_deref()?)?.0);
+        match (secs(self), secs(newer)) {
+            (Some(a), Some(b)) if b > a => format!("{}s", b - a),
+            (Some(_), Some(_)) => "under a second".to_string(),
+            _ => "unknown".to_string(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ManifestFile {
+    pub path: String,
+    pub hash: String,
+    pub size: u64,
+    /// Permission bits for the snapshot copy; 0644 if absent.
+    pub mode: Option<u32>,
+    /// Set when the origin serves this file compressed.
+    pub transfer: Option<ManifestTransfer>,
+    /// Set when the origin serves this file split into several objects.
+    pub parts: Vec<ManifestPart>,
+    /// Hex ed25519 signature over the entry; see `signatures`.
+    pub signature: Option<String>,
+}
+
+/// `{ "hash": "...", "size": N }`: one piece of a file too large for a single
+/// object, fetched from `objects/<hash>`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ManifestPart {
+    pub hash: String,
+    pub size: u64,
+}
+
+/// `{ "encoding": "zstd", "hash": "...", "size": M }`: the object is fetched
+/// from `objects/<hash>` as `size` compressed bytes and decoded into the
+/// store under the file's own hash.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ManifestTransfer {
+    pub encoding: String,
+    pub hash: String,
+    pub size: u64,
+}
+
+/// Transfer encodings the puller can decode.
+const TRANSFER_ENCODINGS: &[&str] = &["zstd"];
+
+/// `{ "path": "old.html", "symlink": "new.html" }`: a link inside the
+/// snapshot, with the target relative to the link's own directory.
+#[derive(Debug)]
+pub struct ManifestSymlink {
+    pub path: String,
+    pub target: String,
+}
+
+/// `latest.json` as published; `files` mixes regular and symlink entries.
+#[derive(Deserialize)]
+struct RawManifest {
+    version: String,
+    files: Vec<ManifestEntry>,
+    #[serde(default)]
+    object_layout: Option<ObjectLayout>,
+    #[serde(default)]
+    sequence: Option<u64>,
+    #[serde(default)]
+    generated_at: Option<String>,
+    #[serde(default)]
+    prefix: Option<String>,
+    #[serde(default)]
+    filters: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ManifestEntry {
+    path: String,
+    hash: Option<String>,
+    /// `"sha256-<base64>"`, in place of or next to `hash`.
+    integrity: Option<String>,
+    size: Option<u64>,
+    /// e.g. `"0755"` or `493`.
+    #[serde(default, deserialize_with = "deserialize_mode")]
+    mode: Option<u32>,
+    symlink: Option<String>,
+    /// Precompressed copies staged next to the file, for `gzip_static` and
+    /// `brotli_static`.
+    #[serde(default)]
+    variants: Vec<ManifestVariant>,
+    transfer: Option<ManifestTransfer>,
+    /// Objects to concatenate, in order, to get the file.
+    #[serde(default)]
+    parts: Vec<ManifestPart>,
+    signature: Option<String>,
+}
+
+/// `{ "encoding": "gzip", "hash": "...", "size": N, "suffix": ".gz" }`, with
+/// `integrity` allowed in place of `hash` as for a file.
+#[derive(Deserialize)]
+struct ManifestVariant {
+    encoding: String,
+    hash: Option<String>,
+    integrity: Option<String>,
+    size: u64,
+    suffix: String,
+    signature: Option<String>,
+}
+
+impl ManifestVariant {
+    /// The variant as a regular file at `path + suffix`.
+    fn into_file(self, path: &str) -> Result<ManifestFile> {
+        let ManifestVariant {
+            encoding,
+            hash,
+            integrity,
+            size,
+            suffix,
+            signature,
+        } = self;
+        let name = suffix.strip_prefix('.').unwrap_or_default();
+        if encoding.trim().is_empty() {
+            bail!("{path}: variant encoding is empty");
+        }
+        if name.is_empty() || name.contains(['/', '\\', '.']) {
+            bail!("{path}: invalid {encoding} variant suffix {suffix:?}");
+        }
+        let path = format!("{path}{suffix}");
+        let hash = entry_hash(&path, hash, integrity)?;
+        Ok(ManifestFile {
+            path,
+            hash,
+            size,
+            mode: None,
+            transfer: None,
+            parts: Vec::new(),
+            signature,
+        })
+    }
+}
+
+/// The hex sha256 of an entry from its `hash`, its `integrity`, or both,
+/// which have to agree.
+fn entry_hash(path: &str, hash: Option<String>, integrity: Option<String>) -> Result<String> {
+    let Some(integrity) = integrity else {
+        return hash.ok_or_else(|| anyhow!("{path}: missing field `hash`"));
+    };
+    let from_integrity = parse_integrity(&integrity)
+        .map_err(|err| anyhow!("{path}: invalid integrity {integrity:?}: {err:#}"))?;
+    match hash {
+        Some(hash) if hash != from_integrity => {
+            bail!("{path}: hash {hash} does not match integrity {integrity} (sha256 {from_integrity})")
+        }
+        _ => Ok(from_integrity),
+    }
+}
+
+/// Algorithms an `integrity` string may use.
+const INTEGRITY_ALGORITHMS: &[&str] = &["sha256"];
+
+/// Parses a subresource-integrity string, `sha256-<base64 digest>`, into the
+/// lowercase hex digest objects are named by. SRI options (`?...`) after the
+/// digest are ignored.
+fn parse_integrity(integrity: &str) -> Result<String> {
+    let integrity = integrity.trim();
+    let (algorithm, digest) = integrity
+        .split_once('-')
+        .context("expected <algorithm>-<base64 digest>")?;
+    if !INTEGRITY_ALGORITHMS.contains(&algorithm) {
+        bail!(
+            "unsupported integrity algorithm {algorithm:?} (supported: {})",
+            INTEGRITY_ALGORITHMS.join(", ")
+        );
+    }
+    let digest = digest.split_once('?').map_or(digest, |(digest, _)| digest);
+    let bytes = decode_base64(digest).context("digest is not base64")?;
+    if bytes.len() != 32 {
+        bail!("{algorithm} digest is {} bytes, expected 32", bytes.len());
+    }
+    Ok(bytes.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+/// Standard base64, padded or not.
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim_end_matches('=');
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    let (mut bits, mut n) = (0u32, 0);
+    for b in text.bytes() {
+        let value = match b {
+            b'A'..=b'Z' => b - b'A',
+            b'a'..=b'z' => b - b'a' + 26,
+            b'0'..=b'9' => b - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        bits = bits << 6 | value as u32;
+        n += 6;
+        if n >= 8 {
+            n -= 8;
+            out.push((bits >> n) as u8);
+        }
+    }
+    Some(out)
+}
+
+impl TryFrom<RawManifest> for Manifest {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: RawManifest) -> Result<Self> {
+        if let Some(at) = &raw.generated_at {
+            if timestamp_key(at).is_none() {
+                bail!("generated_at {at:?} is not a UTC time like 2026-10-16T08:30:00Z");
+            }
+        }
+        let mut files = Vec::new();
+        let mut symlinks = Vec::new();
+        for entry in raw.files {
+            let ManifestEntry {
+                path,
+                hash,
+                integrity,
+                size,
+                mode,
+                symlink,
+                variants,
+                transfer,
+                parts,
+                signature,
+            } = entry;
+            match symlink {
+                Some(target) => {
+                    if hash.is_some()
+                        || integrity.is_some()
+                        || size.is_some()
+                        || mode.is_some()
+                        || !variants.is_empty()
+                        || transfer.is_some()
+                        || !parts.is_empty()
+                        || signature.is_some()
+                    {
+                        bail!(
+                            "{path}: a symlink entry cannot have hash, size, mode, variants, transfer, parts or a signature"
+                        );
+                    }
+                    symlinks.push(ManifestSymlink { path, target });
+                }
+                None => {
+                    let hash = entry_hash(&path, hash, integrity)?;
+                    let size = size.ok_or_else(|| anyhow!("{path}: missing field `size`"))?;
+                    if !parts.is_empty() {
+                        if transfer.is_some() {
+                            bail!("{path}: an entry cannot have both transfer and parts");
+                        }
+                        let total = parts
+                            .iter()
+                            .try_fold(0u64, |sum, part| sum.checked_add(part.size));
+                        if total != Some(size) {
+                            bail!("{path}: parts do not add up to size {size}");
+                        }
+                    }
+                    if let Some(transfer) = &transfer {
+                        if !TRANSFER_ENCODINGS.contains(&transfer.encoding.as_str()) {
+                            bail!(
+                                "{path}: unsupported transfer encoding {:?}",
+                                transfer.encoding
+                            );
+                        }
+                    }
+                    let sidecars = variants
+                        .into_iter()
+                        .map(|variant| variant.into_file(&path))
+                        .collect::<Result<Vec<_>>>()?;
+                    files.push(ManifestFile {
+                        path,
+                        hash,
+                        size,
+                        mode,
+                        transfer,
+                        parts,
+                        signature,
+                    });
+                    files.extend(sidecars);
+                }
+            }
+        }
+        Ok(Manifest {
+            version: raw.version,
+            files,
+            symlinks,
+            object_layout: raw.object_layout,
+            sequence: raw.sequence,
+            generated_at: raw.generated_at,
+            prefix: raw.prefix,
+            filters: raw.filters,
+        })
+    }
+}
+
+/// `generated_at` as whole seconds and fractional digits, which sort in time
+/// order; `None` unless it is `YYYY-MM-DDTHH:MM:SS[.fff]Z`.
+fn timestamp_key(text: &str) -> Option<(&str, &str)> {
+    let text = text.strip_suffix('Z')?;
+    let (secs, frac) = text.split_once('.').unwrap_or((text, ""));
+    let shaped = secs.len() == 19
+        && secs.bytes().enumerate().all(|(i, b)| match i {
+            4 | 7 => b == b'-',
+            10 => b == b'T',
+            13 | 16 => b == b':',
+            _ => b.is_ascii_digit(),
+        });
+    (shaped && frac.bytes().all(|b| b.is_ascii_digit())).then(|| (secs, frac.trim_end_matches('0')))
+}
+
+/// Seconds since the epoch of a `timestamp_key`'s `YYYY-MM-DDTHH:MM:SS`.
+fn timestamp_secs(secs: &str) -> Option<i64> {
+    let field = |range: std::ops::Range<usize>| secs.get(range)?.parse::<i64>().ok();
+    let (year, month, day) = (field(0..4)?, field(5..7)?, field(8..10)?);
+    // A civil date to days since the epoch (Howard Hinnant's algorithm).
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    Some(days * 86_400 + field(11..13)? * 3_600 + field(14..16)? * 60 + field(17..19)?)
+}
+
+/// Accepts an octal string (`"0755"`, `"755"`, `"0o755"`) or a plain integer
+/// holding the mode value (`493`).
+fn deserialize_mode<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RawMode {
+        Int(u32),
+        Octal(String),
+    }
+
+    let mode = match Option::<RawMode>::deserialize(deserializer)? {
+        None => return Ok(None),
+        Some(RawMode::Int(mode)) => mode,
+        Some(RawMode::Octal(text)) => parse_octal_mode(&text).map_err(serde::de::Error::custom)?,
+    };
+    if mode > 0o7777 {
+        return Err(serde::de::Error::custom(format!(
+            "mode {mode:#o} is out of range"
+        )));
+    }
+    Ok(Some(mode))
+}
+
+fn parse_octal_mode(text: &str) -> Result<u32> {
+    let digits = text.trim();
+    let digits = digits.strip_prefix("0o").unwrap_or(digits);
+    u32::from_str_radix(digits, 8).with_context(|| format!("invalid octal mode {text:?}"))
+}
+
+/// Checks every entry before anything is downloaded: paths must be safe,
+/// hashes well-formed, a path listed twice (after normalization) must have
+/// the same hash, size and mode, and symlinks must stay inside the snapshot.
+pub fn validate_manifest(manifest: &Manifest) -> Result<()> {
+    validate_version(&manifest.version)
+        .with_context(|| format!("invalid manifest version {:?}", manifest.version))?;
+    let mut seen: HashMap<PathBuf, &ManifestFile> = HashMap::new();
+    for file in &manifest.files {
+        let rel_path = validate_rel_path(&file.path)
+            .with_context(|| format!("invalid manifest path: {}", file.path))?;
+        validate_hash(&file.hash)
+            .with_context(|| format!("invalid hash for {}: {:?}", file.path, file.hash))?;
+        if let Some(transfer) = &file.transfer {
+            validate_hash(&transfer.hash).with_context(|| {
+                format!(
+                    "invalid transfer hash for {}: {:?}",
+                    file.path, transfer.hash
+                )
+            })?;
+        }
+        for part in &file.parts {
+            validate_hash(&part.hash)
+                .with_context(|| format!("invalid part hash for {}: {:?}", file.path, part.hash))?;
+        }
+        if let Some(prev) = seen.insert(rel_path, file) {
+            if prev.hash != file.hash {
+                bail!(
+                    "conflicting hashes for {}: {} and {}",
+                    file.path,
+                    prev.hash,
+                    file.hash
+                );
+            }
+            if prev.size != file.size {
+                bail!(
+                    "conflicting sizes for {}: {} and {}",
+                    file.path,
+                    prev.size,
+                    file.size
+                );
+            }
+            if prev.mode != file.mode {
+                bail!("conflicting modes for {}", file.path);
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    if let Some(link) = manifest.symlinks.first() {
+        bail!(
+            "manifest has symlink entries (first: {}), which are only supported on unix",
+            link.path
+        );
+    }
+    let mut links: HashMap<PathBuf, &str> = HashMap::new();
+    for link in &manifest.symlinks {
+        let rel_path = validate_rel_path(&link.path)
+            .with_context(|| format!("invalid manifest path: {}", link.path))?;
+        validate_symlink_target(&rel_path, &link.target)
+            .with_context(|| format!("invalid symlink target for {}", link.path))?;
+        if seen.contains_key(&rel_path) || links.insert(rel_path, &link.path).is_some() {
+            bail!("{} is listed more than once", link.path);
+        }
+    }
+
+    // `about` and `about/index.html` can't both be staged.
+    let entries: BTreeMap<&Path, &str> = seen
+        .iter()
+        .map(|(path, file)| (path.as_path(), file.path.as_str()))
+        .chain(links.iter().map(|(path, name)| (path.as_path(), *name)))
+        .collect();
+    for (path, name) in &entries {
+        for dir in path.ancestors().skip(1) {
+            if let Some(entry) = entries.get(dir) {
+                bail!("{entry} is listed as a file but {name} needs it to be a directory");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fails listing every group of entries whose paths differ only in case,
+/// which would land on one file on a case-insensitive filesystem (macOS,
+/// SMB shares) that a snapshot gets copied to.
+pub fn check_case_collisions(manifest: &Manifest) -> Result<()> {
+    let groups = case_collisions(manifest);
+    if groups.is_empty() {
+        return Ok(());
+    }
+    let listed: Vec<String> = groups
+        .iter()
+        .map(|group| format!("[{}]", group.join(", ")))
+        .collect();
+    bail!(
+        "paths collide on case-insensitive filesystems: {} (--allow-case-collisions to deploy anyway)",
+        listed.join(" ")
+    )
+}
+
+/// Groups of spellings that are equal ignoring case. A clash is reported at
+/// the shortest prefix where it happens: `Docs/a.html` and `docs/b.html`
+/// are one group, `[Docs, docs]`, and their children aren't repeated.
+fn case_collisions(manifest: &Manifest) -> Vec<Vec<String>> {
+    let mut spellings: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    let paths = manifest.files.iter().map(|file| &file.path);
+    for path in paths.chain(manifest.symlinks.iter().map(|link| &link.path)) {
+        let Ok(rel) = validate_rel_path(path) else {
+            continue;
+        };
+        let mut prefix = String::new();
+        for part in rel.components() {
+            if !prefix.is_empty() {
+                prefix.push('/');
+            }
+            prefix.push_str(&part.as_os_str().to_string_lossy());
+            spellings
+                .entry(prefix.to_lowercase())
+                .or_default()
+                .insert(prefix.clone());
+        }
+    }
+    spellings
+        .into_values()
+        .filter(|group| group.len() > 1)
+        .filter(|group| {
+            // Differently spelled parents were already reported a level up.
+            let parents: BTreeSet<_> = group
+                .iter()
+                .map(|p| p.rsplit_once('/').map(|(d, _)| d))
+                .collect();
+            parents.len() == 1
+        })
+        .map(|group| group.into_iter().collect())
+        .collect()
+}
+
+/// Drops files listed again after their first entry. Run after
+/// `validate_manifest`, which makes sure every repeat is identical. Returns
+/// the dropped entries' paths, as listed.
+pub fn dedup(manifest: &mut Manifest) -> Vec<String> {
+    let mut seen = HashSet::new();
+    let mut dropped = Vec::new();
+    manifest.files.retain(|file| {
+        let key = validate_rel_path(&file.path).unwrap_or_else(|_| PathBuf::from(&file.path));
+        if seen.insert(key) {
+            return true;
+        }
+        dropped.push(file.path.clone());
+        false
+    });
+    dropped
+}
+
+pub fn validate_rel_path(path_str: &str) -> Result<PathBuf> {
+    if path_str.is_empty() {
+        bail!("path is empty");
+    }
+    let path = Path::new(path_str);
+    let mut out = PathBuf::new();
+    for comp in path.components() {
+        match comp {
+            Component::Normal(part) => {
+                if part == OsStr::new("") || part == OsStr::new(".") {
+                    bail!("invalid path component");
+                }
+                out.push(part);
+            }
+            _ => bail!("path must be relative and must not contain '..'"),
+        }
+    }
+    if out.as_os_str().is_empty() {
+        bail!("path resolves to empty");
+    }
+    Ok(out)
+}
+
+/// Longest version accepted, in bytes.
+const MAX_VERSION_LEN: usize = 128;
+
+/// A version names a directory under `snapshots/` (and a file under
+/// `manifests/`), so it must be one plain path component: no separators, no
+/// leading dot, no control characters.
+pub fn validate_version(version: &str) -> Result<()> {
+    if version.is_empty() {
+        bail!("version is empty");
+    }
+    if version.len() > MAX_VERSION_LEN {
+        bail!(
+            "version is {} bytes, more than {MAX_VERSION_LEN}",
+            version.len()
+        );
+    }
+    if version.trim() != version {
+        bail!("version must not start or end with whitespace");
+    }
+    if version.starts_with('.') {
+        bail!("version must not start with '.'");
+    }
+    if version.contains(['/', '\\']) {
+        bail!("version must not contain path separators");
+    }
+    if version.chars().any(char::is_control) {
+        bail!("version must not contain control characters");
+    }
+    Ok(())
+}
+
+/// Normalizes a symlink target for the link at `link` (relative to the
+/// snapshot root). `..` is only allowed as a leading component, so the
+/// target never resolves through another link, and it may not climb above
+/// the snapshot root. The target itself need not exist.
+pub fn validate_symlink_target(link: &Path, target: &str) -> Result<PathBuf> {
+    if target.is_empty() {
+        bail!("target is empty");
+    }
+    let mut depth = link.components().count().saturating_sub(1);
+    let mut out = PathBuf::new();
+    let mut seen_normal = false;
+    for comp in Path::new(target).components() {
+        match comp {
+            Component::CurDir => {}
+            Component::ParentDir if seen_normal => {
+                bail!("'..' may only appear at the start of the target: {target}");
+            }
+            Component::ParentDir => {
+                if depth == 0 {
+                    bail!("target escapes the snapshot: {target}");
+                }
+                depth -= 1;
+                out.push("..");
+            }
+            Component::Normal(part) => {
+                seen_normal = true;
+                out.push(part);
+            }
+            _ => bail!("target must be relative: {target}"),
+        }
+    }
+    if !seen_normal {
+        bail!("target must name a path below a directory: {target}");
+    }
+    Ok(out)
+}
+
+/// Objects are addressed by sha256, as lowercase hex.
+const HASH_HEX_LEN: usize = 64;
+
+pub fn validate_hash(hash: &str) -> Result<()> {
+    if hash.len() != HASH_HEX_LEN {
+        bail!(
+            "expected {HASH_HEX_LEN} hex chars (sha256), got {}",
+            hash.len()
+        );
+    }
+    if !hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        bail!("expected lowercase hex");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_rel_path_rejects_absolute_and_parent() {
+        assert!(validate_rel_path("/etc/passwd").is_err());
+        assert!(validate_rel_path("../x").is_err());
+        assert!(validate_rel_path("a/../../b").is_err());
+    }
+
+    #[test]
+    fn validate_rel_path_accepts_simple() {
+        let p = validate_rel_path("index.html").unwrap();
+        assert_eq!(p, PathBuf::from("index.html"));
+
+        let p = validate_rel_path("a/b/c.txt").unwrap();
+        assert_eq!(p, PathBuf::from("a/b/c.txt"));
+
+        let p = validate_rel_path("a/./b").unwrap();
+        assert_eq!(p, PathBuf::from("a/b"));
+    }
+
+    #[test]
+    fn validate_version_accepts_only_one_plain_component() {
+        for good in ["v1", "1.4.0-rc.2", "2026-10-16T0830Z", "build+7", "v..1"] {
+            validate_version(good).unwrap_or_else(|err| panic!("{good}: {err}"));
+        }
+        let long = "v".repeat(MAX_VERSION_LEN + 1);
+        let cases = [
+            ("", "version is empty"),
+            ("../evil", "version must not start with '.'"),
+            ("..", "version must not start with '.'"),
+            (" ", "version must not start or end with whitespace"),
+            ("v1 ", "version must not start or end with whitespace"),
+            (".hidden", "version must not start with '.'"),
+            ("/etc", "version must not contain path separators"),
+            ("v1/../../etc", "version must not contain path separators"),
+            ("a/b", "version must not contain path separators"),
+            ("C:\\evil", "version must not contain path separators"),
+            ("v\n1", "version must not contain control characters"),
+            ("v\u{0}1", "version must not contain control characters"),
+            (&long, "version is 129 bytes, more than 128"),
+        ];
+        for (version, want) in cases {
+            let err = validate_version(version).unwrap_err();
+            assert_eq!(err.to_string(), want, "{version:?}");
+        }
+        validate_version(&"v".repeat(MAX_VERSION_LEN)).unwrap();
+    }
+
+    #[test]
+    fn validate_hash_accepts_only_lowercase_sha256_hex() {
+        let good = "0123456789abcdef".repeat(4);
+        let cases = [
+            (good.as_str(), true),
+            (&"f".repeat(64), true),
+            ("", false),
+            ("abc123", false),
+            (&"a".repeat(63), false),
+            (&"a".repeat(65), false),
+            (&"ABCDEF0123456789".repeat(4), false),
+            (&format!("{}%2e%2e", "a".repeat(58)), false),
+            (&format!("../{}", "a".repeat(61)), false),
+            (&format!("{}/b", "a".repeat(62)), false),
+            (&format!("{}g", "a".repeat(63)), false),
+            (&format!("{}é", "a".repeat(62)), false),
+        ];
+        for (hash, ok) in cases {
+            assert_eq!(validate_hash(hash).is_ok(), ok, "{hash:?}");
+        }
+    }
+
+    #[test]
+    fn validate_manifest_rejects_conflicting_duplicate_paths() {
+        let (a, b) = ("a".repeat(64), "b".repeat(64));
+        let manifest = |files: &[(&str, &str)]| Manifest {
+            version: "v1".to_string(),
+            files: files
+                .iter()
+                .map(|(path, hash)| ManifestFile {
+                    path: path.to_string(),
+                    hash: hash.to_string(),
+                    size: 1,
+                    mode: None,
+                    transfer: None,
+                    parts: Vec::new(),
+                    signature: None,
+                })
+                .collect(),
+            symlinks: Vec::new(),
+            object_layout: None,
+            sequence: None,
+            generated_at: None,
+            prefix: None,
+            filters: Vec::new(),
+        };
+
+        let mut identical =
+            manifest(&[("x", &a), ("y", &a), ("d/x", &a), ("x", &a), ("d/./x", &a)]);
+        validate_manifest(&identical).unwrap();
+        assert_eq!(dedup(&mut identical), ["x", "d/./x"]);
+        let paths: Vec<_> = identical.files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, ["x", "y", "d/x"]);
+
+        let err = validate_manifest(&manifest(&[("d/x", &a), ("d/./x", &b)])).unwrap_err();
+        assert!(err.to_string().contains("conflicting hashes for d/./x"));
+        let err =
+            validate_manifest(&manifest(&[("index.html", &a), ("index.html", &b)])).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("conflicting hashes for index.html"));
+        let mut resized = manifest(&[("x", &a), ("x", &a)]);
+        resized.files[1].size = 2;
+        let err = validate_manifest(&resized).unwrap_err();
+        assert_eq!(err.to_string(), "conflicting sizes for x: 1 and 2");
+        let mut remoded = manifest(&[("x", &a), ("x", &a)]);
+        remoded.files[1].mode = Some(0o755);
+        assert!(validate_manifest(&remoded).is_err());
+        let err = validate_manifest(&manifest(&[("x", "nope")])).unwrap_err();
+        assert_eq!(err.to_string(), r#"invalid hash for x: "nope""#);
+    }
+
+    #[test]
+    fn validate_manifest_rejects_paths_used_as_file_and_directory() {
+        let manifest = |paths: &[&str]| Manifest {
+            version: "v1".to_string(),
+            files: paths
+                .iter()
+                .map(|path| ManifestFile {
+                    path: path.to_string(),
+                    hash: "a".repeat(64),
+                    size: 1,
+                    mode: None,
+                    transfer: None,
+                    parts: Vec::new(),
+                    signature: None,
+                })
+                .collect(),
+            symlinks: Vec::new(),
+            object_layout: None,
+            sequence: None,
+            generated_at: None,
+            prefix: None,
+            filters: Vec::new(),
+        };
+
+        let err = validate_manifest(&manifest(&["about", "about/index.html"])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "about is listed as a file but about/index.html needs it to be a directory"
+        );
+        let err = validate_manifest(&manifest(&["a/b/c/d", "x", "a/./b"])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "a/./b is listed as a file but a/b/c/d needs it to be a directory"
+        );
+
+        let mut linked = manifest(&["docs/index.html"]);
+        linked.symlinks.push(ManifestSymlink {
+            path: "docs".to_string(),
+            target: "manual".to_string(),
+        });
+        assert!(validate_manifest(&linked).is_err());
+
+        // Directories shared by many files, and names that only share a prefix.
+        validate_manifest(&manifest(&[
+            "a/b/c",
+            "a/b/d",
+            "a/e",
+            "about.html",
+            "about/index.html",
+            "ab",
+        ]))
+        .unwrap();
+    }
+
+    #[test]
+    fn case_collisions_are_grouped_at_the_clashing_component() {
+        let manifest = |paths: &[&str]| Manifest {
+            version: "v1".to_string(),
+            files: paths
+                .iter()
+                .map(|path| ManifestFile {
+                    path: path.to_string(),
+                    hash: "a".repeat(64),
+                    size: 1,
+                    mode: None,
+                    transfer: None,
+                    parts: Vec::new(),
+                    signature: None,
+                })
+                .collect(),
+            symlinks: vec![ManifestSymlink {
+                path: "LATEST.html".to_string(),
+                target: "index.html".to_string(),
+            }],
+            object_layout: None,
+            sequence: None,
+            generated_at: None,
+            prefix: None,
+            filters: Vec::new(),
+        };
+
+        let clean = manifest(&["index.html", "assets/logo.png", "assets/app.js"]);
+        assert!(case_collisions(&clean).is_empty());
+        check_case_collisions(&clean).unwrap();
+
+        let simple = manifest(&["Assets/Logo.png", "Assets/logo.png", "latest.html"]);
+        assert_eq!(
+            case_collisions(&simple),
+            [
+                vec!["Assets/Logo.png", "Assets/logo.png"],
+                vec!["LATEST.html", "latest.html"]
+            ]
+        );
+
+        // Only a middle directory differs; the files themselves don't clash.
+        let middle = manifest(&["site/Docs/a.html", "site/docs/b.html", "site/docs/c.html"]);
+        assert_eq!(case_collisions(&middle), [vec!["site/Docs", "site/docs"]]);
+
+        // Every group is listed, not just the first.
+        let both = manifest(&["Docs/a.html", "docs/a.html", "Docs/X.css", "Docs/x.css"]);
+        let err = check_case_collisions(&both).unwrap_err().to_string();
+        assert!(
+            err.contains("[Docs, docs] [Docs/X.css, Docs/x.css]"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn validate_symlink_target_keeps_links_inside_the_snapshot() {
+        let ok = |link: &str, target: &str| validate_symlink_target(Path::new(link), target);
+        assert_eq!(ok("old.html", "new.html").unwrap(), Path::new("new.html"));
+        assert_eq!(ok("a/b/old", "../c/./new").unwrap(), Path::new("../c/new"));
+        assert_eq!(ok("a/b/old", "../../new").unwrap(), Path::new("../../new"));
+        // Dangling is fine as long as it would land inside the snapshot.
+        assert!(ok("old", "not/there/yet").is_ok());
+
+        assert!(ok("a/old", "../../etc/passwd").is_err());
+        assert!(ok("old", "../etc").is_err());
+        assert!(ok("old", "/etc/passwd").is_err());
+        assert!(ok("a/old", "b/../../x").is_err());
+        assert!(ok("a/old", "..").is_err());
+        assert!(ok("old", "").is_err());
+    }
+
+    #[test]
+    fn publish_order_prefers_sequence_then_generated_at() {
+        let parse = |extra: &str| -> Manifest {
+            serde_json::from_str(&format!(r#"{{"version": "v", {extra} "files": []}}"#)).unwrap()
+        };
+        let old = parse(r#""sequence": 4, "generated_at": "2026-10-16T09:00:00Z","#);
+        let new = parse(r#""sequence": 5, "generated_at": "2026-10-16T08:00:00Z","#);
+        assert_eq!(old.publish_order(&new), Some(Ordering::Less));
+
+        let a = parse(r#""generated_at": "2026-10-16T08:00:00.5Z","#);
+        let b = parse(r#""generated_at": "2026-10-16T08:00:00Z","#);
+        let c = parse(r#""generated_at": "2026-10-16T08:00:00.50Z","#);
+        assert_eq!(a.publish_order(&b), Some(Ordering::Greater));
+        assert_eq!(a.publish_order(&c), Some(Ordering::Equal));
+        assert_eq!(a.publish_order(&new), Some(Ordering::Greater));
+        assert_eq!(parse("").publish_order(&new), None);
+        assert_eq!(old.lag_behind(&new), "1 publish");
+        let earlier = parse(r#""generated_at": "2026-10-15T23:59:30Z","#);
+        assert_eq!(earlier.lag_behind(&b), "28830s");
+        assert_eq!(b.lag_behind(&a), "under a second");
+
+        for bad in [
+            "2026-10-16 08:00:00Z",
+            "2026-10-16T08:00:00+02:00",
+            "yesterday",
+        ] {
+            let text = format!(r#"{{"version": "v", "generated_at": "{bad}", "files": []}}"#);
+            assert!(serde_json::from_str::<Manifest>(&text).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn manifest_splits_symlink_entries_from_files() {
+        let hash = "a".repeat(64);
+        let parse = |files: &str| {
+            serde_json::from_str::<Manifest>(&format!(r#"{{"version": "v1", "files": [{files}]}}"#))
+        };
+        let manifest = parse(&format!(
+            r#"{{ "path": "new.html", "hash": "{hash}", "size": 1 }},
+               {{ "path": "old.html", "symlink": "new.html" }}"#
+        ))
+        .unwrap();
+        assert_eq!(manifest.files.len(), 1);
+        assert_eq!(manifest.symlinks[0].target, "new.html");
+        validate_manifest(&manifest).unwrap();
+
+        let err = parse(r#"{ "path": "x", "symlink": "y", "size": 1 }"#).unwrap_err();
+        assert!(
+            err.to_string().contains("symlink entry cannot have"),
+            "{err}"
+        );
+        assert!(parse(r#"{ "path": "x", "size": 1 }"#).is_err());
+
+        let clash = parse(&format!(
+            r#"{{ "path": "x", "hash": "{hash}", "size": 1 }},
+               {{ "path": "x", "symlink": "y" }}"#
+        ))
+        .unwrap();
+        let err = validate_manifest(&clash).unwrap_err();
+        assert_eq!(err.to_string(), "x is listed more than once");
+    }
+
+    #[test]
+    fn manifest_variants_become_files_next_to_their_original() {
+        let (a, b) = ("a".repeat(64), "b".repeat(64));
+        let parse = |variant: &str| {
+            serde_json::from_str::<Manifest>(&format!(
+                r#"{{"version": "v1", "files": [
+                    {{ "path": "app.js", "hash": "{a}", "size": 10, "mode": "0755",
+                       "variants": [{variant}] }}
+                ]}}"#
+            ))
+        };
+        let manifest = parse(&format!(
+            r#"{{ "encoding": "gzip", "hash": "{b}", "size": 4, "suffix": ".gz" }}"#
+        ))
+        .unwrap();
+        let files: Vec<_> = manifest
+            .files
+            .iter()
+            .map(|f| (f.path.as_str(), f.hash.as_str(), f.size, f.mode))
+            .collect();
+        assert_eq!(
+            files,
+            [
+                ("app.js", a.as_str(), 10, Some(0o755)),
+                ("app.js.gz", b.as_str(), 4, None)
+            ]
+        );
+
+        for suffix in ["", ".", "gz", "./gz", ".tar.gz", "/../x"] {
+            let variant = format!(
+                r#"{{ "encoding": "gzip", "hash": "{b}", "size": 4, "suffix": "{suffix}" }}"#
+            );
+            assert!(parse(&variant).is_err(), "{suffix:?}");
+        }
+    }
+
+    #[test]
+    fn integrity_strings_stand_for_the_hex_hash() {
+        // sha256("hello\n"), as hex and as SRI.
+        let hex = "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+        let sri = "sha256-WJG1tSLV3whtD/CxEPvZ0hu0/HFjrzTQgoai6Eb2vgM=";
+        let parse = |fields: &str| {
+            serde_json::from_str::<Manifest>(&format!(
+                r#"{{"version": "v1", "files": [{{ "path": "a.txt", "size": 6, {fields} }}]}}"#
+            ))
+            .map(|manifest| manifest.files[0].hash.clone())
+            .map_err(|err| err.to_string())
+        };
+        assert_eq!(parse(&format!(r#""hash": "{hex}""#)).unwrap(), hex);
+        assert_eq!(parse(&format!(r#""integrity": "{sri}""#)).unwrap(), hex);
+        assert_eq!(
+            parse(&format!(r#""hash": "{hex}", "integrity": "{sri}""#)).unwrap(),
+            hex
+        );
+
+        let other = "a".repeat(64);
+        let err = parse(&format!(r#""hash": "{other}", "integrity": "{sri}""#)).unwrap_err();
+        assert!(
+            err.contains(&format!("a.txt: hash {other} does not match integrity")),
+            "{err}"
+        );
+        let err = parse(r#""integrity": "sha384-OLBgp1GsljhM2TJ+sbHjaiH9txEUvgdDTAzHv2P24donTt6/529l+9Ua0vFImLlb""#)
+            .unwrap_err();
+        assert!(
+            err.contains(r#"unsupported integrity algorithm "sha384" (supported: sha256)"#),
+            "{err}"
+        );
+        assert!(parse(r#""integrity": "sha256-AAAA""#).is_err());
+        assert!(parse(r#""integrity": "sha256-not*base64""#).is_err());
+        assert!(parse(r#""integrity": "WJG1tSLV3whtD""#).is_err());
+
+        let variant = serde_json::from_str::<Manifest>(&format!(
+            r#"{{"version": "v1", "files": [{{ "path": "a.txt", "hash": "{other}", "size": 6,
+                "variants": [{{ "encoding": "gzip", "integrity": "{sri}", "size": 6, "suffix": ".gz" }}] }}]}}"#
+        ))
+        .unwrap();
+        assert_eq!(variant.files[1].hash, hex);
+    }
+
+    #[test]
+    fn manifest_transfer_must_be_a_known_encoding() {
+        let (a, b) = ("a".repeat(64), "b".repeat(64));
+        let parse = |encoding: &str| {
+            serde_json::from_str::<Manifest>(&format!(
+                r#"{{"version": "v1", "files": [
+                    {{ "path": "stops.json", "hash": "{a}", "size": 900,
+                       "transfer": {{ "encoding": "{encoding}", "hash": "{b}", "size": 120 }} }}
+                ]}}"#
+            ))
+        };
+        let manifest = parse("zstd").unwrap();
+        let transfer = manifest.files[0].transfer.as_ref().unwrap();
+        assert_eq!((transfer.hash.as_str(), transfer.size), (b.as_str(), 120));
+
+        let err = parse("brotli").unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("stops.json: unsupported transfer encoding \"brotli\""));
+    }
+
+    #[test]
+    fn manifest_parts_must_add_up_to_the_file_size() {
+        let (a, b, c) = ("a".repeat(64), "b".repeat(64), "c".repeat(64));
+        let parse = |size: u64, extra: &str| {
+            serde_json::from_str::<Manifest>(&format!(
+                r#"{{"version": "v1", "files": [
+                    {{ "path": "tour.mp4", "hash": "{a}", "size": {size}{extra},
+                       "parts": [{{ "hash": "{b}", "size": 6 }}, {{ "hash": "{c}", "size": 4 }}] }}
+                ]}}"#
+            ))
+        };
+        let manifest = parse(10, "").unwrap();
+        let parts: Vec<_> = manifest.files[0]
+            .parts
+            .iter()
+            .map(|p| (p.hash.as_str(), p.size))
+            .collect();
+        assert_eq!(parts, [(b.as_str(), 6), (c.as_str(), 4)]);
+
+        let err = parse(11, "").unwrap_err();
+        assert!(err.to_string().contains("parts do not add up to size 11"));
+        let transfer =
+            format!(r#", "transfer": {{ "encoding": "zstd", "hash": "{b}", "size": 1 }}"#);
+        let err = parse(10, &transfer).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("cannot have both transfer and parts"));
+    }
+
+    #[test]
+    fn manifest_mode_accepts_octal_strings_and_integers() {
+        let mode = |json: &str| {
+            serde_json::from_str::<ManifestEntry>(&format!(
+                r#"{{"path": "x", "hash": "h", "size": 1{json}}}"#
+            ))
+            .map(|f| f.mode)
+        };
+        assert_eq!(mode("").unwrap(), None);
+        assert_eq!(mode(r#", "mode": null"#).unwrap(), None);
+        assert_eq!(mode(r#", "mode": "0755""#).unwrap(), Some(0o755));
+        assert_eq!(mode(r#", "mode": "644""#).unwrap(), Some(0o644));
+        assert_eq!(mode(r#", "mode": "0o4755""#).unwrap(), Some(0o4755));
+        assert_eq!(mode(r#", "mode": 493"#).unwrap(), Some(0o755));
+        assert!(mode(r#", "mode": "0789""#).is_err());
+        assert!(mode(r#", "mode": "rwxr-xr-x""#).is_err());
+        assert!(mode(r#", "mode": 65535"#).is_err());
+        assert_eq!(0o4777 & crate::MODE_MASK, 0o755);
+    }
+}
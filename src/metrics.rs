@@ -0,0 +1,336 @@
+//! `--metrics-textfile`: a `.prom` file for node_exporter's textfile
+//! collector, rewritten after every run (failed ones included) so alerts can
+//! fire on an error outcome or a stale success timestamp.
+//!
+//! The CLI exits after each run, so the file itself carries the state: the
+//! last success time and the `_total` counters are read back from the
+//! previous file and carried forward.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+
+use crate::{Outcome, Summary};
+
+const LAST_SUCCESS: &str = "cityfeed_deploy_last_success_timestamp";
+const OBJECTS_TOTAL: &str = "cityfeed_objects_downloaded_total";
+const BYTES_TOTAL: &str = "cityfeed_bytes_downloaded_total";
+
+/// Values carried over from the previous file.
+#[derive(Debug, Default, PartialEq)]
+struct Previous {
+    last_success: Option<u64>,
+    objects: u64,
+    bytes: u64,
+}
+
+/// The file a run writes to: `path` itself, or `<stem>-<site>.prom` next to
+/// it for a `[[site]]` run so sites don't overwrite each other.
+pub fn path_for(path: &Path, site: Option<&str>) -> PathBuf {
+    let Some(site) = site else {
+        return path.to_path_buf();
+    };
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let site: String = site
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    path.with_file_name(format!("{stem}-{site}.prom"))
+}
+
+/// Atomically replaces `path` with the metrics for this run.
+pub fn write(path: &Path, summary: &Summary, exit_code: i32) -> Result<()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let previous = match fs::read_to_string(path) {
+        Ok(text) => parse_previous(&text),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Previous::default(),
+        Err(err) => return Err(err).with_context(|| format!("read {}", path.display())),
+    };
+    let text = render(summary, exit_code, now, &previous);
+
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    // The temp name doesn't end in `.prom`, so the collector never reads a
+    // half-written file.
+    let mut tmp = tempfile::NamedTempFile::new_in(dir)
+        .with_context(|| format!("create temp file in {}", dir.display()))?;
+    tmp.write_all(text.as_bytes())
+        .context("write metrics textfile")?;
+    // Readable by node_exporter, which usually runs as another user.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(tmp.path(), fs::Permissions::from_mode(0o644))
+            .context("chmod metrics textfile")?;
+    }
+    tmp.persist(path)
+        .with_context(|| format!("replace {}", path.display()))?;
+    Ok(())
+}
+
+fn parse_previous(text: &str) -> Previous {
+    let mut previous = Previous::default();
+    for line in text.lines().filter(|line| !line.starts_with('#')) {
+        let Some((series, value)) = line.rsplit_once(' ') else {
+            continue;
+        };
+        let name = series.split('{').next().unwrap_or(series);
+        let Ok(value) = value.parse::<f64>() else {
+            continue;
+        };
+        match name {
+            LAST_SUCCESS => previous.last_success = Some(value as u64),
+            OBJECTS_TOTAL => previous.objects = value as u64,
+            BYTES_TOTAL => previous.bytes = value as u64,
+            _ => {}
+        }
+    }
+    previous
+}
+
+fn render(summary: &Summary, exit_code: i32, now: u64, previous: &Previous) -> String {
+    let site = summary
+        .site
+        .as_deref()
+        .map(|site| format!("site=\"{}\"", escape(site)));
+    let labels = |extra: Option<String>| -> String {
+        let all: Vec<String> = site.iter().cloned().chain(extra).collect();
+        if all.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", all.join(","))
+        }
+    };
+    let succeeded = matches!(summary.outcome, Outcome::Updated | Outcome::AlreadyCurrent);
+    let last_success = if succeeded {
+        Some(now)
+    } else {
+        previous.last_success
+    };
+    // After a failure or a prefetch `current` still points where it did
+    // before the run.
+    let deployed = if succeeded {
+        summary.version.as_deref()
+    } else {
+        summary.previous_version.as_deref()
+    };
+
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, series: &[(String, String)]| {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        for (labels, value) in series {
+            let _ = writeln!(out, "{name}{labels} {value}");
+        }
+    };
+    metric(
+        "cityfeed_deploy_last_run_timestamp",
+        "gauge",
+        "Unix time the last run finished.",
+        &[(labels(None), now.to_string())],
+    );
+    if let Some(at) = last_success {
+        metric(
+            LAST_SUCCESS,
+            "gauge",
+            "Unix time of the last run that updated or confirmed the deployed version.",
+            &[(labels(None), at.to_string())],
+        );
+    }
+    let outcomes = [
+        ("updated", Outcome::Updated),
+        ("already-current", Outcome::AlreadyCurrent),
+        ("prefetched", Outcome::Prefetched),
+        ("error", Outcome::Error),
+    ];
+    metric(
+        "cityfeed_deploy_last_outcome",
+        "gauge",
+        "1 for the outcome of the last run, 0 for the others.",
+        &outcomes.map(|(name, outcome)| {
+            (
+                labels(Some(format!("outcome=\"{name}\""))),
+                u8::from(summary.outcome == outcome).to_string(),
+            )
+        }),
+    );
+    metric(
+        "cityfeed_deploy_last_exit_code",
+        "gauge",
+        "Exit code of the last run.",
+        &[(labels(None), exit_code.to_string())],
+    );
+    metric(
+        "cityfeed_deploy_duration_seconds",
+        "gauge",
+        "Wall time of the last run.",
+        &[(labels(None), format!("{:.3}", summary.elapsed_secs))],
+    );
+    metric(
+        OBJECTS_TOTAL,
+        "counter",
+        "Objects downloaded by all runs.",
+        &[(
+            labels(None),
+            (previous.objects + summary.objects_downloaded).to_string(),
+        )],
+    );
+    metric(
+        BYTES_TOTAL,
+        "counter",
+        "Object bytes downloaded by all runs.",
+        &[(
+            labels(None),
+            (previous.bytes + summary.bytes_downloaded).to_string(),
+        )],
+    );
+    if let Some(version) = deployed {
+        metric(
+            "cityfeed_deploy_version_info",
+            "gauge",
+            "The version `current` points at.",
+            &[(
+                labels(Some(format!("version=\"{}\"", escape(version)))),
+                "1".to_string(),
+            )],
+        );
+    }
+    out
+}
+
+/// Label values escape backslash, double quote and newline.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn updated() -> Summary {
+        Summary {
+            outcome: Outcome::Updated,
+            version: Some("v2".into()),
+            previous_version: Some("v1".into()),
+            objects_downloaded: 3,
+            bytes_downloaded: 1200,
+            elapsed_secs: 1.5,
+            ..Summary::default()
+        }
+    }
+
+    #[test]
+    fn renders_gauges_and_counters() {
+        let previous = Previous {
+            last_success: Some(100),
+            objects: 10,
+            bytes: 5000,
+        };
+        let text = render(&updated(), 0, 200, &previous);
+        for line in [
+            "# TYPE cityfeed_deploy_last_success_timestamp gauge",
+            "cityfeed_deploy_last_run_timestamp 200",
+            "cityfeed_deploy_last_success_timestamp 200",
+            "cityfeed_deploy_last_outcome{outcome=\"updated\"} 1",
+            "cityfeed_deploy_last_outcome{outcome=\"error\"} 0",
+            "cityfeed_deploy_last_exit_code 0",
+            "cityfeed_deploy_duration_seconds 1.500",
+            "# TYPE cityfeed_objects_downloaded_total counter",
+            "cityfeed_objects_downloaded_total 13",
+            "cityfeed_bytes_downloaded_total 6200",
+            "cityfeed_deploy_version_info{version=\"v2\"} 1",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing {line:?} in\n{text}"
+            );
+        }
+        assert_eq!(
+            parse_previous(&text),
+            Previous {
+                last_success: Some(200),
+                objects: 13,
+                bytes: 6200,
+            }
+        );
+
+        let failed = Summary {
+            site: Some("brief \"west\"".into()),
+            previous_version: Some("v1".into()),
+            ..Summary::default()
+        };
+        let text = render(&failed, 4, 300, &parse_previous(&text));
+        for line in [
+            "cityfeed_deploy_last_success_timestamp{site=\"brief \\\"west\\\"\"} 200",
+            "cityfeed_deploy_last_outcome{site=\"brief \\\"west\\\"\",outcome=\"error\"} 1",
+            "cityfeed_deploy_last_exit_code{site=\"brief \\\"west\\\"\"} 4",
+            "cityfeed_deploy_version_info{site=\"brief \\\"west\\\"\",version=\"v1\"} 1",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing {line:?} in\n{text}"
+            );
+        }
+        // A prefetch downloads but leaves the live version where it was.
+        let prefetched = Summary {
+            outcome: Outcome::Prefetched,
+            version: Some("v3".into()),
+            ..updated()
+        };
+        let text = render(&prefetched, 0, 400, &parse_previous(&text));
+        for line in [
+            "cityfeed_deploy_last_success_timestamp 200",
+            "cityfeed_deploy_last_outcome{outcome=\"prefetched\"} 1",
+            "cityfeed_objects_downloaded_total 16",
+            "cityfeed_deploy_version_info{version=\"v1\"} 1",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing {line:?} in\n{text}"
+            );
+        }
+
+        let text = render(&Summary::default(), 1, 300, &Previous::default());
+        assert!(!text.contains(LAST_SUCCESS) && !text.contains("version_info"));
+    }
+
+    #[test]
+    fn write_replaces_the_file_and_carries_state_across_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cityfeed.prom");
+        write(&path, &updated(), 0).unwrap();
+        write(&path, &Summary::default(), 4).unwrap();
+
+        let names: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["cityfeed.prom"]);
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.contains("cityfeed_deploy_last_outcome{outcome=\"error\"} 1"));
+        assert!(text.contains("cityfeed_objects_downloaded_total 3\n"));
+        let previous = parse_previous(&text);
+        assert!(previous.last_success.is_some());
+
+        assert_eq!(
+            path_for(&path, Some("events/west")),
+            dir.path().join("cityfeed-events_west.prom")
+        );
+        assert_eq!(path_for(&path, None), path);
+    }
+}
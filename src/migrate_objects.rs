@@ -0,0 +1,68 @@
+//! `cityfeed-puller migrate-objects`: moves a root's object store to the
+//! nested layout, `objects/ab/cd/<hash>`, for sites with millions of objects
+//! where one or two directory levels make listing, backups and even
+//! creating an object slow.
+//!
+//! The layout is local only; origins keep serving whatever layout they
+//! publish. The root lock is held throughout, so no deploy runs meanwhile.
+//! An interrupted migration is finished by running the command again.
+
+use std::path::Path;
+use std::time::Instant;
+
+use anyhow::{bail, Context, Result};
+use serde_json::json;
+
+use crate::logger::Logger;
+use crate::store::{self, Nested};
+use crate::{lock_root, Args, EXIT_FAILURE};
+
+/// Migrates `args.root()` and returns the exit code.
+pub fn main(args: &Args, log: &Logger) -> i32 {
+    let started = Instant::now();
+    match migrate(args.root()) {
+        Ok((nested, was_nested)) => {
+            log.outcome(
+                "objects_nested",
+                json!({
+                    "moved": nested.moved,
+                    "skipped": nested.skipped,
+                    "elapsed_secs": started.elapsed().as_secs_f64(),
+                }),
+                format_args!(
+                    "{}{}",
+                    match (was_nested, nested.moved) {
+                        (true, 0) => "objects are already in the nested layout".to_string(),
+                        (_, n) => format!("moved {n} objects into the nested layout"),
+                    },
+                    match nested.skipped {
+                        0 => String::new(),
+                        n => format!("; left {n} entries that aren't objects (see fsck)"),
+                    }
+                ),
+            );
+            0
+        }
+        Err(err) => {
+            log.error(
+                "migrate_objects_failed",
+                json!({ "error": format!("{err:#}") }),
+                format_args!("migrate-objects failed: {err:#}"),
+            );
+            EXIT_FAILURE
+        }
+    }
+}
+
+/// Returns what moved and whether the root was already marked nested.
+fn migrate(root: &Path) -> Result<(Nested, bool)> {
+    let objects_dir = root.join("objects");
+    if !objects_dir.is_dir() {
+        bail!("no objects dir at {}", objects_dir.display());
+    }
+    let _lock = lock_root(root)?;
+    let was_nested = store::is_nested(&objects_dir).context("read object layout")?;
+    let nested =
+        store::nest(&objects_dir).with_context(|| format!("migrate {}", objects_dir.display()))?;
+    Ok((nested, was_nested))
+}
@@ -0,0 +1,230 @@
+//! Temp files for objects on their way into `objects/`. On Linux they are
+//! opened with `O_TMPFILE`: the file has no name until `persist_noclobber`
+//! links it in as `objects/<hash>`, so a download cut short, even by
+//! SIGKILL, leaves nothing behind. Where the kernel or filesystem can't do
+//! that, a named `.tmp-<pid>-*` file is used, which the next run's stale
+//! cleanup removes.
+//!
+//! Objects of `PREALLOCATE_MIN` bytes or more get their blocks reserved up
+//! front, so a full disk fails the download at once rather than hours in,
+//! and concurrent large downloads don't interleave their extents.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use tempfile::NamedTempFile;
+
+use crate::stale;
+
+/// Objects this large are preallocated before the body is streamed.
+pub const PREALLOCATE_MIN: u64 = 64 << 20;
+
+pub struct ObjectTemp {
+    inner: Inner,
+}
+
+enum Inner {
+    #[cfg(target_os = "linux")]
+    Anonymous(File),
+    Named(NamedTempFile),
+}
+
+impl ObjectTemp {
+    /// An unnamed file in `dir` if the filesystem supports it, a named one
+    /// otherwise.
+    pub fn new_in(dir: &Path) -> Result<Self> {
+        #[cfg(target_os = "linux")]
+        match linux::anonymous_in(dir) {
+            Ok(file) => {
+                return Ok(Self {
+                    inner: Inner::Anonymous(file),
+                })
+            }
+            Err(err) if linux::unsupported(err) => {}
+            Err(err) => {
+                return Err(io::Error::from(err))
+                    .with_context(|| format!("create temp object file in {}", dir.display()))
+            }
+        }
+        Self::named_in(dir)
+    }
+
+    /// A named `.tmp-<pid>-*` file in `dir`.
+    pub fn named_in(dir: &Path) -> Result<Self> {
+        let file = tempfile::Builder::new()
+            .prefix(&stale::object_temp_prefix())
+            .tempfile_in(dir)
+            .context("create temp object file")?;
+        Ok(Self {
+            inner: Inner::Named(file),
+        })
+    }
+
+    /// Reserves `size` bytes of disk for an object that large, without
+    /// changing the file's length. Filesystems and platforms that can't are
+    /// skipped silently; a full disk is an error. The reservation goes with
+    /// the file if it is dropped.
+    pub fn preallocate(&mut self, size: u64) -> Result<()> {
+        if size < PREALLOCATE_MIN {
+            return Ok(());
+        }
+        #[cfg(target_os = "linux")]
+        match linux::preallocate(self.as_file_mut(), size) {
+            Err(err) if !linux::unsupported(err) => {
+                return Err(io::Error::from(err))
+                    .with_context(|| format!("preallocate {size} bytes for object"));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    pub fn as_file_mut(&mut self) -> &mut File {
+        match &mut self.inner {
+            #[cfg(target_os = "linux")]
+            Inner::Anonymous(file) => file,
+            Inner::Named(file) => file.as_file_mut(),
+        }
+    }
+
+    /// Gives the file the name `dst`. If `dst` already exists it is left as
+    /// is and the error kind is `AlreadyExists`.
+    pub fn persist_noclobber(self, dst: &Path) -> io::Result<()> {
+        match self.inner {
+            #[cfg(target_os = "linux")]
+            Inner::Anonymous(file) => linux::link(&file, dst),
+            Inner::Named(file) => file.persist_noclobber(dst).map(drop).map_err(|e| e.error),
+        }
+    }
+}
+
+impl Write for ObjectTemp {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.as_file_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.as_file_mut().flush()
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::fs::File;
+    use std::io;
+    use std::os::fd::{AsFd, AsRawFd};
+    use std::path::Path;
+
+    use rustix::fs::{fallocate, linkat, open, AtFlags, FallocateFlags, Mode, OFlags, CWD};
+    use rustix::io::Errno;
+
+    pub fn anonymous_in(dir: &Path) -> rustix::io::Result<File> {
+        let flags = OFlags::TMPFILE | OFlags::RDWR | OFlags::CLOEXEC;
+        open(dir, flags, Mode::RUSR | Mode::WUSR).map(File::from)
+    }
+
+    pub fn preallocate(file: &File, size: u64) -> rustix::io::Result<()> {
+        fallocate(file.as_fd(), FallocateFlags::KEEP_SIZE, 0, size)
+    }
+
+    /// Filesystems without `O_TMPFILE` or `fallocate` say EOPNOTSUPP;
+    /// kernels before 3.11 see only the `O_DIRECTORY` half of `O_TMPFILE`
+    /// and say EISDIR.
+    pub fn unsupported(err: Errno) -> bool {
+        matches!(
+            err,
+            Errno::OPNOTSUPP | Errno::ISDIR | Errno::INVAL | Errno::NOSYS
+        )
+    }
+
+    /// `linkat(fd, "", AT_EMPTY_PATH)` needs CAP_DAC_READ_SEARCH; without it
+    /// the file is linked through its `/proc/self/fd` entry instead.
+    pub fn link(file: &File, dst: &Path) -> io::Result<()> {
+        match linkat(file.as_fd(), "", CWD, dst, AtFlags::EMPTY_PATH) {
+            Err(Errno::NOENT | Errno::PERM) => {}
+            result => return result.map_err(io::Error::from),
+        }
+        let proc = format!("/proc/self/fd/{}", file.as_raw_fd());
+        linkat(CWD, proc.as_str(), CWD, dst, AtFlags::SYMLINK_FOLLOW).map_err(io::Error::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Read;
+
+    fn entries(dir: &Path) -> usize {
+        fs::read_dir(dir).unwrap().count()
+    }
+
+    fn round_trip(mut tmp: ObjectTemp, dir: &Path) {
+        tmp.write_all(b"object body").unwrap();
+        tmp.as_file_mut().sync_all().unwrap();
+        let dst = dir.join("abc");
+        tmp.persist_noclobber(&dst).unwrap();
+        let mut body = String::new();
+        File::open(&dst).unwrap().read_to_string(&mut body).unwrap();
+        assert_eq!(body, "object body");
+
+        // A second copy of the same object loses the race politely.
+        let mut again = ObjectTemp::named_in(dir).unwrap();
+        again.write_all(b"other").unwrap();
+        let err = again.persist_noclobber(&dst).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&dst).unwrap(), b"object body");
+        assert_eq!(entries(dir), 1);
+    }
+
+    #[test]
+    fn named_fallback_persists_without_clobbering() {
+        let dir = tempfile::tempdir().unwrap();
+        let tmp = ObjectTemp::named_in(dir.path()).unwrap();
+        assert_eq!(entries(dir.path()), 1);
+        round_trip(tmp, dir.path());
+
+        drop(ObjectTemp::named_in(dir.path()).unwrap());
+        assert_eq!(entries(dir.path()), 1);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn large_objects_are_preallocated_without_growing_the_file() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut small = ObjectTemp::new_in(dir.path()).unwrap();
+        small.preallocate(PREALLOCATE_MIN - 1).unwrap();
+        assert_eq!(small.as_file_mut().metadata().unwrap().blocks(), 0);
+
+        let mut large = ObjectTemp::new_in(dir.path()).unwrap();
+        large.preallocate(PREALLOCATE_MIN).unwrap();
+        let meta = large.as_file_mut().metadata().unwrap();
+        assert_eq!(meta.len(), 0);
+        // Filesystems without fallocate are skipped, not failed.
+        if meta.blocks() > 0 {
+            assert!(meta.blocks() * 512 >= PREALLOCATE_MIN);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn anonymous_temp_has_no_name_until_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let tmp = ObjectTemp::new_in(dir.path()).unwrap();
+        if matches!(tmp.inner, Inner::Named(_)) {
+            eprintln!("no O_TMPFILE on this filesystem; skipping");
+            return;
+        }
+        assert_eq!(entries(dir.path()), 0);
+        round_trip(tmp, dir.path());
+
+        let mut abandoned = ObjectTemp::new_in(dir.path()).unwrap();
+        abandoned.write_all(b"partial").unwrap();
+        drop(abandoned);
+        assert_eq!(entries(dir.path()), 1);
+    }
+}
@@ -0,0 +1,140 @@
+//! Files published as several objects (`"parts": [{ "hash", "size" }, ...]`)
+//! because the origin caps single objects. Parts are streamed in order into
+//! one temp file, each checked on arrival, and the whole file is checked
+//! against its own hash before it is stored. A part that fails is cut off
+//! the temp file and retried from the next origin, so earlier parts are not
+//! fetched again.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::time::Instant;
+
+use anyhow::{bail, Context, Result};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::hashing::{self, Hashing};
+use crate::logger::Logger;
+use crate::object_temp::ObjectTemp;
+use crate::store::ObjectStore;
+use crate::{store_object, validate_hash, Fetcher, ManifestFile, ManifestPart, Served};
+
+/// Downloads every part of `file` and stores the joined result under
+/// `file.hash`. Nothing reaches the store unless the whole file checks out.
+pub fn assemble(
+    fetcher: &Fetcher,
+    log: &Logger,
+    origins: &[String],
+    file: &ManifestFile,
+    store: &ObjectStore,
+) -> Result<Served> {
+    validate_hash(&file.hash).with_context(|| format!("invalid object hash: {:?}", file.hash))?;
+    let mut tmp = ObjectTemp::new_in(store.dir())?;
+    tmp.preallocate(file.size)?;
+
+    let count = file.parts.len();
+    let mut whole = Sha256::new();
+    let mut offset = 0;
+    let mut served = Served {
+        origin: String::new(),
+        retries: 0,
+    };
+    for (i, part) in file.parts.iter().enumerate() {
+        let label = format!("part {}/{count}", i + 1);
+        let (digest, origin, retries) =
+            fetch_part_any(fetcher, log, origins, store, part, tmp.as_file_mut(), whole)
+                .with_context(|| format!("{label} ({})", part.hash))?;
+        whole = digest;
+        served.origin = origin;
+        served.retries += retries;
+        offset += part.size;
+    }
+
+    let got = hashing::hex(whole);
+    if offset != file.size {
+        bail!(
+            "object {} assembled size mismatch: expected {} got {offset}",
+            file.hash,
+            file.size
+        );
+    }
+    if got != file.hash {
+        bail!("object {} assembled hash mismatch: got {got}", file.hash);
+    }
+    store_object(tmp, store, &file.hash)?;
+    Ok(served)
+}
+
+/// Appends `part` to `out`, trying each origin in turn, and returns the
+/// whole-file digest extended by the part's bytes, plus the origin that
+/// served it and how many attempts failed first.
+fn fetch_part_any(
+    fetcher: &Fetcher,
+    log: &Logger,
+    origins: &[String],
+    store: &ObjectStore,
+    part: &ManifestPart,
+    out: &mut File,
+    whole: Sha256,
+) -> Result<(Sha256, String, u32)> {
+    let offset = out.stream_position().context("seek temp object file")?;
+    let mut last_err: Option<anyhow::Error> = None;
+    for (retries, origin) in (0..).zip(fetcher.attempt_order(log, origins)) {
+        fetcher.check_cancelled()?;
+        let url = store.url(&origin, &part.hash);
+        let started = Instant::now();
+        let result = fetch_part(fetcher, log, &url, &origin, part, out, whole.clone());
+        fetcher.record(log, &origin, result.is_ok(), started.elapsed());
+        match result {
+            Ok(digest) => {
+                if retries > 0 {
+                    fetcher.health.failed_over();
+                }
+                return Ok((digest, origin, retries));
+            }
+            Err(err) => {
+                log.warn(
+                    "part_download_failed",
+                    json!({ "origin": &origin, "hash": &part.hash, "error": format!("{err:#}") }),
+                    format_args!(
+                        "part download failed from {origin} hash={}: {err:#}",
+                        part.hash
+                    ),
+                );
+                fetcher.progress.rewind_object(offset);
+                out.set_len(offset).context("truncate temp object file")?;
+                out.seek(SeekFrom::Start(offset))
+                    .context("seek temp object file")?;
+                last_err = Some(err);
+            }
+        }
+    }
+    Err(fetcher.exhausted(origins, last_err))
+}
+
+fn fetch_part(
+    fetcher: &Fetcher,
+    log: &Logger,
+    url: &str,
+    origin: &str,
+    part: &ManifestPart,
+    out: &mut File,
+    whole: Sha256,
+) -> Result<Sha256> {
+    let what = format!("object part {}", part.hash);
+    let body = fetcher.open_object(log, url, origin, &what, part.size)?;
+    let mut reader = Hashing::new(Hashing::resume(body, whole));
+    io::copy(&mut (&mut reader).take(part.size.saturating_add(1)), out)
+        .context("write part body")?;
+    let (got, len, whole) = reader.finish();
+    if len > part.size {
+        bail!("body exceeds declared size {}", part.size);
+    }
+    if len != part.size {
+        bail!("size mismatch: expected {} got {len}", part.size);
+    }
+    if got != part.hash {
+        bail!("hash mismatch: got {got}");
+    }
+    Ok(whole.state())
+}
@@ -0,0 +1,170 @@
+//! `--file-mode`, `--dir-mode` and `--owner`: permissions and ownership of
+//! what a deploy puts in a snapshot. Modes are always set explicitly, so
+//! the service's umask never decides what nginx can read. Objects in
+//! `objects/` are not affected; they stay 0644 and owned by whoever runs
+//! the puller.
+//!
+//! All three are checked when the command line is parsed, so a bad value
+//! fails before anything is downloaded. User and group names are looked up
+//! in `/etc/passwd` and `/etc/group`; numeric ids are taken as given.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+/// Applied to snapshot files whose manifest entry has no mode.
+pub const DEFAULT_FILE_MODE: u32 = 0o644;
+
+/// Applied to every snapshot directory, whatever the umask, so the web
+/// server can always descend into it.
+pub const DEFAULT_DIR_MODE: u32 = 0o755;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Owner {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// What `stage` applies to a snapshot. By default files get 0644 (or their
+/// manifest mode), directories 0755, and nothing is chowned.
+#[derive(Clone, Copy, Debug)]
+pub struct Perms {
+    pub file_mode: u32,
+    pub dir_mode: u32,
+    pub owner: Option<Owner>,
+}
+
+impl Perms {
+    /// Creates `dir` and any missing parents below `top`, applying the
+    /// directory mode and owner to each one created. `top` must exist.
+    pub fn create_dir_all(&self, top: &Path, dir: &Path) -> Result<()> {
+        if dir == top || dir.is_dir() {
+            return Ok(());
+        }
+        if let Some(parent) = dir.parent() {
+            self.create_dir_all(top, parent)?;
+        }
+        match fs::create_dir(dir) {
+            Ok(()) => self.apply_dir(dir),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => Ok(()),
+            Err(err) => Err(err).with_context(|| format!("create dir {}", dir.display())),
+        }
+    }
+
+    /// Applies the directory mode and owner to an existing directory.
+    pub fn apply_dir(&self, dir: &Path) -> Result<()> {
+        crate::set_mode(dir, self.dir_mode)?;
+        self.chown(dir)
+    }
+
+    /// Gives `path` the configured owner, if any. Symlinks themselves are
+    /// changed, not their targets.
+    pub fn chown(&self, path: &Path) -> Result<()> {
+        let Some(owner) = self.owner else {
+            return Ok(());
+        };
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::lchown(path, Some(owner.uid), Some(owner.gid))
+                .with_context(|| format!("chown {}:{} {}", owner.uid, owner.gid, path.display()))
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = (path, owner);
+            bail!("--owner is only supported on unix");
+        }
+    }
+}
+
+/// Parses an octal mode for `--file-mode`/`--dir-mode`. Anything
+/// world-writable, or with setuid, setgid or sticky bits, is rejected.
+pub fn parse_mode(text: &str) -> Result<u32> {
+    let digits = text.strip_prefix("0o").unwrap_or(text);
+    let mode = u32::from_str_radix(digits, 8)
+        .map_err(|_| anyhow!("invalid octal mode {text:?} (e.g. 0640)"))?;
+    if mode > 0o777 {
+        bail!("mode {mode:#o} sets setuid, setgid or sticky bits");
+    }
+    if mode & 0o002 != 0 {
+        bail!("mode {mode:#o} is world-writable");
+    }
+    Ok(mode)
+}
+
+/// Parses `--owner user:group`, each side a name or a numeric id.
+pub fn parse_owner(text: &str) -> Result<Owner> {
+    let (user, group) = text
+        .split_once(':')
+        .ok_or_else(|| anyhow!("expected user:group, got {text:?}"))?;
+    Ok(Owner {
+        uid: lookup_id(user, "/etc/passwd", "user")?,
+        gid: lookup_id(group, "/etc/group", "group")?,
+    })
+}
+
+fn lookup_id(name: &str, db: &str, kind: &str) -> Result<u32> {
+    if name.is_empty() {
+        bail!("empty {kind}");
+    }
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+    let text = fs::read_to_string(db).with_context(|| format!("read {db}"))?;
+    find_id(&text, name).ok_or_else(|| anyhow!("no {kind} named {name:?} in {db}"))
+}
+
+/// The id field (the third) of `name`'s line in a passwd- or group-format
+/// file.
+fn find_id(db: &str, name: &str) -> Option<u32> {
+    db.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        if fields.next()? != name {
+            return None;
+        }
+        fields.nth(1)?.parse().ok()
+    })
+}
+
+/// Whether this process can chown files to other users; `None` when that
+/// can't be determined (no `/proc`).
+pub fn is_root() -> Option<bool> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let uids = status.lines().find_map(|line| line.strip_prefix("Uid:"))?;
+    // Real, effective, saved, filesystem.
+    let effective: u32 = uids.split_whitespace().nth(1)?.parse().ok()?;
+    Some(effective == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modes_are_octal_and_never_world_writable() {
+        assert_eq!(parse_mode("0640").unwrap(), 0o640);
+        assert_eq!(parse_mode("750").unwrap(), 0o750);
+        assert_eq!(parse_mode("0o755").unwrap(), 0o755);
+        assert!(parse_mode("2775").is_err());
+        assert!(parse_mode("0666").is_err());
+        assert!(parse_mode("0777").is_err());
+        assert!(parse_mode("4755").is_err());
+        assert!(parse_mode("rw-r--r--").is_err());
+        assert!(parse_mode("").is_err());
+    }
+
+    #[test]
+    fn owners_resolve_names_and_ids() {
+        let passwd =
+            "root:x:0:0:root:/root:/bin/sh\ncaddy:x:998:997::/var/lib/caddy:/sbin/nologin\n";
+        assert_eq!(find_id(passwd, "caddy"), Some(998));
+        assert_eq!(find_id(passwd, "cadd"), None);
+        assert_eq!(find_id("www-data:x:33:\n", "www-data"), Some(33));
+        assert_eq!(
+            parse_owner("1000:33").unwrap(),
+            Owner { uid: 1000, gid: 33 }
+        );
+        assert!(parse_owner("1000").is_err());
+        assert!(parse_owner(":33").is_err());
+    }
+}
@@ -0,0 +1,209 @@
+//! `--path-portability windows-safe`: refuse manifest paths that Linux stages
+//! fine but that can't be copied onto an NTFS share. That covers reserved
+//! device names (`aux.html`), names ending in a dot or space, the characters
+//! `<>:"|?*\`, and names or paths longer than the configured limits.
+//!
+//! Every entry is checked and every problem is reported, so a publisher can
+//! fix a batch in one go.
+
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+
+use crate::manifest::{validate_rel_path, Manifest};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum PathPortability {
+    /// Any path Linux accepts.
+    Posix,
+    /// Only paths that can also be written to NTFS.
+    WindowsSafe,
+}
+
+/// DOS device names; reserved with any extension and in any case.
+const RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+const FORBIDDEN: &[char] = &['<', '>', ':', '"', '|', '?', '*', '\\'];
+
+/// The rules a deploy checks paths against.
+#[derive(Clone, Copy, Debug)]
+pub struct Portability {
+    pub mode: PathPortability,
+    /// Longest name, in UTF-16 units as NTFS counts them.
+    pub max_component_len: usize,
+    /// Longest path inside the snapshot, in UTF-16 units.
+    pub max_path_len: usize,
+}
+
+impl Portability {
+    /// Fails listing every file and symlink path that breaks a rule. Posix
+    /// mode accepts everything.
+    pub fn check(&self, manifest: &Manifest) -> Result<()> {
+        if self.mode == PathPortability::Posix {
+            return Ok(());
+        }
+        let paths = manifest.files.iter().map(|file| &file.path);
+        let offending: Vec<String> = paths
+            .chain(manifest.symlinks.iter().map(|link| &link.path))
+            .filter_map(|path| {
+                let problems = self.problems(path);
+                (!problems.is_empty()).then(|| format!("{path} ({})", problems.join(", ")))
+            })
+            .collect();
+        if offending.is_empty() {
+            return Ok(());
+        }
+        bail!(
+            "{} path(s) not portable to windows: {}",
+            offending.len(),
+            offending.join("; ")
+        )
+    }
+
+    /// What is wrong with `path`, one entry per broken rule.
+    fn problems(&self, path: &str) -> Vec<String> {
+        // Unsafe paths were already refused by `validate_manifest`.
+        let Ok(rel) = validate_rel_path(path) else {
+            return Vec::new();
+        };
+        let mut problems = Vec::new();
+        let mut units = 0;
+        for (i, part) in rel.components().enumerate() {
+            let name = part.as_os_str().to_string_lossy();
+            units += usize::from(i > 0) + name.encode_utf16().count();
+            let stem = name.split('.').next().unwrap_or_default();
+            if RESERVED
+                .iter()
+                .any(|r| r.eq_ignore_ascii_case(stem.trim_end()))
+            {
+                problems.push(format!("reserved name {name:?}"));
+            }
+            if name.ends_with('.') || name.ends_with(' ') {
+                problems.push(format!("{name:?} ends in a dot or space"));
+            }
+            if let Some(c) = name
+                .chars()
+                .find(|c| FORBIDDEN.contains(c) || c.is_control())
+            {
+                problems.push(format!("{c:?} in {name:?}"));
+            }
+            let len = name.encode_utf16().count();
+            if len > self.max_component_len {
+                problems.push(format!(
+                    "name of {len} chars exceeds {}",
+                    self.max_component_len
+                ));
+            }
+        }
+        if units > self.max_path_len {
+            problems.push(format!(
+                "path of {units} chars exceeds {}",
+                self.max_path_len
+            ));
+        }
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::{ManifestFile, ManifestSymlink};
+
+    fn windows_safe() -> Portability {
+        Portability {
+            mode: PathPortability::WindowsSafe,
+            max_component_len: 16,
+            max_path_len: 24,
+        }
+    }
+
+    #[test]
+    fn windows_safe_rule_table() {
+        let rules = windows_safe();
+        let accepted = [
+            "index.html",
+            "a/b/c.txt",
+            "auxiliary.html",
+            "console/con-tips.html",
+            "com10.html",
+            "lpt.txt",
+            ".well-known/x",
+            "sixteen-chars.md",
+        ];
+        for path in accepted {
+            assert!(
+                rules.problems(path).is_empty(),
+                "{path}: {:?}",
+                rules.problems(path)
+            );
+        }
+        let rejected = [
+            ("aux.html", "reserved name"),
+            ("AUX", "reserved name"),
+            ("docs/Con.tar.gz", "reserved name"),
+            ("nul .txt", "reserved name"),
+            ("lpt9", "reserved name"),
+            ("notes.", "ends in a dot"),
+            ("dir./x", "ends in a dot"),
+            ("trailing ", "ends in a dot or space"),
+            ("a<b", "'<'"),
+            ("what?.html", "'?'"),
+            ("c:d", "':'"),
+            ("say\"hi\"", "'\"'"),
+            ("pipe|x", "'|'"),
+            ("star*", "'*'"),
+            ("back\\slash", "'\\\\'"),
+            ("tab\there", "'\\t'"),
+            ("seventeen-chars.x", "name of 17 chars exceeds 16"),
+            ("abcdefghij/abcdefghij/abc", "path of 25 chars exceeds 24"),
+        ];
+        for (path, problem) in rejected {
+            let problems = rules.problems(path).join(", ");
+            assert!(problems.contains(problem), "{path}: {problems:?}");
+        }
+    }
+
+    #[test]
+    fn check_lists_every_offending_entry_and_posix_allows_all() {
+        let manifest = Manifest {
+            version: "v1".to_string(),
+            files: ["index.html", "aux.html", "notes."]
+                .iter()
+                .map(|path| ManifestFile {
+                    path: path.to_string(),
+                    hash: "a".repeat(64),
+                    size: 1,
+                    mode: None,
+                    transfer: None,
+                    parts: Vec::new(),
+                    signature: None,
+                })
+                .collect(),
+            symlinks: vec![ManifestSymlink {
+                path: "a:b".to_string(),
+                target: "index.html".to_string(),
+            }],
+            object_layout: None,
+            sequence: None,
+            generated_at: None,
+            prefix: None,
+            filters: Vec::new(),
+        };
+        let err = windows_safe().check(&manifest).unwrap_err().to_string();
+        assert!(
+            err.starts_with("3 path(s) not portable to windows: aux.html ("),
+            "{err}"
+        );
+        assert!(err.contains("; notes. ("), "{err}");
+        assert!(err.contains("; a:b ("), "{err}");
+
+        let posix = Portability {
+            mode: PathPortability::Posix,
+            ..windows_safe()
+        };
+        posix.check(&manifest).unwrap();
+    }
+}
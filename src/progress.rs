@@ -0,0 +1,282 @@
+//! Progress bars for interactive runs: one for the object being downloaded
+//! and one across everything the deploy has to fetch.
+//!
+//! Bars are only drawn when stderr is a terminal and `--log-format text` is
+//! in use; log lines are printed through the same `MultiProgress` (see
+//! `Logger::with_progress`) so they land above the bars instead of through
+//! them.
+
+use std::io::{self, IsTerminal, Read};
+use std::sync::{Arc, Mutex};
+
+use clap::ValueEnum;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+
+use crate::logger::{LogFormat, Logger};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ProgressMode {
+    /// Draw bars when stderr is a terminal.
+    Auto,
+    Never,
+}
+
+/// Whether this run should draw bars at all.
+pub fn wanted(mode: ProgressMode, format: LogFormat, quiet: bool) -> bool {
+    mode == ProgressMode::Auto && format == LogFormat::Text && !quiet && io::stderr().is_terminal()
+}
+
+/// Byte counts behind the bars. Downloads are sequential within a deploy, so
+/// there is one object in flight at a time.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Tally {
+    pub total: u64,
+    /// Bytes of finished objects.
+    completed: u64,
+    pub object_total: u64,
+    pub object_done: u64,
+}
+
+impl Tally {
+    pub fn new(total: u64) -> Self {
+        Self {
+            total,
+            ..Self::default()
+        }
+    }
+
+    /// Bytes done across the whole deploy, including the object in flight.
+    pub fn done(&self) -> u64 {
+        self.completed + self.object_done
+    }
+
+    pub fn start_object(&mut self, size: u64) {
+        self.object_total = size;
+        self.object_done = 0;
+    }
+
+    pub fn add(&mut self, n: u64) {
+        self.object_done += n;
+    }
+
+    /// A failed attempt: forget what it read past `to`, since the retry
+    /// fetches those bytes again.
+    pub fn rewind_object(&mut self, to: u64) {
+        self.object_done = self.object_done.min(to);
+    }
+
+    pub fn finish_object(&mut self) {
+        self.completed += self.object_total;
+        self.object_total = 0;
+        self.object_done = 0;
+    }
+}
+
+struct Bars {
+    tally: Tally,
+    overall: ProgressBar,
+    object: Option<ProgressBar>,
+    multi: MultiProgress,
+}
+
+impl Bars {
+    fn sync(&self) {
+        self.overall.set_position(self.tally.done());
+        if let Some(object) = &self.object {
+            object.set_position(self.tally.object_done);
+        }
+    }
+}
+
+/// Shared by the fetcher (which counts body bytes) and the deploy loop (which
+/// says which object is in flight). Does nothing unless `start` found bars
+/// to draw on.
+#[derive(Default)]
+pub struct Progress {
+    bars: Mutex<Option<Bars>>,
+}
+
+impl Progress {
+    /// Begins a deploy that has `total` bytes to fetch; the bars go away when
+    /// the returned guard is dropped, however the deploy ends.
+    pub fn start<'a>(&'a self, log: &Logger, total: u64) -> Deploy<'a> {
+        if let Some(multi) = log.progress() {
+            let overall = multi.add(ProgressBar::new(total).with_style(style(
+                "{prefix:>8} [{bar:30}] {bytes}/{total_bytes} {binary_bytes_per_sec} eta {eta}",
+            )));
+            overall.set_prefix("total");
+            *self.lock() = Some(Bars {
+                tally: Tally::new(total),
+                overall,
+                object: None,
+                multi: multi.clone(),
+            });
+        }
+        Deploy { progress: self }
+    }
+
+    pub fn start_object(&self, name: &str, size: u64) {
+        self.update(|bars| {
+            bars.tally.start_object(size);
+            let object = bars.multi.add(
+                ProgressBar::new(size)
+                    .with_style(style("{prefix:>8} [{bar:30}] {bytes}/{total_bytes} {msg}")),
+            );
+            object.set_prefix("object");
+            object.set_message(name.to_string());
+            bars.object = Some(object);
+        });
+    }
+
+    pub fn add(&self, n: u64) {
+        self.update(|bars| bars.tally.add(n));
+    }
+
+    pub fn rewind_object(&self, to: u64) {
+        self.update(|bars| bars.tally.rewind_object(to));
+    }
+
+    pub fn finish_object(&self) {
+        self.update(|bars| {
+            bars.tally.finish_object();
+            if let Some(object) = bars.object.take() {
+                object.finish_and_clear();
+                bars.multi.remove(&object);
+            }
+        });
+    }
+
+    /// Counts everything read through `inner` towards the object in flight.
+    pub fn reader(self: &Arc<Self>, inner: Box<dyn Read>) -> Box<dyn Read> {
+        Box::new(Counting {
+            inner,
+            progress: Arc::clone(self),
+        })
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Bars)) {
+        if let Some(bars) = self.lock().as_mut() {
+            f(bars);
+            bars.sync();
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<Bars>> {
+        self.bars.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A standalone byte-count bar, e.g. for `fsck`; hidden when the run
+/// draws no bars.
+pub fn bytes_bar(log: &Logger, prefix: &'static str, total: u64) -> ProgressBar {
+    let Some(multi) = log.progress() else {
+        return ProgressBar::hidden();
+    };
+    let bar = multi.add(ProgressBar::new(total).with_style(style(
+        "{prefix:>8} [{bar:30}] {bytes}/{total_bytes} {binary_bytes_per_sec} eta {eta}",
+    )));
+    bar.set_prefix(prefix);
+    bar
+}
+
+fn style(template: &str) -> ProgressStyle {
+    ProgressStyle::with_template(template)
+        .expect("valid progress template")
+        .progress_chars("=> ")
+}
+
+/// Clears the bars when a deploy ends.
+pub struct Deploy<'a> {
+    progress: &'a Progress,
+}
+
+impl Drop for Deploy<'_> {
+    fn drop(&mut self) {
+        if let Some(bars) = self.progress.lock().take() {
+            if let Some(object) = bars.object {
+                object.finish_and_clear();
+            }
+            bars.overall.finish_and_clear();
+        }
+    }
+}
+
+struct Counting {
+    inner: Box<dyn Read>,
+    progress: Arc<Progress>,
+}
+
+impl Read for Counting {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(out)?;
+        self.progress.add(n as u64);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tally_tracks_objects_and_rewinds_failed_attempts() {
+        let mut tally = Tally::new(300);
+        tally.start_object(100);
+        tally.add(60);
+        assert_eq!((tally.object_done, tally.done()), (60, 60));
+
+        // The first origin died at 60 bytes; the next starts from scratch.
+        tally.rewind_object(0);
+        assert_eq!(tally.done(), 0);
+        tally.add(100);
+        tally.finish_object();
+        assert_eq!((tally.object_done, tally.done()), (0, 100));
+
+        // A multi-part object keeps the parts that already arrived.
+        tally.start_object(200);
+        tally.add(150);
+        tally.rewind_object(120);
+        assert_eq!(tally.done(), 220);
+        tally.add(80);
+        tally.finish_object();
+        assert_eq!(tally.done(), tally.total);
+    }
+
+    #[test]
+    fn progress_without_bars_is_a_no_op() {
+        let progress = Arc::new(Progress::default());
+        let log = Logger::new(LogFormat::Text, 0);
+        let _deploy = progress.start(&log, 10);
+        progress.start_object("index.html", 10);
+        let mut body = progress.reader(Box::new(&b"0123456789"[..]));
+        io::copy(&mut body, &mut io::sink()).unwrap();
+        progress.finish_object();
+        assert!(progress.lock().is_none());
+    }
+
+    #[test]
+    fn bars_follow_the_tally() {
+        let progress = Arc::new(Progress::default());
+        let log = Logger::new(LogFormat::Text, 0).with_progress(MultiProgress::with_draw_target(
+            indicatif::ProgressDrawTarget::hidden(),
+        ));
+        let deploy = progress.start(&log, 30);
+        progress.start_object("a", 10);
+        io::copy(
+            &mut progress.reader(Box::new(&[0u8; 10][..])),
+            &mut io::sink(),
+        )
+        .unwrap();
+        progress.finish_object();
+        progress.start_object("b", 20);
+        progress.add(5);
+        {
+            let bars = progress.lock();
+            let bars = bars.as_ref().unwrap();
+            assert_eq!(bars.overall.position(), 15);
+            assert_eq!(bars.object.as_ref().unwrap().position(), 5);
+        }
+        drop(deploy);
+        assert!(progress.lock().is_none());
+    }
+}
@@ -0,0 +1,192 @@
+//! `cityfeed-puller promote --version V`: points `current` at a snapshot
+//! that is already on disk, without contacting any origin.
+//!
+//! The snapshot is checked against its recorded manifest first: every file
+//! present with its manifest size, every symlink pointing where it should.
+//! The switch itself is the normal deploy path, so `previous`,
+//! deploy-state.json, the change summary and `--on-switch` hooks all behave
+//! as after a network deploy. A snapshot without a recorded manifest (one
+//! deployed before manifests were recorded) gets one built from its files.
+
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use std::time::Instant;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::json;
+
+use crate::hashing::Hashing;
+use crate::http::Http;
+use crate::logger::Logger;
+use crate::{
+    current_version, diff, finish, install_stop_flag, validate_rel_path, validate_symlink_target,
+    validate_version, Args, FailAs, Failure, Manifest, ManifestFile, ManifestSymlink, Outcome,
+    Puller, RunError, Summary,
+};
+
+/// Problems listed before the rest are only counted.
+const MAX_LISTED: usize = 5;
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct PromoteArgs {
+    /// Snapshot under snapshots/ to make current.
+    #[arg(long, value_name = "VERSION")]
+    pub version: String,
+}
+
+/// Runs `promote` and returns the exit code: 0 after a switch, 3 if the
+/// snapshot was already current.
+pub fn main(args: &Args, promote: &PromoteArgs, log: &Logger) -> i32 {
+    let started = Instant::now();
+    let mut summary = Summary::default();
+    let puller = install_stop_flag().and_then(|stop| Puller::new(args, &Http::new(args)?, stop));
+    let (puller, result) = match puller {
+        Ok(puller) => {
+            let result = run(&puller, log, &mut summary, &promote.version);
+            (Some(puller), result)
+        }
+        Err(err) => (None, Err(err.into())),
+    };
+    summary.elapsed_secs = started.elapsed().as_secs_f64();
+    let code = finish(args, log, &mut summary, result);
+    if let Some(puller) = puller {
+        puller.record_history(args, log, &summary);
+    }
+    code
+}
+
+fn run(
+    puller: &Puller,
+    log: &Logger,
+    summary: &mut Summary,
+    version: &str,
+) -> Result<Outcome, RunError> {
+    puller.reclaim_stale(log);
+    summary.previous_version = current_version(&puller.current_link);
+    validate_version(version).with_context(|| format!("invalid version {version:?}"))?;
+    let snapshot = puller.snapshots_dir.join(version);
+    if !snapshot.is_dir() {
+        return Err(anyhow!("no snapshot {}", snapshot.display()).into());
+    }
+
+    let manifest = match diff::load(&puller.manifests_dir, version)? {
+        Some(manifest) => {
+            check_complete(&snapshot, &manifest)
+                .with_context(|| format!("snapshot {version} is incomplete"))
+                .fail_as(Failure::Object)?;
+            manifest
+        }
+        None => {
+            log.info(
+                "manifest_rebuilt",
+                json!({ "version": version }),
+                format_args!("no recorded manifest for {version}; building one from the snapshot"),
+            );
+            from_snapshot(&snapshot, version)
+                .with_context(|| format!("read snapshot {}", snapshot.display()))?
+        }
+    };
+    summary.file_count = Some(manifest.files.len() as u64);
+    puller.deploy(log, summary, &manifest, &snapshot.display().to_string())
+}
+
+/// Fails listing what is missing or the wrong size in `snapshot`.
+fn check_complete(snapshot: &Path, manifest: &Manifest) -> Result<()> {
+    let mut problems = Vec::new();
+    for file in &manifest.files {
+        let rel = validate_rel_path(&file.path)?;
+        match fs::symlink_metadata(snapshot.join(&rel)) {
+            Ok(meta) if meta.is_file() && meta.len() == file.size => {}
+            Ok(meta) if meta.is_file() => problems.push(format!(
+                "{}: {} bytes, expected {}",
+                file.path,
+                meta.len(),
+                file.size
+            )),
+            Ok(_) => problems.push(format!("{}: not a regular file", file.path)),
+            Err(_) => problems.push(format!("{}: missing", file.path)),
+        }
+    }
+    for link in &manifest.symlinks {
+        let rel = validate_rel_path(&link.path)?;
+        let want = validate_symlink_target(&rel, &link.target)?;
+        match fs::read_link(snapshot.join(&rel)) {
+            Ok(found) if found == want => {}
+            Ok(found) => problems.push(format!(
+                "{}: points to {}, expected {}",
+                link.path,
+                found.display(),
+                want.display()
+            )),
+            Err(_) => problems.push(format!("{}: missing symlink", link.path)),
+        }
+    }
+    if problems.is_empty() {
+        return Ok(());
+    }
+    let mut listed = problems[..problems.len().min(MAX_LISTED)].join("; ");
+    if problems.len() > MAX_LISTED {
+        listed.push_str(&format!("; and {} more", problems.len() - MAX_LISTED));
+    }
+    bail!("{listed}")
+}
+
+/// A manifest describing `snapshot` as it is on disk, hashing every file.
+pub fn from_snapshot(snapshot: &Path, version: &str) -> Result<Manifest> {
+    let mut manifest = Manifest {
+        version: version.to_string(),
+        files: Vec::new(),
+        symlinks: Vec::new(),
+        object_layout: None,
+        sequence: None,
+        generated_at: None,
+        prefix: None,
+        filters: Vec::new(),
+    };
+    walk(snapshot, "", &mut manifest)?;
+    manifest.files.sort_by(|a, b| a.path.cmp(&b.path));
+    manifest.symlinks.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(manifest)
+}
+
+fn walk(dir: &Path, prefix: &str, manifest: &mut Manifest) -> Result<()> {
+    for entry in fs::read_dir(dir).with_context(|| format!("list {}", dir.display()))? {
+        let entry = entry.with_context(|| format!("list {}", dir.display()))?;
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|name| anyhow!("non-UTF-8 name {name:?} in {}", dir.display()))?;
+        let rel = format!("{prefix}{name}");
+        let path = entry.path();
+        let kind = entry
+            .file_type()
+            .with_context(|| format!("stat {}", path.display()))?;
+        if kind.is_dir() {
+            walk(&path, &format!("{rel}/"), manifest)?;
+        } else if kind.is_symlink() {
+            let target =
+                fs::read_link(&path).with_context(|| format!("read link {}", path.display()))?;
+            manifest.symlinks.push(ManifestSymlink {
+                path: rel,
+                target: target.to_string_lossy().into_owned(),
+            });
+        } else {
+            let file = File::open(&path).with_context(|| format!("open {}", path.display()))?;
+            let mut reader = Hashing::new(file);
+            io::copy(&mut reader, &mut io::sink())
+                .with_context(|| format!("read {}", path.display()))?;
+            let (hash, size, _) = reader.finish();
+            manifest.files.push(ManifestFile {
+                path: rel,
+                hash,
+                size,
+                mode: None,
+                transfer: None,
+                parts: Vec::new(),
+                signature: None,
+            });
+        }
+    }
+    Ok(())
+}
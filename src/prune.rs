@@ -0,0 +1,186 @@
+//! `--keep-days`: removes snapshots that were last deployed more than N days
+//! ago, once a run has finished deploying.
+//!
+//! A snapshot's deploy time is the mtime of its record in `manifests/`,
+//! which is rewritten every time `current` is switched to it. Without a
+//! record, the snapshot directory's own mtime is used. The snapshots
+//! `current` and `previous` point at are always kept, however old. A root
+//! without a `previous` link keeps the most recently deployed snapshot
+//! after `current` instead.
+//!
+//! A snapshot is first renamed to a staging name and then deleted. A
+//! half-deleted snapshot therefore never keeps its version name, and the
+//! stale cleanup finishes it off if the run dies part way through.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use serde_json::json;
+
+use crate::logger::Logger;
+use crate::{diff, sanitize_prefix, stale};
+
+/// What a prune removed.
+#[derive(Debug, Default)]
+pub struct Pruned {
+    pub versions: Vec<String>,
+    pub bytes: u64,
+}
+
+/// Removes every snapshot under `snapshots_dir` older than `keep`, except
+/// `current` and `previous` (or, without one, the newest of the rest).
+/// Failing to remove one snapshot is logged and the others are still tried.
+pub fn prune(
+    snapshots_dir: &Path,
+    manifests_dir: &Path,
+    current: Option<&str>,
+    previous: Option<&str>,
+    keep: Duration,
+    log: &Logger,
+) -> Result<Pruned> {
+    let mut snapshots = Vec::new();
+    for entry in
+        fs::read_dir(snapshots_dir).with_context(|| format!("list {}", snapshots_dir.display()))?
+    {
+        let entry = entry.with_context(|| format!("list {}", snapshots_dir.display()))?;
+        let Ok(version) = entry.file_name().into_string() else {
+            continue;
+        };
+        // Staging dirs and anything else hidden belong to someone else.
+        if version.starts_with('.') || !entry.file_type().is_ok_and(|t| t.is_dir()) {
+            continue;
+        }
+        let deployed_at = fs::metadata(diff::record_path(manifests_dir, &version))
+            .or_else(|_| entry.metadata())
+            .and_then(|meta| meta.modified())
+            .with_context(|| format!("stat {}", entry.path().display()))?;
+        snapshots.push((version, deployed_at));
+    }
+
+    let mut pruned = Pruned::default();
+    for version in expired(snapshots, current, previous, keep, SystemTime::now()) {
+        match remove(snapshots_dir, &version) {
+            Ok(bytes) => {
+                log.info(
+                    "pruned_snapshot",
+                    json!({ "version": &version, "bytes": bytes }),
+                    format_args!("pruned snapshot {version} ({bytes} bytes)"),
+                );
+                pruned.versions.push(version);
+                pruned.bytes += bytes;
+            }
+            Err(err) => log.warn(
+                "prune_failed",
+                json!({ "version": &version, "error": format!("{err:#}") }),
+                format_args!("could not prune snapshot {version}: {err:#}"),
+            ),
+        }
+    }
+    Ok(pruned)
+}
+
+/// The versions to remove, oldest first.
+fn expired(
+    mut snapshots: Vec<(String, SystemTime)>,
+    current: Option<&str>,
+    previous: Option<&str>,
+    keep: Duration,
+    now: SystemTime,
+) -> Vec<String> {
+    snapshots.retain(|(version, _)| {
+        Some(version.as_str()) != current && Some(version.as_str()) != previous
+    });
+    snapshots.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+    if previous.is_none() {
+        // The newest non-current snapshot is the rollback target.
+        snapshots.pop();
+    }
+    snapshots
+        .into_iter()
+        .filter(|(_, deployed_at)| now.duration_since(*deployed_at).is_ok_and(|age| age > keep))
+        .map(|(version, _)| version)
+        .collect()
+}
+
+/// Deletes `snapshots/<version>` and returns the bytes it held.
+fn remove(snapshots_dir: &Path, version: &str) -> Result<u64> {
+    let doomed = snapshots_dir.join(format!(
+        "{}pruned",
+        stale::staging_prefix(&sanitize_prefix(version))
+    ));
+    fs::rename(snapshots_dir.join(version), &doomed)
+        .with_context(|| format!("move aside {}", snapshots_dir.join(version).display()))?;
+    let bytes = disk_bytes(&doomed);
+    fs::remove_dir_all(&doomed).with_context(|| format!("remove {}", doomed.display()))?;
+    Ok(bytes)
+}
+
+/// Total size of the regular files under `dir`, not following symlinks.
+/// Files hard-linked at several paths count once.
+pub fn disk_bytes(dir: &Path) -> u64 {
+    files_bytes(dir, &mut HashSet::new())
+}
+
+fn files_bytes(dir: &Path, seen: &mut HashSet<(u64, u64)>) -> u64 {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.file_type() {
+            Ok(t) if t.is_dir() => files_bytes(&entry.path(), seen),
+            Ok(t) if t.is_file() => entry.metadata().map_or(0, |meta| {
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::MetadataExt;
+                    if meta.nlink() > 1 && !seen.insert((meta.dev(), meta.ino())) {
+                        return 0;
+                    }
+                }
+                meta.len()
+            }),
+            _ => 0,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn snapshots(now: SystemTime, ages: &[(&str, u32)]) -> Vec<(String, SystemTime)> {
+        ages.iter()
+            .map(|(version, days)| (version.to_string(), now - DAY * *days))
+            .collect()
+    }
+
+    #[test]
+    fn keeps_current_and_the_newest_previous_however_old() {
+        let now = SystemTime::now();
+        let all = snapshots(now, &[("v1", 90), ("v2", 60), ("v3", 40), ("v4", 3)]);
+        assert_eq!(
+            expired(all.clone(), Some("v4"), None, 7 * DAY, now),
+            ["v1", "v2"]
+        );
+        // An ancient current (say, after a rollback) is never removed.
+        assert_eq!(
+            expired(all.clone(), Some("v1"), None, 7 * DAY, now),
+            ["v2", "v3"]
+        );
+        assert_eq!(
+            expired(all.clone(), None, None, 7 * DAY, now),
+            ["v1", "v2", "v3"]
+        );
+        assert!(expired(all.clone(), Some("v4"), None, 100 * DAY, now).is_empty());
+        // `previous` is protected instead of the newest other snapshot.
+        assert_eq!(
+            expired(all, Some("v4"), Some("v1"), 7 * DAY, now),
+            ["v2", "v3"]
+        );
+    }
+}
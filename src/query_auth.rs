@@ -0,0 +1,180 @@
+//! Token-in-query authentication for CDNs that check signed URLs
+//! (`?token=...&expires=...`): fixed `--query-auth key=value` pairs and/or
+//! the query string printed by `--query-auth-command`, which is run again for
+//! every request so short-lived tokens never go stale mid-deploy.
+//!
+//! Only the request on the wire carries the parameters. Logs and errors show
+//! the unsigned URL, and values an origin echoes back are redacted.
+
+use std::fmt;
+use std::process::Stdio;
+
+use anyhow::{bail, Context, Result};
+use reqwest::Url;
+
+use crate::hooks;
+
+/// One `--query-auth key=value`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct QueryParam {
+    pub key: String,
+    pub value: String,
+}
+
+impl fmt::Debug for QueryParam {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}=<redacted>", self.key)
+    }
+}
+
+/// Parses `--query-auth`. The error never repeats the input.
+pub fn parse_param(s: &str) -> Result<QueryParam> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok(QueryParam {
+            key: key.to_string(),
+            value: value.to_string(),
+        }),
+        _ => bail!("expected KEY=VALUE"),
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct QueryAuth {
+    pub params: Vec<QueryParam>,
+    pub command: Option<String>,
+}
+
+impl QueryAuth {
+    /// `url` with the parameters appended, or `None` when there are none.
+    pub fn sign(&self, url: &str) -> Result<Option<Signed>> {
+        if self.params.is_empty() && self.command.is_none() {
+            return Ok(None);
+        }
+        let mut pairs: Vec<(String, String)> = self
+            .params
+            .iter()
+            .map(|p| (p.key.clone(), p.value.clone()))
+            .collect();
+        if let Some(cmd) = &self.command {
+            pairs.extend(run(cmd)?);
+        }
+        let unsigned = Url::parse(url).with_context(|| format!("parse url {url}"))?;
+        let mut url = unsigned.clone();
+        url.query_pairs_mut().extend_pairs(&pairs);
+        Ok(Some(Signed {
+            url,
+            unsigned,
+            values: pairs
+                .into_iter()
+                .map(|(_, value)| value)
+                .filter(|value| !value.is_empty())
+                .collect(),
+        }))
+    }
+}
+
+/// Runs `--query-auth-command` and parses its stdout as a query string.
+fn run(cmd: &str) -> Result<Vec<(String, String)>> {
+    let out = hooks::shell(cmd)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .with_context(|| format!("spawn query-auth command `{cmd}`"))?;
+    if !out.status.success() {
+        bail!("query-auth command `{cmd}` exited with {}", out.status);
+    }
+    let text = String::from_utf8(out.stdout)
+        .with_context(|| format!("query-auth command `{cmd}` printed non-UTF-8 output"))?;
+    let query = text.trim().trim_start_matches('?');
+    if query.is_empty() {
+        bail!("query-auth command `{cmd}` printed nothing");
+    }
+    Ok(form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect())
+}
+
+/// A request URL carrying the auth parameters.
+pub struct Signed {
+    pub url: Url,
+    unsigned: Url,
+    values: Vec<String>,
+}
+
+impl Signed {
+    /// `text` with every parameter value, raw or URL-encoded, replaced.
+    pub fn redact(&self, text: &str) -> String {
+        self.values.iter().fold(text.to_string(), |text, value| {
+            let encoded: String = form_urlencoded::byte_serialize(value.as_bytes()).collect();
+            text.replace(&encoded, "<redacted>")
+                .replace(value, "<redacted>")
+        })
+    }
+
+    /// Points a transport error at the unsigned URL.
+    pub fn redact_error(&self, err: reqwest::Error) -> reqwest::Error {
+        err.with_url(self.unsigned.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn params_are_encoded_and_redacted() {
+        let auth = QueryAuth {
+            params: vec![
+                parse_param("token=a b/c+d").unwrap(),
+                parse_param("expires=1767225600").unwrap(),
+            ],
+            command: None,
+        };
+        let signed = auth
+            .sign("https://cdn.example/objects/ab?v=1")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            signed.url.as_str(),
+            "https://cdn.example/objects/ab?v=1&token=a+b%2Fc%2Bd&expires=1767225600"
+        );
+        assert_eq!(
+            signed.redact(signed.url.as_str()),
+            "https://cdn.example/objects/ab?v=1&token=<redacted>&expires=<redacted>"
+        );
+        assert_eq!(
+            signed.redact("token a b/c+d expired"),
+            "token <redacted> expired"
+        );
+        assert_eq!(format!("{:?}", auth.params[0]), "token=<redacted>");
+        assert!(QueryAuth::default().sign("https://x/").unwrap().is_none());
+        for bad in ["token", "=x", ""] {
+            assert_eq!(
+                parse_param(bad).unwrap_err().to_string(),
+                "expected KEY=VALUE"
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn command_output_is_appended_and_failures_are_errors() {
+        let auth = QueryAuth {
+            params: Vec::new(),
+            command: Some("echo '?token=x%2Fy&expires=9'".into()),
+        };
+        let signed = auth.sign("http://cdn.example/m.json").unwrap().unwrap();
+        assert_eq!(
+            signed.url.as_str(),
+            "http://cdn.example/m.json?token=x%2Fy&expires=9"
+        );
+        assert_eq!(signed.redact("x/y"), "<redacted>");
+
+        let auth = QueryAuth {
+            params: Vec::new(),
+            command: Some("exit 3".into()),
+        };
+        let err = auth.sign("http://cdn.example/m.json").err().unwrap();
+        assert!(format!("{err:#}").contains("exited with"), "{err:#}");
+    }
+}
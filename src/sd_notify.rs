@@ -0,0 +1,180 @@
+//! Minimal `sd_notify(3)` client so `--watch` can run as a `Type=notify`
+//! unit: readiness, status text and watchdog keep-alives are sent as
+//! datagrams to `$NOTIFY_SOCKET`. Without that variable every call is a no-op.
+
+use std::cell::Cell;
+use std::ffi::OsString;
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+pub struct Notifier {
+    #[cfg(unix)]
+    socket: Option<(UnixDatagram, SocketAddr)>,
+    watchdog: Option<Duration>,
+    last_ping: Cell<Option<Instant>>,
+}
+
+impl Notifier {
+    pub fn from_env() -> Self {
+        Self::from_vars(
+            std::env::var_os("NOTIFY_SOCKET"),
+            std::env::var("WATCHDOG_USEC").ok(),
+            std::env::var("WATCHDOG_PID").ok(),
+        )
+    }
+
+    /// `watchdog_usec` only applies when `watchdog_pid` is unset or names
+    /// this process, matching `sd_watchdog_enabled(3)`.
+    pub fn from_vars(
+        notify_socket: Option<OsString>,
+        watchdog_usec: Option<String>,
+        watchdog_pid: Option<String>,
+    ) -> Self {
+        let for_us = watchdog_pid
+            .map(|pid| pid.trim().parse::<u32>().ok() == Some(std::process::id()))
+            .unwrap_or(true);
+        let watchdog = watchdog_usec
+            .and_then(|usec| usec.trim().parse::<u64>().ok())
+            .filter(|&usec| usec > 0 && for_us)
+            .map(Duration::from_micros);
+
+        #[cfg(unix)]
+        let socket = notify_socket.and_then(|path| connect(&path));
+        #[cfg(not(unix))]
+        let _ = notify_socket;
+
+        Self {
+            #[cfg(unix)]
+            socket,
+            watchdog,
+            last_ping: Cell::new(None),
+        }
+    }
+
+    pub fn ready(&self, status: &str) {
+        self.send(&format!("READY=1\nSTATUS={status}"));
+    }
+
+    pub fn status(&self, status: &str) {
+        self.send(&format!("STATUS={status}"));
+    }
+
+    pub fn stopping(&self) {
+        self.send("STOPPING=1");
+    }
+
+    /// Pings the watchdog if `WATCHDOG_USEC` asked for it.
+    pub fn watchdog(&self) {
+        if self.watchdog.is_some() {
+            self.last_ping.set(Some(Instant::now()));
+            self.send("WATCHDOG=1");
+        }
+    }
+
+    /// Pings the watchdog only once half its period has passed since the last
+    /// ping; cheap enough to call from sleep loops.
+    pub fn keepalive(&self) {
+        let Some(period) = self.watchdog else {
+            return;
+        };
+        let due = match self.last_ping.get() {
+            Some(last) => last.elapsed() >= period / 2,
+            None => true,
+        };
+        if due {
+            self.watchdog();
+        }
+    }
+
+    fn send(&self, msg: &str) {
+        #[cfg(unix)]
+        if let Some((sock, addr)) = &self.socket {
+            // Best effort: systemd going away must not take the puller with it.
+            let _ = sock.send_to_addr(msg.as_bytes(), addr);
+        }
+        #[cfg(not(unix))]
+        let _ = msg;
+    }
+}
+
+#[cfg(unix)]
+fn connect(path: &OsString) -> Option<(UnixDatagram, SocketAddr)> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let bytes = path.as_bytes();
+    let addr = match bytes.first() {
+        #[cfg(target_os = "linux")]
+        Some(b'@') => {
+            use std::os::linux::net::SocketAddrExt;
+            SocketAddr::from_abstract_name(&bytes[1..]).ok()?
+        }
+        Some(b'/') => SocketAddr::from_pathname(path).ok()?,
+        _ => return None,
+    };
+    let sock = UnixDatagram::unbound().ok()?;
+    Some((sock, addr))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn listener() -> (tempfile::TempDir, UnixDatagram, OsString) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify.sock");
+        let sock = UnixDatagram::bind(&path).unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        (dir, sock, path.into_os_string())
+    }
+
+    fn recv(sock: &UnixDatagram) -> String {
+        let mut buf = [0u8; 512];
+        let n = sock.recv(&mut buf).unwrap();
+        String::from_utf8(buf[..n].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn sends_ready_status_watchdog_and_stopping() {
+        let (_dir, sock, path) = listener();
+        let n = Notifier::from_vars(Some(path), Some("5000000".into()), None);
+
+        n.watchdog();
+        assert_eq!(recv(&sock), "WATCHDOG=1");
+        n.ready("deployed v1");
+        assert_eq!(recv(&sock), "READY=1\nSTATUS=deployed v1");
+        n.keepalive(); // not due yet: half of 5s hasn't passed
+        n.status("deployed v2");
+        assert_eq!(recv(&sock), "STATUS=deployed v2");
+        n.stopping();
+        assert_eq!(recv(&sock), "STOPPING=1");
+    }
+
+    #[test]
+    fn watchdog_is_silent_when_not_requested_or_meant_for_another_pid() {
+        let (_dir, sock, path) = listener();
+
+        let n = Notifier::from_vars(Some(path.clone()), None, None);
+        n.watchdog();
+        n.keepalive();
+        n.ready("x");
+        assert_eq!(recv(&sock), "READY=1\nSTATUS=x");
+
+        let other = (std::process::id() + 1).to_string();
+        let n = Notifier::from_vars(Some(path), Some("1000".into()), Some(other));
+        n.watchdog();
+        n.stopping();
+        assert_eq!(recv(&sock), "STOPPING=1");
+    }
+
+    #[test]
+    fn without_notify_socket_everything_is_a_no_op() {
+        let n = Notifier::from_vars(None, Some("1000".into()), None);
+        n.ready("x");
+        n.watchdog();
+        n.keepalive();
+        n.stopping();
+        assert!(n.socket.is_none());
+    }
+}
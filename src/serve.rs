@@ -0,0 +1,288 @@
+//! `cityfeed-puller serve`: a small HTTP server over whatever `current`
+//! points at, for smoke-testing a box before it goes behind the load
+//! balancer. It serves GET and HEAD requests only, with no TLS, caching or
+//! compression.
+//!
+//! `current` is resolved again for every request, so a deploy that runs
+//! during a smoke test is picked up. Request paths go through the same
+//! checks as manifest paths, and a resolved file must still be inside the
+//! snapshot. Anything else is a 404.
+
+use std::fs::{self, File};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::json;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::logger::Logger;
+use crate::{install_stop_flag, parse_duration, validate_rel_path, EXIT_FAILURE};
+
+/// How often the accept loop checks for a stop signal or the deadline.
+const POLL: Duration = Duration::from_millis(200);
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct ServeArgs {
+    /// Address to listen on; port 0 picks a free one.
+    #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:8099")]
+    pub bind: SocketAddr,
+
+    /// Exit after answering one request.
+    #[arg(long)]
+    pub once: bool,
+
+    /// Exit after serving for this long (e.g. 30s).
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub timeout: Option<Duration>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Target {
+    File(PathBuf),
+    /// A directory requested without its trailing slash.
+    Redirect(String),
+    NotFound,
+}
+
+/// Serves `<root>/current` until stopped; returns the exit code.
+pub fn main(root: &Path, args: &ServeArgs, log: &Logger) -> i32 {
+    match serve(root, args, log) {
+        Ok(()) => 0,
+        Err(err) => {
+            log.error(
+                "serve_failed",
+                json!({ "error": format!("{err:#}") }),
+                format_args!("serve failed: {err:#}"),
+            );
+            EXIT_FAILURE
+        }
+    }
+}
+
+fn serve(root: &Path, args: &ServeArgs, log: &Logger) -> Result<()> {
+    let stop = install_stop_flag()?;
+    let server =
+        Server::http(args.bind).map_err(|err| anyhow!("listen on {}: {err}", args.bind))?;
+    let addr = server
+        .server_addr()
+        .to_ip()
+        .ok_or_else(|| anyhow!("listen on {}: not an IP socket", args.bind))?;
+    let current = root.join("current");
+    log.info(
+        "serving",
+        json!({ "root": root, "addr": addr.to_string() }),
+        format_args!("serving {} on http://{addr}/", current.display()),
+    );
+
+    let deadline = args.timeout.map(|timeout| Instant::now() + timeout);
+    while !stop.load(Ordering::SeqCst) {
+        let wait = match deadline {
+            Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(left) => left.min(POLL),
+                None => break,
+            },
+            None => POLL,
+        };
+        let Some(request) = server.recv_timeout(wait).context("accept request")? else {
+            continue;
+        };
+        respond(&current, request, log);
+        if args.once {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn respond(current: &Path, request: Request, log: &Logger) {
+    let method = request.method().clone();
+    let url = request.url().to_string();
+    let (status, result) = match method {
+        Method::Get | Method::Head => match fs::canonicalize(current) {
+            Ok(snapshot) => match resolve(&snapshot, &url) {
+                Target::File(path) => match File::open(&path) {
+                    Ok(file) => {
+                        let response = Response::from_file(file)
+                            .with_header(header("Content-Type", content_type(&path)));
+                        (200, request.respond(response))
+                    }
+                    Err(_) => (404, request.respond(text(404, "not found"))),
+                },
+                Target::Redirect(location) => {
+                    let response = text(301, "moved").with_header(header("Location", &location));
+                    (301, request.respond(response))
+                }
+                Target::NotFound => (404, request.respond(text(404, "not found"))),
+            },
+            Err(_) => (503, request.respond(text(503, "no current snapshot"))),
+        },
+        _ => (405, request.respond(text(405, "method not allowed"))),
+    };
+    if let Err(err) = result {
+        log.warn(
+            "serve_respond_failed",
+            json!({ "path": &url, "error": err.to_string() }),
+            format_args!("{method} {url}: {err}"),
+        );
+    }
+    log.info(
+        "serve_request",
+        json!({ "method": method.as_str(), "path": &url, "status": status }),
+        format_args!("{method} {url} {status}"),
+    );
+}
+
+/// Maps a request URL to a file inside `snapshot` (already canonical).
+fn resolve(snapshot: &Path, url: &str) -> Target {
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    let Some(decoded) = path.strip_prefix('/').and_then(percent_decode) else {
+        return Target::NotFound;
+    };
+    let wants_dir = decoded.is_empty() || decoded.ends_with('/');
+    let rel = if wants_dir {
+        format!("{decoded}index.html")
+    } else {
+        decoded
+    };
+    let Ok(rel) = validate_rel_path(&rel) else {
+        return Target::NotFound;
+    };
+    // Symlinks in the snapshot may not lead outside it.
+    let Ok(full) = fs::canonicalize(snapshot.join(rel)) else {
+        return Target::NotFound;
+    };
+    if !full.starts_with(snapshot) {
+        return Target::NotFound;
+    }
+    if full.is_dir() {
+        return if full.join("index.html").is_file() {
+            Target::Redirect(format!("{path}/"))
+        } else {
+            Target::NotFound
+        };
+    }
+    Target::File(full)
+}
+
+/// `%XX` escapes to bytes; `None` for bad escapes, NUL or non-UTF-8.
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    if out.contains(&0) {
+        return None;
+    }
+    String::from_utf8(out).ok()
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_default();
+    match ext.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "webmanifest" => "application/manifest+json",
+        "xml" => "application/xml",
+        "txt" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+        "mp4" => "video/mp4",
+        "gz" => "application/gzip",
+        _ => "application/octet-stream",
+    }
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("valid header")
+}
+
+fn text(status: u16, body: &str) -> Response<std::io::Cursor<Vec<u8>>> {
+    Response::from_string(format!("{body}\n"))
+        .with_status_code(status)
+        .with_header(header("Content-Type", "text/plain; charset=utf-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_paths_stay_inside_the_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot = dir.path().join("snap");
+        fs::create_dir_all(snapshot.join("docs")).unwrap();
+        fs::write(snapshot.join("index.html"), "home").unwrap();
+        fs::write(snapshot.join("docs/index.html"), "docs").unwrap();
+        fs::write(snapshot.join("docs/a b.css"), "css").unwrap();
+        fs::write(dir.path().join("secret"), "no").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("../secret", snapshot.join("leak")).unwrap();
+        let snapshot = fs::canonicalize(&snapshot).unwrap();
+        let file = |rel: &str| Target::File(snapshot.join(rel));
+
+        assert_eq!(resolve(&snapshot, "/"), file("index.html"));
+        assert_eq!(resolve(&snapshot, "/?v=2"), file("index.html"));
+        assert_eq!(resolve(&snapshot, "/docs/"), file("docs/index.html"));
+        assert_eq!(
+            resolve(&snapshot, "/docs?x=1"),
+            Target::Redirect("/docs/".into())
+        );
+        assert_eq!(resolve(&snapshot, "/docs/a%20b.css"), file("docs/a b.css"));
+        for bad in [
+            "/../secret",
+            "/docs/../../secret",
+            "/%2e%2e/secret",
+            "/docs/%2E%2E/%2E%2E/secret",
+            "//etc/passwd",
+            "/leak",
+            "/missing.html",
+            "/bad%zz",
+            "/nul%00",
+            "relative",
+        ] {
+            assert_eq!(resolve(&snapshot, bad), Target::NotFound, "{bad}");
+        }
+    }
+
+    #[test]
+    fn content_types_follow_the_extension() {
+        assert_eq!(
+            content_type(Path::new("a/INDEX.HTML")),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(
+            content_type(Path::new("app.mjs")),
+            "text/javascript; charset=utf-8"
+        );
+        assert_eq!(content_type(Path::new("logo.svg")), "image/svg+xml");
+        assert_eq!(content_type(Path::new("feed")), "application/octet-stream");
+    }
+}
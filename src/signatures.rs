@@ -0,0 +1,216 @@
+//! Per-file signatures: a manifest entry (or variant) may carry
+//! `"signature"`, an ed25519 signature in hex over
+//!
+//! ```text
+//! sha256 digest (32 bytes) || size (8 bytes, big-endian) || path (UTF-8)
+//! ```
+//!
+//! with `path` as published, before any `--prefix` strips it. With
+//! `--manifest-pubkey`, every signed entry is checked when the manifest is
+//! fetched (or read back for a `304`), and `fsck` checks the recorded
+//! manifests again. `--require-file-signatures` also refuses a manifest with
+//! any unsigned file. Symlinks have no content and are never signed.
+
+use anyhow::{bail, Context, Result};
+use ring::signature::{UnparsedPublicKey, ED25519};
+
+use crate::{Manifest, ManifestFile};
+
+/// `--manifest-pubkey` and `--require-file-signatures`.
+#[derive(Clone, Debug, Default)]
+pub struct Policy {
+    pub key: Option<PublicKey>,
+    pub require: bool,
+}
+
+/// An ed25519 public key, as 64 hex digits on the command line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PublicKey([u8; 32]);
+
+pub fn parse_pubkey(s: &str) -> Result<PublicKey> {
+    let bytes = decode_hex(s.trim())
+        .filter(|bytes| bytes.len() == 32)
+        .with_context(|| format!("invalid ed25519 public key {s:?} (expected 64 hex digits)"))?;
+    let mut key = [0; 32];
+    key.copy_from_slice(&bytes);
+    Ok(PublicKey(key))
+}
+
+/// What a file's signature covers.
+pub fn message(hash: &str, size: u64, path: &str) -> Result<Vec<u8>> {
+    let mut message = decode_hex(hash).with_context(|| format!("{path}: hash is not hex"))?;
+    message.extend_from_slice(&size.to_be_bytes());
+    message.extend_from_slice(path.as_bytes());
+    Ok(message)
+}
+
+impl Policy {
+    /// Checks every signed file in `manifest` against the key, and with
+    /// `require` that no file is unsigned. Without a key there is nothing to
+    /// check against, so signatures are ignored.
+    pub fn check(&self, manifest: &Manifest) -> Result<()> {
+        let Some(key) = &self.key else {
+            return Ok(());
+        };
+        let problems = problems(key, manifest);
+        let unsigned: Vec<&str> = manifest
+            .files
+            .iter()
+            .filter(|file| file.signature.is_none())
+            .map(|file| file.path.as_str())
+            .collect();
+        if let Some(first) = problems.first() {
+            bail!(
+                "{} of {} files fail their signature check, first {first}",
+                problems.len(),
+                manifest.files.len()
+            );
+        }
+        if self.require && !unsigned.is_empty() {
+            bail!(
+                "{} of {} files are unsigned (first: {}) and --require-file-signatures is set",
+                unsigned.len(),
+                manifest.files.len(),
+                unsigned[0]
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Every signed file whose signature doesn't verify, as `path: reason`.
+pub fn problems(key: &PublicKey, manifest: &Manifest) -> Vec<String> {
+    let key = UnparsedPublicKey::new(&ED25519, key.0);
+    manifest
+        .files
+        .iter()
+        .filter_map(|file| {
+            let signature = file.signature.as_deref()?;
+            verify(&key, manifest.prefix.as_deref(), file, signature)
+                .err()
+                .map(|err| format!("{}: {err:#}", file.path))
+        })
+        .collect()
+}
+
+fn verify(
+    key: &UnparsedPublicKey<[u8; 32]>,
+    prefix: Option<&str>,
+    file: &ManifestFile,
+    signature: &str,
+) -> Result<()> {
+    let published = match prefix {
+        Some(prefix) => format!("{prefix}/{}", file.path),
+        None => file.path.clone(),
+    };
+    let signature = decode_hex(signature).context("signature is not hex")?;
+    let message = message(&file.hash, file.size, &published)?;
+    if key.verify(&message, &signature).is_err() {
+        bail!("bad signature");
+    }
+    Ok(())
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::Ed25519KeyPair;
+
+    /// Fixture key: seed 0x01 repeated.
+    fn keypair() -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(&[1; 32]).unwrap()
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    fn sign(path: &str, hash: &str, size: u64) -> String {
+        hex(keypair().sign(&message(hash, size, path).unwrap()).as_ref())
+    }
+
+    fn policy(require: bool) -> Policy {
+        let public = hex(ring::signature::KeyPair::public_key(&keypair()).as_ref());
+        Policy {
+            key: Some(parse_pubkey(&public).unwrap()),
+            require,
+        }
+    }
+
+    fn manifest(entries: &[(&str, u64, Option<String>)]) -> Manifest {
+        let hash = "ab".repeat(32);
+        let files: Vec<String> = entries
+            .iter()
+            .map(|(path, size, signature)| {
+                let signature = signature
+                    .as_ref()
+                    .map(|s| format!(r#", "signature": "{s}""#))
+                    .unwrap_or_default();
+                format!(r#"{{"path": "{path}", "hash": "{hash}", "size": {size}{signature}}}"#)
+            })
+            .collect();
+        serde_json::from_str(&format!(
+            r#"{{"version": "v1", "files": [{}]}}"#,
+            files.join(",")
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn signed_entries_verify_and_tampering_does_not() {
+        let hash = "ab".repeat(32);
+        let good = manifest(&[
+            ("index.html", 4, Some(sign("index.html", &hash, 4))),
+            ("app.js", 9, Some(sign("app.js", &hash, 9))),
+        ]);
+        policy(true).check(&good).unwrap();
+
+        // The signature was made for another path, or another size.
+        let moved = manifest(&[("admin.html", 4, Some(sign("index.html", &hash, 4)))]);
+        let err = policy(false).check(&moved).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("admin.html: bad signature"),
+            "{err:#}"
+        );
+        let grown = manifest(&[("index.html", 5, Some(sign("index.html", &hash, 4)))]);
+        assert!(policy(false).check(&grown).is_err());
+
+        // Without a key nothing is checked.
+        Policy::default().check(&moved).unwrap();
+    }
+
+    #[test]
+    fn unsigned_files_only_fail_when_required() {
+        let hash = "ab".repeat(32);
+        let mixed = manifest(&[
+            ("index.html", 4, Some(sign("index.html", &hash, 4))),
+            ("extra.html", 4, None),
+        ]);
+        policy(false).check(&mixed).unwrap();
+        let err = policy(true).check(&mixed).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "1 of 2 files are unsigned (first: extra.html) and --require-file-signatures is set"
+        );
+    }
+
+    #[test]
+    fn prefixed_records_check_the_published_path() {
+        let hash = "ab".repeat(32);
+        let mut record = manifest(&[("index.html", 4, Some(sign("daily/index.html", &hash, 4)))]);
+        assert!(policy(false).check(&record).is_err());
+        record.prefix = Some("daily".into());
+        policy(false).check(&record).unwrap();
+        assert!(parse_pubkey("abc").is_err());
+    }
+}
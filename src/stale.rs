@@ -0,0 +1,201 @@
+//! Startup cleanup of temp artifacts left behind by crashed or killed runs:
+//! staging snapshot dirs, partial object downloads and temp `current` and
+//! `previous` links.
+//!
+//! Every name carries the creating process's PID so entries from a live run
+//! are left alone. The caller must hold the root lock before reclaiming;
+//! `status` only lists them.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde_json::json;
+
+use crate::logger::Logger;
+
+/// Prefix for in-flight object downloads in `objects/`.
+pub fn object_temp_prefix() -> String {
+    format!(".tmp-{}-", std::process::id())
+}
+
+/// Prefix for the staging dir a snapshot is built in before it is renamed
+/// into place; `version` must already be sanitized.
+pub fn staging_prefix(version: &str) -> String {
+    format!(".{version}.staging-{}-", std::process::id())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Owner {
+    /// Created by the process with this PID.
+    Pid(u32),
+    /// Temp name from before PIDs were recorded.
+    Unknown,
+}
+
+fn staging_owner(name: &str) -> Option<Owner> {
+    let (_, rest) = name.strip_prefix('.')?.split_once(".staging-")?;
+    Some(pid_prefix(rest))
+}
+
+fn object_temp_owner(name: &str) -> Option<Owner> {
+    let rest = name.strip_prefix(".tmp")?;
+    Some(
+        rest.strip_prefix('-')
+            .map(pid_prefix)
+            .unwrap_or(Owner::Unknown),
+    )
+}
+
+fn current_temp_owner(name: &str) -> Option<Owner> {
+    let pid = name
+        .strip_prefix(".current.new.")
+        .or_else(|| name.strip_prefix(".previous.new."))?;
+    Some(pid.parse().map(Owner::Pid).unwrap_or(Owner::Unknown))
+}
+
+/// Parses `<pid>-<random>`.
+fn pid_prefix(s: &str) -> Owner {
+    s.split_once('-')
+        .and_then(|(pid, _)| pid.parse().ok())
+        .map(Owner::Pid)
+        .unwrap_or(Owner::Unknown)
+}
+
+fn is_stale(owner: &Owner) -> bool {
+    match owner {
+        Owner::Pid(pid) => *pid != std::process::id() && !pid_alive(*pid),
+        // We hold the root lock, so nothing unlabelled can still be in use.
+        Owner::Unknown => true,
+    }
+}
+
+fn pid_alive(pid: u32) -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        Path::new("/proc").join(pid.to_string()).exists()
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        // No cheap check; the root lock already excludes other pullers.
+        let _ = pid;
+        false
+    }
+}
+
+/// Whether `name` in `objects/` is a partial download no live run owns.
+pub fn is_orphaned_object_temp(name: &str) -> bool {
+    object_temp_owner(name).is_some_and(|owner| is_stale(&owner))
+}
+
+/// A temp entry left in the root by a run, live or not.
+#[derive(Debug)]
+pub struct Leftover {
+    pub path: PathBuf,
+    /// The process that created it is still running.
+    pub live: bool,
+}
+
+/// Lists temp entries without removing anything; safe to call without the
+/// root lock. Directories that don't exist yet are skipped.
+pub fn leftovers(root: &Path, objects_dir: &Path, snapshots_dir: &Path) -> Result<Vec<Leftover>> {
+    Ok(temps(root, objects_dir, snapshots_dir)?
+        .into_iter()
+        .map(|(entry, owner)| Leftover {
+            path: entry.path(),
+            live: !is_stale(&owner),
+        })
+        .collect())
+}
+
+/// Every temp entry in the three directories, with its owner.
+fn temps(
+    root: &Path,
+    objects_dir: &Path,
+    snapshots_dir: &Path,
+) -> Result<Vec<(fs::DirEntry, Owner)>> {
+    let mut found = Vec::new();
+    for (dir, owner_of) in [
+        (snapshots_dir, staging_owner as fn(&str) -> Option<Owner>),
+        (objects_dir, object_temp_owner),
+        (root, current_temp_owner),
+    ] {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err).with_context(|| format!("list {}", dir.display())),
+        };
+        for entry in entries {
+            let entry = entry.with_context(|| format!("list {}", dir.display()))?;
+            let owner = entry.file_name().to_str().and_then(owner_of);
+            if let Some(owner) = owner {
+                found.push((entry, owner));
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// Removes stale entries and returns how many were reclaimed.
+pub fn reclaim(
+    root: &Path,
+    objects_dir: &Path,
+    snapshots_dir: &Path,
+    log: &Logger,
+) -> Result<usize> {
+    let mut reclaimed = 0;
+    for (entry, owner) in temps(root, objects_dir, snapshots_dir)? {
+        if !is_stale(&owner) {
+            continue;
+        }
+        let path = entry.path();
+        let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+        let removed = if is_dir {
+            fs::remove_dir_all(&path)
+        } else {
+            // Windows directory symlinks need remove_dir.
+            fs::remove_file(&path).or_else(|_| fs::remove_dir(&path))
+        };
+        match removed {
+            Ok(()) => {
+                reclaimed += 1;
+                log.info(
+                    "reclaimed_stale",
+                    json!({ "path": &path }),
+                    format_args!("removed stale {}", path.display()),
+                );
+            }
+            Err(err) => log.warn(
+                "reclaim_failed",
+                json!({ "path": &path, "error": err.to_string() }),
+                format_args!("could not remove stale {}: {err}", path.display()),
+            ),
+        }
+    }
+    Ok(reclaimed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognizes_temp_names_and_their_owners() {
+        assert_eq!(staging_owner(".v1.staging-42-aB3dE"), Some(Owner::Pid(42)));
+        assert_eq!(staging_owner(".v1.staging-aB3dE"), Some(Owner::Unknown));
+        assert_eq!(staging_owner("v1"), None);
+        assert_eq!(staging_owner(".v1"), None);
+        assert_eq!(object_temp_owner(".tmp-7-xyz"), Some(Owner::Pid(7)));
+        assert_eq!(object_temp_owner(".tmpA1b2C3"), Some(Owner::Unknown));
+        assert_eq!(object_temp_owner("abc123"), None);
+        assert_eq!(current_temp_owner(".current.new.99"), Some(Owner::Pid(99)));
+        assert_eq!(current_temp_owner(".previous.new.7"), Some(Owner::Pid(7)));
+        assert_eq!(current_temp_owner("current"), None);
+
+        let ours = staging_prefix("v1") + "zz";
+        assert_eq!(staging_owner(&ours), Some(Owner::Pid(std::process::id())));
+        assert!(!is_stale(&Owner::Pid(std::process::id())));
+        assert!(is_stale(&Owner::Unknown));
+    }
+}
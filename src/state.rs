@@ -0,0 +1,157 @@
+//! `<root>/deploy-state.json`: which version is live, for tooling on the box
+//! (the nginx config generator, the UI) that shouldn't have to readlink
+//! `current`. Rewritten atomically after every switch; "already current" runs
+//! only move `checked_at`.
+
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{fsync_dir, Manifest};
+
+pub const STATE_FILE: &str = "deploy-state.json";
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeployState {
+    pub version: String,
+    /// Origin (or `--manifest-url`) the manifest came from.
+    pub origin: String,
+    /// `--channel` the box follows; absent for a `--manifest-url` or
+    /// `--offline` deploy, and for roots deployed before channels.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+    pub file_count: u64,
+    /// Sum of the manifest's file sizes.
+    pub total_bytes: u64,
+    /// When `current` was switched to `version`; unknown for roots deployed
+    /// before this file existed.
+    pub switched_at: Option<String>,
+    pub checked_at: String,
+    pub puller_version: String,
+}
+
+impl DeployState {
+    fn new(
+        manifest: &Manifest,
+        origin: &str,
+        channel: Option<&str>,
+        switched_at: Option<String>,
+        now: String,
+    ) -> Self {
+        Self {
+            version: manifest.version.clone(),
+            origin: origin.to_string(),
+            channel: channel.map(str::to_string),
+            file_count: manifest.files.len() as u64,
+            total_bytes: manifest.files.iter().map(|f| f.size).sum(),
+            switched_at,
+            checked_at: now,
+            puller_version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+}
+
+/// Records that `current` now points at `manifest.version`.
+pub fn switched(
+    root: &Path,
+    manifest: &Manifest,
+    origin: &str,
+    channel: Option<&str>,
+) -> Result<()> {
+    let now = utc_now();
+    write(
+        root,
+        &DeployState::new(manifest, origin, channel, Some(now.clone()), now),
+    )
+}
+
+/// Refreshes `checked_at` after a run found `manifest.version` already live.
+/// A missing or outdated file is rewritten in full. The channel is always
+/// the one just checked, so moving a box to a channel that serves the live
+/// version shows at once.
+pub fn checked(
+    root: &Path,
+    manifest: &Manifest,
+    origin: &str,
+    channel: Option<&str>,
+) -> Result<()> {
+    let now = utc_now();
+    let state = match read(root) {
+        Some(state) if state.version == manifest.version => DeployState {
+            checked_at: now,
+            channel: channel.map(str::to_string),
+            ..state
+        },
+        _ => DeployState::new(manifest, origin, channel, None, now),
+    };
+    write(root, &state)
+}
+
+/// The state file as last written, if it exists and parses.
+pub fn read(root: &Path) -> Option<DeployState> {
+    let text = fs::read_to_string(root.join(STATE_FILE)).ok()?;
+    serde_json::from_str(&text).ok()
+}
+
+fn write(root: &Path, state: &DeployState) -> Result<()> {
+    let path = root.join(STATE_FILE);
+    let mut tmp = tempfile::NamedTempFile::new_in(root)
+        .with_context(|| format!("create temp file in {}", root.display()))?;
+    serde_json::to_writer_pretty(&mut tmp, state).context("write deploy state")?;
+    tmp.write_all(b"\n").context("write deploy state")?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(tmp.path(), fs::Permissions::from_mode(0o644))
+            .context("chmod deploy state")?;
+    }
+    tmp.as_file().sync_all().context("fsync deploy state")?;
+    tmp.persist(&path)
+        .with_context(|| format!("replace {}", path.display()))?;
+    fsync_dir(root).context("fsync root dir")
+}
+
+pub fn utc_now() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    rfc3339(secs)
+}
+
+/// `2026-10-16T08:30:00Z` for a Unix time.
+pub fn rfc3339(secs: u64) -> String {
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+    // Days since the epoch to a civil date (Howard Hinnant's algorithm).
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        rem / 3_600,
+        rem % 3_600 / 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_utc_timestamps() {
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(rfc3339(1_792_139_400), "2026-10-16T08:30:00Z");
+    }
+}
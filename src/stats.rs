@@ -0,0 +1,127 @@
+//! Per-run timing and throughput, carried in the run's `Summary` and printed
+//! once the run ends.
+//!
+//! Phase timings come from `Summary::enter`: each phase runs until the next
+//! one starts or the run ends. Throughput only counts time spent fetching
+//! objects, so the size checks and free-space probe in the download phase do
+//! not drag the average down.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::copy_strategy::{CopyStrategy, Downgrade};
+use crate::error_report::Phase;
+use crate::health::Transition;
+
+/// Object fetch time a throughput sample must cover before it can set the
+/// peak; shorter bursts say more about buffering than about the link.
+const PEAK_WINDOW: Duration = Duration::from_secs(1);
+
+/// What one run spent its time on, beyond the object and byte counts the
+/// summary already carries.
+#[derive(Debug, Default, Serialize)]
+pub struct Stats {
+    /// Seconds spent in each phase the run reached.
+    pub phase_secs: BTreeMap<Phase, f64>,
+    /// Seconds spent fetching objects, summed over every download.
+    pub download_secs: f64,
+    /// Bytes over the wire per second of `download_secs`.
+    pub avg_bytes_per_sec: Option<f64>,
+    /// Fastest rate over a run of consecutive downloads lasting `PEAK_WINDOW`,
+    /// or over all of them when they took less in total.
+    pub peak_bytes_per_sec: Option<f64>,
+    /// Requests that only succeeded after moving on from a failed origin.
+    pub failovers: u64,
+    /// How the first root's snapshot files were placed, once `auto` settled;
+    /// absent when nothing was staged.
+    pub copy_strategy: Option<CopyStrategy>,
+    /// Steps `--copy-strategy auto` took down its chain, in any root.
+    pub copy_downgrades: Vec<Downgrade>,
+    /// Origin circuit breakers that opened, were probed or closed again.
+    pub circuit_transitions: Vec<Transition>,
+    #[serde(skip)]
+    current: Option<(Phase, Instant)>,
+    #[serde(skip)]
+    bytes: u64,
+    #[serde(skip)]
+    window: (u64, Duration),
+}
+
+impl Stats {
+    /// Ends the running phase, if any, and starts timing `phase`.
+    pub fn enter(&mut self, phase: Phase) {
+        self.end_phase();
+        self.current = Some((phase, Instant::now()));
+    }
+
+    /// Counts one object download of `bytes` over the wire that took `took`.
+    pub fn downloaded(&mut self, bytes: u64, took: Duration) {
+        self.bytes += bytes;
+        self.download_secs += took.as_secs_f64();
+        self.window.0 += bytes;
+        self.window.1 += took;
+        if self.window.1 >= PEAK_WINDOW {
+            self.close_window();
+        }
+    }
+
+    /// Closes the running phase and works out the throughput figures.
+    pub fn finish(&mut self) {
+        self.end_phase();
+        if self.peak_bytes_per_sec.is_none() {
+            self.close_window();
+        }
+        self.avg_bytes_per_sec = rate(self.bytes, self.download_secs);
+    }
+
+    fn end_phase(&mut self) {
+        if let Some((phase, started)) = self.current.take() {
+            *self.phase_secs.entry(phase).or_default() += started.elapsed().as_secs_f64();
+        }
+    }
+
+    fn close_window(&mut self) {
+        let (bytes, took) = std::mem::take(&mut self.window);
+        if let Some(rate) = rate(bytes, took.as_secs_f64()) {
+            self.peak_bytes_per_sec = Some(self.peak_bytes_per_sec.map_or(rate, |p| p.max(rate)));
+        }
+    }
+}
+
+fn rate(bytes: u64, secs: f64) -> Option<f64> {
+    (bytes > 0 && secs > 0.0).then(|| bytes as f64 / secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peak_needs_a_full_window_unless_the_run_was_shorter() {
+        let mut stats = Stats::default();
+        stats.downloaded(1000, Duration::from_millis(500));
+        stats.downloaded(1000, Duration::from_millis(500));
+        // A quick small object after the window must not set the peak.
+        stats.downloaded(100, Duration::from_millis(1));
+        stats.finish();
+        assert_eq!(stats.peak_bytes_per_sec, Some(2000.0));
+        let avg = stats.avg_bytes_per_sec.unwrap();
+        assert!((avg - 2100.0 / 1.001).abs() < 0.01, "{avg}");
+
+        let mut short = Stats::default();
+        short.downloaded(300, Duration::from_millis(100));
+        short.finish();
+        assert_eq!(short.peak_bytes_per_sec, Some(3000.0));
+
+        let mut none = Stats::default();
+        none.enter(Phase::Manifest);
+        none.enter(Phase::Switch);
+        none.finish();
+        assert_eq!(none.avg_bytes_per_sec, None);
+        assert_eq!(none.peak_bytes_per_sec, None);
+        let phases: Vec<Phase> = none.phase_secs.keys().copied().collect();
+        assert_eq!(phases, [Phase::Manifest, Phase::Switch]);
+    }
+}
@@ -0,0 +1,350 @@
+//! `cityfeed-puller status`: what is going on in a root. It reports the live
+//! version and when it was switched, whether `current` resolves, snapshot and
+//! object store sizes, temp debris from past or running deploys, and free
+//! space on the filesystem.
+//!
+//! Everything comes from walking the root and reading deploy-state.json and
+//! the deploy history.
+//! Nothing is written and the root lock is not taken, so it is safe to run
+//! during a deploy. Entries that disappear mid-walk are skipped.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::history::{self, Entry};
+use crate::logger::Logger;
+use crate::store::MAX_SHARD_DEPTH;
+use crate::{
+    current_version, prune, read_current, stale, state, Args, OutputFormat, EXIT_FAILURE,
+    PREVIOUS_LINK,
+};
+
+/// Deploy attempts listed from the history.
+const RECENT_DEPLOYS: usize = 5;
+
+/// The `--output json` document.
+#[derive(Debug, Serialize)]
+struct Report {
+    root: PathBuf,
+    /// Version `current` points at.
+    version: Option<String>,
+    /// `current` exists and leads to a snapshot directory.
+    current_ok: bool,
+    previous_version: Option<String>,
+    /// From deploy-state.json, when it describes `version`.
+    switched_at: Option<String>,
+    checked_at: Option<String>,
+    origin: Option<String>,
+    /// `--channel` the box follows, as of its last deploy or check.
+    channel: Option<String>,
+    snapshot_count: usize,
+    snapshot_bytes: u64,
+    snapshots: Vec<Snapshot>,
+    object_count: u64,
+    object_bytes: u64,
+    debris: Vec<Debris>,
+    /// The last few deploy attempts from state/history.jsonl, oldest first.
+    history: Vec<Entry>,
+    /// Space left for the puller's user; `None` if it couldn't be queried.
+    free_bytes: Option<u64>,
+    fs_bytes: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct Snapshot {
+    version: String,
+    bytes: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct Debris {
+    path: PathBuf,
+    /// A running deploy still owns it.
+    live: bool,
+}
+
+/// Prints the status of `args.root()` and returns the exit code: 0 once a
+/// report is printed, whatever it says.
+pub fn main(args: &Args, log: &Logger) -> i32 {
+    let report = match report(args.root()) {
+        Ok(report) => report,
+        Err(err) => {
+            log.error(
+                "status_failed",
+                json!({ "error": format!("{err:#}") }),
+                format_args!("status failed: {err:#}"),
+            );
+            return EXIT_FAILURE;
+        }
+    };
+    match args.output {
+        OutputFormat::Json => match serde_json::to_string(&report) {
+            Ok(doc) => println!("{doc}"),
+            Err(err) => {
+                log.error(
+                    "summary_failed",
+                    json!({ "error": err.to_string() }),
+                    format_args!("render summary: {err}"),
+                );
+                return EXIT_FAILURE;
+            }
+        },
+        OutputFormat::Text => print!("{}", render(&report)),
+    }
+    0
+}
+
+fn report(root: &Path) -> Result<Report> {
+    if !root.is_dir() {
+        bail!("no root at {}", root.display());
+    }
+    let current = root.join("current");
+    let version = current_version(&current);
+    let current_ok = read_current(&current)
+        .ok()
+        .flatten()
+        .is_some_and(|target| root.join(target).is_dir());
+    let deploy_state = state::read(root).filter(|s| Some(&s.version) == version.as_ref());
+
+    let snapshots_dir = root.join("snapshots");
+    let objects_dir = root.join("objects");
+    let snapshots = snapshots(&snapshots_dir);
+    let (object_count, object_bytes) = objects(&objects_dir, 0);
+    let debris = stale::leftovers(root, &objects_dir, &snapshots_dir)?
+        .into_iter()
+        .map(|leftover| Debris {
+            path: leftover.path,
+            live: leftover.live,
+        })
+        .collect();
+
+    Ok(Report {
+        root: root.to_path_buf(),
+        version,
+        current_ok,
+        previous_version: current_version(&root.join(PREVIOUS_LINK)),
+        switched_at: deploy_state.as_ref().and_then(|s| s.switched_at.clone()),
+        checked_at: deploy_state.as_ref().map(|s| s.checked_at.clone()),
+        channel: deploy_state.as_ref().and_then(|s| s.channel.clone()),
+        origin: deploy_state.map(|s| s.origin),
+        snapshot_count: snapshots.len(),
+        snapshot_bytes: snapshots.iter().map(|s| s.bytes).sum(),
+        snapshots,
+        object_count,
+        object_bytes,
+        debris,
+        history: history::recent(root, RECENT_DEPLOYS),
+        free_bytes: fs4::available_space(root).ok(),
+        fs_bytes: fs4::total_space(root).ok(),
+    })
+}
+
+/// Every snapshot directory, by version name. Staging dirs are debris.
+fn snapshots(dir: &Path) -> Vec<Snapshot> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut snapshots: Vec<Snapshot> = entries
+        .flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_dir()))
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|version| !version.starts_with('.'))
+        .map(|version| Snapshot {
+            bytes: prune::disk_bytes(&dir.join(&version)),
+            version,
+        })
+        .collect();
+    snapshots.sort_by(|a, b| a.version.cmp(&b.version));
+    snapshots
+}
+
+/// Count and total size of the objects under `dir`, descending into shard
+/// directories. Hidden entries (temps, the layout marker, quarantine) are
+/// not objects.
+fn objects(dir: &Path, depth: usize) -> (u64, u64) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return (0, 0);
+    };
+    let mut total = (0, 0);
+    for entry in entries.flatten() {
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let (count, bytes) = match entry.file_type() {
+            Ok(t) if t.is_dir() && depth < MAX_SHARD_DEPTH => objects(&entry.path(), depth + 1),
+            Ok(t) if t.is_file() => (1, entry.metadata().map_or(0, |meta| meta.len())),
+            _ => (0, 0),
+        };
+        total.0 += count;
+        total.1 += bytes;
+    }
+    total
+}
+
+/// The text table.
+fn render(report: &Report) -> String {
+    let mut out = String::new();
+    let mut row = |label: &str, value: String| out.push_str(&format!("{label:<12} {value}\n"));
+    row("root", report.root.display().to_string());
+    row(
+        "current",
+        match (&report.version, report.current_ok) {
+            (Some(version), true) => version.clone(),
+            (Some(version), false) => format!("{version} (BROKEN: snapshot missing)"),
+            (None, true) => "(not a snapshot)".to_string(),
+            (None, false) => "(none)".to_string(),
+        },
+    );
+    row(
+        "previous",
+        report
+            .previous_version
+            .as_deref()
+            .unwrap_or("-")
+            .to_string(),
+    );
+    row(
+        "switched at",
+        report
+            .switched_at
+            .as_deref()
+            .unwrap_or("unknown")
+            .to_string(),
+    );
+    row(
+        "origin",
+        report.origin.as_deref().unwrap_or("-").to_string(),
+    );
+    row(
+        "channel",
+        report.channel.as_deref().unwrap_or("-").to_string(),
+    );
+    row(
+        "snapshots",
+        format!(
+            "{} ({} bytes)",
+            report.snapshot_count, report.snapshot_bytes
+        ),
+    );
+    for snapshot in &report.snapshots {
+        row(
+            "",
+            format!("{:<24} {:>14} bytes", snapshot.version, snapshot.bytes),
+        );
+    }
+    row(
+        "objects",
+        format!("{} ({} bytes)", report.object_count, report.object_bytes),
+    );
+    row(
+        "debris",
+        match report.debris.len() {
+            0 => "none".to_string(),
+            n => format!("{n} entries"),
+        },
+    );
+    for debris in &report.debris {
+        let owner = if debris.live { "live deploy" } else { "stale" };
+        row("", format!("{} ({owner})", debris.path.display()));
+    }
+    row(
+        "deploys",
+        match report.history.len() {
+            0 => "no history".to_string(),
+            n => format!("last {n}"),
+        },
+    );
+    for entry in &report.history {
+        let versions = match (&entry.previous_version, &entry.version) {
+            (Some(from), Some(to)) if from != to => format!("{from} -> {to}"),
+            (_, Some(version)) => version.clone(),
+            (_, None) => "-".to_string(),
+        };
+        row(
+            "",
+            format!("{}  {:<15} {versions}", entry.at, entry.outcome),
+        );
+    }
+    row(
+        "free space",
+        match (report.free_bytes, report.fs_bytes) {
+            (Some(free), Some(total)) => format!("{free} of {total} bytes"),
+            (Some(free), None) => format!("{free} bytes"),
+            _ => "unknown".to_string(),
+        },
+    );
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_objects_in_both_layouts_and_skips_hidden_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let objects_dir = dir.path();
+        fs::write(objects_dir.join("aa11"), "four").unwrap();
+        fs::create_dir(objects_dir.join("bb")).unwrap();
+        fs::write(objects_dir.join("bb/bb22"), "sixsix").unwrap();
+        fs::write(objects_dir.join(".layout"), "sharded").unwrap();
+        fs::write(objects_dir.join(".tmp-1-x"), "partial").unwrap();
+        fs::create_dir(objects_dir.join(".quarantine")).unwrap();
+        fs::write(objects_dir.join(".quarantine/cc33"), "bad").unwrap();
+        assert_eq!(objects(objects_dir, 0), (2, 10));
+        assert_eq!(objects(&objects_dir.join("missing"), 0), (0, 0));
+    }
+
+    #[test]
+    fn table_flags_a_broken_current() {
+        let report = Report {
+            root: PathBuf::from("/var/www/mspmetro"),
+            version: Some("v2".into()),
+            current_ok: false,
+            previous_version: None,
+            switched_at: None,
+            checked_at: None,
+            origin: None,
+            channel: Some("beta".into()),
+            snapshot_count: 0,
+            snapshot_bytes: 0,
+            snapshots: Vec::new(),
+            object_count: 0,
+            object_bytes: 0,
+            debris: vec![Debris {
+                path: PathBuf::from("/var/www/mspmetro/snapshots/.v2.staging-9-x"),
+                live: false,
+            }],
+            history: vec![Entry {
+                at: "2026-10-16T08:30:00Z".into(),
+                outcome: "updated".into(),
+                previous_version: Some("v1".into()),
+                version: Some("v2".into()),
+                origin: None,
+                channel: Some("beta".into()),
+                objects_downloaded: 0,
+                bytes_downloaded: 0,
+                preserved: Vec::new(),
+                error: None,
+            }],
+            free_bytes: Some(10),
+            fs_bytes: Some(100),
+        };
+        let text = render(&report);
+        assert!(
+            text.contains("current      v2 (BROKEN: snapshot missing)\n"),
+            "{text}"
+        );
+        assert!(text.contains("channel      beta\n"), "{text}");
+        assert!(text.contains("snapshots/.v2.staging-9-x (stale)"), "{text}");
+        assert!(
+            text.contains("2026-10-16T08:30:00Z  updated         v1 -> v2\n"),
+            "{text}"
+        );
+        assert!(text.contains("free space   10 of 100 bytes\n"), "{text}");
+    }
+}
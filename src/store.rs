@@ -0,0 +1,384 @@
+//! Where objects live, locally under `root/objects` and remotely under
+//! `<origin>/objects`. Both sides share one layout, so everything that turns a
+//! hash into a path or URL goes through `ObjectStore`.
+//!
+//! - `flat`: `objects/<hash>`
+//! - `sharded`: `objects/<first two chars>/<hash>`, for buckets (and local
+//!   directories) holding millions of objects.
+//!
+//! A root can also keep its objects `nested`, at `objects/ab/cd/<hash>`,
+//! whatever the origins use. That layout is local only: `migrate-objects`
+//! switches a root to it, and deploys leave it alone from then on.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::logger::Logger;
+use crate::{ensure_dir, fsync_dir, validate_hash};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ObjectLayout {
+    #[default]
+    Flat,
+    Sharded,
+}
+
+impl ObjectLayout {
+    fn as_str(self) -> &'static str {
+        match self {
+            ObjectLayout::Flat => "flat",
+            ObjectLayout::Sharded => "sharded",
+        }
+    }
+
+    /// Location of `hash` relative to `objects/`, with `/` separators.
+    pub fn rel_path(self, hash: &str) -> String {
+        match self {
+            ObjectLayout::Flat => hash.to_string(),
+            ObjectLayout::Sharded => format!("{}/{hash}", shard(hash)),
+        }
+    }
+}
+
+fn shard(hash: &str) -> &str {
+    hash.get(..2).unwrap_or(hash)
+}
+
+/// The second level of the nested layout: hex chars 3 and 4.
+fn subshard(hash: &str) -> &str {
+    hash.get(2..4).unwrap_or("")
+}
+
+/// Records which layout `objects/` is in, so migration only runs on a change.
+const LAYOUT_MARKER: &str = ".layout";
+const NESTED: &str = "nested";
+
+/// Directory levels above an object in any layout.
+pub const MAX_SHARD_DEPTH: usize = 2;
+
+pub struct ObjectStore {
+    dir: PathBuf,
+    /// How origins lay out objects, and this root too unless `nested`.
+    layout: ObjectLayout,
+    nested: bool,
+}
+
+impl ObjectStore {
+    pub fn new(dir: PathBuf, layout: ObjectLayout) -> Self {
+        Self {
+            dir,
+            layout,
+            nested: false,
+        }
+    }
+
+    /// This store, kept at `objects/ab/cd/<hash>` locally.
+    pub fn nested(self) -> Self {
+        Self {
+            nested: true,
+            ..self
+        }
+    }
+
+    /// The store in whatever layout `dir` is in now.
+    pub fn open(dir: PathBuf) -> Result<Self> {
+        let nested = is_nested(&dir)?;
+        let mut store = Self::new(dir, ObjectLayout::Flat);
+        store.layout = store.on_disk_layout()?;
+        store.nested = nested;
+        Ok(store)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn path(&self, hash: &str) -> PathBuf {
+        if self.nested {
+            return self.dir.join(shard(hash)).join(subshard(hash)).join(hash);
+        }
+        match self.layout {
+            ObjectLayout::Flat => self.dir.join(hash),
+            ObjectLayout::Sharded => self.dir.join(shard(hash)).join(hash),
+        }
+    }
+
+    pub fn url(&self, origin: &str, hash: &str) -> String {
+        format!("{origin}/objects/{}", self.layout.rel_path(hash))
+    }
+
+    /// Layout `objects/` is currently in; roots from before sharding are flat.
+    fn on_disk_layout(&self) -> Result<ObjectLayout> {
+        match read_marker(&self.dir)?.as_deref() {
+            Some("sharded") => Ok(ObjectLayout::Sharded),
+            _ => Ok(ObjectLayout::Flat),
+        }
+    }
+
+    /// Moves existing objects into this store's layout if `objects/` is in
+    /// the other one. Interrupted migrations resume on the next run: objects
+    /// are moved one rename at a time and the marker is written last.
+    /// A nested root stays nested.
+    pub fn migrate(&self, log: &Logger) -> Result<usize> {
+        if self.nested || is_nested(&self.dir)? {
+            return Ok(0);
+        }
+        let from = self.on_disk_layout()?;
+        if from == self.layout {
+            return Ok(0);
+        }
+        let mut moved = 0;
+        for entry in
+            fs::read_dir(&self.dir).with_context(|| format!("list {}", self.dir.display()))?
+        {
+            let entry = entry.with_context(|| format!("list {}", self.dir.display()))?;
+            let name = entry.file_name();
+            if name.to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let file_type = entry.file_type().context("stat object entry")?;
+            match self.layout {
+                ObjectLayout::Sharded if file_type.is_file() => {
+                    let hash = name.to_string_lossy();
+                    let dst = self.path(&hash);
+                    ensure_dir(dst.parent().unwrap())?;
+                    fs::rename(entry.path(), &dst).with_context(|| {
+                        format!("move {} -> {}", entry.path().display(), dst.display())
+                    })?;
+                    moved += 1;
+                }
+                ObjectLayout::Flat if file_type.is_dir() => {
+                    let shard_dir = entry.path();
+                    for obj in fs::read_dir(&shard_dir)
+                        .with_context(|| format!("list {}", shard_dir.display()))?
+                    {
+                        let obj = obj.with_context(|| format!("list {}", shard_dir.display()))?;
+                        let dst = self.dir.join(obj.file_name());
+                        fs::rename(obj.path(), &dst).with_context(|| {
+                            format!("move {} -> {}", obj.path().display(), dst.display())
+                        })?;
+                        moved += 1;
+                    }
+                    fs::remove_dir(&shard_dir)
+                        .with_context(|| format!("remove {}", shard_dir.display()))?;
+                }
+                _ => {}
+            }
+        }
+
+        write_marker(&self.dir, self.layout.as_str())?;
+
+        log.info(
+            "objects_migrated",
+            json!({ "from": from.as_str(), "to": self.layout.as_str(), "moved": moved }),
+            format_args!(
+                "migrated {moved} objects from {} to {} layout",
+                from.as_str(),
+                self.layout.as_str()
+            ),
+        );
+        Ok(moved)
+    }
+}
+
+fn read_marker(dir: &Path) -> Result<Option<String>> {
+    let marker = dir.join(LAYOUT_MARKER);
+    match fs::read_to_string(&marker) {
+        Ok(text) => Ok(Some(text.trim().to_string())),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("read {}", marker.display())),
+    }
+}
+
+fn write_marker(dir: &Path, layout: &str) -> Result<()> {
+    let marker = dir.join(LAYOUT_MARKER);
+    let mut tmp = tempfile::NamedTempFile::new_in(dir).context("create layout marker")?;
+    writeln!(tmp, "{layout}").context("write layout marker")?;
+    tmp.persist(&marker)
+        .with_context(|| format!("persist {}", marker.display()))?;
+    fsync_dir(dir).context("fsync objects dir")
+}
+
+/// Whether `migrate-objects` has switched `dir` to the nested layout.
+pub fn is_nested(dir: &Path) -> Result<bool> {
+    Ok(read_marker(dir)?.as_deref() == Some(NESTED))
+}
+
+/// What `nest` did.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Nested {
+    pub moved: u64,
+    /// Left where they were: names that aren't sha256 hashes.
+    pub skipped: u64,
+}
+
+/// Moves every flat or sharded object in `dir` to `objects/ab/cd/<hash>`.
+///
+/// The marker is written first, so from then on deploys look objects up at
+/// their nested paths. Each object is hard-linked there before its old name
+/// is removed, so it is always reachable under one of them. If this is
+/// interrupted, deploys download the objects not yet moved again, and the
+/// next call finishes the move.
+pub fn nest(dir: &Path) -> Result<Nested> {
+    write_marker(dir, NESTED)?;
+    let store = ObjectStore::new(dir.to_path_buf(), ObjectLayout::Flat).nested();
+    let mut nested = Nested::default();
+    for entry in fs::read_dir(dir).with_context(|| format!("list {}", dir.display()))? {
+        let entry = entry.with_context(|| format!("list {}", dir.display()))?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        let file_type = entry
+            .file_type()
+            .with_context(|| format!("stat {}", entry.path().display()))?;
+        if file_type.is_file() {
+            nest_one(&store, &entry.path(), &name, &mut nested)?;
+        } else if file_type.is_dir() {
+            // A sharded `ab/` is also the nested layout's first level; only
+            // its files move, into `ab/cd/`.
+            let shard_dir = entry.path();
+            let mut moved_here = false;
+            for obj in
+                fs::read_dir(&shard_dir).with_context(|| format!("list {}", shard_dir.display()))?
+            {
+                let obj = obj.with_context(|| format!("list {}", shard_dir.display()))?;
+                let obj_name = obj.file_name().to_string_lossy().into_owned();
+                let is_file = obj
+                    .file_type()
+                    .with_context(|| format!("stat {}", obj.path().display()))?
+                    .is_file();
+                if is_file && !obj_name.starts_with('.') {
+                    nest_one(&store, &obj.path(), &obj_name, &mut nested)?;
+                    moved_here = true;
+                }
+            }
+            if moved_here {
+                fsync_dir(&shard_dir).context("fsync objects dir")?;
+            }
+        }
+    }
+    fsync_dir(dir).context("fsync objects dir")?;
+    Ok(nested)
+}
+
+fn nest_one(store: &ObjectStore, src: &Path, hash: &str, nested: &mut Nested) -> Result<()> {
+    if validate_hash(hash).is_err() {
+        nested.skipped += 1;
+        return Ok(());
+    }
+    let dst = store.path(hash);
+    ensure_dir(dst.parent().unwrap())?;
+    match fs::hard_link(src, &dst) {
+        // Linked by an earlier, interrupted run, or downloaded again since.
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+        res => res.with_context(|| format!("link {} -> {}", src.display(), dst.display()))?,
+    }
+    fs::remove_file(src).with_context(|| format!("remove {}", src.display()))?;
+    nested.moved += 1;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layouts_resolve_paths_and_urls_the_same_way() {
+        let flat = ObjectStore::new(PathBuf::from("/r/objects"), ObjectLayout::Flat);
+        let sharded = ObjectStore::new(PathBuf::from("/r/objects"), ObjectLayout::Sharded);
+        assert_eq!(flat.path("abcdef"), Path::new("/r/objects/abcdef"));
+        assert_eq!(sharded.path("abcdef"), Path::new("/r/objects/ab/abcdef"));
+        assert_eq!(flat.url("https://o", "abcdef"), "https://o/objects/abcdef");
+        assert_eq!(
+            sharded.url("https://o", "abcdef"),
+            "https://o/objects/ab/abcdef"
+        );
+    }
+
+    #[test]
+    fn migrate_moves_objects_both_ways() {
+        let dir = tempfile::tempdir().unwrap();
+        let objects = dir.path().to_path_buf();
+        fs::write(objects.join("aa11"), b"1").unwrap();
+        fs::write(objects.join("bb22"), b"2").unwrap();
+        fs::write(objects.join(".tmp-1-x"), b"partial").unwrap();
+        let log = Logger::new(crate::logger::LogFormat::Text, -1);
+
+        let sharded = ObjectStore::new(objects.clone(), ObjectLayout::Sharded);
+        assert_eq!(sharded.migrate(&log).unwrap(), 2);
+        assert_eq!(fs::read(objects.join("aa/aa11")).unwrap(), b"1");
+        assert_eq!(fs::read(objects.join("bb/bb22")).unwrap(), b"2");
+        assert!(!objects.join("aa11").exists());
+        assert!(objects.join(".tmp-1-x").exists());
+        assert_eq!(sharded.migrate(&log).unwrap(), 0);
+
+        let flat = ObjectStore::new(objects.clone(), ObjectLayout::Flat);
+        assert_eq!(flat.migrate(&log).unwrap(), 2);
+        assert_eq!(fs::read(objects.join("aa11")).unwrap(), b"1");
+        assert!(!objects.join("aa").exists());
+        assert_eq!(
+            fs::read_to_string(objects.join(LAYOUT_MARKER)).unwrap(),
+            "flat\n"
+        );
+    }
+
+    #[test]
+    fn nest_moves_flat_and_sharded_objects_and_resumes() {
+        let dir = tempfile::tempdir().unwrap();
+        let objects = dir.path().to_path_buf();
+        let (a, b, c) = ("a1".repeat(32), "b2".repeat(32), "c3".repeat(32));
+        fs::write(objects.join(&a), b"1").unwrap();
+        fs::create_dir(objects.join("b2")).unwrap();
+        fs::write(objects.join("b2").join(&b), b"2").unwrap();
+        // An interrupted run already linked c but didn't remove the old name.
+        fs::write(objects.join(&c), b"3").unwrap();
+        fs::create_dir_all(objects.join("c3/c3")).unwrap();
+        fs::hard_link(objects.join(&c), objects.join("c3/c3").join(&c)).unwrap();
+        fs::write(objects.join("README"), b"?").unwrap();
+        fs::write(objects.join(".tmp-1-x"), b"partial").unwrap();
+
+        let nested = nest(&objects).unwrap();
+        assert_eq!(
+            nested,
+            Nested {
+                moved: 3,
+                skipped: 1
+            }
+        );
+        assert_eq!(fs::read(objects.join("a1/a1").join(&a)).unwrap(), b"1");
+        assert_eq!(fs::read(objects.join("b2/b2").join(&b)).unwrap(), b"2");
+        assert_eq!(fs::read(objects.join("c3/c3").join(&c)).unwrap(), b"3");
+        for old in [
+            objects.join(&a),
+            objects.join("b2").join(&b),
+            objects.join(&c),
+        ] {
+            assert!(!old.exists(), "{}", old.display());
+        }
+        assert!(objects.join("README").exists());
+        assert!(objects.join(".tmp-1-x").exists());
+        assert_eq!(
+            nest(&objects).unwrap(),
+            Nested {
+                moved: 0,
+                skipped: 1
+            }
+        );
+
+        // Deploys keep the nested layout whatever the manifest says.
+        let log = Logger::new(crate::logger::LogFormat::Text, -1);
+        let opened = ObjectStore::open(objects.clone()).unwrap();
+        assert_eq!(opened.path(&a), objects.join("a1/a1").join(&a));
+        let sharded = ObjectStore::new(objects.clone(), ObjectLayout::Sharded);
+        assert_eq!(sharded.migrate(&log).unwrap(), 0);
+        assert!(is_nested(&objects).unwrap());
+    }
+}
@@ -0,0 +1,249 @@
+//! Moving `current` (and `previous`) between snapshots. Readers of the root
+//! always see a complete snapshot: links are replaced through a temp link
+//! and a rename.
+
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+#[cfg(not(any(unix, windows)))]
+use anyhow::bail;
+use anyhow::{Context, Result};
+
+use crate::fsync_dir;
+
+/// Whether `current` points at `target_rel`.
+pub fn current_points_to(current: &Path, target_rel: &Path) -> Result<bool> {
+    Ok(read_current(current)?.as_deref() == Some(target_rel))
+}
+
+/// Target of `current` relative to the root (`snapshots/<version>`), or `None`
+/// when nothing has been deployed yet.
+pub fn read_current(current: &Path) -> Result<Option<PathBuf>> {
+    #[cfg(windows)]
+    {
+        let pointer = current.with_file_name(CURRENT_POINTER);
+        match fs::read_to_string(&pointer) {
+            Ok(text) => return Ok(Some(PathBuf::from(text.trim()))),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => {
+                return Err(err).with_context(|| format!("read {}", pointer.display()));
+            }
+        }
+    }
+
+    match fs::read_link(current) {
+        Ok(link) => Ok(Some(link)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("readlink {}", current.display())),
+    }
+}
+
+/// Version `current` points at, if it points into `snapshots/`.
+pub fn current_version(current: &Path) -> Option<String> {
+    let target = read_current(current).ok()??;
+    let mut parts = target.components();
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(Component::Normal(dir)), Some(Component::Normal(version)), None)
+            if dir == "snapshots" =>
+        {
+            Some(version.to_string_lossy().into_owned())
+        }
+        _ => None,
+    }
+}
+
+/// Where `current` pointed before the last switch, for a manual rollback.
+pub const PREVIOUS_LINK: &str = "previous";
+
+/// Name of the pointer file used on Windows when directory symlinks can't be created.
+#[cfg(windows)]
+pub const CURRENT_POINTER: &str = "current.pointer";
+
+/// Points `current` at `target_rel` (relative to `root`), moving `previous`
+/// to the old target first.
+pub fn switch_symlink_atomically(current: &Path, target_rel: &Path, root: &Path) -> Result<()> {
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = (current, target_rel, root);
+        bail!("symlink switching is only implemented for unix and windows platforms");
+    }
+
+    #[cfg(windows)]
+    {
+        switch_current_windows(current, target_rel, root)
+    }
+
+    #[cfg(unix)]
+    {
+        // `previous` moves first: a crash in between leaves both links on
+        // the old snapshot rather than losing track of it.
+        if let Some(old) = read_current(current).ok().flatten() {
+            if old != target_rel {
+                set_previous(root, Some(&old)).context("update previous symlink")?;
+            }
+        }
+        replace_symlink(current, target_rel, root)
+    }
+}
+
+/// Points `<root>/previous` at `target`, or removes it for `None`. Only
+/// kept on unix.
+pub fn set_previous(root: &Path, target: Option<&Path>) -> Result<()> {
+    let link = root.join(PREVIOUS_LINK);
+    match target {
+        #[cfg(unix)]
+        Some(target) => replace_symlink(&link, target, root),
+        #[cfg(not(unix))]
+        Some(_) => Ok(()),
+        None => match fs::remove_file(&link) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                Err(err).with_context(|| format!("remove {}", link.display()))
+            }
+            _ => Ok(()),
+        },
+    }
+}
+
+/// Replaces `link` (in `root`) with a symlink to `target` through a temp
+/// link and a rename, so readers always see one or the other.
+#[cfg(unix)]
+fn replace_symlink(link: &Path, target: &Path, root: &Path) -> Result<()> {
+    use std::os::unix::fs as unix_fs;
+
+    let name = link.file_name().unwrap_or_default().to_string_lossy();
+    let tmp_link = root.join(format!(".{name}.new.{}", std::process::id()));
+
+    let _ = fs::remove_file(&tmp_link);
+    unix_fs::symlink(target, &tmp_link).with_context(|| {
+        format!(
+            "create symlink {} -> {}",
+            tmp_link.display(),
+            target.display()
+        )
+    })?;
+
+    fs::rename(&tmp_link, link).with_context(|| {
+        format!(
+            "rename symlink {} -> {}",
+            tmp_link.display(),
+            link.display()
+        )
+    })?;
+
+    fsync_dir(root).context("fsync root dir")?;
+    Ok(())
+}
+
+/// Uses a directory symlink when the process holds the privilege to create one
+/// (Administrator or Developer Mode); otherwise records the target in
+/// `current.pointer`, which the serving side has to read itself.
+#[cfg(windows)]
+fn switch_current_windows(current: &Path, target_rel: &Path, root: &Path) -> Result<()> {
+    use std::os::windows::fs as windows_fs;
+
+    // ERROR_PRIVILEGE_NOT_HELD
+    const NO_SYMLINK_PRIVILEGE: i32 = 1314;
+
+    let tmp_link = root.join(format!(".current.new.{}", std::process::id()));
+    let _ = fs::remove_dir(&tmp_link);
+    match windows_fs::symlink_dir(target_rel, &tmp_link) {
+        Ok(()) => {}
+        Err(err) if err.raw_os_error() == Some(NO_SYMLINK_PRIVILEGE) => {
+            return write_current_pointer(root, target_rel);
+        }
+        Err(err) => {
+            return Err(err).with_context(|| {
+                format!(
+                    "create symlink {} -> {}",
+                    tmp_link.display(),
+                    target_rel.display()
+                )
+            });
+        }
+    }
+
+    // MoveFileEx(REPLACE_EXISTING) won't replace a directory, and a directory
+    // symlink counts as one, so the old link has to go first. Readers may see
+    // `current` missing for that instant.
+    if fs::rename(&tmp_link, current).is_err() {
+        if let Err(err) = fs::remove_dir(current) {
+            if err.kind() != io::ErrorKind::NotFound {
+                let _ = fs::remove_dir(&tmp_link);
+                return Err(err).with_context(|| format!("remove {}", current.display()));
+            }
+        }
+        fs::rename(&tmp_link, current).with_context(|| {
+            format!(
+                "rename symlink {} -> {}",
+                tmp_link.display(),
+                current.display()
+            )
+        })?;
+    }
+
+    // A stale pointer from an earlier unprivileged run would shadow the link.
+    match fs::remove_file(root.join(CURRENT_POINTER)) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err).context("remove stale current.pointer"),
+    }
+}
+
+/// Atomically replaces `current.pointer` (temp file + MoveFileEx(REPLACE_EXISTING)).
+#[cfg(windows)]
+fn write_current_pointer(root: &Path, target_rel: &Path) -> Result<()> {
+    use std::io::Write;
+
+    let pointer = root.join(CURRENT_POINTER);
+    let text: Vec<String> = target_rel
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    let mut tmp = tempfile::NamedTempFile::new_in(root).context("create temp pointer file")?;
+    writeln!(tmp, "{}", text.join("/")).context("write pointer file")?;
+    tmp.as_file_mut()
+        .sync_all()
+        .context("fsync pointer temp file")?;
+    tmp.persist(&pointer)
+        .map_err(|e| e.error)
+        .with_context(|| format!("replace {}", pointer.display()))?;
+    Ok(())
+}
+
+#[cfg(all(test, windows))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows_pointer_flow_switches_and_short_circuits() {
+        let root = tempfile::tempdir().unwrap();
+        let current = root.path().join("current");
+        let v1 = PathBuf::from("snapshots").join("v1");
+        let v2 = PathBuf::from("snapshots").join("v2");
+
+        assert!(!current_points_to(&current, &v1).unwrap());
+        write_current_pointer(root.path(), &v1).unwrap();
+        assert!(current_points_to(&current, &v1).unwrap());
+        assert!(!current_points_to(&current, &v2).unwrap());
+
+        write_current_pointer(root.path(), &v2).unwrap();
+        assert!(current_points_to(&current, &v2).unwrap());
+        assert_eq!(
+            fs::read_to_string(root.path().join(CURRENT_POINTER)).unwrap(),
+            "snapshots/v2\n"
+        );
+    }
+
+    #[test]
+    fn windows_switch_updates_current_either_way() {
+        let root = tempfile::tempdir().unwrap();
+        let current = root.path().join("current");
+        for v in ["v1", "v2"] {
+            let target = PathBuf::from("snapshots").join(v);
+            fs::create_dir_all(root.path().join(&target)).unwrap();
+            switch_symlink_atomically(&current, &target, root.path()).unwrap();
+            assert!(current_points_to(&current, &target).unwrap());
+        }
+    }
+}
@@ -0,0 +1,334 @@
+//! `--otlp-endpoint` (cargo feature `otlp`): one trace per deploy, posted as
+//! OTLP/HTTP JSON to `<endpoint>/v1/traces` when the run ends. A `deploy` root
+//! span carries the manifest version; the manifest fetch, every object
+//! download, staging and the switch are its children.
+//!
+//! Spans are buffered in memory, so a slow collector only delays the end of
+//! the run, never a download. Without an endpoint `Trace` is empty and every
+//! span is a no-op that doesn't even read the clock.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use reqwest::blocking::Client;
+use serde_json::{json, Value};
+
+use crate::ensure_success;
+use crate::logger::Logger;
+
+/// Like webhooks, a dead collector must not hold up the next run.
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AttrValue {
+    Str(String),
+    Int(i64),
+}
+
+impl From<&str> for AttrValue {
+    fn from(s: &str) -> Self {
+        AttrValue::Str(s.to_string())
+    }
+}
+
+impl From<&String> for AttrValue {
+    fn from(s: &String) -> Self {
+        AttrValue::Str(s.clone())
+    }
+}
+
+impl From<u64> for AttrValue {
+    fn from(n: u64) -> Self {
+        AttrValue::Int(i64::try_from(n).unwrap_or(i64::MAX))
+    }
+}
+
+impl From<u32> for AttrValue {
+    fn from(n: u32) -> Self {
+        AttrValue::Int(n.into())
+    }
+}
+
+#[derive(Debug)]
+struct SpanData {
+    id: u64,
+    parent: Option<u64>,
+    name: &'static str,
+    start: SystemTime,
+    end: SystemTime,
+    attrs: Vec<(&'static str, AttrValue)>,
+    /// `None` once the span was marked ok.
+    error: Option<String>,
+}
+
+struct Inner {
+    endpoint: String,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    trace_id: u128,
+    root: Option<SpanData>,
+    done: Vec<SpanData>,
+}
+
+/// The trace for the current deploy, shared by everything that opens spans.
+#[derive(Clone, Default)]
+pub struct Trace {
+    inner: Option<Arc<Inner>>,
+}
+
+impl Trace {
+    pub fn new(endpoint: Option<&str>) -> Self {
+        Self {
+            inner: endpoint.map(|endpoint| {
+                Arc::new(Inner {
+                    endpoint: endpoint.trim_end_matches('/').to_string(),
+                    state: Mutex::default(),
+                })
+            }),
+        }
+    }
+
+    /// Starts a new trace, dropping anything left from the previous one.
+    pub fn begin(&self, name: &'static str) {
+        if let Some(inner) = &self.inner {
+            let mut state = lock(inner);
+            state.trace_id = (u128::from(random_id()) << 64) | u128::from(random_id());
+            state.root = Some(SpanData::open(None, name));
+            state.done.clear();
+        }
+    }
+
+    /// Opens a child of the root span.
+    pub fn span(&self, name: &'static str) -> Span {
+        let open = self.inner.as_ref().map(|inner| {
+            let parent = lock(inner).root.as_ref().map(|root| root.id);
+            (Arc::clone(inner), SpanData::open(parent, name))
+        });
+        Span { open }
+    }
+
+    pub fn root_attr(&self, key: &'static str, value: impl Into<AttrValue>) {
+        if let Some(inner) = &self.inner {
+            if let Some(root) = lock(inner).root.as_mut() {
+                root.attrs.push((key, value.into()));
+            }
+        }
+    }
+
+    /// Forgets the current trace, e.g. a watch cycle that found nothing to do.
+    pub fn discard(&self) {
+        if let Some(inner) = &self.inner {
+            let mut state = lock(inner);
+            state.root = None;
+            state.done.clear();
+        }
+    }
+
+    /// Ends the root span (failed if `error` is set) and posts the trace.
+    /// Export failures are logged as warnings only.
+    pub fn export(&self, client: &Client, log: &Logger, error: Option<String>) {
+        let Some(inner) = &self.inner else {
+            return;
+        };
+        let body = {
+            let mut state = lock(inner);
+            let Some(mut root) = state.root.take() else {
+                return;
+            };
+            root.end = SystemTime::now();
+            root.error = error;
+            let spans: Vec<SpanData> = std::iter::once(root).chain(state.done.drain(..)).collect();
+            payload(state.trace_id, &spans)
+        };
+        let url = format!("{}/v1/traces", inner.endpoint);
+        match post(client, &url, &body) {
+            Ok(()) => log.debug(
+                "trace_exported",
+                json!({ "url": &url }),
+                format_args!("exported trace to {url}"),
+            ),
+            Err(err) => log.warn(
+                "trace_export_failed",
+                json!({ "url": &url, "error": format!("{err:#}") }),
+                format_args!("export trace to {url} failed: {err:#}"),
+            ),
+        }
+    }
+}
+
+/// An open span. It is recorded when dropped: as failed unless `ok` was
+/// called first, so an early `?` return shows up as an error in the trace.
+pub struct Span {
+    open: Option<(Arc<Inner>, SpanData)>,
+}
+
+impl Span {
+    pub fn attr(&mut self, key: &'static str, value: impl Into<AttrValue>) {
+        if let Some((_, span)) = &mut self.open {
+            span.attrs.push((key, value.into()));
+        }
+    }
+
+    pub fn ok(mut self) {
+        if let Some((_, span)) = &mut self.open {
+            span.error = None;
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some((inner, mut span)) = self.open.take() {
+            span.end = SystemTime::now();
+            lock(&inner).done.push(span);
+        }
+    }
+}
+
+impl SpanData {
+    fn open(parent: Option<u64>, name: &'static str) -> Self {
+        let now = SystemTime::now();
+        Self {
+            id: random_id(),
+            parent,
+            name,
+            start: now,
+            end: now,
+            attrs: Vec::new(),
+            error: Some(format!("{name} did not complete")),
+        }
+    }
+
+    fn to_json(&self, trace_id: u128) -> Value {
+        let mut span = json!({
+            "traceId": format!("{trace_id:032x}"),
+            "spanId": format!("{:016x}", self.id),
+            "name": self.name,
+            // SPAN_KIND_INTERNAL
+            "kind": 1,
+            "startTimeUnixNano": unix_nanos(self.start).to_string(),
+            "endTimeUnixNano": unix_nanos(self.end).to_string(),
+            "attributes": attributes(&self.attrs),
+            "status": match &self.error {
+                // STATUS_CODE_OK / STATUS_CODE_ERROR
+                None => json!({ "code": 1 }),
+                Some(message) => json!({ "code": 2, "message": message }),
+            },
+        });
+        if let Some(parent) = self.parent {
+            span["parentSpanId"] = format!("{parent:016x}").into();
+        }
+        span
+    }
+}
+
+fn payload(trace_id: u128, spans: &[SpanData]) -> Value {
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": attributes(&[("service.name", "cityfeed-puller".into())]),
+            },
+            "scopeSpans": [{
+                "scope": { "name": "cityfeed-puller", "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans.iter().map(|span| span.to_json(trace_id)).collect::<Vec<_>>(),
+            }],
+        }],
+    })
+}
+
+fn attributes(attrs: &[(&'static str, AttrValue)]) -> Value {
+    attrs
+        .iter()
+        .map(|(key, value)| {
+            // OTLP JSON carries 64-bit integers as strings.
+            let value = match value {
+                AttrValue::Str(s) => json!({ "stringValue": s }),
+                AttrValue::Int(n) => json!({ "intValue": n.to_string() }),
+            };
+            json!({ "key": key, "value": value })
+        })
+        .collect()
+}
+
+fn post(client: &Client, url: &str, body: &Value) -> Result<()> {
+    let resp = client
+        .post(url)
+        .timeout(EXPORT_TIMEOUT)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body.to_string())
+        .send()
+        .context("send trace")?;
+    ensure_success(resp).context("trace export http status")?;
+    Ok(())
+}
+
+fn lock(inner: &Inner) -> MutexGuard<'_, State> {
+    inner.state.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn unix_nanos(at: SystemTime) -> u128 {
+    at.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0)
+}
+
+/// A non-zero id; `RandomState` is seeded per process and per call.
+fn random_id() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(unix_nanos(SystemTime::now()));
+    hasher.finish().max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disabled_trace_records_nothing() {
+        let trace = Trace::default();
+        trace.begin("deploy");
+        let mut span = trace.span("object.download");
+        span.attr("hash", "abc");
+        assert!(span.open.is_none());
+        span.ok();
+    }
+
+    #[test]
+    fn spans_are_children_of_the_root_and_fail_unless_ok() {
+        let trace = Trace::new(Some("http://collector:4318/"));
+        trace.begin("deploy");
+        trace.root_attr("cityfeed.version", "v1");
+        let mut ok = trace.span("manifest.fetch");
+        ok.attr("origin", "https://a");
+        ok.ok();
+        drop(trace.span("stage"));
+
+        let inner = trace.inner.as_ref().unwrap();
+        let state = lock(inner);
+        let root = state.root.as_ref().unwrap();
+        let doc = payload(state.trace_id, &state.done);
+        let spans = doc["resourceSpans"][0]["scopeSpans"][0]["spans"]
+            .as_array()
+            .unwrap();
+        assert_eq!(spans.len(), 2);
+        for span in spans {
+            assert_eq!(span["parentSpanId"], format!("{:016x}", root.id));
+            assert_eq!(span["traceId"], format!("{:032x}", state.trace_id));
+        }
+        assert_eq!(spans[0]["name"], "manifest.fetch");
+        assert_eq!(spans[0]["status"]["code"], 1);
+        assert_eq!(
+            spans[0]["attributes"][0],
+            json!({ "key": "origin", "value": { "stringValue": "https://a" } })
+        );
+        assert_eq!(spans[1]["status"]["code"], 2);
+        assert_eq!(spans[1]["status"]["message"], "stage did not complete");
+        assert_eq!(inner.endpoint, "http://collector:4318");
+    }
+}
@@ -0,0 +1,140 @@
+//! Objects shipped compressed: a manifest file entry with
+//! `"transfer": { "encoding": "zstd", "hash": "...", "size": M }` is fetched
+//! from `objects/<transfer hash>` and decoded into the store under its
+//! content hash. Both sides are checked, the compressed bytes first.
+
+use std::io::{self, Read, Write};
+
+use anyhow::{bail, Context, Result};
+
+use crate::hashing::Hashing;
+use crate::{BodyMismatch, ManifestTransfer};
+
+/// Decodes `body` (the compressed object) into `out`, checking the compressed
+/// bytes against `transfer` and the decoded bytes against `hash`/`size`.
+pub fn decode_into(
+    body: impl Read,
+    out: &mut impl Write,
+    transfer: &ManifestTransfer,
+    hash: &str,
+    size: u64,
+) -> Result<()> {
+    let mut decoder = Hashing::new(zstd::stream::read::Decoder::new(Hashing::new(body))?);
+    // A small body can decode to something huge; stop just past `size`.
+    let copied = io::copy(&mut (&mut decoder).take(size.saturating_add(1)), out);
+
+    // Read whatever the decoder left behind so a bad transfer hash is reported
+    // ahead of the decode error it usually causes.
+    let (decoded_hash, decoded_size, decoder) = decoder.finish();
+    let mut wire = decoder.finish().into_inner();
+    let drained = io::copy(&mut wire, &mut io::sink());
+    let (wire_hash, wire_size, _) = wire.finish();
+    if wire_size != transfer.size {
+        bail!(BodyMismatch(format!(
+            "object {hash} transfer size mismatch: expected {} got {wire_size}",
+            transfer.size
+        )));
+    }
+    if wire_hash != transfer.hash {
+        bail!(BodyMismatch(format!(
+            "object {hash} transfer hash mismatch: expected {} got {wire_hash}",
+            transfer.hash
+        )));
+    }
+    copied.with_context(|| format!("decode {} object {hash}", transfer.encoding))?;
+    drained.context("read object body")?;
+
+    if decoded_size > size {
+        bail!(BodyMismatch(format!(
+            "object {hash} decoded body exceeds declared size {size}"
+        )));
+    }
+    if decoded_size != size {
+        bail!(BodyMismatch(format!(
+            "object {hash} decoded size mismatch: expected {size} got {decoded_size}"
+        )));
+    }
+    if decoded_hash != hash {
+        bail!(BodyMismatch(format!(
+            "object {hash} decoded hash mismatch: got {decoded_hash}"
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hashing::sha256;
+
+    fn compressed(content: &[u8]) -> (Vec<u8>, ManifestTransfer) {
+        let wire = zstd::encode_all(content, 3).unwrap();
+        let transfer = ManifestTransfer {
+            encoding: "zstd".to_string(),
+            hash: sha256(&wire),
+            size: wire.len() as u64,
+        };
+        (wire, transfer)
+    }
+
+    #[test]
+    fn compressed_object_decodes_to_its_content() {
+        let content = "<li>stop</li>\n".repeat(500).into_bytes();
+        let (wire, transfer) = compressed(&content);
+        assert!(wire.len() < content.len());
+        let mut out = Vec::new();
+        decode_into(
+            &wire[..],
+            &mut out,
+            &transfer,
+            &sha256(&content),
+            content.len() as u64,
+        )
+        .unwrap();
+        assert_eq!(out, content);
+    }
+
+    #[test]
+    fn corrupted_body_fails_the_transfer_check() {
+        let content = "<li>stop</li>\n".repeat(500).into_bytes();
+        let (mut wire, transfer) = compressed(&content);
+        let mid = wire.len() / 2;
+        wire[mid] ^= 0xff;
+        let err = decode_into(
+            &wire[..],
+            &mut Vec::new(),
+            &transfer,
+            &sha256(&content),
+            content.len() as u64,
+        )
+        .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("transfer hash mismatch"),
+            "{err:#}"
+        );
+
+        let err = decode_into(
+            &wire[..mid],
+            &mut Vec::new(),
+            &transfer,
+            &sha256(&content),
+            content.len() as u64,
+        )
+        .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("transfer size mismatch"),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn wrong_content_hash_is_caught_after_decoding() {
+        let (wire, transfer) = compressed(b"hello");
+        let err =
+            decode_into(&wire[..], &mut Vec::new(), &transfer, &"0".repeat(64), 5).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("decoded hash mismatch"),
+            "{err:#}"
+        );
+    }
+}
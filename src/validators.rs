@@ -0,0 +1,229 @@
+//! `<root>/state/manifest-validators.json`: the `ETag` and `Last-Modified`
+//! each origin last sent with `latest.json`, so the next request for it can
+//! be conditional. Mirrors can disagree on both, so they are kept per origin.
+//! Each `--channel` other than `latest` has its own file,
+//! `manifest-validators.<channel>.json`.
+//!
+//! A `304 Not Modified` means the manifest is the version that origin served
+//! last time, which is read back from its record under `manifests/`. Without
+//! a record the request goes out unconditional, so a 304 always has something
+//! to stand for.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use reqwest::blocking::{RequestBuilder, Response};
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use serde::{Deserialize, Serialize};
+
+use crate::filter::PathFilter;
+use crate::{diff, fsync_dir};
+
+const VALIDATORS_DIR: &str = "state";
+const VALIDATORS_FILE: &str = "manifest-validators";
+
+/// What a response said about its own freshness.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Validators {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    pub fn of(resp: &Response) -> Self {
+        let header = |name| {
+            resp.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+        };
+        Self {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+
+    /// Makes `req` conditional: `If-None-Match` when there is an ETag, which
+    /// is exact, otherwise `If-Modified-Since` with the date as it was sent.
+    pub fn apply(&self, req: RequestBuilder) -> RequestBuilder {
+        match (&self.etag, &self.last_modified) {
+            (Some(etag), _) => req.header(IF_NONE_MATCH, etag),
+            (None, Some(date)) => req.header(IF_MODIFIED_SINCE, date),
+            (None, None) => req,
+        }
+    }
+}
+
+/// One origin's validators and the manifest version they were sent with.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub version: String,
+    #[serde(flatten)]
+    pub validators: Validators,
+}
+
+/// The validators file for one root, shared by every request of the process.
+#[derive(Debug)]
+pub struct ManifestValidators {
+    root: PathBuf,
+    path: PathBuf,
+    manifests_dir: PathBuf,
+    filter: PathFilter,
+    by_origin: Mutex<BTreeMap<String, Entry>>,
+}
+
+impl ManifestValidators {
+    /// Reads the file in `root`. A missing or unreadable one only costs the
+    /// next request its condition, so it starts out empty. `filter` is the
+    /// run's `--prefix`/`--only`/`--exclude`, which a usable record must share.
+    pub fn load(root: &Path, manifests_dir: &Path, filter: PathFilter, channel: &str) -> Self {
+        let path = path(root, channel);
+        let by_origin = fs::read_to_string(&path)
+            .ok()
+            .and_then(|text| serde_json::from_str(&text).ok())
+            .unwrap_or_default();
+        Self {
+            root: root.to_path_buf(),
+            path,
+            manifests_dir: manifests_dir.to_path_buf(),
+            filter,
+            by_origin: Mutex::new(by_origin),
+        }
+    }
+
+    /// What to send `origin`, if it sent validators last time and the version
+    /// they stand for is still recorded.
+    pub fn get(&self, origin: &str) -> Option<Entry> {
+        let entry = self.by_origin.lock().unwrap().get(origin).cloned()?;
+        (!entry.validators.is_empty()
+            && diff::record_path(&self.manifests_dir, &entry.version).is_file())
+        .then_some(entry)
+    }
+
+    pub fn manifests_dir(&self) -> &Path {
+        &self.manifests_dir
+    }
+
+    pub fn filter(&self) -> &PathFilter {
+        &self.filter
+    }
+
+    /// Keeps what `origin` sent with `version`. An origin that sent nothing
+    /// is forgotten, so a stale validator is never replayed to it.
+    pub fn remember(&self, origin: &str, version: &str, validators: Validators) -> Result<()> {
+        let mut by_origin = self.by_origin.lock().unwrap();
+        let changed = if validators.is_empty() {
+            by_origin.remove(origin).is_some()
+        } else {
+            let entry = Entry {
+                version: version.to_string(),
+                validators,
+            };
+            by_origin.insert(origin.to_string(), entry.clone()) != Some(entry)
+        };
+        if changed {
+            write(&self.root, &self.path, &by_origin)?;
+        }
+        Ok(())
+    }
+}
+
+fn path(root: &Path, channel: &str) -> PathBuf {
+    let name = match channel {
+        crate::DEFAULT_CHANNEL => format!("{VALIDATORS_FILE}.json"),
+        channel => format!("{VALIDATORS_FILE}.{channel}.json"),
+    };
+    root.join(VALIDATORS_DIR).join(name)
+}
+
+fn write(root: &Path, path: &Path, by_origin: &BTreeMap<String, Entry>) -> Result<()> {
+    let dir = root.join(VALIDATORS_DIR);
+    fs::create_dir_all(&dir).with_context(|| format!("create {}", dir.display()))?;
+    let mut tmp = tempfile::NamedTempFile::new_in(&dir)
+        .with_context(|| format!("create temp file in {}", dir.display()))?;
+    serde_json::to_writer_pretty(&mut tmp, by_origin).context("write manifest validators")?;
+    tmp.write_all(b"\n").context("write manifest validators")?;
+    tmp.as_file()
+        .sync_all()
+        .context("fsync manifest validators")?;
+    tmp.persist(path)
+        .with_context(|| format!("persist {}", path.display()))?;
+    fsync_dir(&dir).context("fsync state dir")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remembers_per_origin_and_needs_the_record() {
+        let root = tempfile::tempdir().unwrap();
+        let manifests = root.path().join("manifests");
+        fs::create_dir(&manifests).unwrap();
+        let etag = Validators {
+            etag: Some("\"abc\"".into()),
+            last_modified: Some("Fri, 16 Oct 2026 08:30:00 GMT".into()),
+        };
+        let dated = Validators {
+            etag: None,
+            last_modified: Some("Thu, 15 Oct 2026 08:30:00 GMT".into()),
+        };
+
+        let cache =
+            ManifestValidators::load(root.path(), &manifests, PathFilter::default(), "latest");
+        cache
+            .remember("https://a.example", "v1", etag.clone())
+            .unwrap();
+        cache
+            .remember("https://b.example", "v1", dated.clone())
+            .unwrap();
+        // Nothing to replay until v1 has been recorded.
+        assert_eq!(cache.get("https://a.example"), None);
+        fs::write(diff::record_path(&manifests, "v1"), "{}").unwrap();
+
+        let reloaded =
+            ManifestValidators::load(root.path(), &manifests, PathFilter::default(), "latest");
+        assert_eq!(
+            reloaded.get("https://a.example").map(|e| e.validators),
+            Some(etag.clone())
+        );
+        assert_eq!(
+            reloaded.get("https://b.example").map(|e| e.validators),
+            Some(dated.clone())
+        );
+
+        reloaded
+            .remember("https://a.example", "v2", Validators::default())
+            .unwrap();
+        let reloaded =
+            ManifestValidators::load(root.path(), &manifests, PathFilter::default(), "latest");
+        assert_eq!(reloaded.get("https://a.example"), None);
+        assert!(reloaded.get("https://b.example").is_some());
+
+        // Another channel's validators are its own.
+        let beta = ManifestValidators::load(root.path(), &manifests, PathFilter::default(), "beta");
+        assert_eq!(beta.get("https://b.example"), None);
+        beta.remember("https://b.example", "v1", etag).unwrap();
+        assert!(root
+            .path()
+            .join("state/manifest-validators.beta.json")
+            .is_file());
+        let reloaded =
+            ManifestValidators::load(root.path(), &manifests, PathFilter::default(), "latest");
+        assert_eq!(
+            reloaded.get("https://b.example").map(|e| e.validators),
+            Some(dated)
+        );
+    }
+}
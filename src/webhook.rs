@@ -0,0 +1,100 @@
+//! `--notify-url` webhooks: a small JSON POST at the end of each run so chat
+//! or ntfy channels hear about edges picking up (or failing) a version.
+
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use reqwest::blocking::Client;
+use serde_json::{json, Value};
+
+use crate::logger::Logger;
+use crate::{ensure_success, Outcome, Summary};
+
+/// A dead webhook endpoint must not hold up the deploy (or the next watch cycle).
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Which runs produce a notification.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum NotifyOn {
+    Always,
+    /// Runs that switched `current` or failed.
+    Change,
+    Failure,
+}
+
+impl NotifyOn {
+    fn wants(self, summary: &Summary) -> bool {
+        let failed = summary.outcome == Outcome::Error;
+        match self {
+            NotifyOn::Always => true,
+            NotifyOn::Change => summary.switched || failed,
+            NotifyOn::Failure => failed,
+        }
+    }
+}
+
+/// Posts the run summary to every URL. Failures are logged as warnings only.
+pub fn notify(
+    client: &Client,
+    log: &Logger,
+    urls: &[String],
+    on: NotifyOn,
+    root: &Path,
+    summary: &Summary,
+) {
+    if urls.is_empty() || !on.wants(summary) {
+        return;
+    }
+    let body = payload(root, summary);
+    for url in urls {
+        match post(client, url, &body) {
+            Ok(()) => log.debug(
+                "notify_sent",
+                json!({ "url": url }),
+                format_args!("notified {url}"),
+            ),
+            Err(err) => log.warn(
+                "notify_failed",
+                json!({ "url": url, "error": format!("{err:#}") }),
+                format_args!("notify {url} failed: {err:#}"),
+            ),
+        }
+    }
+}
+
+fn post(client: &Client, url: &str, body: &Value) -> Result<()> {
+    let resp = client
+        .post(url)
+        .timeout(NOTIFY_TIMEOUT)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body.to_string())
+        .send()
+        .context("send webhook")?;
+    ensure_success(resp).context("webhook http status")?;
+    Ok(())
+}
+
+fn payload(root: &Path, summary: &Summary) -> Value {
+    json!({
+        "hostname": hostname(),
+        "root": root,
+        "outcome": summary.outcome,
+        "switched": summary.switched,
+        "old_version": summary.previous_version,
+        "new_version": summary.version,
+        "error": (!summary.error.is_empty()).then(|| summary.error.join(": ")),
+        "elapsed_secs": summary.elapsed_secs,
+    })
+}
+
+fn hostname() -> String {
+    #[cfg(target_os = "linux")]
+    if let Ok(name) = std::fs::read_to_string("/proc/sys/kernel/hostname") {
+        return name.trim().to_string();
+    }
+    std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
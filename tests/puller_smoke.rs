@@ -2,15 +2,35 @@
 mod unix_only {
     use std::collections::HashMap;
     use std::fs;
-    use std::io::Write;
+    use std::io::{Read, Write};
     use std::net::{TcpListener, TcpStream};
     use std::process::Command;
     use std::sync::atomic::{AtomicUsize, Ordering};
-    use std::sync::Arc;
+    use std::sync::{Arc, Mutex};
     use std::thread;
+    use std::time::{Duration, Instant};
 
     use tiny_http::{Header, Response, Server, StatusCode};
 
+    /// A well-formed sha256 hex string derived from `label`, so tests can
+    /// tell objects apart by name. Only for objects a run never finishes
+    /// downloading; see `sha`.
+    fn h(label: &str) -> String {
+        let hex: String = label.bytes().map(|b| format!("{b:02x}")).collect();
+        format!("{hex:0<64}")
+    }
+
+    /// The real sha256 hex of `body`. Downloaded objects are checked
+    /// against their hash, so the ones a run fetches need it.
+    fn sha(body: &[u8]) -> String {
+        use sha2::{Digest, Sha256};
+
+        Sha256::digest(body)
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+
     fn send_quit(addr: std::net::SocketAddr) {
         if let Ok(mut stream) = TcpStream::connect(addr) {
             let _ = stream.write_all(b"GET /__quit HTTP/1.1\r\nHost: localhost\r\n\r\n");
@@ -79,22 +99,27 @@ mod unix_only {
     #[test]
     fn puller_fetches_objects_builds_snapshot_and_switches_current() {
         let version = "v-test-1";
-        let hash = "hash1";
         let obj = b"hello world".to_vec();
+        let hash = &sha(&obj);
+        let gz = b"\x1f\x8bnot really gzip".to_vec();
+        let gz_hash = &sha(&gz);
 
         let manifest = format!(
             r#"{{
   "version": "{version}",
   "files": [
-    {{ "path": "index.html", "hash": "{hash}", "size": {} }}
+    {{ "path": "index.html", "hash": "{hash}", "size": {},
+       "variants": [{{ "encoding": "gzip", "hash": "{gz_hash}", "size": {}, "suffix": ".gz" }}] }}
   ]
 }}"#,
-            obj.len()
+            obj.len(),
+            gz.len()
         );
         let manifest_bytes = manifest.as_bytes().to_vec();
 
         let mut objects = HashMap::new();
         objects.insert(hash.to_string(), obj.clone());
+        objects.insert(gz_hash.to_string(), gz.clone());
 
         let manifest_hits = Arc::new(AtomicUsize::new(0));
         let object_hits = Arc::new(AtomicUsize::new(0));
@@ -128,12 +153,18 @@ mod unix_only {
             fs::read(snapshots_dir.join(version).join("index.html")).unwrap(),
             obj
         );
+        assert_eq!(fs::read(objects_dir.join(gz_hash)).unwrap(), gz);
+        assert_eq!(
+            fs::read(snapshots_dir.join(version).join("index.html.gz")).unwrap(),
+            gz
+        );
         assert_eq!(
             fs::read_link(&current).unwrap(),
             std::path::PathBuf::from("snapshots").join(version)
         );
 
-        // Second run should short-circuit once it sees the snapshot already exists.
+        // Second run should short-circuit once it sees the snapshot already exists,
+        // and say so with the "already current" exit code.
         let status2 = Command::new(bin)
             .arg("--origin")
             .arg(&origin)
@@ -141,12 +172,6856 @@ mod unix_only {
             .arg(root.path())
             .status()
             .unwrap();
-        assert!(status2.success());
+        assert_eq!(status2.code(), Some(3));
 
         send_quit(addr);
         handle.join().unwrap();
 
         assert!(manifest_hits.load(Ordering::SeqCst) >= 2);
-        assert_eq!(object_hits.load(Ordering::SeqCst), 1);
+        assert_eq!(object_hits.load(Ordering::SeqCst), 2);
+    }
+
+    /// Accepts connections, sends response headers promising a large body,
+    /// then goes silent without closing the socket.
+    fn start_stalling_origin() -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { continue };
+                thread::spawn(move || {
+                    let mut buf = [0u8; 4096];
+                    let _ = stream.read(&mut buf);
+                    let _ =
+                        stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 1000000\r\n\r\n{");
+                    thread::sleep(Duration::from_secs(60));
+                });
+            }
+        });
+        addr
+    }
+
+    fn single_file_origin(
+        version: &str,
+        hash: &str,
+        body: &[u8],
+    ) -> (std::net::SocketAddr, thread::JoinHandle<()>) {
+        let manifest = format!(
+            r#"{{"version": "{version}", "files": [{{ "path": "index.html", "hash": "{hash}", "size": {} }}]}}"#,
+            body.len()
+        );
+        let mut objects = HashMap::new();
+        objects.insert(hash.to_string(), body.to_vec());
+        start_origin(
+            version,
+            manifest.into_bytes(),
+            objects,
+            Arc::new(AtomicUsize::new(0)),
+            Arc::new(AtomicUsize::new(0)),
+        )
+    }
+
+    #[test]
+    fn resolve_pins_a_hostname_to_the_mock_origin() {
+        let (addr, handle) = single_file_origin("v-pinned", &sha(b"pinned"), b"pinned");
+        let root = tempfile::tempdir().unwrap();
+        let bin = env!("CARGO_BIN_EXE_cityfeed-puller");
+        let port = addr.port();
+
+        // `.invalid` never resolves, so only the pin can get us there.
+        let out = Command::new(bin)
+            .args(["--origin", &format!("http://cdn.cityfeed.invalid:{port}")])
+            .args([
+                "--resolve",
+                &format!("cdn.cityfeed.invalid:{port}:127.0.0.1"),
+            ])
+            .arg("--root")
+            .arg(root.path())
+            .output()
+            .unwrap();
+        assert_eq!(
+            out.status.code(),
+            Some(0),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        assert_eq!(
+            fs::read(root.path().join("current/index.html")).unwrap(),
+            b"pinned"
+        );
+
+        let out = Command::new(bin)
+            .args(["--origin", &format!("http://cdn.cityfeed.invalid:{port}")])
+            .args(["--resolve", "cdn.cityfeed.invalid:127.0.0.1"])
+            .arg("--root")
+            .arg(root.path())
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(out.status.code(), Some(2), "{stderr}");
+        assert!(stderr.contains("expected HOST:PORT:ADDRESS"), "{stderr}");
+
+        send_quit(addr);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn ip_family_flags_restrict_connections() {
+        let (addr, handle) = single_file_origin("v-family", &sha(b"family"), b"family");
+        let root = tempfile::tempdir().unwrap();
+        let bin = env!("CARGO_BIN_EXE_cityfeed-puller");
+        let run = |flags: &[&str]| {
+            Command::new(bin)
+                .args(["--origin", &format!("http://{addr}")])
+                .args(flags)
+                .arg("--root")
+                .arg(root.path())
+                .output()
+                .unwrap()
+        };
+
+        let out = run(&["--ipv4-only", "--ipv6-only"]);
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(out.status.code(), Some(2), "{stderr}");
+        assert!(stderr.contains("cannot be used with"), "{stderr}");
+
+        // The mock only listens on 127.0.0.1, so there is nothing to try.
+        let started = Instant::now();
+        let out = run(&["--ipv6-only", "--connect-timeout", "30s"]);
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(out.status.code(), Some(4), "{stderr}");
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(
+            stderr.contains("only IPv6 addresses were tried (--ipv6-only)"),
+            "{stderr}"
+        );
+
+        let out = run(&["--ipv4-only"]);
+        assert_eq!(
+            out.status.code(),
+            Some(0),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+
+        send_quit(addr);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn objects_reuse_pooled_connections() {
+        let mut objects = HashMap::new();
+        let mut files = Vec::new();
+        for i in 0..40 {
+            let hash = sha(format!("{i:04}").as_bytes());
+            files.push(format!(
+                r#"{{ "path": "stop-{i}.html", "hash": "{hash}", "size": 4 }}"#
+            ));
+            objects.insert(hash, format!("{i:04}").into_bytes());
+        }
+        let manifest = format!(r#"{{"version": "v-pool", "files": [{}]}}"#, files.join(","));
+        let (addr, handle) = start_origin(
+            "v-pool",
+            manifest.into_bytes(),
+            objects,
+            Arc::new(AtomicUsize::new(0)),
+            Arc::new(AtomicUsize::new(0)),
+        );
+        let connections = |flags: &[&str]| {
+            let root = tempfile::tempdir().unwrap();
+            let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .args(["--origin", &format!("http://{addr}")])
+                .arg("--root")
+                .arg(root.path())
+                .args(["--log-format", "json", "-v"])
+                .args(flags)
+                .output()
+                .unwrap();
+            let stderr = String::from_utf8(out.stderr).unwrap();
+            assert_eq!(out.status.code(), Some(0), "{stderr}");
+            let event: serde_json::Value = stderr
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .find(|e: &serde_json::Value| e["event"] == "http_connections")
+                .unwrap_or_else(|| panic!("no http_connections event: {stderr}"));
+            assert_eq!(event["requests"], 41);
+            event["connections"].as_u64().unwrap()
+        };
+
+        let pooled = connections(&["--tcp-keepalive", "30s"]);
+        assert!(pooled <= 2, "{pooled} connections for 41 requests");
+        // Without idle connections to reuse, every request dials again.
+        assert_eq!(connections(&["--pool-max-idle-per-host", "0"]), 41);
+
+        send_quit(addr);
+        handle.join().unwrap();
+    }
+
+    #[cfg(feature = "otlp")]
+    #[test]
+    fn otlp_endpoint_receives_one_trace_per_deploy() {
+        let (origin, origin_handle) = single_file_origin("v-traced", &sha(b"traced"), b"traced");
+        let collector = Server::http("127.0.0.1:0").unwrap();
+        let collector_addr = collector.server_addr().to_ip().unwrap();
+        let collector_handle = thread::spawn(move || {
+            let mut req = collector.recv().unwrap();
+            let path = req.url().to_string();
+            let mut body = String::new();
+            req.as_reader().read_to_string(&mut body).unwrap();
+            let _ = req.respond(Response::empty(200));
+            (path, body)
+        });
+
+        let root = tempfile::tempdir().unwrap();
+        let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+            .args(["--origin", &format!("http://{origin}")])
+            .args(["--otlp-endpoint", &format!("http://{collector_addr}/")])
+            .arg("--root")
+            .arg(root.path())
+            .output()
+            .unwrap();
+        assert_eq!(
+            out.status.code(),
+            Some(0),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+
+        let (path, body) = collector_handle.join().unwrap();
+        assert_eq!(path, "/v1/traces");
+        let doc: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let spans = doc["resourceSpans"][0]["scopeSpans"][0]["spans"]
+            .as_array()
+            .unwrap();
+        let attr = |span: &serde_json::Value, key: &str| {
+            span["attributes"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|a| a["key"] == key)
+                .map(|a| a["value"].clone())
+        };
+        let names: Vec<&str> = spans.iter().map(|s| s["name"].as_str().unwrap()).collect();
+        assert_eq!(
+            names,
+            [
+                "deploy",
+                "manifest.fetch",
+                "object.download",
+                "stage",
+                "switch"
+            ]
+        );
+
+        let root_span = &spans[0];
+        assert!(root_span.get("parentSpanId").is_none());
+        assert_eq!(
+            attr(root_span, "cityfeed.version").unwrap()["stringValue"],
+            "v-traced"
+        );
+        assert_eq!(
+            attr(root_span, "cityfeed.outcome").unwrap()["stringValue"],
+            "updated"
+        );
+        for span in &spans[1..] {
+            assert_eq!(span["traceId"], root_span["traceId"]);
+            assert_eq!(span["parentSpanId"], root_span["spanId"]);
+            assert_eq!(span["status"]["code"], 1, "{span}");
+        }
+        let object = &spans[2];
+        assert_eq!(
+            attr(object, "hash").unwrap()["stringValue"],
+            sha(b"traced").as_str()
+        );
+        assert_eq!(
+            attr(object, "origin").unwrap()["stringValue"],
+            format!("http://{origin}").as_str()
+        );
+        assert_eq!(attr(object, "bytes").unwrap()["intValue"], "6");
+        assert_eq!(attr(object, "retries").unwrap()["intValue"], "0");
+
+        send_quit(origin);
+        origin_handle.join().unwrap();
+    }
+
+    #[test]
+    fn connect_timeout_fails_over_quickly_from_blackholed_origin() {
+        let (addr, handle) = single_file_origin("v-fast", &sha(b"fast"), b"fast");
+        let root = tempfile::tempdir().unwrap();
+        let bin = env!("CARGO_BIN_EXE_cityfeed-puller");
+
+        let started = Instant::now();
+        let status = Command::new(bin)
+            // TEST-NET-1 (RFC 5737) is never routed, so connects either hang or fail fast.
+            .args(["--origin", "http://192.0.2.1:81"])
+            .arg("--origin")
+            .arg(format!("http://{addr}"))
+            .args(["--connect-timeout", "1s"])
+            .arg("--root")
+            .arg(root.path())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_eq!(
+            fs::read(root.path().join("current/index.html")).unwrap(),
+            b"fast"
+        );
+
+        send_quit(addr);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn stall_timeout_abandons_silent_origin() {
+        let stalling = start_stalling_origin();
+        let (addr, handle) = single_file_origin("v-stall", &sha(b"stall"), b"stall");
+        let root = tempfile::tempdir().unwrap();
+        let bin = env!("CARGO_BIN_EXE_cityfeed-puller");
+
+        let started = Instant::now();
+        let status = Command::new(bin)
+            .arg("--origin")
+            .arg(format!("http://{stalling}"))
+            .arg("--origin")
+            .arg(format!("http://{addr}"))
+            .args(["--stall-timeout", "1s"])
+            .arg("--root")
+            .arg(root.path())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_eq!(
+            fs::read(root.path().join("current/index.html")).unwrap(),
+            b"stall"
+        );
+
+        send_quit(addr);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn max_rate_throttles_object_downloads() {
+        let body = vec![b'x'; 300 * 1024];
+        let (addr, handle) = single_file_origin("v-rate", &sha(&body), &body);
+        let root = tempfile::tempdir().unwrap();
+        let bin = env!("CARGO_BIN_EXE_cityfeed-puller");
+
+        let started = Instant::now();
+        let status = Command::new(bin)
+            .arg("--origin")
+            .arg(format!("http://{addr}"))
+            .args(["--max-rate", "100K"])
+            .arg("--root")
+            .arg(root.path())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let elapsed = started.elapsed();
+        // 300 KiB at 100 KiB/s from an initially empty bucket is ~3s.
+        assert!(elapsed >= Duration::from_secs(2), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(8), "{elapsed:?}");
+        assert_eq!(
+            fs::read(root.path().join("current/index.html")).unwrap(),
+            body
+        );
+
+        send_quit(addr);
+        handle.join().unwrap();
+    }
+
+    /// Lays out `manifests/latest.json` and `objects/<hash>` the way a published bucket does.
+    fn write_file_origin(dir: &std::path::Path, version: &str, files: &[(&str, &str, &[u8])]) {
+        fs::create_dir_all(dir.join("manifests")).unwrap();
+        fs::create_dir_all(dir.join("objects")).unwrap();
+        let entries: Vec<String> = files
+            .iter()
+            .map(|(path, hash, body)| {
+                fs::write(dir.join("objects").join(hash), body).unwrap();
+                format!(
+                    r#"{{ "path": "{path}", "hash": "{hash}", "size": {} }}"#,
+                    body.len()
+                )
+            })
+            .collect();
+        fs::write(
+            dir.join("manifests/latest.json"),
+            format!(
+                r#"{{"version": "{version}", "files": [{}]}}"#,
+                entries.join(",")
+            ),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn file_origin_deploys_without_network() {
+        let usb = tempfile::tempdir().unwrap();
+        write_file_origin(
+            usb.path(),
+            "v-usb",
+            &[
+                ("index.html", &sha(b"<h1>usb</h1>"), b"<h1>usb</h1>"),
+                ("css/site.css", &sha(b"body{}"), b"body{}"),
+            ],
+        );
+        let root = tempfile::tempdir().unwrap();
+        let bin = env!("CARGO_BIN_EXE_cityfeed-puller");
+
+        let status = Command::new(bin)
+            .arg("--origin")
+            .arg(format!("file://{}/", usb.path().display()))
+            .arg("--root")
+            .arg(root.path())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert_eq!(
+            fs::read(root.path().join("current/index.html")).unwrap(),
+            b"<h1>usb</h1>"
+        );
+        assert_eq!(
+            fs::read(root.path().join("current/css/site.css")).unwrap(),
+            b"body{}"
+        );
+    }
+
+    #[test]
+    fn small_manifests_are_refused_unless_allowed() {
+        let usb = tempfile::tempdir().unwrap();
+        write_file_origin(usb.path(), "v-empty", &[]);
+        let origin = format!("file://{}/", usb.path().display());
+        let root = tempfile::tempdir().unwrap();
+
+        let (code, summary) = run_json(&origin, root.path());
+        assert_eq!(code, Some(4));
+        assert_eq!(summary["file_count"], 0);
+        assert!(
+            summary["error"][0]
+                .as_str()
+                .unwrap()
+                .contains("manifest lists 0 files, under --min-files 1"),
+            "{summary}"
+        );
+        assert!(!root.path().join("current").exists());
+
+        write_file_origin(
+            usb.path(),
+            "v-small",
+            &[("index.html", &sha(b"tiny"), b"tiny")],
+        );
+        let bin = env!("CARGO_BIN_EXE_cityfeed-puller");
+        let run = |extra: &[&str]| {
+            Command::new(bin)
+                .arg("--origin")
+                .arg(&origin)
+                .arg("--root")
+                .arg(root.path())
+                .args(extra)
+                .output()
+                .unwrap()
+        };
+        let out = run(&["--min-files", "2"]);
+        assert_eq!(out.status.code(), Some(4));
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(
+            stderr.contains("manifest lists 1 files, under --min-files 2"),
+            "stderr: {stderr}"
+        );
+        let out = run(&["--min-total-bytes", "5"]);
+        assert_eq!(out.status.code(), Some(4));
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(
+            stderr.contains("add up to 4 bytes, under --min-total-bytes 5"),
+            "stderr: {stderr}"
+        );
+
+        assert!(run(&["--min-files", "2", "--allow-small-manifest"])
+            .status
+            .success());
+        assert_eq!(
+            fs::read(root.path().join("current/index.html")).unwrap(),
+            b"tiny"
+        );
+    }
+
+    #[test]
+    fn case_colliding_paths_fail_before_downloads_unless_allowed() {
+        let usb = tempfile::tempdir().unwrap();
+        write_file_origin(
+            usb.path(),
+            "v-case",
+            &[
+                ("Assets/Logo.png", &sha(b"upper"), b"upper"),
+                ("assets/logo.png", &sha(b"lower"), b"lower"),
+            ],
+        );
+        let origin = format!("file://{}/", usb.path().display());
+        let root = tempfile::tempdir().unwrap();
+        let bin = env!("CARGO_BIN_EXE_cityfeed-puller");
+
+        let out = Command::new(bin)
+            .arg("--origin")
+            .arg(&origin)
+            .arg("--root")
+            .arg(root.path())
+            .output()
+            .unwrap();
+        assert_eq!(out.status.code(), Some(4));
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(stderr.contains("[Assets, assets]"), "stderr: {stderr}");
+        let stored = fs::read_dir(root.path().join("objects"))
+            .map(|entries| entries.count())
+            .unwrap_or(0);
+        assert_eq!(stored, 0);
+        assert!(!root.path().join("current").exists());
+
+        // A watching box refuses it the same way, every cycle.
+        let stderr = watch_briefly(&origin, root.path(), &[]);
+        assert!(stderr.contains("[Assets, assets]"), "stderr: {stderr}");
+        let stored = fs::read_dir(root.path().join("objects"))
+            .map(|entries| entries.count())
+            .unwrap_or(0);
+        assert_eq!(stored, 0);
+
+        let status = Command::new(bin)
+            .arg("--origin")
+            .arg(&origin)
+            .arg("--root")
+            .arg(root.path())
+            .arg("--allow-case-collisions")
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert_eq!(
+            fs::read(root.path().join("current/assets/logo.png")).unwrap(),
+            b"lower"
+        );
+    }
+
+    /// Runs `--watch` against `origin` for half a second, then stops it with
+    /// SIGTERM and returns its stderr.
+    fn watch_briefly(origin: &str, root: &std::path::Path, extra: &[&str]) -> String {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+            .args(["--origin", origin])
+            .arg("--root")
+            .arg(root)
+            .args(["--watch", "--interval", "100ms"])
+            .args(extra)
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_millis(500));
+        Command::new("kill")
+            .args(["-TERM", &child.id().to_string()])
+            .status()
+            .unwrap();
+        let mut stderr = String::new();
+        child
+            .stderr
+            .take()
+            .unwrap()
+            .read_to_string(&mut stderr)
+            .unwrap();
+        child.wait().unwrap();
+        stderr
+    }
+
+    #[test]
+    fn windows_safe_refuses_unportable_paths_in_watch_cycles_too() {
+        let usb = tempfile::tempdir().unwrap();
+        write_file_origin(usb.path(), "v-aux", &[("aux.html", &h("aux"), b"aux")]);
+        let origin = format!("file://{}/", usb.path().display());
+        let root = tempfile::tempdir().unwrap();
+        let portable = ["--path-portability", "windows-safe"];
+
+        let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+            .args(["--origin", &origin])
+            .arg("--root")
+            .arg(root.path())
+            .args(portable)
+            .output()
+            .unwrap();
+        assert_eq!(out.status.code(), Some(4));
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(stderr.contains("reserved name"), "stderr: {stderr}");
+
+        let stderr = watch_briefly(&origin, root.path(), &portable);
+        assert!(stderr.contains("reserved name"), "stderr: {stderr}");
+        let stored = fs::read_dir(root.path().join("objects"))
+            .map(|entries| entries.count())
+            .unwrap_or(0);
+        assert_eq!(stored, 0);
+        assert!(!root.path().join("current").exists());
+    }
+
+    /// A primary publishing v5 (sequence 5) and a mirror still on v4.
+    fn primary_and_stale_mirror() -> (tempfile::TempDir, tempfile::TempDir) {
+        let (primary, mirror) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        for (dir, version, sequence) in [(&primary, "v5", 5), (&mirror, "v4", 4)] {
+            let hash = sha(version.as_bytes());
+            write_file_origin(
+                dir.path(),
+                version,
+                &[("index.html", &hash, version.as_bytes())],
+            );
+            let latest = dir.path().join("manifests/latest.json");
+            let text = fs::read_to_string(&latest).unwrap().replacen(
+                "\"files\"",
+                &format!("\"sequence\": {sequence}, \"files\""),
+                1,
+            );
+            fs::write(&latest, text).unwrap();
+        }
+        (primary, mirror)
+    }
+
+    #[test]
+    fn stale_mirror_cannot_downgrade_unless_allowed() {
+        let (primary, mirror) = primary_and_stale_mirror();
+        let root = tempfile::tempdir().unwrap();
+        let mirror_origin = format!("file://{}", mirror.path().display());
+        let run = |extra: &[&str]| {
+            Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .arg("--origin")
+                .arg(format!("file://{}/", primary.path().display()))
+                .arg("--origin")
+                .arg(&mirror_origin)
+                .arg("--root")
+                .arg(root.path())
+                .args(extra)
+                .output()
+                .unwrap()
+        };
+        assert!(run(&[]).status.success());
+        assert_eq!(
+            fs::read(root.path().join("current/index.html")).unwrap(),
+            b"v5"
+        );
+
+        // The primary is briefly down; the mirror still serves last week's v4.
+        fs::remove_file(primary.path().join("manifests/latest.json")).unwrap();
+        let out = run(&[]);
+        assert_eq!(out.status.code(), Some(4));
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(
+            stderr.contains(&format!(
+                "{mirror_origin} offered v4, older than live v5 (sequence 4 < 5)"
+            )),
+            "stderr: {stderr}"
+        );
+        assert_eq!(
+            fs::read(root.path().join("current/index.html")).unwrap(),
+            b"v5"
+        );
+
+        assert!(run(&["--allow-downgrade"]).status.success());
+        assert_eq!(
+            fs::read(root.path().join("current/index.html")).unwrap(),
+            b"v4"
+        );
+        // Re-checking the now-live v4 is unaffected.
+        assert_eq!(run(&[]).status.code(), Some(3));
+    }
+
+    #[test]
+    fn stale_mirror_cannot_downgrade_a_watching_box() {
+        let (primary, mirror) = primary_and_stale_mirror();
+        let root = tempfile::tempdir().unwrap();
+        let mirror_origin = format!("file://{}", mirror.path().display());
+        let mut child = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+            .arg("--origin")
+            .arg(format!("file://{}/", primary.path().display()))
+            .arg("--origin")
+            .arg(&mirror_origin)
+            .arg("--root")
+            .arg(root.path())
+            .args(["--watch", "--interval", "100ms"])
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        wait_for_current(root.path(), "snapshots/v5");
+
+        // The primary goes down mid-watch; the mirror still serves v4.
+        fs::remove_file(primary.path().join("manifests/latest.json")).unwrap();
+        thread::sleep(Duration::from_millis(1500));
+        Command::new("kill")
+            .args(["-TERM", &child.id().to_string()])
+            .status()
+            .unwrap();
+        let mut stderr = String::new();
+        child
+            .stderr
+            .take()
+            .unwrap()
+            .read_to_string(&mut stderr)
+            .unwrap();
+        assert_eq!(child.wait().unwrap().code(), Some(0));
+        assert!(
+            stderr.contains(&format!(
+                "{mirror_origin} offered v4, older than live v5 (sequence 4 < 5)"
+            )),
+            "stderr: {stderr}"
+        );
+        assert_eq!(
+            fs::read_link(root.path().join("current")).unwrap(),
+            std::path::Path::new("snapshots/v5")
+        );
+    }
+
+    #[test]
+    fn reused_version_with_other_files_warns_or_fails_when_strict() {
+        let usb = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let origin = format!("file://{}/", usb.path().display());
+        let run = |extra: &[&str]| {
+            let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .args(["--origin", &origin, "--output", "json"])
+                .arg("--root")
+                .arg(root.path())
+                .args(extra)
+                .output()
+                .unwrap();
+            let summary: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+            (
+                out.status.code(),
+                summary,
+                String::from_utf8(out.stderr).unwrap(),
+            )
+        };
+
+        write_file_origin(
+            usb.path(),
+            "v-same",
+            &[("index.html", &sha(b"one"), b"one")],
+        );
+        let (code, summary, _) = run(&[]);
+        assert_eq!(code, Some(0));
+        assert_eq!(summary["version_content_mismatch"], false);
+
+        // The publisher adds a file and edits another without a new version.
+        write_file_origin(
+            usb.path(),
+            "v-same",
+            &[
+                ("index.html", &sha(b"two"), b"two"),
+                ("alerts.html", &sha(b"none"), b"none"),
+            ],
+        );
+        let (code, summary, stderr) = run(&[]);
+        assert_eq!(code, Some(3), "{stderr}");
+        assert_eq!(summary["version_content_mismatch"], true);
+        assert!(
+            stderr.contains(
+                "does not match the v-same already deployed here: \
+                 2 paths differ (1 added, 0 removed, 1 modified)"
+            ),
+            "{stderr}"
+        );
+        assert_eq!(
+            fs::read(root.path().join("current/index.html")).unwrap(),
+            b"one"
+        );
+
+        let (code, summary, stderr) = run(&["--strict-version"]);
+        assert_eq!(code, Some(4), "{stderr}");
+        assert_eq!(summary["version_content_mismatch"], true);
+        assert!(
+            summary["error"][0]
+                .as_str()
+                .unwrap()
+                .contains("refusing it under --strict-version"),
+            "{summary}"
+        );
+
+        // Without the record, deploy-state.json's counts still tell.
+        fs::remove_file(root.path().join("manifests/v-same.json")).unwrap();
+        let (code, _, stderr) = run(&["--strict-version"]);
+        assert_eq!(code, Some(4), "{stderr}");
+        assert!(
+            stderr.contains("2 files (7 bytes), 1 (3 bytes) when it was deployed"),
+            "{stderr}"
+        );
+    }
+
+    #[test]
+    fn mixed_file_and_http_origins_fail_over_in_order() {
+        let usb = tempfile::tempdir().unwrap();
+        write_file_origin(
+            usb.path(),
+            "v-mixed",
+            &[("index.html", &sha(b"mixed"), b"mixed")],
+        );
+        // The stick is missing the object; the http mirror has it.
+        fs::remove_file(usb.path().join("objects").join(sha(b"mixed"))).unwrap();
+        let (addr, handle) = single_file_origin("v-mixed", &sha(b"mixed"), b"mixed");
+
+        let root = tempfile::tempdir().unwrap();
+        let bin = env!("CARGO_BIN_EXE_cityfeed-puller");
+        let status = Command::new(bin)
+            .arg("--origin")
+            .arg(format!("file://{}", usb.path().display()))
+            .arg("--origin")
+            .arg(format!("http://{addr}"))
+            .arg("--root")
+            .arg(root.path())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert_eq!(
+            fs::read(root.path().join("current/index.html")).unwrap(),
+            b"mixed"
+        );
+
+        send_quit(addr);
+        handle.join().unwrap();
+    }
+
+    fn run_json(origin: &str, root: &std::path::Path) -> (Option<i32>, serde_json::Value) {
+        let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+            .arg("--origin")
+            .arg(origin)
+            .arg("--root")
+            .arg(root)
+            .args(["--output", "json"])
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8(out.stdout).unwrap();
+        assert_eq!(stdout.lines().count(), 1, "stdout: {stdout}");
+        (out.status.code(), serde_json::from_str(&stdout).unwrap())
+    }
+
+    #[test]
+    fn output_json_summarizes_fresh_and_already_current_runs() {
+        let (addr, handle) = single_file_origin("v-json", &sha(b"json!"), b"json!");
+        let origin = format!("http://{addr}");
+        let root = tempfile::tempdir().unwrap();
+
+        let (code, doc) = run_json(&origin, root.path());
+        assert_eq!(code, Some(0));
+        assert_eq!(doc["outcome"], "updated");
+        assert_eq!(doc["version"], "v-json");
+        assert_eq!(doc["origin"], origin.as_str());
+        assert_eq!(doc["objects_downloaded"], 1);
+        assert_eq!(doc["bytes_downloaded"], 5);
+        assert_eq!(doc["objects_reused"], 0);
+        assert_eq!(doc["switched"], true);
+        assert!(doc["snapshot"]
+            .as_str()
+            .unwrap()
+            .ends_with("snapshots/v-json"));
+        assert!(doc["elapsed_secs"].as_f64().unwrap() >= 0.0);
+        assert_eq!(doc["error"], serde_json::json!([]));
+
+        let (code, doc) = run_json(&origin, root.path());
+        assert_eq!(code, Some(3));
+        assert_eq!(doc["outcome"], "already-current");
+        assert_eq!(doc["objects_downloaded"], 0);
+        assert_eq!(doc["switched"], false);
+
+        send_quit(addr);
+        handle.join().unwrap();
+
+        // With the origin gone the run fails but still emits the document.
+        let (code, doc) = run_json(&origin, root.path());
+        assert_eq!(code, Some(4));
+        assert_eq!(doc["outcome"], "error");
+        assert!(doc["version"].is_null());
+        let chain = doc["error"].as_array().unwrap();
+        assert!(chain.len() > 1);
+        assert_eq!(chain[0], "fetch latest manifest from all origins");
+    }
+
+    #[test]
+    fn deploy_history_appends_one_line_per_attempt() {
+        let (addr, handle) = single_file_origin("v-hist", &sha(b"history"), b"history");
+        let origin = format!("http://{addr}");
+        let root = tempfile::tempdir().unwrap();
+        assert_eq!(run_json(&origin, root.path()).0, Some(0));
+        assert_eq!(run_json(&origin, root.path()).0, Some(3));
+        send_quit(addr);
+        handle.join().unwrap();
+
+        let text = fs::read_to_string(root.path().join("state/history.jsonl")).unwrap();
+        let entries: Vec<serde_json::Value> = text
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 2, "{text}");
+        assert_eq!(entries[0]["outcome"], "updated");
+        assert_eq!(entries[0]["previous_version"], serde_json::Value::Null);
+        assert_eq!(entries[0]["version"], "v-hist");
+        assert_eq!(entries[0]["origin"], origin.as_str());
+        assert_eq!(entries[0]["bytes_downloaded"], 7);
+        assert!(entries[0]["at"].as_str().unwrap().ends_with('Z'));
+        assert_eq!(entries[1]["outcome"], "already-current");
+        assert_eq!(entries[1]["previous_version"], "v-hist");
+        assert_eq!(entries[1]["bytes_downloaded"], 0);
+        assert!(entries.iter().all(|entry| entry.get("error").is_none()));
+
+        // A failed attempt is recorded with its error.
+        let (code, _) = run_json(&origin, root.path());
+        assert_eq!(code, Some(4));
+        let text = fs::read_to_string(root.path().join("state/history.jsonl")).unwrap();
+        let last: serde_json::Value = serde_json::from_str(text.lines().last().unwrap()).unwrap();
+        assert_eq!(last["outcome"], "error");
+        assert!(last["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("fetch latest manifest from all origins: "));
+    }
+
+    #[test]
+    fn deploy_state_records_the_switch_and_later_checks() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (addr, handle) = single_file_origin("v-state", &sha(b"state!"), b"state!");
+        let origin = format!("http://{addr}");
+        let root = tempfile::tempdir().unwrap();
+        let state_path = root.path().join("deploy-state.json");
+        let read_state = || -> serde_json::Value {
+            serde_json::from_str(&fs::read_to_string(&state_path).unwrap()).unwrap()
+        };
+
+        let (code, _) = run_json(&origin, root.path());
+        assert_eq!(code, Some(0));
+        let first = read_state();
+        assert_eq!(first["version"], "v-state");
+        assert_eq!(first["origin"], origin.as_str());
+        assert_eq!(first["file_count"], 1);
+        assert_eq!(first["total_bytes"], 6);
+        assert_eq!(first["puller_version"], env!("CARGO_PKG_VERSION"));
+        let switched_at = first["switched_at"].as_str().unwrap();
+        assert!(switched_at.ends_with('Z'), "{switched_at}");
+        assert_eq!(first["checked_at"], switched_at);
+        assert_eq!(
+            fs::metadata(&state_path).unwrap().permissions().mode() & 0o777,
+            0o644
+        );
+
+        // Timestamps have one-second resolution.
+        thread::sleep(Duration::from_millis(1100));
+        let (code, _) = run_json(&origin, root.path());
+        assert_eq!(code, Some(3));
+        let second = read_state();
+        assert_eq!(second["switched_at"], first["switched_at"]);
+        assert!(second["checked_at"].as_str().unwrap() > switched_at);
+        for key in ["version", "origin", "file_count", "total_bytes"] {
+            assert_eq!(second[key], first[key], "{key}");
+        }
+        assert!(!dir_entries(root.path())
+            .iter()
+            .any(|name| name.starts_with(".tmp")));
+
+        send_quit(addr);
+        handle.join().unwrap();
+    }
+
+    /// Relative path -> (mode, file bytes or `-> target`) for everything
+    /// under `dir`.
+    fn tree(dir: &std::path::Path) -> std::collections::BTreeMap<String, (u32, Vec<u8>)> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut out = std::collections::BTreeMap::new();
+        let mut stack = vec![dir.to_path_buf()];
+        while let Some(next) = stack.pop() {
+            for entry in fs::read_dir(&next).unwrap() {
+                let path = entry.unwrap().path();
+                let rel = path
+                    .strip_prefix(dir)
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned();
+                let meta = fs::symlink_metadata(&path).unwrap();
+                let mode = meta.permissions().mode() & 0o7777;
+                if meta.is_symlink() {
+                    let target = fs::read_link(&path).unwrap();
+                    let link = format!("-> {}", target.display());
+                    out.insert(rel, (0, link.into_bytes()));
+                } else if meta.is_dir() {
+                    out.insert(rel, (mode, Vec::new()));
+                    stack.push(path);
+                } else {
+                    out.insert(rel, (mode, fs::read(&path).unwrap()));
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn export_round_trips_a_snapshot_deterministically() {
+        use sha2::{Digest, Sha256};
+
+        let sha256 = |bytes: &[u8]| {
+            Sha256::digest(bytes)
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect::<String>()
+        };
+        let usb = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let out = tempfile::tempdir().unwrap();
+        fs::create_dir_all(usb.path().join("manifests")).unwrap();
+        fs::create_dir_all(usb.path().join("objects")).unwrap();
+        let files: [(&str, &[u8], &str); 3] = [
+            ("index.html", b"<h1>routes</h1>", "0644"),
+            ("assets/app.js", b"console.log(1)", "0644"),
+            ("bin/refresh.sh", b"#!/bin/sh\n", "0755"),
+        ];
+        let mut entries: Vec<String> = files
+            .iter()
+            .map(|(path, body, mode)| {
+                fs::write(usb.path().join("objects").join(sha256(body)), body).unwrap();
+                format!(
+                    r#"{{ "path": "{path}", "hash": "{}", "size": {}, "mode": "{mode}" }}"#,
+                    sha256(body),
+                    body.len()
+                )
+            })
+            .collect();
+        entries.push(r#"{ "path": "home.html", "symlink": "index.html" }"#.into());
+        fs::write(
+            usb.path().join("manifests/latest.json"),
+            format!(
+                r#"{{"version": "v-export", "files": [{}]}}"#,
+                entries.join(",")
+            ),
+        )
+        .unwrap();
+        let status = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+            .arg("--origin")
+            .arg(format!("file://{}/", usb.path().display()))
+            .arg("--root")
+            .arg(root.path())
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let export = |args: &[&str], file: &str| {
+            Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .arg("export")
+                .arg("--root")
+                .arg(root.path())
+                .args(args)
+                .arg("--out")
+                .arg(out.path().join(file))
+                .output()
+                .unwrap()
+        };
+        let run = export(&[], "current.tar.zst");
+        assert_eq!(
+            run.status.code(),
+            Some(0),
+            "{}",
+            String::from_utf8_lossy(&run.stderr)
+        );
+        thread::sleep(Duration::from_millis(1100));
+        assert!(export(&["--version", "v-export"], "pinned.tar.zst")
+            .status
+            .success());
+        let archive = fs::read(out.path().join("current.tar.zst")).unwrap();
+        assert_eq!(
+            archive,
+            fs::read(out.path().join("pinned.tar.zst")).unwrap(),
+            "exports of one snapshot differ"
+        );
+
+        let unpacked = tempfile::tempdir().unwrap();
+        let mut tar = tar::Archive::new(zstd::Decoder::new(archive.as_slice()).unwrap());
+        let mut names = Vec::new();
+        for entry in tar.entries().unwrap() {
+            let entry = entry.unwrap();
+            assert_eq!(entry.header().mtime().unwrap(), 0);
+            assert_eq!(entry.header().uid().unwrap(), 0);
+            names.push(entry.path().unwrap().to_string_lossy().into_owned());
+        }
+        assert_eq!(
+            names,
+            [
+                "manifest.json",
+                "snapshot/assets",
+                "snapshot/assets/app.js",
+                "snapshot/bin",
+                "snapshot/bin/refresh.sh",
+                "snapshot/home.html",
+                "snapshot/index.html",
+            ]
+        );
+        let mut tar = tar::Archive::new(zstd::Decoder::new(archive.as_slice()).unwrap());
+        tar.set_preserve_permissions(true);
+        tar.unpack(unpacked.path()).unwrap();
+        let snapshot = root.path().join("snapshots/v-export");
+        assert_eq!(tree(&unpacked.path().join("snapshot")), tree(&snapshot));
+        assert_eq!(
+            fs::read(unpacked.path().join("manifest.json")).unwrap(),
+            fs::read(root.path().join("manifests/v-export.json")).unwrap()
+        );
+
+        // Corruption on disk fails the export and leaves no archive behind.
+        let js = snapshot.join("assets/app.js");
+        let mut perms = fs::metadata(&js).unwrap().permissions();
+        std::os::unix::fs::PermissionsExt::set_mode(&mut perms, 0o644);
+        fs::set_permissions(&js, perms).unwrap();
+        fs::write(&js, b"console.log(2)").unwrap();
+        let run = export(&[], "corrupt.tar.zst");
+        assert_eq!(run.status.code(), Some(1));
+        let stderr = String::from_utf8_lossy(&run.stderr);
+        assert!(
+            stderr.contains("assets/app.js") && stderr.contains("does not match the manifest"),
+            "{stderr}"
+        );
+        assert_eq!(dir_entries(out.path()).len(), 2);
+    }
+
+    #[test]
+    fn import_seeds_a_fresh_root_from_an_export() {
+        let usb = tempfile::tempdir().unwrap();
+        let source = tempfile::tempdir().unwrap();
+        let work = tempfile::tempdir().unwrap();
+        let body: &[u8] = b"<p>stop 42: northbound</p>";
+        let hash = {
+            use sha2::{Digest, Sha256};
+            Sha256::digest(body)
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect::<String>()
+        };
+        fs::create_dir_all(usb.path().join("manifests")).unwrap();
+        fs::create_dir_all(usb.path().join("objects")).unwrap();
+        fs::write(usb.path().join("objects").join(&hash), body).unwrap();
+        fs::write(
+            usb.path().join("manifests/latest.json"),
+            format!(
+                r#"{{"version": "v-import", "files": [
+                    {{ "path": "stops/42.html", "hash": "{hash}", "size": {} }},
+                    {{ "path": "index.html", "symlink": "stops/42.html" }}
+                ]}}"#,
+                body.len()
+            ),
+        )
+        .unwrap();
+        let puller = |args: &[&std::ffi::OsStr]| {
+            Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .args(args)
+                .output()
+                .unwrap()
+        };
+        let origin = format!("file://{}/", usb.path().display());
+        assert!(puller(&[
+            "--origin".as_ref(),
+            origin.as_ref(),
+            "--root".as_ref(),
+            source.path().as_os_str(),
+        ])
+        .status
+        .success());
+        let archive = work.path().join("site.tar.zst");
+        assert!(puller(&[
+            "export".as_ref(),
+            "--root".as_ref(),
+            source.path().as_os_str(),
+            "--out".as_ref(),
+            archive.as_os_str(),
+        ])
+        .status
+        .success());
+
+        let fresh = tempfile::tempdir().unwrap();
+        let import = |extra: &[&str]| {
+            let mut args: Vec<&std::ffi::OsStr> = vec![
+                "import".as_ref(),
+                archive.as_os_str(),
+                "--root".as_ref(),
+                fresh.path().as_os_str(),
+            ];
+            args.extend(extra.iter().map(|a| std::ffi::OsStr::new(*a)));
+            puller(&args)
+        };
+        let out = import(&[]);
+        assert_eq!(
+            out.status.code(),
+            Some(0),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        assert_eq!(
+            tree(&fresh.path().join("snapshots/v-import")),
+            tree(&source.path().join("snapshots/v-import"))
+        );
+        assert!(fresh.path().join("objects").join(&hash).exists());
+        assert!(fs::symlink_metadata(fresh.path().join("current")).is_err());
+
+        let out = import(&[]);
+        assert_eq!(out.status.code(), Some(3));
+        assert!(String::from_utf8_lossy(&out.stderr).contains("already exists; nothing to import"));
+
+        let out = import(&["--switch"]);
+        assert_eq!(
+            out.status.code(),
+            Some(0),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        assert_eq!(
+            fs::read(fresh.path().join("current/index.html")).unwrap(),
+            body
+        );
+        let state: serde_json::Value = serde_json::from_str(
+            &fs::read_to_string(fresh.path().join("deploy-state.json")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(state["version"], "v-import");
+        assert_eq!(state["origin"], archive.to_str().unwrap());
+
+        // Same size, different bytes: rejected before any snapshot exists.
+        let tampered = work.path().join("tampered.tar.zst");
+        {
+            let bytes = fs::read(&archive).unwrap();
+            let mut src = tar::Archive::new(zstd::Decoder::new(bytes.as_slice()).unwrap());
+            let out = zstd::Encoder::new(fs::File::create(&tampered).unwrap(), 3).unwrap();
+            let mut dst = tar::Builder::new(out);
+            for entry in src.entries().unwrap() {
+                let mut entry = entry.unwrap();
+                let header = entry.header().clone();
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data).unwrap();
+                if data == body {
+                    data[4] = b'X';
+                }
+                dst.append(&header, data.as_slice()).unwrap();
+            }
+            dst.into_inner().unwrap().finish().unwrap();
+        }
+        let other = tempfile::tempdir().unwrap();
+        let out = puller(&[
+            "import".as_ref(),
+            tampered.as_os_str(),
+            "--root".as_ref(),
+            other.path().as_os_str(),
+        ]);
+        assert_eq!(out.status.code(), Some(5));
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(
+            stderr.contains("stops/42.html") && stderr.contains("does not match the manifest"),
+            "{stderr}"
+        );
+        assert_eq!(
+            dir_entries(&other.path().join("snapshots")),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            dir_entries(&other.path().join("objects")),
+            Vec::<String>::new()
+        );
+    }
+
+    /// Sends one raw request line (so `..` reaches the server as written)
+    /// and returns the status code, lowercased headers and body.
+    fn raw_get(addr: &str, path: &str) -> (u16, String, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "GET {path} HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\n\r\n"
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (
+            head[9..12].parse().unwrap(),
+            head.to_ascii_lowercase(),
+            body.to_string(),
+        )
+    }
+
+    #[test]
+    fn serve_answers_from_current_and_refuses_traversal() {
+        use std::io::BufRead;
+
+        let (addr, handle) =
+            single_file_origin("v-serve", &sha(b"<h1>serve</h1>"), b"<h1>serve</h1>");
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("outside.txt"), b"private").unwrap();
+        let status = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+            .arg("--origin")
+            .arg(format!("http://{addr}"))
+            .arg("--root")
+            .arg(root.path())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        send_quit(addr);
+        handle.join().unwrap();
+
+        let serve = |extra: &[&str]| {
+            let mut child = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .args(["serve", "--bind", "127.0.0.1:0"])
+                .args(extra)
+                .arg("--root")
+                .arg(root.path())
+                .stderr(std::process::Stdio::piped())
+                .spawn()
+                .unwrap();
+            let mut stderr = std::io::BufReader::new(child.stderr.take().unwrap());
+            let mut line = String::new();
+            let listening = loop {
+                line.clear();
+                assert!(
+                    stderr.read_line(&mut line).unwrap() > 0,
+                    "serve exited early"
+                );
+                if let Some((_, rest)) = line.split_once(" on http://") {
+                    break rest.trim().trim_end_matches('/').to_string();
+                }
+            };
+            // Keep draining so request log lines never hit a closed pipe.
+            thread::spawn(move || std::io::copy(&mut stderr, &mut std::io::sink()));
+            (child, listening)
+        };
+
+        let (mut child, listening) = serve(&["--timeout", "30s"]);
+        let (status, head, body) = raw_get(&listening, "/");
+        assert_eq!((status, body.as_str()), (200, "<h1>serve</h1>"));
+        assert!(
+            head.contains("content-type: text/html; charset=utf-8"),
+            "{head}"
+        );
+        for escape in [
+            "/../outside.txt",
+            "/../../outside.txt",
+            "/%2e%2e/outside.txt",
+        ] {
+            let (status, _, body) = raw_get(&listening, escape);
+            assert_eq!(status, 404, "{escape}");
+            assert!(!body.contains("private"));
+        }
+        assert_eq!(raw_get(&listening, "/missing.html").0, 404);
+        let status = Command::new("kill")
+            .args(["-TERM", &child.id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert!(child.wait().unwrap().success());
+
+        // --once exits by itself after one request.
+        let (mut child, listening) = serve(&["--once"]);
+        assert_eq!(raw_get(&listening, "/index.html").0, 200);
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn canary_failure_rolls_current_back() {
+        use std::io::BufRead;
+
+        let usb = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let deploy = |canary: &str| {
+            Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .arg("--origin")
+                .arg(format!("file://{}/", usb.path().display()))
+                .arg("--root")
+                .arg(root.path())
+                .args(["--canary-url", canary, "--canary-grace", "50ms"])
+                .args(["--canary-timeout", "5s"])
+                .output()
+                .unwrap()
+        };
+        write_file_origin(
+            usb.path(),
+            "v-can1",
+            &[("index.html", &sha(b"one"), b"one")],
+        );
+        assert!(Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+            .arg("--origin")
+            .arg(format!("file://{}/", usb.path().display()))
+            .arg("--root")
+            .arg(root.path())
+            .status()
+            .unwrap()
+            .success());
+
+        // The local web server, serving whatever `current` points at.
+        let mut server = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+            .args([
+                "serve",
+                "--bind",
+                "127.0.0.1:0",
+                "--timeout",
+                "30s",
+                "--root",
+            ])
+            .arg(root.path())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stderr = std::io::BufReader::new(server.stderr.take().unwrap());
+        let mut line = String::new();
+        let listening = loop {
+            line.clear();
+            assert!(
+                stderr.read_line(&mut line).unwrap() > 0,
+                "serve exited early"
+            );
+            if let Some((_, rest)) = line.split_once(" on http://") {
+                break rest.trim().trim_end_matches('/').to_string();
+            }
+        };
+        thread::spawn(move || std::io::copy(&mut stderr, &mut std::io::sink()));
+        let canary = format!("http://{listening}/index.html");
+
+        // v-can2 lost its index page: the server can't serve it.
+        write_file_origin(
+            usb.path(),
+            "v-can2",
+            &[("other.html", &sha(b"two"), b"two")],
+        );
+        let out = deploy(&canary);
+        assert_eq!(out.status.code(), Some(8));
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(
+            stderr.contains("canary failed after switching to v-can2; current rolled back"),
+            "stderr: {stderr}"
+        );
+        assert!(stderr.contains("404"), "stderr: {stderr}");
+        assert_eq!(
+            fs::read_link(root.path().join("current")).unwrap(),
+            std::path::Path::new("snapshots/v-can1")
+        );
+        let state: serde_json::Value =
+            serde_json::from_slice(&fs::read(root.path().join("deploy-state.json")).unwrap())
+                .unwrap();
+        assert_eq!(state["version"], "v-can1");
+        let history = fs::read_to_string(root.path().join("state/history.jsonl")).unwrap();
+        let last: serde_json::Value =
+            serde_json::from_str(history.lines().last().unwrap()).unwrap();
+        assert_eq!(last["outcome"], "error");
+        assert_eq!(last["version"], "v-can2");
+
+        write_file_origin(
+            usb.path(),
+            "v-can3",
+            &[("index.html", &sha(b"three"), b"three")],
+        );
+        let out = deploy(&canary);
+        assert_eq!(
+            out.status.code(),
+            Some(0),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        assert_eq!(
+            fs::read_link(root.path().join("current")).unwrap(),
+            std::path::Path::new("snapshots/v-can3")
+        );
+
+        Command::new("kill")
+            .args(["-TERM", &server.id().to_string()])
+            .status()
+            .unwrap();
+        server.wait().unwrap();
+    }
+
+    #[test]
+    fn migrate_objects_nests_a_flat_store_that_deploys_keep_using() {
+        use sha2::{Digest, Sha256};
+
+        let sha256 = |bytes: &[u8]| {
+            Sha256::digest(bytes)
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect::<String>()
+        };
+        let usb = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let objects = root.path().join("objects");
+        let nested = |hash: &str| objects.join(&hash[..2]).join(&hash[2..4]).join(hash);
+        let cli = |args: &[&str]| {
+            Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .args(args)
+                .arg("--root")
+                .arg(root.path())
+                .output()
+                .unwrap()
+        };
+        let origin = format!("file://{}/", usb.path().display());
+        let deploy = || {
+            let out = cli(&["--origin", &origin, "--output", "json"]);
+            assert_eq!(
+                out.status.code(),
+                Some(0),
+                "{}",
+                String::from_utf8_lossy(&out.stderr)
+            );
+            serde_json::from_slice::<serde_json::Value>(&out.stdout).unwrap()
+        };
+        let (home, stops, alerts) = (
+            b"<h1>metro</h1>".as_slice(),
+            b"stops".as_slice(),
+            b"alerts".as_slice(),
+        );
+        write_file_origin(
+            usb.path(),
+            "v-flat",
+            &[
+                ("index.html", &sha256(home), home),
+                ("stops.html", &sha256(stops), stops),
+            ],
+        );
+        deploy();
+        assert!(objects.join(sha256(home)).is_file());
+
+        let out = cli(&["migrate-objects"]);
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(out.status.code(), Some(0), "{stderr}");
+        assert!(
+            stderr.contains("moved 2 objects into the nested layout"),
+            "{stderr}"
+        );
+        for body in [home, stops] {
+            assert_eq!(fs::read(nested(&sha256(body))).unwrap(), body);
+            assert!(!objects.join(sha256(body)).exists());
+        }
+        assert_eq!(
+            fs::read_to_string(objects.join(".layout")).unwrap(),
+            "nested\n"
+        );
+        let out = cli(&["migrate-objects"]);
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(
+            stderr.contains("objects are already in the nested layout"),
+            "{stderr}"
+        );
+        let out = cli(&["fsck"]);
+        assert_eq!(
+            out.status.code(),
+            Some(0),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+
+        // The next deploy finds the moved objects and stores new ones nested.
+        write_file_origin(
+            usb.path(),
+            "v-nested",
+            &[
+                ("index.html", &sha256(home), home),
+                ("alerts.html", &sha256(alerts), alerts),
+            ],
+        );
+        let summary = deploy();
+        assert_eq!(summary["objects_downloaded"], 1, "{summary}");
+        assert_eq!(summary["objects_reused"], 1, "{summary}");
+        assert_eq!(fs::read(nested(&sha256(alerts))).unwrap(), alerts);
+        assert!(!objects.join(sha256(alerts)).exists());
+        let current = root.path().join("current");
+        assert_eq!(fs::read(current.join("index.html")).unwrap(), home);
+        assert_eq!(fs::read(current.join("alerts.html")).unwrap(), alerts);
+    }
+
+    #[test]
+    fn fsck_detects_and_quarantines_a_corrupt_object() {
+        use sha2::{Digest, Sha256};
+        use std::os::unix::fs::PermissionsExt;
+
+        let sha256 = |bytes: &[u8]| {
+            Sha256::digest(bytes)
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect::<String>()
+        };
+        let usb = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let (good, bad) = (b"route 2 on time".as_slice(), b"route 3 delayed".as_slice());
+        write_file_origin(
+            usb.path(),
+            "v-fsck",
+            &[
+                ("a.html", &sha256(good), good),
+                ("b.html", &sha256(bad), bad),
+            ],
+        );
+        let status = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+            .arg("--origin")
+            .arg(format!("file://{}/", usb.path().display()))
+            .arg("--root")
+            .arg(root.path())
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let fsck = |extra: &[&str]| {
+            let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .arg("fsck")
+                .arg("--root")
+                .arg(root.path())
+                .args(["--output", "json"])
+                .args(extra)
+                .output()
+                .unwrap();
+            let doc: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+            (
+                out.status.code(),
+                doc,
+                String::from_utf8_lossy(&out.stderr).into_owned(),
+            )
+        };
+        let (code, doc, _) = fsck(&[]);
+        assert_eq!(code, Some(0));
+        assert_eq!(doc["objects_checked"], 2);
+        assert_eq!(doc["corrupt"], 0);
+
+        // Same size, one flipped byte.
+        let planted = root.path().join("objects").join(sha256(bad));
+        fs::set_permissions(&planted, fs::Permissions::from_mode(0o644)).unwrap();
+        fs::write(&planted, b"route 3 delayeD").unwrap();
+        let (code, doc, stderr) = fsck(&[]);
+        assert_eq!(code, Some(5));
+        assert_eq!(doc["corrupt"], 1);
+        assert_eq!(doc["problems"][0]["kind"], "corrupt");
+        assert_eq!(doc["problems"][0]["path"], planted.to_str().unwrap());
+        assert!(stderr.contains("1 corrupt"), "{stderr}");
+        assert!(planted.exists(), "quarantined without --delete-corrupt");
+
+        let (code, doc, _) = fsck(&["--delete-corrupt"]);
+        assert_eq!(code, Some(5));
+        assert_eq!(doc["quarantined"], 1);
+        assert!(!planted.exists());
+        let quarantined = root.path().join("objects/.quarantine").join(sha256(bad));
+        assert_eq!(fs::read(quarantined).unwrap(), b"route 3 delayeD");
+
+        let (code, doc, _) = fsck(&[]);
+        assert_eq!(code, Some(0));
+        assert_eq!(doc["objects_checked"], 1);
+    }
+
+    #[test]
+    fn exit_code_distinguishes_object_failures() {
+        let manifest = format!(
+            r#"{{"version": "v-missing", "files": [{{ "path": "a.html", "hash": "{}", "size": 3 }}]}}"#,
+            h("nothere")
+        );
+        let (addr, handle) = start_origin(
+            "v-missing",
+            manifest.as_bytes().to_vec(),
+            HashMap::new(),
+            Arc::new(AtomicUsize::new(0)),
+            Arc::new(AtomicUsize::new(0)),
+        );
+        let root = tempfile::tempdir().unwrap();
+        let status = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+            .arg("--origin")
+            .arg(format!("http://{addr}"))
+            .arg("--root")
+            .arg(root.path())
+            .status()
+            .unwrap();
+        assert_eq!(status.code(), Some(5));
+        assert!(fs::symlink_metadata(root.path().join("current")).is_err());
+
+        send_quit(addr);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn malformed_hash_is_rejected_before_any_object_request() {
+        let manifest = r#"{"version": "v-bad", "files": [{ "path": "a.html", "hash": "..%2e%2e", "size": 3 }]}"#;
+        let mut objects = HashMap::new();
+        objects.insert("..%2e%2e".to_string(), b"bad".to_vec());
+        let object_hits = Arc::new(AtomicUsize::new(0));
+        let (addr, handle) = start_origin(
+            "v-bad",
+            manifest.as_bytes().to_vec(),
+            objects,
+            Arc::new(AtomicUsize::new(0)),
+            object_hits.clone(),
+        );
+        let root = tempfile::tempdir().unwrap();
+        let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+            .arg("--origin")
+            .arg(format!("http://{addr}"))
+            .arg("--root")
+            .arg(root.path())
+            .output()
+            .unwrap();
+        assert_eq!(out.status.code(), Some(4));
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(
+            stderr.contains(r#"invalid hash for a.html: "..%2e%2e""#),
+            "{stderr}"
+        );
+        assert_eq!(object_hits.load(Ordering::SeqCst), 0);
+
+        send_quit(addr);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn keep_going_reports_every_missing_object_in_one_run() {
+        let names = ["a", "b", "c", "d", "e"];
+        let entries: Vec<String> = names
+            .iter()
+            .map(|name| {
+                format!(
+                    r#"{{ "path": "{name}.html", "hash": "{}", "size": 4 }}"#,
+                    sha(format!("{name}!!!").as_bytes())
+                )
+            })
+            .collect();
+        let manifest = format!(
+            r#"{{"version": "v-keep", "files": [{}]}}"#,
+            entries.join(",")
+        );
+        // b and d are missing everywhere.
+        let objects: HashMap<String, Vec<u8>> = ["a", "c", "e"]
+            .iter()
+            .map(|name| {
+                (
+                    sha(format!("{name}!!!").as_bytes()),
+                    format!("{name}!!!").into_bytes(),
+                )
+            })
+            .collect();
+        let object_hits = Arc::new(AtomicUsize::new(0));
+        let (addr, handle) = start_origin(
+            "v-keep",
+            manifest.into_bytes(),
+            objects,
+            Arc::new(AtomicUsize::new(0)),
+            object_hits.clone(),
+        );
+        let (origin, down) = (format!("http://{addr}"), closed_origin());
+        let root = tempfile::tempdir().unwrap();
+        let run = |extra: &[&str]| {
+            let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .args(["--origin", &origin, &down])
+                .arg("--root")
+                .arg(root.path())
+                .args(["--output", "json"])
+                .args(extra)
+                .output()
+                .unwrap();
+            let doc: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+            (
+                out.status.code(),
+                doc,
+                String::from_utf8_lossy(&out.stderr).into_owned(),
+            )
+        };
+
+        // Without --keep-going the first missing object ends the run.
+        let (code, doc, stderr) = run(&[]);
+        assert_eq!(code, Some(5), "{stderr}");
+        assert_eq!(doc["objects_failed"], 1);
+        assert_eq!(doc["failed_objects"][0]["paths"][0], "b.html");
+        assert_eq!(object_hits.load(Ordering::SeqCst), 1);
+
+        let (code, doc, stderr) = run(&["--keep-going"]);
+        assert_eq!(code, Some(5), "{stderr}");
+        assert_eq!(doc["objects_failed"], 2);
+        assert_eq!(doc["objects_downloaded"], 2);
+        assert_eq!(object_hits.load(Ordering::SeqCst), 3);
+        let failed: Vec<(&str, &str)> = doc["failed_objects"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| (f["paths"][0].as_str().unwrap(), f["hash"].as_str().unwrap()))
+            .collect();
+        let (b, d) = (sha(b"b!!!"), sha(b"d!!!"));
+        assert_eq!(failed, [("b.html", b.as_str()), ("d.html", d.as_str())]);
+        let origins: Vec<&str> = doc["failed_objects"][1]["origins"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|o| o["origin"].as_str().unwrap())
+            .collect();
+        assert_eq!(origins, [origin.as_str(), down.as_str()]);
+        assert!(
+            doc["failed_objects"][1]["origins"][0]["error"]
+                .as_str()
+                .unwrap()
+                .contains("404"),
+            "{doc}"
+        );
+        assert!(
+            stderr.contains("2 objects could not be downloaded from any origin: b.html"),
+            "{stderr}"
+        );
+        assert!(fs::symlink_metadata(root.path().join("current")).is_err());
+
+        let report: serde_json::Value =
+            serde_json::from_slice(&fs::read(root.path().join("state/last-error.json")).unwrap())
+                .unwrap();
+        assert_eq!(report["phase"], "download");
+        assert_eq!(report["failed_objects"].as_array().unwrap().len(), 2);
+
+        send_quit(addr);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn circuit_breaker_abandons_a_timing_out_origin() {
+        // Accepts connections and never answers.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let slow = format!("http://{}", listener.local_addr().unwrap());
+        let accepted = Arc::new(AtomicUsize::new(0));
+        {
+            let accepted = Arc::clone(&accepted);
+            thread::spawn(move || {
+                let mut held = Vec::new();
+                for stream in listener.incoming() {
+                    accepted.fetch_add(1, Ordering::SeqCst);
+                    held.push(stream);
+                }
+            });
+        }
+        let names: Vec<String> = (0..8).map(|i| format!("page{i}")).collect();
+        let entries: Vec<String> = names
+            .iter()
+            .map(|name| {
+                format!(
+                    r#"{{ "path": "{name}.html", "hash": "{}", "size": 5 }}"#,
+                    sha(&name.as_bytes()[..5])
+                )
+            })
+            .collect();
+        let manifest = format!(
+            r#"{{"version": "v-breaker", "files": [{}]}}"#,
+            entries.join(",")
+        );
+        let objects: HashMap<String, Vec<u8>> = names
+            .iter()
+            .map(|name| (sha(&name.as_bytes()[..5]), name.as_bytes()[..5].to_vec()))
+            .collect();
+        let (addr, handle) = start_origin(
+            "v-breaker",
+            manifest.into_bytes(),
+            objects,
+            Arc::new(AtomicUsize::new(0)),
+            Arc::new(AtomicUsize::new(0)),
+        );
+        let healthy = format!("http://{addr}");
+        let run = |origins: &[&str], extra: &[&str]| {
+            let root = tempfile::tempdir().unwrap();
+            let started = std::time::Instant::now();
+            let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .arg("--origin")
+                .args(origins)
+                .arg("--root")
+                .arg(root.path())
+                .args(["--request-timeout", "300ms"])
+                .args(["--output", "json"])
+                .args(extra)
+                .output()
+                .unwrap();
+            let doc: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+            (
+                out.status.code(),
+                doc,
+                String::from_utf8_lossy(&out.stderr).into_owned(),
+                started.elapsed(),
+            )
+        };
+
+        let (code, doc, stderr, took) = run(&[&slow, &healthy], &["--origin-error-budget", "2"]);
+        assert_eq!(code, Some(0), "{stderr}");
+        assert_eq!(doc["objects_downloaded"], 8);
+        // The manifest and the first object; after that it is skipped.
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+        assert_eq!(doc["origins"][0]["origin"], slow.as_str());
+        assert_eq!(doc["origins"][0]["requests"], 2);
+        assert_eq!(doc["origins"][0]["circuit"], "open");
+        let transitions = &doc["stats"]["circuit_transitions"];
+        assert_eq!(transitions.as_array().unwrap().len(), 1, "{transitions}");
+        assert_eq!(transitions[0]["origin"], slow.as_str());
+        assert_eq!(transitions[0]["from"], "closed");
+        assert_eq!(transitions[0]["to"], "open");
+        assert_eq!(transitions[0]["reason"], "2 failed requests");
+        assert!(took < Duration::from_secs(5), "{took:?}");
+
+        // Every origin tripped: the error says so, not just the last failure.
+        let (a, b) = (closed_origin(), closed_origin());
+        let manifest_url = format!("{healthy}/manifests/latest.json");
+        let (code, _, stderr, _) = run(
+            &[&a, &b],
+            &[
+                "--manifest-url",
+                &manifest_url,
+                "--origin-error-budget",
+                "1",
+            ],
+        );
+        assert_eq!(code, Some(5), "{stderr}");
+        assert!(
+            stderr.contains("every origin's circuit breaker is open"),
+            "{stderr}"
+        );
+
+        send_quit(addr);
+        handle.join().unwrap();
+    }
+
+    /// A loopback address nothing listens on, so connections are refused.
+    fn closed_origin() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        format!("http://{addr}")
+    }
+
+    #[test]
+    fn log_format_json_emits_one_event_object_per_line() {
+        let (addr, handle) = single_file_origin("v-log", &sha(b"log"), b"log");
+        let root = tempfile::tempdir().unwrap();
+
+        let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+            .arg("--origin")
+            .arg(closed_origin())
+            .arg("--origin")
+            .arg(format!("http://{addr}"))
+            .arg("--root")
+            .arg(root.path())
+            .args(["--log-format", "json"])
+            .output()
+            .unwrap();
+        assert!(out.status.success());
+
+        let stderr = String::from_utf8(out.stderr).unwrap();
+        let events: Vec<serde_json::Value> = stderr
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap_or_else(|e| panic!("{e}: {line}")))
+            .collect();
+        assert!(events.iter().all(|e| e["event"].is_string()));
+        let names: Vec<&str> = events
+            .iter()
+            .map(|e| e["event"].as_str().unwrap())
+            .collect();
+        assert!(names.contains(&"manifest_fetch_failed"));
+        assert!(names.contains(&"download_object"));
+        // The closed origin failed once, so per-origin counts follow the
+        // outcome, then the run's statistics.
+        let switched = names.iter().position(|n| *n == "switched").unwrap();
+        assert_eq!(names[switched + 1..], ["origin_stats", "run_stats"]);
+
+        let manifest = events.iter().find(|e| e["event"] == "manifest").unwrap();
+        assert_eq!(manifest["version"], "v-log");
+        let download = events
+            .iter()
+            .find(|e| e["event"] == "download_object")
+            .unwrap();
+        assert_eq!(download["hash"], sha(b"log"));
+        assert_eq!(download["bytes"], 3);
+
+        send_quit(addr);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn quiet_and_verbose_control_stderr_detail() {
+        let (addr, handle) = single_file_origin("v-verb", &sha(b"verbose"), b"verbose");
+        let origin = format!("http://{addr}");
+        let bin = env!("CARGO_BIN_EXE_cityfeed-puller");
+
+        let root = tempfile::tempdir().unwrap();
+        let out = Command::new(bin)
+            .args(["--origin", &origin, "--quiet"])
+            .arg("--root")
+            .arg(root.path())
+            .output()
+            .unwrap();
+        assert!(out.status.success());
+        assert_eq!(
+            String::from_utf8(out.stderr).unwrap(),
+            "switched current -> snapshots/v-verb\n"
+        );
+
+        let root = tempfile::tempdir().unwrap();
+        let out = Command::new(bin)
+            .args(["--origin", &origin, "-v"])
+            .arg("--root")
+            .arg(root.path())
+            .output()
+            .unwrap();
+        assert!(out.status.success());
+        let stderr = String::from_utf8(out.stderr).unwrap();
+        assert!(stderr.contains(&format!("GET {origin}/manifests/latest.json -> 200 OK in ")));
+        assert!(stderr.contains(&format!(
+            "GET {origin}/objects/{} -> 200 OK in ",
+            sha(b"verbose")
+        )));
+        assert!(stderr.contains(&format!("manifest attempt origin={origin} ok in ")));
+        assert!(stderr.contains(&format!("download object hash={} size=7", sha(b"verbose"))));
+
+        let out = Command::new(bin)
+            .args(["--origin", &origin, "-q", "-v"])
+            .output()
+            .unwrap();
+        assert_eq!(out.status.code(), Some(2));
+
+        send_quit(addr);
+        handle.join().unwrap();
+    }
+
+    fn wait_for_current(root: &std::path::Path, want: &str) {
+        let deadline = Instant::now() + Duration::from_secs(20);
+        while Instant::now() < deadline {
+            if fs::read_link(root.join("current")).ok().as_deref()
+                == Some(std::path::Path::new(want))
+            {
+                return;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        panic!("current never switched to {want}");
+    }
+
+    #[test]
+    fn watch_deploys_each_new_version_and_exits_on_sigterm() {
+        let usb = tempfile::tempdir().unwrap();
+        write_file_origin(usb.path(), "v-w1", &[("index.html", &sha(b"one"), b"one")]);
+        let root = tempfile::tempdir().unwrap();
+        let bin = env!("CARGO_BIN_EXE_cityfeed-puller");
+
+        let mut child = Command::new(bin)
+            .arg("--origin")
+            .arg(format!("file://{}/", usb.path().display()))
+            .arg("--root")
+            .arg(root.path())
+            .args(["--watch", "--interval", "200ms"])
+            .spawn()
+            .unwrap();
+
+        wait_for_current(root.path(), "snapshots/v-w1");
+
+        // Publish the next version the way the publisher does: objects first,
+        // then swap the manifest in with a rename.
+        let next = tempfile::tempdir_in(usb.path()).unwrap();
+        write_file_origin(next.path(), "v-w2", &[("index.html", &sha(b"two"), b"two")]);
+        fs::rename(
+            next.path().join("objects").join(sha(b"two")),
+            usb.path().join("objects").join(sha(b"two")),
+        )
+        .unwrap();
+        fs::rename(
+            next.path().join("manifests/latest.json"),
+            usb.path().join("manifests/latest.json"),
+        )
+        .unwrap();
+
+        wait_for_current(root.path(), "snapshots/v-w2");
+        assert_eq!(
+            fs::read(root.path().join("current/index.html")).unwrap(),
+            b"two"
+        );
+        assert!(child.try_wait().unwrap().is_none(), "watch exited early");
+
+        // Every cycle is an attempt in the deploy history, deploy or not;
+        // the one after v-w2 finds it already current.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let entries = loop {
+            let history =
+                fs::read_to_string(root.path().join("state/history.jsonl")).unwrap_or_default();
+            let entries: Vec<serde_json::Value> = history
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect();
+            if entries
+                .last()
+                .is_some_and(|e| e["outcome"] == "already-current")
+            {
+                break entries;
+            }
+            assert!(Instant::now() < deadline, "history: {history}");
+            thread::sleep(Duration::from_millis(50));
+        };
+        let updated: Vec<&str> = entries
+            .iter()
+            .filter(|e| e["outcome"] == "updated")
+            .map(|e| e["version"].as_str().unwrap())
+            .collect();
+        assert_eq!(updated, ["v-w1", "v-w2"]);
+
+        let status = Command::new("kill")
+            .args(["-TERM", &child.id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let exit = loop {
+            if let Some(exit) = child.try_wait().unwrap() {
+                break exit;
+            }
+            assert!(Instant::now() < deadline, "watch ignored SIGTERM");
+            thread::sleep(Duration::from_millis(50));
+        };
+        assert_eq!(exit.code(), Some(0));
+    }
+
+    #[test]
+    fn watch_notifies_systemd_ready_after_first_deploy() {
+        use std::os::unix::net::UnixDatagram;
+
+        let usb = tempfile::tempdir().unwrap();
+        write_file_origin(usb.path(), "v-sd", &[("index.html", &sha(b"sd"), b"sd")]);
+        let root = tempfile::tempdir().unwrap();
+        let sock_dir = tempfile::tempdir().unwrap();
+        let sock_path = sock_dir.path().join("notify.sock");
+        let sock = UnixDatagram::bind(&sock_path).unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(20)))
+            .unwrap();
+        let bin = env!("CARGO_BIN_EXE_cityfeed-puller");
+
+        let mut child = Command::new(bin)
+            .arg("--origin")
+            .arg(format!("file://{}/", usb.path().display()))
+            .arg("--root")
+            .arg(root.path())
+            .args(["--watch", "--interval", "100ms"])
+            .env("NOTIFY_SOCKET", &sock_path)
+            .env("WATCHDOG_USEC", "400000")
+            .spawn()
+            .unwrap();
+
+        let mut buf = [0u8; 512];
+        let mut recv = || {
+            let n = sock.recv(&mut buf).unwrap();
+            String::from_utf8(buf[..n].to_vec()).unwrap()
+        };
+        assert_eq!(recv(), "WATCHDOG=1");
+        assert_eq!(recv(), "READY=1\nSTATUS=deployed v-sd");
+        // READY must only go out once the first deploy has switched current.
+        assert_eq!(
+            fs::read_link(root.path().join("current")).unwrap(),
+            std::path::Path::new("snapshots/v-sd")
+        );
+        assert_eq!(recv(), "WATCHDOG=1");
+
+        Command::new("kill")
+            .args(["-TERM", &child.id().to_string()])
+            .status()
+            .unwrap();
+        loop {
+            let msg = recv();
+            if msg != "WATCHDOG=1" {
+                assert_eq!(msg, "STOPPING=1");
+                break;
+            }
+        }
+        assert_eq!(child.wait().unwrap().code(), Some(0));
+    }
+
+    fn dir_entries(dir: &std::path::Path) -> Vec<String> {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn sigterm_mid_download_cleans_up_and_leaves_current_alone() {
+        use std::io::BufRead;
+
+        let body = vec![b'x'; 400 * 1024];
+        let (addr, handle) = single_file_origin("v-term", &h("term"), &body);
+        let root = tempfile::tempdir().unwrap();
+        let bin = env!("CARGO_BIN_EXE_cityfeed-puller");
+
+        let mut child = Command::new(bin)
+            .arg("--origin")
+            .arg(format!("http://{addr}"))
+            .args(["--max-rate", "20K"])
+            .arg("--root")
+            .arg(root.path())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        // Wait until the download is under way. On Linux its temp file may
+        // have no name, so watch the log rather than objects/.
+        let mut stderr = std::io::BufReader::new(child.stderr.take().unwrap());
+        let mut line = String::new();
+        while !line.starts_with("download object") {
+            line.clear();
+            assert!(
+                stderr.read_line(&mut line).unwrap() > 0,
+                "download never started"
+            );
+        }
+        thread::spawn(move || std::io::copy(&mut stderr, &mut std::io::sink()));
+        thread::sleep(Duration::from_millis(200));
+        let objects = root.path().join("objects");
+
+        Command::new("kill")
+            .args(["-TERM", &child.id().to_string()])
+            .status()
+            .unwrap();
+        let status = child.wait().unwrap();
+        assert_eq!(status.code(), Some(6));
+
+        assert_eq!(dir_entries(&objects), Vec::<String>::new());
+        assert_eq!(
+            dir_entries(&root.path().join("snapshots")),
+            Vec::<String>::new()
+        );
+        assert!(fs::symlink_metadata(root.path().join("current")).is_err());
+
+        send_quit(addr);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn on_switch_hooks_run_only_when_current_moves() {
+        let usb = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let marker = root.path().join("hook.log");
+        let hook = format!(
+            "echo \"$CITYFEED_OLD_VERSION -> $CITYFEED_NEW_VERSION\" >> '{}'",
+            marker.display()
+        );
+        let bin = env!("CARGO_BIN_EXE_cityfeed-puller");
+        let run = |extra: &[&str]| {
+            Command::new(bin)
+                .arg("--origin")
+                .arg(format!("file://{}/", usb.path().display()))
+                .arg("--root")
+                .arg(root.path())
+                .args(["--on-switch", &hook])
+                .args(extra)
+                .output()
+                .unwrap()
+        };
+
+        write_file_origin(usb.path(), "v-h1", &[("index.html", &sha(b"one"), b"one")]);
+        assert_eq!(run(&[]).status.code(), Some(0));
+        assert_eq!(run(&[]).status.code(), Some(3));
+        write_file_origin(usb.path(), "v-h2", &[("index.html", &sha(b"two"), b"two")]);
+        assert_eq!(run(&[]).status.code(), Some(0));
+        assert_eq!(
+            fs::read_to_string(&marker).unwrap(),
+            " -> v-h1\nv-h1 -> v-h2\n"
+        );
+
+        // A failing hook is a warning by default and an exit code with
+        // --hook-failure fail; either way the switch stands.
+        write_file_origin(
+            usb.path(),
+            "v-h3",
+            &[("index.html", &sha(b"three"), b"three")],
+        );
+        let out = run(&["--on-switch", "exit 9"]);
+        assert_eq!(out.status.code(), Some(0));
+        assert!(String::from_utf8_lossy(&out.stderr).contains("`exit 9` exited with"));
+        write_file_origin(
+            usb.path(),
+            "v-h4",
+            &[("index.html", &sha(b"four"), b"four")],
+        );
+        let out = run(&["--on-switch", "exit 9", "--hook-failure", "fail"]);
+        assert_eq!(out.status.code(), Some(7));
+        assert_eq!(
+            fs::read_link(root.path().join("current")).unwrap(),
+            std::path::Path::new("snapshots/v-h4")
+        );
+        assert_eq!(
+            fs::read_to_string(&marker).unwrap(),
+            " -> v-h1\nv-h1 -> v-h2\nv-h2 -> v-h3\nv-h3 -> v-h4\n"
+        );
+    }
+
+    #[test]
+    fn on_switch_hooks_get_the_deploy_context() {
+        let usb = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let dumps = tempfile::tempdir().unwrap();
+        let hook = format!(
+            "env | grep ^CITYFEED_ | sort > '{0}/env-'$CITYFEED_NEW_VERSION; \
+             cat \"$CITYFEED_CHANGED_PATHS_FILE\" > '{0}/changed-'$CITYFEED_NEW_VERSION",
+            dumps.path().display()
+        );
+        let deploy = || {
+            let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .arg("--origin")
+                .arg(format!("file://{}/", usb.path().display()))
+                .arg("--root")
+                .arg(root.path())
+                .args(["--on-switch", &hook])
+                .output()
+                .unwrap();
+            assert_eq!(
+                out.status.code(),
+                Some(0),
+                "{}",
+                String::from_utf8_lossy(&out.stderr)
+            );
+        };
+        let env = |version: &str| -> std::collections::BTreeMap<String, String> {
+            fs::read_to_string(dumps.path().join(format!("env-{version}")))
+                .unwrap()
+                .lines()
+                .filter_map(|line| line.split_once('='))
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        let changed = |version: &str| {
+            fs::read_to_string(dumps.path().join(format!("changed-{version}"))).unwrap()
+        };
+
+        write_file_origin(
+            usb.path(),
+            "v-ctx1",
+            &[
+                ("index.html", &sha(b"one"), b"one"),
+                ("css/site.css", &sha(b"body{}"), b"body{}"),
+            ],
+        );
+        deploy();
+        let first = env("v-ctx1");
+        let root_path = root.path().display().to_string();
+        assert_eq!(first["CITYFEED_ROOT"], root_path);
+        assert_eq!(first["CITYFEED_OLD_VERSION"], "");
+        assert_eq!(first["CITYFEED_NEW_VERSION"], "v-ctx1");
+        assert_eq!(first["CITYFEED_FIRST_DEPLOY"], "1");
+        assert_eq!(
+            first["CITYFEED_SNAPSHOT_PATH"],
+            format!("{root_path}/snapshots/v-ctx1")
+        );
+        assert_eq!(changed("v-ctx1"), "css/site.css\nindex.html\n");
+
+        write_file_origin(
+            usb.path(),
+            "v-ctx2",
+            &[
+                ("index.html", &sha(b"two"), b"two"),
+                ("js/app.js", &sha(b"app()"), b"app()"),
+            ],
+        );
+        deploy();
+        let second = env("v-ctx2");
+        assert_eq!(second["CITYFEED_OLD_VERSION"], "v-ctx1");
+        assert_eq!(second["CITYFEED_FIRST_DEPLOY"], "0");
+        assert_eq!(changed("v-ctx2"), "css/site.css\nindex.html\njs/app.js\n");
+        // The list is gone once the hooks have run.
+        let list = &second["CITYFEED_CHANGED_PATHS_FILE"];
+        assert!(!list.is_empty());
+        assert!(!std::path::Path::new(list).exists());
+    }
+
+    fn start_webhook_receiver() -> (
+        std::net::SocketAddr,
+        std::sync::mpsc::Receiver<serde_json::Value>,
+    ) {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            for mut req in server.incoming_requests() {
+                let mut body = String::new();
+                req.as_reader().read_to_string(&mut body).unwrap();
+                let _ = req.respond(Response::empty(204));
+                if tx.send(serde_json::from_str(&body).unwrap()).is_err() {
+                    break;
+                }
+            }
+        });
+        (addr, rx)
+    }
+
+    #[test]
+    fn notify_url_posts_run_summary() {
+        let usb = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let (addr, hooks) = start_webhook_receiver();
+        let bin = env!("CARGO_BIN_EXE_cityfeed-puller");
+        let run = |origin: &str, extra: &[&str]| {
+            Command::new(bin)
+                .args(["--origin", origin])
+                .arg("--root")
+                .arg(root.path())
+                .args(["--notify-url", &format!("http://{addr}/hook")])
+                .args(extra)
+                .status()
+                .unwrap()
+        };
+        let usb_origin = format!("file://{}/", usb.path().display());
+
+        write_file_origin(usb.path(), "v-n1", &[("index.html", &sha(b"one"), b"one")]);
+        assert!(run(&usb_origin, &[]).success());
+        let v = hooks.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(v["outcome"], "updated");
+        assert_eq!(v["switched"], true);
+        assert_eq!(v["old_version"], serde_json::Value::Null);
+        assert_eq!(v["new_version"], "v-n1");
+        assert_eq!(v["root"], root.path().to_str().unwrap());
+        assert_eq!(v["error"], serde_json::Value::Null);
+        assert!(!v["hostname"].as_str().unwrap().is_empty());
+        assert!(v["elapsed_secs"].as_f64().unwrap() >= 0.0);
+
+        // Nothing changed: silent by default, reported with --notify-on always.
+        assert_eq!(run(&usb_origin, &[]).code(), Some(3));
+        assert_eq!(run(&usb_origin, &["--notify-on", "always"]).code(), Some(3));
+        let v = hooks.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(v["outcome"], "already-current");
+
+        write_file_origin(usb.path(), "v-n2", &[("index.html", &sha(b"two"), b"two")]);
+        assert!(run(&usb_origin, &["--notify-on", "failure"]).success());
+        assert_eq!(
+            run(&closed_origin(), &["--notify-on", "failure"]).code(),
+            Some(4)
+        );
+        let v = hooks.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(v["outcome"], "error");
+        assert_eq!(v["old_version"], "v-n2");
+        assert!(v["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("fetch latest manifest from all origins"));
+        assert!(hooks.try_recv().is_err());
+
+        // A dead webhook is a warning, not a failed deploy.
+        write_file_origin(
+            usb.path(),
+            "v-n3",
+            &[("index.html", &sha(b"three"), b"three")],
+        );
+        let out = Command::new(bin)
+            .args(["--origin", &usb_origin])
+            .arg("--root")
+            .arg(root.path())
+            .args(["--notify-url", &closed_origin()])
+            .output()
+            .unwrap();
+        assert_eq!(out.status.code(), Some(0));
+        assert!(String::from_utf8_lossy(&out.stderr).contains("warn: notify http://"));
+    }
+
+    #[test]
+    fn max_total_bytes_refuses_oversized_deploys_before_downloading() {
+        let object_hits = Arc::new(AtomicUsize::new(0));
+        let manifest = format!(
+            r#"{{"version": "v-big", "files": [{{ "path": "index.html", "hash": "{}", "size": 7 }}]}}"#,
+            h("big")
+        );
+        let mut objects = HashMap::new();
+        objects.insert(h("big"), b"payload".to_vec());
+        let (addr, handle) = start_origin(
+            "v-big",
+            manifest.into_bytes(),
+            objects,
+            Arc::new(AtomicUsize::new(0)),
+            object_hits.clone(),
+        );
+        let root = tempfile::tempdir().unwrap();
+        let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+            .args(["--origin", &format!("http://{addr}")])
+            .args(["--max-total-bytes", "6", "--output", "json"])
+            .arg("--root")
+            .arg(root.path())
+            .output()
+            .unwrap();
+        assert_eq!(out.status.code(), Some(4));
+        assert!(String::from_utf8_lossy(&out.stderr)
+            .contains("deploy needs 7 bytes of new objects, over --max-total-bytes 6"));
+        let summary: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+        assert_eq!(summary["bytes_needed"], 7);
+        assert_eq!(summary["file_count"], 1);
+        assert_eq!(object_hits.load(Ordering::SeqCst), 0);
+
+        send_quit(addr);
+        handle.join().unwrap();
+    }
+
+    /// Reads forever, counting what it hands out.
+    struct Endless(Arc<AtomicUsize>);
+
+    impl Read for Endless {
+        fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+            out.fill(b'x');
+            self.0.fetch_add(out.len(), Ordering::SeqCst);
+            Ok(out.len())
+        }
+    }
+
+    #[test]
+    fn endless_object_body_is_cut_off_at_declared_size() {
+        let sent = Arc::new(AtomicUsize::new(0));
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        let manifest = format!(
+            r#"{{"version": "v-endless", "files": [{{ "path": "index.html", "hash": "{}", "size": 1000 }}]}}"#,
+            h("endless")
+        );
+        let streamed = sent.clone();
+        let handle = thread::spawn(move || {
+            for req in server.incoming_requests() {
+                match req.url() {
+                    "/__quit" => {
+                        let _ = req.respond(Response::empty(200));
+                        break;
+                    }
+                    "/manifests/latest.json" => {
+                        let _ = req.respond(Response::from_string(manifest.clone()));
+                    }
+                    _ => {
+                        // No Content-Length: chunked, and it never ends.
+                        let body = Endless(streamed.clone());
+                        let _ =
+                            req.respond(Response::new(StatusCode(200), vec![], body, None, None));
+                    }
+                }
+            }
+        });
+
+        let root = tempfile::tempdir().unwrap();
+        let started = Instant::now();
+        let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+            .args(["--origin", &format!("http://{addr}")])
+            .arg("--root")
+            .arg(root.path())
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(out.status.code(), Some(5), "{stderr}");
+        assert!(
+            stderr.contains("body exceeds declared size 1000"),
+            "{stderr}"
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(dir_entries(&root.path().join("objects")).is_empty());
+        // Only what fits in socket buffers went out before the puller hung up.
+        let sent = sent.load(Ordering::SeqCst);
+        assert!(sent < 64 << 20, "origin streamed {sent} bytes");
+
+        send_quit(addr);
+        handle.join().unwrap();
+    }
+
+    /// An origin whose first `short` answers for the object are `bad` rather
+    /// than `body`: cut short, or the right size with the wrong bytes. Sent
+    /// chunked, so there is no Content-Length to give it away.
+    fn start_mangling_origin(
+        version: &str,
+        body: &'static [u8],
+        bad: &'static [u8],
+        short: usize,
+        hits: Arc<AtomicUsize>,
+    ) -> (std::net::SocketAddr, thread::JoinHandle<()>) {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        let manifest = format!(
+            r#"{{"version": "{version}", "files": [{{ "path": "index.html", "hash": "{}", "size": {} }}]}}"#,
+            sha(body),
+            body.len()
+        );
+        let handle = thread::spawn(move || {
+            for req in server.incoming_requests() {
+                match req.url() {
+                    "/__quit" => {
+                        let _ = req.respond(Response::empty(200));
+                        break;
+                    }
+                    "/manifests/latest.json" => {
+                        let _ = req.respond(Response::from_string(manifest.clone()));
+                    }
+                    _ => {
+                        let hit = hits.fetch_add(1, Ordering::SeqCst);
+                        let sent = if hit < short { bad } else { body };
+                        let _ =
+                            req.respond(Response::new(StatusCode(200), vec![], sent, None, None));
+                    }
+                }
+            }
+        });
+        (addr, handle)
+    }
+
+    #[test]
+    fn truncated_object_is_retried_on_the_same_origin() {
+        let run = |addr: std::net::SocketAddr, root: &std::path::Path, extra: &[&str]| {
+            Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .args(["--origin", &format!("http://{addr}")])
+                .arg("--root")
+                .arg(root)
+                .args(extra)
+                .output()
+                .unwrap()
+        };
+
+        // Cut short once: the retry on the same origin gets the whole body.
+        let hits = Arc::new(AtomicUsize::new(0));
+        let (addr, handle) =
+            start_mangling_origin("v-cut1", b"full body", b"full", 1, hits.clone());
+        let root = tempfile::tempdir().unwrap();
+        let out = run(addr, root.path(), &[]);
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(out.status.code(), Some(0), "{stderr}");
+        assert!(stderr.contains("(attempt 1): object "), "{stderr}");
+        assert!(
+            stderr.contains("size mismatch: expected 9 got 4"),
+            "{stderr}"
+        );
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            fs::read(root.path().join("current/index.html")).unwrap(),
+            b"full body"
+        );
+        send_quit(addr);
+        handle.join().unwrap();
+
+        // The right size but the wrong bytes: caught by the hash, retried the
+        // same way, and never stored under the manifest's hash.
+        let hits = Arc::new(AtomicUsize::new(0));
+        let (addr, handle) =
+            start_mangling_origin("v-cut3", b"full body", b"full bod!", 1, hits.clone());
+        let root = tempfile::tempdir().unwrap();
+        let out = run(addr, root.path(), &[]);
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(out.status.code(), Some(0), "{stderr}");
+        assert!(
+            stderr.contains(&format!(
+                "object {} hash mismatch: got {}",
+                sha(b"full body"),
+                sha(b"full bod!")
+            )),
+            "{stderr}"
+        );
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            fs::read(root.path().join("objects").join(sha(b"full body"))).unwrap(),
+            b"full body"
+        );
+        send_quit(addr);
+        handle.join().unwrap();
+
+        // Cut short every time: the retries run out and the object fails.
+        let hits = Arc::new(AtomicUsize::new(0));
+        let (addr, handle) =
+            start_mangling_origin("v-cut2", b"full body", b"full", 9, hits.clone());
+        let root = tempfile::tempdir().unwrap();
+        let out = run(addr, root.path(), &["--mismatch-retries", "2"]);
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(out.status.code(), Some(5), "{stderr}");
+        assert!(stderr.contains("(attempt 3): object"), "{stderr}");
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+        assert!(!root.path().join("current").exists());
+
+        // --mismatch-retries 0 fails over (here: gives up) on the first mismatch.
+        let root = tempfile::tempdir().unwrap();
+        hits.store(0, Ordering::SeqCst);
+        let out = run(addr, root.path(), &["--mismatch-retries", "0"]);
+        assert_eq!(out.status.code(), Some(5));
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        send_quit(addr);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn parallel_staging_builds_complete_snapshots_and_stops_on_error() {
+        use sha2::{Digest, Sha256};
+
+        let sha256 = |bytes: &[u8]| {
+            Sha256::digest(bytes)
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect::<String>()
+        };
+        let usb = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let run = |extra: &[&str]| {
+            Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .args(["--origin", &format!("file://{}/", usb.path().display())])
+                .args(["--stage-jobs", "8"])
+                .arg("--root")
+                .arg(root.path())
+                .args(extra)
+                .output()
+                .unwrap()
+        };
+        let files: Vec<(String, Vec<u8>)> = (0..300)
+            .map(|i| {
+                (
+                    format!("d{}/s{}/f{i}.html", i % 7, i % 3),
+                    format!("<p>{i}</p>").into_bytes(),
+                )
+            })
+            .collect();
+        let publish = |version: &str, extra: Option<(&str, &[u8])>| {
+            let hashes: Vec<String> = files.iter().map(|(_, body)| sha256(body)).collect();
+            let mut entries: Vec<(&str, &str, &[u8])> = files
+                .iter()
+                .zip(&hashes)
+                .map(|((path, body), hash)| (path.as_str(), hash.as_str(), body.as_slice()))
+                .collect();
+            let extra_hash = extra.map(|(_, body)| sha256(body));
+            if let (Some((path, body)), Some(hash)) = (extra, &extra_hash) {
+                entries.push((path, hash, body));
+            }
+            write_file_origin(usb.path(), version, &entries);
+        };
+
+        publish("v-par1", None);
+        let out = run(&[]);
+        assert_eq!(
+            out.status.code(),
+            Some(0),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        for (path, body) in &files {
+            assert_eq!(
+                &fs::read(root.path().join("current").join(path)).unwrap(),
+                body
+            );
+        }
+
+        // One corrupt object among hundreds fails the run and leaves no snapshot.
+        let stored = root.path().join("objects").join(sha256(&files[150].1));
+        let mut perms = fs::metadata(&stored).unwrap().permissions();
+        std::os::unix::fs::PermissionsExt::set_mode(&mut perms, 0o644);
+        fs::set_permissions(&stored, perms).unwrap();
+        let mut garbled = files[150].1.clone();
+        garbled[0] = b'#';
+        fs::write(&stored, garbled).unwrap();
+        publish("v-par2", Some(("new.html", b"new")));
+        let out = run(&["--verify-on-stage"]);
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(out.status.code(), Some(5), "{stderr}");
+        assert!(
+            stderr.contains(&format!("stage {}: ", files[150].0)),
+            "{stderr}"
+        );
+        assert_eq!(dir_entries(&root.path().join("snapshots")), ["v-par1"]);
+        assert!(fs::read_link(root.path().join("current"))
+            .unwrap()
+            .ends_with("v-par1"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn large_object_temp_is_preallocated_before_the_body_arrives() {
+        use std::os::unix::fs::MetadataExt;
+
+        const SIZE: u64 = 80 << 20;
+        // Serves the manifest, then 1 KiB of the object and nothing more.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let manifest = format!(
+            r#"{{"version": "v-big", "files": [{{ "path": "big.bin", "hash": "{}", "size": {SIZE} }}]}}"#,
+            h("prealloc")
+        );
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { break };
+                let manifest = manifest.clone();
+                thread::spawn(move || {
+                    let mut head = [0u8; 4096];
+                    let n = stream.read(&mut head).unwrap_or(0);
+                    if String::from_utf8_lossy(&head[..n]).starts_with("GET /objects/") {
+                        let _ = stream.write_all(
+                            format!("HTTP/1.1 200 OK\r\nContent-Length: {SIZE}\r\n\r\n").as_bytes(),
+                        );
+                        let _ = stream.write_all(&[b'x'; 1024]);
+                        thread::sleep(Duration::from_secs(30));
+                    } else {
+                        let _ = stream.write_all(
+                            format!(
+                                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{manifest}",
+                                manifest.len()
+                            )
+                            .as_bytes(),
+                        );
+                    }
+                });
+            }
+        });
+
+        let root = tempfile::tempdir().unwrap();
+        let mut child = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+            .args(["--origin", &format!("http://{addr}")])
+            .arg("--root")
+            .arg(root.path())
+            .stderr(std::process::Stdio::null())
+            .spawn()
+            .unwrap();
+
+        // The temp object may be unnamed, so find it through the process's fds.
+        let objects = root.path().join("objects");
+        let fds = std::path::PathBuf::from(format!("/proc/{}/fd", child.id()));
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let allocated = loop {
+            let temp = fs::read_dir(&fds).ok().and_then(|entries| {
+                entries.flatten().find(|fd| {
+                    fs::read_link(fd.path()).is_ok_and(|target| target.starts_with(&objects))
+                })
+            });
+            if let Some(meta) = temp.and_then(|fd| fs::metadata(fd.path()).ok()) {
+                if meta.len() > 0 {
+                    break meta.blocks() * 512;
+                }
+            }
+            assert!(Instant::now() < deadline, "download never started");
+            thread::sleep(Duration::from_millis(20));
+        };
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(allocated >= SIZE, "only {allocated} bytes allocated");
+    }
+
+    #[test]
+    fn wrong_content_length_fails_over_without_reading_the_body() {
+        // Announces a 500-byte body for every object, then never sends it.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let object_requests = Arc::new(AtomicUsize::new(0));
+        let requests = object_requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { break };
+                let requests = requests.clone();
+                thread::spawn(move || {
+                    let mut head = [0u8; 4096];
+                    let n = stream.read(&mut head).unwrap_or(0);
+                    let head = String::from_utf8_lossy(&head[..n]);
+                    if head.starts_with("GET /objects/") {
+                        requests.fetch_add(1, Ordering::SeqCst);
+                        let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 500\r\n\r\n");
+                        thread::sleep(Duration::from_secs(30));
+                    } else {
+                        let _ = stream
+                            .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n");
+                    }
+                });
+            }
+        });
+
+        let usb = tempfile::tempdir().unwrap();
+        write_file_origin(
+            usb.path(),
+            "v-cl",
+            &[("index.html", &sha(b"right body"), b"right body")],
+        );
+        let root = tempfile::tempdir().unwrap();
+        let started = Instant::now();
+        let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+            .args(["--origin", &format!("http://{addr}")])
+            .args(["--origin", &format!("file://{}/", usb.path().display())])
+            .arg("-v")
+            .arg("--root")
+            .arg(root.path())
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(out.status.code(), Some(0), "{stderr}");
+        assert!(started.elapsed() < Duration::from_secs(10), "{stderr}");
+        assert_eq!(object_requests.load(Ordering::SeqCst), 1);
+        assert!(
+            stderr.contains(": Content-Length 500, expected 10"),
+            "{stderr}"
+        );
+        assert!(
+            stderr.contains("has Content-Length 500, expected 10"),
+            "{stderr}"
+        );
+        assert_eq!(
+            fs::read(root.path().join("current/index.html")).unwrap(),
+            b"right body"
+        );
+    }
+
+    #[test]
+    fn verify_on_stage_catches_objects_corrupted_between_deploys() {
+        use sha2::{Digest, Sha256};
+
+        let sha256 = |bytes: &[u8]| {
+            Sha256::digest(bytes)
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect::<String>()
+        };
+        let usb = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let origin = format!("file://{}/", usb.path().display());
+        let run = || {
+            Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .args(["--origin", &origin, "--verify-on-stage"])
+                .arg("--root")
+                .arg(root.path())
+                .output()
+                .unwrap()
+        };
+        let logo: &[u8] = b"<svg>logo</svg>";
+        let logo_hash = sha256(logo);
+
+        write_file_origin(
+            usb.path(),
+            "v-vs1",
+            &[
+                ("index.html", &sha256(b"one"), b"one"),
+                ("logo.svg", &logo_hash, logo),
+            ],
+        );
+        assert_eq!(run().status.code(), Some(0));
+
+        // Bitrot of the same size: the size check still reuses the object.
+        let stored = root.path().join("objects").join(&logo_hash);
+        let mut perms = fs::metadata(&stored).unwrap().permissions();
+        std::os::unix::fs::PermissionsExt::set_mode(&mut perms, 0o644);
+        fs::set_permissions(&stored, perms).unwrap();
+        fs::write(&stored, b"<svg>l0go</svg>").unwrap();
+
+        write_file_origin(
+            usb.path(),
+            "v-vs2",
+            &[
+                ("index.html", &sha256(b"two"), b"two"),
+                ("logo.svg", &logo_hash, logo),
+            ],
+        );
+        let out = run();
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(out.status.code(), Some(5), "{stderr}");
+        assert!(
+            stderr.contains(&format!(
+                "stage logo.svg: stored object {logo_hash} is corrupt"
+            )),
+            "{stderr}"
+        );
+        assert!(stderr.contains("quarantining it"), "{stderr}");
+        assert!(fs::read_link(root.path().join("current"))
+            .unwrap()
+            .ends_with("v-vs1"));
+        assert!(!root.path().join("snapshots/v-vs2").exists());
+        assert!(!stored.exists());
+
+        // The quarantined object is fetched again and the deploy goes through.
+        let out = run();
+        assert_eq!(
+            out.status.code(),
+            Some(0),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        assert_eq!(
+            fs::read(root.path().join("current/logo.svg")).unwrap(),
+            logo
+        );
+    }
+
+    #[test]
+    fn offline_deploy_uses_only_local_objects() {
+        use sha2::{Digest, Sha256};
+
+        let sha256 = |bytes: &[u8]| {
+            Sha256::digest(bytes)
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect::<String>()
+        };
+        let (page, logo): (&[u8], &[u8]) = (b"<h1>brief</h1>", b"<svg>logo</svg>");
+        let (page_hash, logo_hash) = (sha256(page), sha256(logo));
+        let manifest = |version: &str, files: &[(&str, &str, usize)]| {
+            let files: Vec<String> = files
+                .iter()
+                .map(|(path, hash, size)| {
+                    format!(r#"{{ "path": "{path}", "hash": "{hash}", "size": {size} }}"#)
+                })
+                .collect();
+            format!(
+                r#"{{"version": "{version}", "files": [{}]}}"#,
+                files.join(", ")
+            )
+        };
+
+        let root = tempfile::tempdir().unwrap();
+        let mut objects = HashMap::new();
+        objects.insert(page_hash.clone(), page.to_vec());
+        objects.insert(logo_hash.clone(), logo.to_vec());
+        let (addr, handle) = start_origin(
+            "v-off1",
+            manifest(
+                "v-off1",
+                &[
+                    ("index.html", &page_hash, page.len()),
+                    ("logo.svg", &logo_hash, logo.len()),
+                ],
+            )
+            .into_bytes(),
+            objects,
+            Arc::new(AtomicUsize::new(0)),
+            Arc::new(AtomicUsize::new(0)),
+        );
+        let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+            .args(["--origin", &format!("http://{addr}")])
+            .arg("--root")
+            .arg(root.path())
+            .output()
+            .unwrap();
+        assert_eq!(out.status.code(), Some(0));
+        send_quit(addr);
+        handle.join().unwrap();
+
+        let offline = |manifest_file: &std::path::Path, extra: &[&str]| {
+            Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .args(["--offline", "--manifest-file"])
+                .arg(manifest_file)
+                .args(extra)
+                .arg("--root")
+                .arg(root.path())
+                .output()
+                .unwrap()
+        };
+        let current = || fs::read_link(root.path().join("current")).unwrap();
+
+        // A new version made of objects the store already holds.
+        let v2 = root.path().join("v-off2.json");
+        fs::write(
+            &v2,
+            manifest(
+                "v-off2",
+                &[
+                    ("index.html", &logo_hash, logo.len()),
+                    ("brief/index.html", &page_hash, page.len()),
+                ],
+            ),
+        )
+        .unwrap();
+        let out = offline(&v2, &[]);
+        assert_eq!(
+            out.status.code(),
+            Some(0),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        assert!(current().ends_with("v-off2"));
+        assert_eq!(
+            fs::read(root.path().join("current/brief/index.html")).unwrap(),
+            page
+        );
+
+        // Back to the manifest the online run saved.
+        let saved = root.path().join("manifests/v-off1.json");
+        let out = offline(&saved, &["--allow-downgrade"]);
+        assert_eq!(
+            out.status.code(),
+            Some(0),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        assert!(current().ends_with("v-off1"));
+
+        // Missing and corrupted objects are all listed, and nothing switches.
+        let gone = sha256(b"never fetched");
+        let stored = root.path().join("objects").join(&logo_hash);
+        let mut perms = fs::metadata(&stored).unwrap().permissions();
+        std::os::unix::fs::PermissionsExt::set_mode(&mut perms, 0o644);
+        fs::set_permissions(&stored, perms).unwrap();
+        fs::write(&stored, b"<svg>l0go</svg>").unwrap();
+        let v3 = root.path().join("v-off3.json");
+        fs::write(
+            &v3,
+            manifest(
+                "v-off3",
+                &[
+                    ("index.html", &page_hash, page.len()),
+                    ("logo.svg", &logo_hash, logo.len()),
+                    ("new.html", &gone, 13),
+                ],
+            ),
+        )
+        .unwrap();
+        let out = offline(&v3, &[]);
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(out.status.code(), Some(5), "{stderr}");
+        assert!(
+            stderr.contains("2 of 3 objects not available offline"),
+            "{stderr}"
+        );
+        assert!(
+            stderr.contains(&format!("new.html ({gone}): missing")),
+            "{stderr}"
+        );
+        assert!(
+            stderr.contains(&format!("logo.svg ({logo_hash}): content hashes to")),
+            "{stderr}"
+        );
+        assert!(current().ends_with("v-off1"));
+        assert!(!root.path().join("snapshots/v-off3").exists());
+    }
+
+    #[test]
+    fn prefetch_only_stores_objects_without_staging() {
+        use sha2::{Digest, Sha256};
+
+        let sha256 = |bytes: &[u8]| {
+            Sha256::digest(bytes)
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect::<String>()
+        };
+        // Prefetching checks content hashes, so these have to be real.
+        let (page_hash, brief_hash) = (sha256(b"page"), sha256(b"brief"));
+        let manifest = format!(
+            r#"{{"version": "v-pre1", "files": [
+                {{ "path": "index.html", "hash": "{page_hash}", "size": 4 }},
+                {{ "path": "brief/index.html", "hash": "{brief_hash}", "size": 5 }}
+            ]}}"#
+        );
+        let mut objects = HashMap::new();
+        objects.insert(page_hash, b"page".to_vec());
+        objects.insert(brief_hash, b"brief".to_vec());
+        let object_hits = Arc::new(AtomicUsize::new(0));
+        let (addr, handle) = start_origin(
+            "v-pre1",
+            manifest.into_bytes(),
+            objects,
+            Arc::new(AtomicUsize::new(0)),
+            object_hits.clone(),
+        );
+        let root = tempfile::tempdir().unwrap();
+        let run = |extra: &[&str]| {
+            Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .args(["--origin", &format!("http://{addr}"), "--output", "json"])
+                .args(extra)
+                .arg("--root")
+                .arg(root.path())
+                .output()
+                .unwrap()
+        };
+
+        let out = run(&["--prefetch-only"]);
+        assert_eq!(
+            out.status.code(),
+            Some(0),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        let summary: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+        assert_eq!(summary["outcome"], "prefetched");
+        assert_eq!(summary["objects_downloaded"], 2);
+        assert_eq!(summary["bytes_downloaded"], 9);
+        assert_eq!(summary["switched"], false);
+        assert!(String::from_utf8_lossy(&out.stderr)
+            .contains("prefetched v-pre1: 2 objects (9 bytes) downloaded, 0 already stored"));
+        assert_eq!(object_hits.load(Ordering::SeqCst), 2);
+        let snapshots = root.path().join("snapshots");
+        assert!(!snapshots.exists() || fs::read_dir(&snapshots).unwrap().next().is_none());
+        assert!(fs::symlink_metadata(root.path().join("current")).is_err());
+        assert!(!root.path().join("deploy-state.json").exists());
+
+        // The real deploy only stages and switches.
+        let out = run(&[]);
+        assert_eq!(out.status.code(), Some(0));
+        let summary: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+        assert_eq!(summary["objects_downloaded"], 0);
+        assert_eq!(summary["objects_reused"], 2);
+        assert_eq!(object_hits.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            fs::read(root.path().join("current/brief/index.html")).unwrap(),
+            b"brief"
+        );
+
+        send_quit(addr);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn run_stats_count_reuse_phases_and_failovers() {
+        let usb = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let dead = closed_origin();
+        let file = format!("file://{}/", usb.path().display());
+        let run = || {
+            let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .args(["--origin", &dead, "--origin", &file, "--output", "json"])
+                .arg("--root")
+                .arg(root.path())
+                .output()
+                .unwrap();
+            assert_eq!(
+                out.status.code(),
+                Some(0),
+                "{}",
+                String::from_utf8_lossy(&out.stderr)
+            );
+            let summary: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+            (summary, String::from_utf8(out.stderr).unwrap())
+        };
+
+        write_file_origin(
+            usb.path(),
+            "v-stats1",
+            &[
+                ("index.html", &sha(b"home"), b"home"),
+                ("map.svg", &sha(b"<svg/>"), b"<svg/>"),
+            ],
+        );
+        let (summary, _) = run();
+        assert_eq!(summary["objects_downloaded"], 2);
+        assert_eq!(summary["objects_reused"], 0);
+
+        // The second version keeps both files and adds one.
+        write_file_origin(
+            usb.path(),
+            "v-stats2",
+            &[
+                ("index.html", &sha(b"home"), b"home"),
+                ("map.svg", &sha(b"<svg/>"), b"<svg/>"),
+                ("alerts.html", &sha(b"all clear"), b"all clear"),
+            ],
+        );
+        let (summary, stderr) = run();
+        assert_eq!(summary["objects_downloaded"], 1, "{summary}");
+        assert_eq!(summary["bytes_downloaded"], 9);
+        assert_eq!(summary["objects_reused"], 2);
+        assert_eq!(summary["bytes_reused"], 10);
+        let stats = &summary["stats"];
+        let phases: Vec<&String> = stats["phase_secs"].as_object().unwrap().keys().collect();
+        assert_eq!(phases, ["download", "manifest", "staging", "switch"]);
+        assert!(stats["download_secs"].as_f64().unwrap() > 0.0, "{stats}");
+        assert!(stats["avg_bytes_per_sec"].as_f64().unwrap() > 0.0);
+        assert!(stats["peak_bytes_per_sec"].as_f64().unwrap() > 0.0);
+        // The manifest at least came from the second origin after the first refused.
+        assert!(stats["failovers"].as_u64().unwrap() >= 1, "{stats}");
+        assert!(
+            stderr
+                .contains("stats: 1 objects (9 bytes) downloaded, 2 (10 bytes) reused; manifest "),
+            "{stderr}"
+        );
+    }
+
+    #[test]
+    fn copy_strategy_places_snapshot_files_as_asked() {
+        use std::os::unix::fs::MetadataExt;
+
+        let usb = tempfile::tempdir().unwrap();
+        let origin = format!("file://{}/", usb.path().display());
+        write_file_origin(
+            usb.path(),
+            "v-copy",
+            &[("index.html", &sha(b"home"), b"home")],
+        );
+        let run = |strategy: &str| {
+            let root = tempfile::tempdir().unwrap();
+            let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .args(["--origin", &origin, "--output", "json"])
+                .args(["--copy-strategy", strategy])
+                .arg("--root")
+                .arg(root.path())
+                .output()
+                .unwrap();
+            assert_eq!(
+                out.status.code(),
+                Some(0),
+                "{}",
+                String::from_utf8_lossy(&out.stderr)
+            );
+            let summary: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+            let object = fs::metadata(root.path().join("objects").join(sha(b"home"))).unwrap();
+            let staged = fs::metadata(root.path().join("current/index.html")).unwrap();
+            let stderr = String::from_utf8(out.stderr).unwrap();
+            (
+                summary["stats"].clone(),
+                object.ino() == staged.ino(),
+                stderr,
+            )
+        };
+
+        let (stats, shared, stderr) = run("copy");
+        assert_eq!(stats["copy_strategy"], "copy");
+        assert_eq!(stats["copy_downgrades"], serde_json::json!([]));
+        assert!(!shared);
+        assert!(stderr.contains("; staged with copy"), "{stderr}");
+
+        // Root and objects share a filesystem, so the link always works.
+        let (stats, shared, _) = run("hardlink");
+        assert_eq!(stats["copy_strategy"], "hardlink");
+        assert!(shared);
+
+        // Auto takes reflink where the filesystem clones, hard links otherwise.
+        let (stats, shared, stderr) = run("auto");
+        match stats["copy_strategy"].as_str() {
+            Some("reflink") => {
+                assert!(!shared);
+                assert_eq!(stats["copy_downgrades"], serde_json::json!([]));
+            }
+            Some("hardlink") => {
+                assert!(shared);
+                let downgrades = stats["copy_downgrades"].as_array().unwrap();
+                assert_eq!(downgrades.len(), 1, "{stats}");
+                assert_eq!(downgrades[0]["from"], "reflink");
+                assert_eq!(downgrades[0]["to"], "hardlink");
+                assert!(
+                    stderr.contains("using hardlink for the rest of the run"),
+                    "{stderr}"
+                );
+            }
+            other => panic!("auto settled on {other:?}: {stats}"),
+        }
+    }
+
+    #[test]
+    fn preserved_files_survive_deploys() {
+        let usb = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let origin = format!("file://{}/", usb.path().display());
+        let run = |extra: &[&str]| {
+            Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .args(["--origin", &origin, "--output", "json"])
+                .args(["--preserve", "robots.txt", "--preserve", "venue.json"])
+                .args(["--preserve", "missing.txt"])
+                .arg("--root")
+                .arg(root.path())
+                .args(extra)
+                .output()
+                .unwrap()
+        };
+
+        write_file_origin(
+            usb.path(),
+            "v-keep1",
+            &[
+                ("index.html", &sha(b"one"), b"one"),
+                ("robots.txt", &sha(b"User-agent: *\n"), b"User-agent: *\n"),
+            ],
+        );
+        let out = run(&[]);
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(out.status.code(), Some(0), "{stderr}");
+        assert!(
+            stderr
+                .contains("--preserve missing.txt: in neither the live snapshot nor the manifest"),
+            "{stderr}"
+        );
+
+        // The venue edits robots.txt in place and adds its own config.
+        let live = root.path().join("current");
+        fs::write(live.join("robots.txt"), b"Disallow: /\n").unwrap();
+        fs::write(live.join("venue.json"), b"{\"venue\": \"orpheum\"}").unwrap();
+
+        write_file_origin(
+            usb.path(),
+            "v-keep2",
+            &[
+                ("index.html", &sha(b"two"), b"two"),
+                ("robots.txt", &sha(b"User-agent: *\n"), b"User-agent: *\n"),
+            ],
+        );
+        let out = run(&[]);
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(out.status.code(), Some(0), "{stderr}");
+        assert_eq!(
+            fs::read_link(root.path().join("current")).unwrap(),
+            std::path::Path::new("snapshots/v-keep2")
+        );
+        assert_eq!(fs::read(live.join("index.html")).unwrap(), b"two");
+        assert_eq!(fs::read(live.join("robots.txt")).unwrap(), b"Disallow: /\n");
+        assert_eq!(
+            fs::read(live.join("venue.json")).unwrap(),
+            b"{\"venue\": \"orpheum\"}"
+        );
+        let summary: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+        assert_eq!(
+            summary["preserved"],
+            serde_json::json!(["robots.txt", "venue.json"])
+        );
+        assert!(
+            stderr.contains("preserved from the live snapshot: robots.txt, venue.json"),
+            "{stderr}"
+        );
+        let history = fs::read_to_string(root.path().join("state/history.jsonl")).unwrap();
+        let last: serde_json::Value =
+            serde_json::from_str(history.lines().last().unwrap()).unwrap();
+        assert_eq!(last["preserved"], summary["preserved"]);
+
+        let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+            .args(["--origin", &origin, "--preserve", "../etc/passwd"])
+            .arg("--root")
+            .arg(root.path())
+            .output()
+            .unwrap();
+        assert_eq!(out.status.code(), Some(2));
+        assert!(String::from_utf8_lossy(&out.stderr).contains("invalid --preserve path"));
+    }
+
+    #[test]
+    fn only_and_exclude_deploy_part_of_the_manifest() {
+        let paths = [
+            "kiosk/index.html",
+            "kiosk/video/loop.mp4",
+            "assets/app.css",
+            "archive/2024.tar",
+            "index.html",
+        ];
+        let mut objects = HashMap::new();
+        let mut files = Vec::new();
+        for path in paths {
+            let hash = sha(path.as_bytes());
+            files.push(format!(
+                r#"{{ "path": "{path}", "hash": "{hash}", "size": {} }}"#,
+                path.len()
+            ));
+            objects.insert(hash, path.as_bytes().to_vec());
+        }
+        let manifest = format!(
+            r#"{{ "version": "v-part", "files": [{}] }}"#,
+            files.join(", ")
+        );
+        let object_hits = Arc::new(AtomicUsize::new(0));
+        let (addr, handle) = start_origin(
+            "v-part",
+            manifest.into_bytes(),
+            objects,
+            Arc::new(AtomicUsize::new(0)),
+            Arc::clone(&object_hits),
+        );
+        let origin = format!("http://{addr}");
+        let bin = env!("CARGO_BIN_EXE_cityfeed-puller");
+
+        let root = tempfile::tempdir().unwrap();
+        let out = Command::new(bin)
+            .args(["--origin", &origin, "--exclude", "kiosk/video/**"])
+            .args(["--only", "kiosk/**", "--only", "/assets/**"])
+            .arg("--root")
+            .arg(root.path())
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(out.status.code(), Some(0), "{stderr}");
+        assert!(stderr.contains("kept 2 of 5 manifest entries"), "{stderr}");
+        let snapshot = root.path().join("snapshots/v-part");
+        assert_eq!(
+            tree(&snapshot).into_keys().collect::<Vec<_>>(),
+            ["assets", "assets/app.css", "kiosk", "kiosk/index.html"]
+        );
+        // Nothing outside the filter was downloaded.
+        assert_eq!(object_hits.load(Ordering::SeqCst), 2);
+        assert!(!root
+            .path()
+            .join("objects")
+            .join(sha(b"archive/2024.tar"))
+            .exists());
+        let record: serde_json::Value =
+            serde_json::from_slice(&fs::read(root.path().join("manifests/v-part.json")).unwrap())
+                .unwrap();
+        assert_eq!(
+            record["filters"],
+            serde_json::json!(["exclude kiosk/video/**", "only kiosk/**", "only assets/**"])
+        );
+
+        // Filtering everything out leaves nothing to deploy.
+        let empty = tempfile::tempdir().unwrap();
+        let out = Command::new(bin)
+            .args(["--origin", &origin, "--exclude", "**"])
+            .arg("--root")
+            .arg(empty.path())
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(out.status.code(), Some(4), "{stderr}");
+        assert!(
+            stderr.contains("manifest lists 0 files, under --min-files 1"),
+            "{stderr}"
+        );
+        assert!(!empty.path().join("current").exists());
+
+        send_quit(addr);
+        handle.join().unwrap();
+        assert_eq!(object_hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn prefix_deploys_a_subtree_as_the_whole_snapshot() {
+        let paths = [
+            "daily/index.html",
+            "daily/2024/index.html",
+            "metro/index.html",
+            "metro/map.svg",
+        ];
+        let mut objects = HashMap::new();
+        let mut entries = Vec::new();
+        for path in paths {
+            let hash = sha(path.as_bytes());
+            entries.push(format!(
+                r#"{{ "path": "{path}", "hash": "{hash}", "size": {} }}"#,
+                path.len()
+            ));
+            objects.insert(hash, path.as_bytes().to_vec());
+        }
+        entries.push(r#"{ "path": "daily/latest", "symlink": "2024/index.html" }"#.into());
+        entries.push(r#"{ "path": "daily/metro", "symlink": "../metro/index.html" }"#.into());
+        let manifest = format!(
+            r#"{{ "version": "v-sub", "files": [{}] }}"#,
+            entries.join(", ")
+        );
+        let object_hits = Arc::new(AtomicUsize::new(0));
+        let (addr, handle) = start_origin(
+            "v-sub",
+            manifest.into_bytes(),
+            objects,
+            Arc::new(AtomicUsize::new(0)),
+            Arc::clone(&object_hits),
+        );
+        let origin = format!("http://{addr}");
+        let run = |root: &std::path::Path, extra: &[&str]| {
+            Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .args(["--origin", &origin])
+                .arg("--root")
+                .arg(root)
+                .args(extra)
+                .output()
+                .unwrap()
+        };
+
+        let daily = tempfile::tempdir().unwrap();
+        let out = run(daily.path(), &["--prefix", "/daily/"]);
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(out.status.code(), Some(0), "{stderr}");
+        assert!(
+            stderr.contains("left out symlinks that point outside --prefix: daily/metro"),
+            "{stderr}"
+        );
+        assert_eq!(object_hits.load(Ordering::SeqCst), 2);
+
+        let whole = tempfile::tempdir().unwrap();
+        let out = run(whole.path(), &[]);
+        assert_eq!(out.status.code(), Some(0));
+        assert_eq!(object_hits.load(Ordering::SeqCst), 6);
+
+        // The prefixed snapshot is the whole one's daily/, less the link out.
+        let mut expected = tree(&whole.path().join("snapshots/v-sub/daily"));
+        expected.remove("metro");
+        assert_eq!(tree(&daily.path().join("snapshots/v-sub")), expected);
+
+        let record: serde_json::Value =
+            serde_json::from_slice(&fs::read(daily.path().join("manifests/v-sub.json")).unwrap())
+                .unwrap();
+        assert_eq!(record["prefix"], "daily");
+        let recorded: Vec<&str> = record["files"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| f["path"].as_str().unwrap())
+            .collect();
+        assert_eq!(recorded, ["index.html", "2024/index.html", "latest"]);
+
+        // The record matches the snapshot, so it still checks out.
+        let out = run(daily.path(), &["--prefix", "daily"]);
+        assert_eq!(out.status.code(), Some(3));
+        let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+            .args(["promote", "--version", "v-sub"])
+            .arg("--root")
+            .arg(daily.path())
+            .output()
+            .unwrap();
+        assert_eq!(
+            out.status.code(),
+            Some(3),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+
+        send_quit(addr);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn repeated_content_is_staged_as_hard_links() {
+        use std::os::unix::fs::MetadataExt;
+
+        let pdf = b"%PDF route map".repeat(300);
+        let manifest = format!(
+            r#"{{"version": "v-links", "files": [
+                {{ "path": "maps/system.pdf", "hash": "{pdf}", "size": {len} }},
+                {{ "path": "system-map.pdf", "hash": "{pdf}", "size": {len} }},
+                {{ "path": "riders/map.pdf", "hash": "{pdf}", "size": {len} }},
+                {{ "path": "tools/map.pdf", "hash": "{pdf}", "size": {len}, "mode": "0755" }},
+                {{ "path": "index.html", "hash": "{home}", "size": 4 }}
+            ]}}"#,
+            pdf = sha(&pdf),
+            home = sha(b"home"),
+            len = pdf.len()
+        );
+        let mut objects = HashMap::new();
+        objects.insert(sha(&pdf), pdf.clone());
+        objects.insert(sha(b"home"), b"home".to_vec());
+        let (addr, handle) = start_origin(
+            "v-links",
+            manifest.into_bytes(),
+            objects,
+            Arc::new(AtomicUsize::new(0)),
+            Arc::new(AtomicUsize::new(0)),
+        );
+        let root = tempfile::tempdir().unwrap();
+        let (code, summary) = run_json(&format!("http://{addr}"), root.path());
+        send_quit(addr);
+        handle.join().unwrap();
+        assert_eq!(code, Some(0), "{summary}");
+
+        let snapshot = root.path().join("snapshots/v-links");
+        let meta = |path: &str| fs::metadata(snapshot.join(path)).unwrap();
+        let inode = meta("maps/system.pdf").ino();
+        for path in ["system-map.pdf", "riders/map.pdf"] {
+            assert_eq!(meta(path).ino(), inode, "{path}");
+            assert_eq!(meta(path).mode() & 0o777, 0o644);
+        }
+        // Under the default --copy-strategy the first copy may be the object.
+        let object = fs::metadata(root.path().join("objects").join(sha(&pdf))).unwrap();
+        let shared = u64::from(object.ino() == inode);
+        assert_eq!(meta("maps/system.pdf").nlink(), 3 + shared);
+        // A different mode needs its own copy.
+        assert_ne!(meta("tools/map.pdf").ino(), inode);
+        assert_eq!(meta("tools/map.pdf").mode() & 0o777, 0o755);
+        for path in [
+            "maps/system.pdf",
+            "system-map.pdf",
+            "riders/map.pdf",
+            "tools/map.pdf",
+        ] {
+            assert_eq!(fs::read(snapshot.join(path)).unwrap(), pdf, "{path}");
+        }
+
+        let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+            .args(["status", "--output", "json", "--root"])
+            .arg(root.path())
+            .output()
+            .unwrap();
+        let status: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+        assert_eq!(
+            status["snapshot_bytes"],
+            2 * pdf.len() as u64 + 4,
+            "{status}"
+        );
+    }
+
+    #[test]
+    fn manifest_sha256_accepts_only_the_approved_bytes() {
+        use sha2::{Digest, Sha256};
+
+        let sha256 = |bytes: &[u8]| {
+            Sha256::digest(bytes)
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect::<String>()
+        };
+        let approved = format!(
+            r#"{{"version": "v-pin1", "files": [{{ "path": "index.html", "hash": "{}", "size": 4 }}]}}"#,
+            sha(b"page")
+        );
+        // latest.json after it moved on to the next, unapproved version.
+        let moved = format!(
+            r#"{{"version": "v-pin2", "files": [{{ "path": "index.html", "hash": "{}", "size": 4 }}]}}"#,
+            sha(b"page")
+        );
+        let (approved_digest, moved_digest) =
+            (sha256(approved.as_bytes()), sha256(moved.as_bytes()));
+        let origin = |manifest: &str| {
+            let mut objects = HashMap::new();
+            objects.insert(sha(b"page"), b"page".to_vec());
+            start_origin(
+                "v-pin",
+                manifest.as_bytes().to_vec(),
+                objects,
+                Arc::new(AtomicUsize::new(0)),
+                Arc::new(AtomicUsize::new(0)),
+            )
+        };
+        let (good, good_handle) = origin(&approved);
+        let (stale, stale_handle) = origin(&moved);
+        let run = |origins: &[std::net::SocketAddr]| {
+            let root = tempfile::tempdir().unwrap();
+            let mut cmd = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"));
+            for addr in origins {
+                cmd.args(["--origin", &format!("http://{addr}")]);
+            }
+            let out = cmd
+                .args(["--manifest-sha256", &approved_digest.to_uppercase()])
+                .arg("--root")
+                .arg(root.path())
+                .output()
+                .unwrap();
+            let current = fs::read_link(root.path().join("current")).ok();
+            (
+                out.status.code(),
+                String::from_utf8_lossy(&out.stderr).into_owned(),
+                current,
+            )
+        };
+
+        let (code, stderr, current) = run(&[good]);
+        assert_eq!(code, Some(0), "{stderr}");
+        assert!(current.unwrap().ends_with("v-pin1"));
+
+        let (code, stderr, current) = run(&[stale]);
+        assert_eq!(code, Some(4), "{stderr}");
+        assert!(
+            stderr.contains(&format!(
+                "manifest from http://{stale} has sha256 {moved_digest}, \
+                 but --manifest-sha256 is {approved_digest}"
+            )),
+            "{stderr}"
+        );
+        assert!(current.is_none());
+
+        // Only the second mirror has the approved bytes; the first is skipped.
+        let (code, stderr, current) = run(&[stale, good]);
+        assert_eq!(code, Some(0), "{stderr}");
+        assert!(
+            stderr.contains(&format!("fetch failed from http://{stale}")),
+            "{stderr}"
+        );
+        assert!(
+            stderr.contains(&format!("manifest origin=http://{good}")),
+            "{stderr}"
+        );
+        assert!(current.unwrap().ends_with("v-pin1"));
+
+        for (addr, handle) in [(good, good_handle), (stale, stale_handle)] {
+            send_quit(addr);
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn failed_run_leaves_an_error_report_until_the_next_success() {
+        let manifest = format!(
+            r#"{{"version": "v-err1", "files": [
+                {{ "path": "index.html", "hash": "{}", "size": 4 }},
+                {{ "path": "gone.html", "hash": "{}", "size": 4 }}
+            ]}}"#,
+            sha(b"page"),
+            h("err-gone")
+        );
+        let mut objects = HashMap::new();
+        objects.insert(sha(b"page"), b"page".to_vec());
+        let (broken, broken_handle) = start_origin(
+            "v-err1",
+            manifest.into_bytes(),
+            objects.clone(),
+            Arc::new(AtomicUsize::new(0)),
+            Arc::new(AtomicUsize::new(0)),
+        );
+        let fixed_manifest = format!(
+            r#"{{"version": "v-err2", "files": [{{ "path": "index.html", "hash": "{}", "size": 4 }}]}}"#,
+            sha(b"page")
+        );
+        let (fixed, fixed_handle) = start_origin(
+            "v-err2",
+            fixed_manifest.into_bytes(),
+            objects,
+            Arc::new(AtomicUsize::new(0)),
+            Arc::new(AtomicUsize::new(0)),
+        );
+        let root = tempfile::tempdir().unwrap();
+        let run = |origin: std::net::SocketAddr| {
+            Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .args(["--origin", &format!("http://{origin}")])
+                .arg("--root")
+                .arg(root.path())
+                .output()
+                .unwrap()
+        };
+        let report_path = root.path().join("state/last-error.json");
+
+        let out = run(broken);
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(out.status.code(), Some(5), "{stderr}");
+        assert!(
+            stderr.contains(&format!("(report: {})", report_path.display())),
+            "{stderr}"
+        );
+        let report: serde_json::Value =
+            serde_json::from_slice(&fs::read(&report_path).unwrap()).unwrap();
+        assert_eq!(report["phase"], "download");
+        assert_eq!(report["hash"], h("err-gone"));
+        assert_eq!(report["origin"], format!("http://{broken}"));
+        assert_eq!(report["version"], "v-err1");
+        assert_eq!(report["exit_code"], 5);
+        let chain: Vec<&str> = report["error"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| e.as_str().unwrap())
+            .collect();
+        assert_eq!(chain[0], format!("download object {}", h("err-gone")));
+        assert!(chain.len() > 1, "{chain:?}");
+        assert!(report["started_at"].as_str().unwrap() <= report["failed_at"].as_str().unwrap());
+
+        let out = run(fixed);
+        assert_eq!(out.status.code(), Some(0));
+        assert!(!report_path.exists());
+
+        for (addr, handle) in [(broken, broken_handle), (fixed, fixed_handle)] {
+            send_quit(addr);
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn free_space_preflight_fails_before_downloading() {
+        let manifest = format!(
+            r#"{{"version": "v-huge", "files": [
+                {{ "path": "index.html", "hash": "{}", "size": 1000000000000000000 }}
+            ]}}"#,
+            h("huge")
+        );
+        let mut objects = HashMap::new();
+        objects.insert(h("huge"), b"tiny".to_vec());
+        objects.insert(h("small"), b"tiny".to_vec());
+        let object_hits = Arc::new(AtomicUsize::new(0));
+        let (addr, handle) = start_origin(
+            "v-huge",
+            manifest.into_bytes(),
+            objects,
+            Arc::new(AtomicUsize::new(0)),
+            object_hits.clone(),
+        );
+        let root = tempfile::tempdir().unwrap();
+        let bin = env!("CARGO_BIN_EXE_cityfeed-puller");
+
+        let run = |strategy: &str| {
+            let out = Command::new(bin)
+                .arg("--origin")
+                .arg(format!("http://{addr}"))
+                .arg("--root")
+                .arg(root.path())
+                .args(["--copy-strategy", strategy])
+                .output()
+                .unwrap();
+            assert_eq!(out.status.code(), Some(1));
+            String::from_utf8(out.stderr).unwrap()
+        };
+        let stderr = run("copy");
+        assert!(
+            stderr.contains("insufficient disk space on")
+                && stderr.contains("need 2000000000000000000 bytes"),
+            "{stderr}"
+        );
+        // Linked snapshot files cost no space beyond their objects.
+        let stderr = run("hardlink");
+        assert!(
+            stderr.contains(
+                "need 1000000000000000000 bytes (1000000000000000000 objects + 0 snapshot)"
+            ),
+            "{stderr}"
+        );
+        assert_eq!(object_hits.load(Ordering::SeqCst), 0);
+        assert!(fs::read_dir(root.path().join("objects"))
+            .unwrap()
+            .next()
+            .is_none());
+        send_quit(addr);
+        handle.join().unwrap();
+
+        // --min-free-bytes headroom counts against a deploy that would fit.
+        let usb = tempfile::tempdir().unwrap();
+        write_file_origin(
+            usb.path(),
+            "v-small",
+            &[("index.html", &h("small"), b"tiny")],
+        );
+        let out = Command::new(bin)
+            .arg("--origin")
+            .arg(format!("file://{}/", usb.path().display()))
+            .arg("--root")
+            .arg(root.path())
+            .args(["--min-free-bytes", "1000000T"])
+            .output()
+            .unwrap();
+        assert_eq!(out.status.code(), Some(1));
+        assert!(String::from_utf8_lossy(&out.stderr).contains("plus 1099511627776000000 reserved"));
+    }
+
+    #[test]
+    fn run_reclaims_stale_temp_files_but_not_live_ones() {
+        let usb = tempfile::tempdir().unwrap();
+        write_file_origin(usb.path(), "v-clean", &[("index.html", &sha(b"ok"), b"ok")]);
+        let root = tempfile::tempdir().unwrap();
+        let snapshots = root.path().join("snapshots");
+        let objects = root.path().join("objects");
+        fs::create_dir_all(snapshots.join(".v-old.staging-999999-aaaa/css")).unwrap();
+        fs::write(snapshots.join(".v-old.staging-999999-aaaa/css/x.css"), b"x").unwrap();
+        fs::create_dir_all(snapshots.join(".v-older.staging-bbbb")).unwrap();
+        fs::create_dir_all(&objects).unwrap();
+        fs::write(objects.join(".tmpAbCdEf"), b"partial").unwrap();
+        fs::write(objects.join(".tmp-999998-cccc"), b"partial").unwrap();
+        fs::write(root.path().join(".current.new.999997"), b"").unwrap();
+        // Owned by a live process (this test): must survive.
+        let live = format!(".v-live.staging-{}-dddd", std::process::id());
+        fs::create_dir_all(snapshots.join(&live)).unwrap();
+
+        let bin = env!("CARGO_BIN_EXE_cityfeed-puller");
+        let out = Command::new(bin)
+            .arg("--origin")
+            .arg(format!("file://{}/", usb.path().display()))
+            .arg("--root")
+            .arg(root.path())
+            .output()
+            .unwrap();
+        assert!(out.status.success());
+        let stderr = String::from_utf8(out.stderr).unwrap();
+        assert_eq!(stderr.matches("removed stale ").count(), 5, "{stderr}");
+
+        let mut snaps = dir_entries(&snapshots);
+        snaps.sort();
+        assert_eq!(snaps, vec![live, "v-clean".to_string()]);
+        assert_eq!(dir_entries(&objects), vec![sha(b"ok")]);
+        assert!(!root.path().join(".current.new.999997").exists());
+    }
+
+    #[test]
+    fn second_puller_on_same_root_is_refused() {
+        let root = tempfile::tempdir().unwrap();
+        let lock = fs::File::create(root.path().join(".cityfeed-puller.lock")).unwrap();
+        lock.try_lock().unwrap();
+
+        let bin = env!("CARGO_BIN_EXE_cityfeed-puller");
+        let out = Command::new(bin)
+            .args(["--origin", &closed_origin()])
+            .arg("--root")
+            .arg(root.path())
+            .output()
+            .unwrap();
+        assert_eq!(out.status.code(), Some(1));
+        assert!(
+            String::from_utf8_lossy(&out.stderr).contains("is locked by another cityfeed-puller")
+        );
+    }
+
+    #[test]
+    fn dangling_current_is_repaired() {
+        let usb = tempfile::tempdir().unwrap();
+        write_file_origin(
+            usb.path(),
+            "v-heal",
+            &[("index.html", &sha(b"heal"), b"heal")],
+        );
+        let root = tempfile::tempdir().unwrap();
+        let bin = env!("CARGO_BIN_EXE_cityfeed-puller");
+        let run = || {
+            Command::new(bin)
+                .arg("--origin")
+                .arg(format!("file://{}/", usb.path().display()))
+                .arg("--root")
+                .arg(root.path())
+                .output()
+                .unwrap()
+        };
+        let current = root.path().join("current");
+
+        // Points at a snapshot someone deleted, for a different version.
+        std::os::unix::fs::symlink("snapshots/v-gone", &current).unwrap();
+        let out = run();
+        assert_eq!(out.status.code(), Some(0));
+        assert!(String::from_utf8_lossy(&out.stderr)
+            .contains("current -> snapshots/v-gone is dangling"));
+        assert_eq!(fs::read(current.join("index.html")).unwrap(), b"heal");
+
+        // Correct link: nothing to do.
+        assert_eq!(run().status.code(), Some(3));
+
+        // Points at the right version, but its snapshot was deleted.
+        fs::remove_dir_all(root.path().join("snapshots/v-heal")).unwrap();
+        let out = run();
+        assert_eq!(out.status.code(), Some(0));
+        assert!(String::from_utf8_lossy(&out.stderr)
+            .contains("current -> snapshots/v-heal is dangling"));
+        assert_eq!(fs::read(current.join("index.html")).unwrap(), b"heal");
+    }
+
+    #[test]
+    fn regular_file_at_current_needs_force_current() {
+        let usb = tempfile::tempdir().unwrap();
+        write_file_origin(usb.path(), "v-force", &[("index.html", &sha(b"f"), b"f")]);
+        let root = tempfile::tempdir().unwrap();
+        let current = root.path().join("current");
+        fs::write(&current, b"not a link").unwrap();
+        let bin = env!("CARGO_BIN_EXE_cityfeed-puller");
+        let run = |extra: &[&str]| {
+            Command::new(bin)
+                .arg("--origin")
+                .arg(format!("file://{}/", usb.path().display()))
+                .arg("--root")
+                .arg(root.path())
+                .args(extra)
+                .output()
+                .unwrap()
+        };
+
+        let out = run(&[]);
+        assert_eq!(out.status.code(), Some(1));
+        assert!(String::from_utf8_lossy(&out.stderr)
+            .contains("is a regular file, not a symlink; move it away or pass --force-current"));
+        assert_eq!(fs::read(&current).unwrap(), b"not a link");
+
+        let out = run(&["--force-current"]);
+        assert_eq!(out.status.code(), Some(0));
+        assert_eq!(
+            fs::read_link(&current).unwrap(),
+            std::path::Path::new("snapshots/v-force")
+        );
+        let aside: Vec<String> = dir_entries(root.path())
+            .into_iter()
+            .filter(|n| n.starts_with("current.replaced-"))
+            .collect();
+        assert_eq!(aside.len(), 1);
+        assert_eq!(
+            fs::read(root.path().join(&aside[0])).unwrap(),
+            b"not a link"
+        );
+    }
+
+    #[test]
+    fn retry_after_on_429_waits_and_retries_same_origin() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = Server::from_listener(listener, None).unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        let manifest_hits = Arc::new(AtomicUsize::new(0));
+        let hits = manifest_hits.clone();
+        let hash = sha(b"ok");
+        let manifest = format!(
+            r#"{{"version": "v-429", "files": [{{ "path": "index.html", "hash": "{hash}", "size": 2 }}]}}"#
+        );
+        let object_url = format!("/objects/{hash}");
+        let handle = thread::spawn(move || {
+            for req in server.incoming_requests() {
+                match req.url() {
+                    "/__quit" => {
+                        let _ = req.respond(Response::empty(200));
+                        break;
+                    }
+                    "/manifests/latest.json" if hits.fetch_add(1, Ordering::SeqCst) == 0 => {
+                        let resp = Response::from_string("slow down")
+                            .with_status_code(429)
+                            .with_header(
+                                Header::from_bytes(&b"Retry-After"[..], &b"1"[..]).unwrap(),
+                            );
+                        let _ = req.respond(resp);
+                    }
+                    "/manifests/latest.json" => {
+                        let _ = req.respond(Response::from_string(manifest.as_str()));
+                    }
+                    url if url == object_url => {
+                        let _ = req.respond(Response::from_string("ok"));
+                    }
+                    _ => {
+                        let _ = req.respond(Response::empty(StatusCode(404)));
+                    }
+                }
+            }
+        });
+
+        // A second origin that must not be consulted while the first is only busy.
+        let backup_hits = Arc::new(AtomicUsize::new(0));
+        let (backup, backup_handle) = start_origin(
+            "v-backup",
+            br#"{"version": "v-backup", "files": []}"#.to_vec(),
+            HashMap::new(),
+            backup_hits.clone(),
+            Arc::new(AtomicUsize::new(0)),
+        );
+
+        let root = tempfile::tempdir().unwrap();
+        let bin = env!("CARGO_BIN_EXE_cityfeed-puller");
+        let started = Instant::now();
+        let out = Command::new(bin)
+            .args(["--origin", &format!("http://{addr}")])
+            .args(["--origin", &format!("http://{backup}")])
+            .arg("--root")
+            .arg(root.path())
+            .output()
+            .unwrap();
+        let elapsed = started.elapsed();
+        assert_eq!(out.status.code(), Some(0));
+        assert!(elapsed >= Duration::from_secs(1), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(5), "{elapsed:?}");
+        assert!(String::from_utf8_lossy(&out.stderr).contains("HTTP 429 Too Many Requests"));
+        assert_eq!(manifest_hits.load(Ordering::SeqCst), 2);
+        assert_eq!(backup_hits.load(Ordering::SeqCst), 0);
+        assert_eq!(
+            fs::read_link(root.path().join("current")).unwrap(),
+            std::path::Path::new("snapshots/v-429")
+        );
+
+        send_quit(addr);
+        handle.join().unwrap();
+        send_quit(backup);
+        backup_handle.join().unwrap();
+    }
+
+    #[test]
+    fn race_manifest_ignores_hanging_origin() {
+        // Accepts connections (via the backlog) but never answers.
+        let hang = TcpListener::bind("127.0.0.1:0").unwrap();
+        let hang_addr = hang.local_addr().unwrap();
+        let (fast, handle) = single_file_origin("v-race", &sha(b"fast"), b"fast");
+        let root = tempfile::tempdir().unwrap();
+        let bin = env!("CARGO_BIN_EXE_cityfeed-puller");
+
+        let started = Instant::now();
+        let out = Command::new(bin)
+            .args(["--origin", &format!("http://{hang_addr}")])
+            .args(["--origin", &format!("http://{fast}")])
+            .arg("--race-manifest")
+            .arg("--root")
+            .arg(root.path())
+            .output()
+            .unwrap();
+        let elapsed = started.elapsed();
+        assert_eq!(out.status.code(), Some(0));
+        assert!(elapsed < Duration::from_secs(3), "{elapsed:?}");
+        assert!(String::from_utf8_lossy(&out.stderr)
+            .contains(&format!("manifest origin=http://{fast}")));
+        assert_eq!(
+            fs::read(root.path().join("current/index.html")).unwrap(),
+            b"fast"
+        );
+
+        drop(hang);
+        send_quit(fast);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn newest_manifest_policy_deploys_the_newest_whatever_the_origin_order() {
+        let sequenced = |version: &str, sequence: u64, hash: &str, body: &[u8]| {
+            let manifest = format!(
+                r#"{{"version": "{version}", "sequence": {sequence}, "files": [{{ "path": "index.html", "hash": "{hash}", "size": {} }}]}}"#,
+                body.len()
+            );
+            start_origin(
+                version,
+                manifest.into_bytes(),
+                HashMap::from([(hash.to_string(), body.to_vec())]),
+                Arc::new(AtomicUsize::new(0)),
+                Arc::new(AtomicUsize::new(0)),
+            )
+        };
+        // The lagging mirror doesn't have the new version's object yet.
+        let (stale, stale_handle) = sequenced("v-old", 7, &sha(b"old"), b"old");
+        let (fresh, fresh_handle) = sequenced("v-new", 9, &sha(b"new"), b"new");
+        let (stale_url, fresh_url) = (format!("http://{stale}"), format!("http://{fresh}"));
+        let bin = env!("CARGO_BIN_EXE_cityfeed-puller");
+
+        for origins in [[&stale_url, &fresh_url], [&fresh_url, &stale_url]] {
+            let root = tempfile::tempdir().unwrap();
+            let out = Command::new(bin)
+                .args(["--origin", &closed_origin()])
+                .args(["--origin", origins[0], "--origin", origins[1]])
+                .args(["--manifest-policy", "newest"])
+                .arg("--root")
+                .arg(root.path())
+                .output()
+                .unwrap();
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            assert_eq!(out.status.code(), Some(0), "{stderr}");
+            assert_eq!(
+                fs::read_link(root.path().join("current")).unwrap(),
+                std::path::Path::new("snapshots/v-new")
+            );
+            assert!(
+                stderr.contains(&format!(
+                    "{stale_url} is behind: serves v-old, 2 publishes older than v-new from {fresh_url}"
+                )),
+                "{stderr}"
+            );
+            assert!(stderr.contains("skipping http://"), "{stderr}");
+        }
+
+        // The default still takes the first answer.
+        let root = tempfile::tempdir().unwrap();
+        let out = Command::new(bin)
+            .args(["--origin", &stale_url, "--origin", &fresh_url])
+            .arg("--root")
+            .arg(root.path())
+            .output()
+            .unwrap();
+        assert_eq!(out.status.code(), Some(0));
+        assert_eq!(
+            fs::read_link(root.path().join("current")).unwrap(),
+            std::path::Path::new("snapshots/v-old")
+        );
+
+        send_quit(stale);
+        stale_handle.join().unwrap();
+        send_quit(fresh);
+        fresh_handle.join().unwrap();
+    }
+
+    #[test]
+    fn manifest_url_fetches_manifest_elsewhere_and_objects_from_origin() {
+        let body = b"release candidate";
+        let hash = sha(body);
+        let manifest = format!(
+            r#"{{"version": "v-rc", "files": [{{ "path": "index.html", "hash": "{hash}", "size": {} }}]}}"#,
+            body.len()
+        );
+
+        // Serves only the manifest, at a path the puller would never guess.
+        let manifest_server = Server::http("127.0.0.1:0").unwrap();
+        let manifest_addr = manifest_server.server_addr().to_ip().unwrap();
+        let manifest_requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = manifest_requests.clone();
+        let manifest_handle = thread::spawn(move || {
+            for req in manifest_server.incoming_requests() {
+                let url = req.url().to_string();
+                if url == "/__quit" {
+                    let _ = req.respond(Response::empty(200));
+                    break;
+                }
+                seen.lock().unwrap().push(url.clone());
+                if url == "/releases/rc.json" {
+                    let _ = req.respond(Response::from_data(manifest.clone().into_bytes()));
+                } else {
+                    let _ = req.respond(Response::empty(StatusCode(404)));
+                }
+            }
+        });
+
+        // The object origin still publishes an older latest.json.
+        let stale = format!(
+            r#"{{"version": "v-old", "files": [{{ "path": "index.html", "hash": "{}", "size": 3 }}]}}"#,
+            sha(b"old")
+        );
+        let mut objects = HashMap::new();
+        objects.insert(hash.clone(), body.to_vec());
+        objects.insert(sha(b"old"), b"old".to_vec());
+        let manifest_hits = Arc::new(AtomicUsize::new(0));
+        let object_hits = Arc::new(AtomicUsize::new(0));
+        let (objects_addr, objects_handle) = start_origin(
+            "v-old",
+            stale.into_bytes(),
+            objects,
+            manifest_hits.clone(),
+            object_hits.clone(),
+        );
+
+        let root = tempfile::tempdir().unwrap();
+        let bin = env!("CARGO_BIN_EXE_cityfeed-puller");
+        let out = Command::new(bin)
+            .args(["--origin", &format!("http://{objects_addr}")])
+            .args([
+                "--manifest-url",
+                &format!("http://{manifest_addr}/releases/rc.json"),
+            ])
+            .arg("--root")
+            .arg(root.path())
+            .output()
+            .unwrap();
+        assert_eq!(
+            out.status.code(),
+            Some(0),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        assert_eq!(
+            fs::read(root.path().join("current/index.html")).unwrap(),
+            body
+        );
+        assert_eq!(
+            *manifest_requests.lock().unwrap(),
+            ["/releases/rc.json"],
+            "objects must not be fetched from the manifest URL's host"
+        );
+        assert_eq!(manifest_hits.load(Ordering::SeqCst), 0);
+        assert_eq!(object_hits.load(Ordering::SeqCst), 1);
+
+        let out = Command::new(bin)
+            .args(["--origin", &format!("http://{objects_addr}")])
+            .args(["--manifest-url", "ftp://example.com/latest.json"])
+            .arg("--root")
+            .arg(root.path())
+            .output()
+            .unwrap();
+        assert_eq!(out.status.code(), Some(1));
+        assert!(
+            String::from_utf8_lossy(&out.stderr).contains("unsupported --manifest-url scheme: ftp")
+        );
+
+        send_quit(manifest_addr);
+        manifest_handle.join().unwrap();
+        send_quit(objects_addr);
+        objects_handle.join().unwrap();
+    }
+
+    /// Conditional headers seen on each manifest request.
+    type Conditionals = Arc<Mutex<Vec<(Option<String>, Option<String>)>>>;
+
+    /// Serves whatever manifest `latest` holds with a fixed Last-Modified and,
+    /// with `etag`, an ETag. With `honor`, a request whose validator matches
+    /// gets a bare 304; otherwise conditionals are ignored.
+    fn start_conditional_origin(
+        latest: Arc<Mutex<String>>,
+        objects: HashMap<String, Vec<u8>>,
+        etag: bool,
+        honor: bool,
+    ) -> (std::net::SocketAddr, thread::JoinHandle<()>, Conditionals) {
+        const DATE: &str = "Fri, 16 Oct 2026 08:30:00 GMT";
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        let seen: Conditionals = Arc::default();
+        let conditionals = seen.clone();
+        let handle = thread::spawn(move || {
+            for req in server.incoming_requests() {
+                let header = |name: &'static str| {
+                    req.headers()
+                        .iter()
+                        .find(|h| h.field.equiv(name))
+                        .map(|h| h.value.to_string())
+                };
+                match req.url() {
+                    "/__quit" => {
+                        let _ = req.respond(Response::empty(200));
+                        break;
+                    }
+                    "/manifests/latest.json" => {
+                        let (if_none_match, if_modified_since) =
+                            (header("If-None-Match"), header("If-Modified-Since"));
+                        conditionals
+                            .lock()
+                            .unwrap()
+                            .push((if_none_match.clone(), if_modified_since.clone()));
+                        let manifest = latest.lock().unwrap().clone();
+                        let tag = format!("\"{}\"", manifest.len());
+                        let fresh = if etag {
+                            if_none_match.as_deref() == Some(tag.as_str())
+                        } else {
+                            if_modified_since.as_deref() == Some(DATE)
+                        };
+                        if honor && fresh {
+                            let _ = req.respond(Response::empty(304));
+                            continue;
+                        }
+                        let mut resp = Response::from_string(manifest).with_header(
+                            Header::from_bytes(&b"Last-Modified"[..], DATE.as_bytes()).unwrap(),
+                        );
+                        if etag {
+                            resp.add_header(
+                                Header::from_bytes(&b"ETag"[..], tag.as_bytes()).unwrap(),
+                            );
+                        }
+                        let _ = req.respond(resp);
+                    }
+                    url => {
+                        let body = url.strip_prefix("/objects/").and_then(|h| objects.get(h));
+                        let _ = match body {
+                            Some(body) => req.respond(Response::from_data(body.clone())),
+                            None => req.respond(Response::empty(StatusCode(404))),
+                        };
+                    }
+                }
+            }
+        });
+        (addr, handle, seen)
+    }
+
+    fn one_file_manifest(version: &str, hash: &str, body: &[u8]) -> String {
+        format!(
+            r#"{{"version": "{version}", "files": [{{ "path": "index.html", "hash": "{hash}", "size": {} }}]}}"#,
+            body.len()
+        )
+    }
+
+    #[test]
+    fn channels_deploy_their_own_manifests_to_separate_roots() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        let channels = HashMap::from([
+            (
+                "/manifests/latest.json".to_string(),
+                one_file_manifest("v-prod", &sha(b"prod"), b"prod"),
+            ),
+            (
+                "/manifests/beta.json".to_string(),
+                one_file_manifest("v-beta", &sha(b"beta!"), b"beta!"),
+            ),
+        ]);
+        let objects = HashMap::from([
+            (sha(b"prod"), b"prod".to_vec()),
+            (sha(b"beta!"), b"beta!".to_vec()),
+        ]);
+        let handle = thread::spawn(move || {
+            for req in server.incoming_requests() {
+                let url = req.url().to_string();
+                if url == "/__quit" {
+                    let _ = req.respond(Response::empty(200));
+                    break;
+                }
+                let body = channels
+                    .get(&url)
+                    .map(|m| m.as_bytes().to_vec())
+                    .or_else(|| {
+                        url.strip_prefix("/objects/")
+                            .and_then(|hash| objects.get(hash))
+                            .cloned()
+                    });
+                let _ = match body {
+                    Some(body) => req.respond(Response::from_data(body)),
+                    None => req.respond(Response::empty(StatusCode(404))),
+                };
+            }
+        });
+        let origin = format!("http://{addr}");
+        let run = |root: &std::path::Path, channel: &[&str]| {
+            Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .args(["--origin", &origin, "--output", "json"])
+                .args(channel)
+                .arg("--root")
+                .arg(root)
+                .output()
+                .unwrap()
+        };
+
+        let prod = tempfile::tempdir().unwrap();
+        let beta = tempfile::tempdir().unwrap();
+        for (root, channel, version, body) in [
+            (prod.path(), &[][..], "v-prod", &b"prod"[..]),
+            (
+                beta.path(),
+                &["--channel", "beta"][..],
+                "v-beta",
+                &b"beta!"[..],
+            ),
+        ] {
+            let out = run(root, channel);
+            assert_eq!(
+                out.status.code(),
+                Some(0),
+                "{}",
+                String::from_utf8_lossy(&out.stderr)
+            );
+            let summary: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+            let name = channel.get(1).copied().unwrap_or("latest");
+            assert_eq!(summary["version"], version);
+            assert_eq!(summary["channel"], name);
+            assert_eq!(fs::read(root.join("current/index.html")).unwrap(), body);
+
+            let state: serde_json::Value =
+                serde_json::from_slice(&fs::read(root.join("deploy-state.json")).unwrap()).unwrap();
+            assert_eq!(state["channel"], name);
+            let history = fs::read_to_string(root.join("state/history.jsonl")).unwrap();
+            assert!(
+                history.contains(&format!(r#""channel":"{name}""#)),
+                "{history}"
+            );
+
+            let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .args(["status", "--root"])
+                .arg(root)
+                .output()
+                .unwrap();
+            let text = String::from_utf8(out.stdout).unwrap();
+            assert!(text.contains(&format!("channel      {name}\n")), "{text}");
+        }
+
+        // A channel names a file; anything that could leave manifests/ is refused.
+        let out = run(beta.path(), &["--channel", "../objects/x"]);
+        assert_eq!(out.status.code(), Some(2));
+
+        send_quit(addr);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn last_modified_only_origin_answers_304_to_if_modified_since() {
+        let latest = Arc::new(Mutex::new(one_file_manifest("v-lm1", &sha(b"one"), b"one")));
+        let objects = HashMap::from([(sha(b"one"), b"one".to_vec())]);
+        let (addr, handle, seen) = start_conditional_origin(latest, objects, false, true);
+        let root = tempfile::tempdir().unwrap();
+        let run = || {
+            Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .args(["--origin", &format!("http://{addr}"), "-v"])
+                .arg("--root")
+                .arg(root.path())
+                .output()
+                .unwrap()
+        };
+
+        let out = run();
+        assert_eq!(
+            out.status.code(),
+            Some(0),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        let out = run();
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        // The 304 stands for the recorded manifest, which is already live.
+        assert_eq!(out.status.code(), Some(3), "{stderr}");
+        assert!(
+            stderr.contains("latest.json not modified; using the recorded v-lm1"),
+            "{stderr}"
+        );
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [
+                (None, None),
+                (None, Some("Fri, 16 Oct 2026 08:30:00 GMT".to_string())),
+            ]
+        );
+
+        send_quit(addr);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn etag_is_preferred_and_304_is_treated_the_same() {
+        let manifest = one_file_manifest("v-et1", &sha(b"one"), b"one");
+        let tag = format!("\"{}\"", manifest.len());
+        let latest = Arc::new(Mutex::new(manifest));
+        let objects = HashMap::from([(sha(b"one"), b"one".to_vec())]);
+        let (addr, handle, seen) = start_conditional_origin(latest, objects, true, true);
+        let root = tempfile::tempdir().unwrap();
+        for code in [0, 3] {
+            let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .args(["--origin", &format!("http://{addr}")])
+                .arg("--root")
+                .arg(root.path())
+                .output()
+                .unwrap();
+            assert_eq!(
+                out.status.code(),
+                Some(code),
+                "{}",
+                String::from_utf8_lossy(&out.stderr)
+            );
+        }
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen[1].0.as_deref(), Some(tag.as_str()), "{seen:?}");
+        assert_eq!(seen[1].1, None, "only the ETag is sent when there is one");
+
+        send_quit(addr);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn origin_ignoring_conditionals_still_deploys_new_versions() {
+        let latest = Arc::new(Mutex::new(one_file_manifest("v-ig1", &sha(b"one"), b"one")));
+        let objects = HashMap::from([
+            (sha(b"one"), b"one".to_vec()),
+            (sha(b"two"), b"two".to_vec()),
+        ]);
+        let (addr, handle, seen) = start_conditional_origin(latest.clone(), objects, false, false);
+        let root = tempfile::tempdir().unwrap();
+        let run = || {
+            Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .args(["--origin", &format!("http://{addr}")])
+                .arg("--root")
+                .arg(root.path())
+                .output()
+                .unwrap()
+        };
+
+        assert_eq!(run().status.code(), Some(0));
+        // Same bytes, full body: an ordinary "already current".
+        assert_eq!(run().status.code(), Some(3));
+        *latest.lock().unwrap() = one_file_manifest("v-ig2", &sha(b"two"), b"two");
+        let out = run();
+        assert_eq!(
+            out.status.code(),
+            Some(0),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        assert_eq!(
+            fs::read(root.path().join("current/index.html")).unwrap(),
+            b"two"
+        );
+        assert_eq!(seen.lock().unwrap().len(), 3);
+        assert!(seen.lock().unwrap()[1..]
+            .iter()
+            .all(|(_, since)| since.is_some()));
+
+        send_quit(addr);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn gzip_encoded_manifest_and_objects_are_decoded() {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+
+        let gzip = |bytes: &[u8]| {
+            let mut enc = GzEncoder::new(Vec::new(), Compression::default());
+            enc.write_all(bytes).unwrap();
+            enc.finish().unwrap()
+        };
+        let body = "<p>compressible</p>\n".repeat(200).into_bytes();
+        let hash = sha(&body);
+        let manifest = format!(
+            r#"{{"version": "v-gz", "files": [{{ "path": "index.html", "hash": "{hash}", "size": {} }}]}}"#,
+            body.len()
+        );
+        let wire_manifest = gzip(manifest.as_bytes());
+        let wire_object = gzip(&body);
+
+        // Compresses whatever it serves, as long as the client asked for gzip.
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        let object_path = format!("/objects/{hash}");
+        let (wire_manifest_len, wire_object_len) = (wire_manifest.len(), wire_object.len());
+        let handle = thread::spawn(move || {
+            for req in server.incoming_requests() {
+                let accepts_gzip = req.headers().iter().any(|header| {
+                    header.field.equiv("Accept-Encoding") && header.value.as_str().contains("gzip")
+                });
+                let data = match req.url() {
+                    "/__quit" => {
+                        let _ = req.respond(Response::empty(200));
+                        break;
+                    }
+                    _ if !accepts_gzip => None,
+                    "/manifests/latest.json" => Some(wire_manifest.clone()),
+                    url if url == object_path => Some(wire_object.clone()),
+                    _ => None,
+                };
+                let _ = match data {
+                    Some(data) => req.respond(Response::from_data(data).with_header(
+                        Header::from_bytes(&b"Content-Encoding"[..], &b"gzip"[..]).unwrap(),
+                    )),
+                    None => req.respond(Response::empty(StatusCode(404))),
+                };
+            }
+        });
+
+        let root = tempfile::tempdir().unwrap();
+        let origin = format!("http://{addr}");
+        let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+            .args(["--origin", &origin, "-v"])
+            .arg("--root")
+            .arg(root.path())
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(out.status.code(), Some(0), "{stderr}");
+        assert_eq!(
+            fs::read(root.path().join("current/index.html")).unwrap(),
+            body
+        );
+        assert!(
+            stderr.contains(&format!(
+                "GET {origin}/manifests/latest.json arrived gzip: {} -> {} bytes",
+                wire_manifest_len,
+                manifest.len()
+            )),
+            "{stderr}"
+        );
+        assert!(
+            stderr.contains(&format!(
+                "GET {origin}/objects/{hash} arrived gzip: {} -> {} bytes",
+                wire_object_len,
+                body.len()
+            )),
+            "{stderr}"
+        );
+
+        send_quit(addr);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn zstd_transfer_objects_are_decoded_next_to_plain_ones() {
+        use sha2::{Digest, Sha256};
+
+        let sha256 = |bytes: &[u8]| {
+            Sha256::digest(bytes)
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect::<String>()
+        };
+        let stops = "{\"stop\": \"Nicollet Mall\"}\n".repeat(300).into_bytes();
+        let wire = zstd::encode_all(&stops[..], 3).unwrap();
+        let origin_with = |compressed: Vec<u8>| {
+            let manifest = format!(
+                r#"{{"version": "v-zstd", "files": [
+                    {{ "path": "index.html", "hash": "{}", "size": 5 }},
+                    {{ "path": "stops.json", "hash": "{}", "size": {},
+                       "transfer": {{ "encoding": "zstd", "hash": "{}", "size": {} }} }}
+                ]}}"#,
+                sha(b"plain"),
+                sha256(&stops),
+                stops.len(),
+                sha256(&wire),
+                wire.len()
+            );
+            let mut objects = HashMap::new();
+            objects.insert(sha(b"plain"), b"plain".to_vec());
+            objects.insert(sha256(&wire), compressed);
+            start_origin(
+                "v-zstd",
+                manifest.into_bytes(),
+                objects,
+                Arc::new(AtomicUsize::new(0)),
+                Arc::new(AtomicUsize::new(0)),
+            )
+        };
+        let bin = env!("CARGO_BIN_EXE_cityfeed-puller");
+
+        let mut corrupted = wire.clone();
+        let mid = corrupted.len() / 2;
+        corrupted[mid] ^= 0xff;
+        let (bad, bad_handle) = origin_with(corrupted);
+        let root = tempfile::tempdir().unwrap();
+        let out = Command::new(bin)
+            .args(["--origin", &format!("http://{bad}")])
+            .arg("--root")
+            .arg(root.path())
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(out.status.code(), Some(5), "{stderr}");
+        assert!(stderr.contains("transfer hash mismatch"), "{stderr}");
+        assert!(!root.path().join("current").exists());
+        assert!(!root.path().join("objects").join(sha256(&stops)).exists());
+
+        let (good, good_handle) = origin_with(wire.clone());
+        let out = Command::new(bin)
+            .args(["--origin", &format!("http://{good}"), "--output", "json"])
+            .arg("--root")
+            .arg(root.path())
+            .output()
+            .unwrap();
+        assert_eq!(
+            out.status.code(),
+            Some(0),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        assert_eq!(
+            fs::read(root.path().join("current/stops.json")).unwrap(),
+            stops
+        );
+        assert_eq!(
+            fs::read(root.path().join("current/index.html")).unwrap(),
+            b"plain"
+        );
+        let summary: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+        // index.html was already fetched by the failed run; only the
+        // compressed bytes count as downloaded.
+        assert_eq!(summary["objects_reused"], 1);
+        assert_eq!(summary["bytes_downloaded"], wire.len() as u64);
+
+        send_quit(bad);
+        bad_handle.join().unwrap();
+        send_quit(good);
+        good_handle.join().unwrap();
+    }
+
+    #[test]
+    fn file_and_dir_modes_and_owner_apply_to_the_snapshot() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let usb = tempfile::tempdir().unwrap();
+        write_file_origin(
+            usb.path(),
+            "v1",
+            &[
+                ("index.html", &sha(b"home"), b"home"),
+                ("docs/deep/a.html", &sha(b"a"), b"a"),
+            ],
+        );
+        let origin = format!("file://{}/", usb.path().display());
+        let root = tempfile::tempdir().unwrap();
+        let bin = env!("CARGO_BIN_EXE_cityfeed-puller");
+
+        // Invalid modes are usage errors, before the root is even touched.
+        for bad in ["0666", "4755", "rwx"] {
+            let out = Command::new(bin)
+                .args(["--origin", &origin, "--file-mode", bad, "--root"])
+                .arg(root.path().join("never"))
+                .output()
+                .unwrap();
+            assert_eq!(out.status.code(), Some(2), "{bad}");
+            assert!(!root.path().join("never").exists());
+        }
+
+        let as_root = fs::metadata("/proc/self").is_ok_and(|meta| meta.uid() == 0);
+        let mut cmd = Command::new(bin);
+        cmd.args([
+            "--origin",
+            &origin,
+            "--file-mode",
+            "0640",
+            "--dir-mode",
+            "0750",
+        ])
+        .arg("--root")
+        .arg(root.path());
+        if as_root {
+            cmd.args(["--owner", "1234:5678"]);
+        }
+        let out = cmd.output().unwrap();
+        assert_eq!(
+            out.status.code(),
+            Some(0),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+
+        let snapshot = root.path().join("snapshots/v1");
+        for (rel, mode) in [
+            ("", 0o750),
+            ("docs", 0o750),
+            ("docs/deep", 0o750),
+            ("index.html", 0o640),
+            ("docs/deep/a.html", 0o640),
+        ] {
+            let meta = fs::metadata(snapshot.join(rel)).unwrap();
+            assert_eq!(meta.permissions().mode() & 0o7777, mode, "{rel}");
+            if as_root {
+                assert_eq!((meta.uid(), meta.gid()), (1234, 5678), "{rel}");
+            }
+        }
+        // Objects keep their own mode.
+        let object = fs::metadata(root.path().join("objects").join(sha(b"home"))).unwrap();
+        assert_eq!(object.permissions().mode() & 0o7777, 0o644);
+    }
+
+    #[test]
+    fn snapshot_tree_is_world_traversable_under_a_strict_umask() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let usb = tempfile::tempdir().unwrap();
+        write_file_origin(
+            usb.path(),
+            "v1",
+            &[
+                ("index.html", &sha(b"home"), b"home"),
+                ("assets/css/site.css", &sha(b"body{}"), b"body{}"),
+            ],
+        );
+        let parent = tempfile::tempdir().unwrap();
+        let root = parent.path().join("site");
+        let out = Command::new("sh")
+            .args(["-c", "umask 077 && exec \"$@\"", "sh"])
+            .arg(env!("CARGO_BIN_EXE_cityfeed-puller"))
+            .arg("--origin")
+            .arg(format!("file://{}/", usb.path().display()))
+            .arg("--root")
+            .arg(&root)
+            .output()
+            .unwrap();
+        assert_eq!(
+            out.status.code(),
+            Some(0),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+
+        let mode = |rel: &str| fs::metadata(root.join(rel)).unwrap().permissions().mode() & 0o7777;
+        for dir in [
+            "",
+            "objects",
+            "snapshots",
+            "snapshots/v1",
+            "snapshots/v1/assets",
+            "snapshots/v1/assets/css",
+        ] {
+            assert_eq!(mode(dir), 0o755, "{dir:?}");
+        }
+        assert_eq!(mode("current/assets/css/site.css"), 0o644);
+    }
+
+    #[test]
+    fn multiple_roots_switch_together_or_not_at_all() {
+        let a = tempfile::tempdir().unwrap();
+        let b = tempfile::tempdir().unwrap();
+        let deploy = |version: &str| {
+            let (addr, handle) =
+                single_file_origin(version, &sha(version.as_bytes()), version.as_bytes());
+            let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .args(["--origin", &format!("http://{addr}"), "--root"])
+                .arg(a.path())
+                .arg("--root")
+                .arg(b.path())
+                .output()
+                .unwrap();
+            send_quit(addr);
+            handle.join().unwrap();
+            out
+        };
+        let current =
+            |root: &tempfile::TempDir| fs::read_link(root.path().join("current")).unwrap();
+
+        let out = deploy("v1");
+        assert_eq!(
+            out.status.code(),
+            Some(0),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        for root in [&a, &b] {
+            assert_eq!(current(root), std::path::Path::new("snapshots/v1"));
+            assert_eq!(
+                fs::read(root.path().join("current/index.html")).unwrap(),
+                b"v1"
+            );
+            assert!(root.path().join("deploy-state.json").exists());
+        }
+        // Downloaded once, into the first root.
+        assert!(a.path().join("objects").join(sha(b"v1")).exists());
+        assert!(dir_entries(&b.path().join("objects")).is_empty());
+
+        // Something in the way of the second root's snapshot: staging fails
+        // there, and neither root moves.
+        std::os::unix::fs::symlink("nowhere", b.path().join("snapshots/v2")).unwrap();
+        let out = deploy("v2");
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(out.status.code(), Some(1), "{stderr}");
+        assert!(
+            stderr.contains(&format!("root {}", b.path().display())),
+            "{stderr}"
+        );
+        assert_eq!(current(&a), std::path::Path::new("snapshots/v1"));
+        assert_eq!(current(&b), std::path::Path::new("snapshots/v1"));
+
+        fs::remove_file(b.path().join("snapshots/v2")).unwrap();
+        let out = deploy("v2");
+        assert_eq!(
+            out.status.code(),
+            Some(0),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        assert_eq!(current(&a), std::path::Path::new("snapshots/v2"));
+        assert_eq!(
+            fs::read(b.path().join("current/index.html")).unwrap(),
+            b"v2"
+        );
+    }
+
+    #[test]
+    fn previous_link_tracks_the_snapshot_before_current() {
+        let root = tempfile::tempdir().unwrap();
+        let deploy = |version: &str| {
+            let (addr, handle) =
+                single_file_origin(version, &sha(version.as_bytes()), version.as_bytes());
+            let (code, summary) = run_json(&format!("http://{addr}"), root.path());
+            send_quit(addr);
+            handle.join().unwrap();
+            assert!(matches!(code, Some(0 | 3)), "{summary}");
+        };
+        let link = |name: &str| fs::read_link(root.path().join(name)).unwrap();
+
+        deploy("v1");
+        assert!(fs::symlink_metadata(root.path().join("previous")).is_err());
+
+        deploy("v2");
+        assert_eq!(link("current"), std::path::Path::new("snapshots/v2"));
+        assert_eq!(link("previous"), std::path::Path::new("snapshots/v1"));
+        assert_eq!(
+            fs::read(root.path().join("previous/index.html")).unwrap(),
+            b"v1"
+        );
+
+        // A run that finds v2 already live leaves `previous` alone.
+        deploy("v2");
+        assert_eq!(link("previous"), std::path::Path::new("snapshots/v1"));
+    }
+
+    #[test]
+    fn library_deploy_reports_what_it_did_without_a_process() {
+        let root = tempfile::tempdir().unwrap();
+        let options = cityfeed_pull::DeployOptions {
+            quiet: true,
+            ..Default::default()
+        };
+        let deploy = |version: &str, body: &[u8]| {
+            let (addr, handle) = single_file_origin(version, &sha(body), body);
+            let outcome = cityfeed_pull::deploy(root.path(), &[format!("http://{addr}")], &options);
+            send_quit(addr);
+            handle.join().unwrap();
+            outcome.unwrap()
+        };
+
+        let first = deploy("v1", b"one");
+        assert_eq!(first.version, "v1");
+        assert!(first.switched);
+        assert_eq!(first.previous_version, None);
+        assert_eq!((first.objects_downloaded, first.bytes_downloaded), (1, 3));
+        assert_eq!(first.snapshot, root.path().join("snapshots/v1"));
+        assert_eq!(
+            fs::read(root.path().join("current/index.html")).unwrap(),
+            b"one"
+        );
+
+        let again = deploy("v1", b"one");
+        assert!(!again.switched);
+        assert_eq!(again.objects_downloaded, 0);
+
+        let second = deploy("v2", b"two!");
+        assert!(second.switched);
+        assert_eq!(second.previous_version.as_deref(), Some("v1"));
+        let changes = second.changes.unwrap();
+        assert_eq!(
+            (changes.added, changes.removed, changes.modified),
+            (0, 0, 1)
+        );
+        assert_eq!(
+            cityfeed_pull::switch::current_version(&root.path().join("current")).as_deref(),
+            Some("v2")
+        );
+    }
+
+    #[test]
+    fn library_deploy_errors_carry_the_failure_class() {
+        let root = tempfile::tempdir().unwrap();
+        let dead = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = dead.local_addr().unwrap();
+        drop(dead);
+        let options = cityfeed_pull::DeployOptions {
+            quiet: true,
+            extra_args: vec!["--connect-timeout".into(), "2s".into()],
+            ..Default::default()
+        };
+        let err =
+            cityfeed_pull::deploy(root.path(), &[format!("http://{addr}")], &options).unwrap_err();
+        assert_eq!(err.failure, cityfeed_pull::Failure::Manifest);
+        assert_eq!(err.failure.exit_code(), 4);
+        assert!(fs::symlink_metadata(root.path().join("current")).is_err());
+
+        let bad = cityfeed_pull::DeployOptions {
+            extra_args: vec!["--no-such-flag".into()],
+            ..Default::default()
+        };
+        let err =
+            cityfeed_pull::deploy(root.path(), &["http://127.0.0.1:9".into()], &bad).unwrap_err();
+        assert_eq!(err.failure, cityfeed_pull::Failure::Other);
+    }
+
+    #[test]
+    fn manifest_version_must_not_escape_snapshots() {
+        let parent = tempfile::tempdir().unwrap();
+        let root = parent.path().join("root");
+        for version in ["../evil", "a/b", ".hidden", ""] {
+            let (addr, handle) = single_file_origin(version, &h("esc"), b"escape");
+            let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .args(["--origin", &format!("http://{addr}"), "--root"])
+                .arg(&root)
+                .output()
+                .unwrap();
+            send_quit(addr);
+            handle.join().unwrap();
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            assert_eq!(out.status.code(), Some(4), "{version:?}: {stderr}");
+            assert!(
+                stderr.contains(&format!("invalid manifest version {version:?}")),
+                "{stderr}"
+            );
+            assert_eq!(fs::read_dir(root.join("objects")).unwrap().count(), 0);
+            assert_eq!(fs::read_dir(root.join("snapshots")).unwrap().count(), 0);
+        }
+        assert!(!parent.path().join("evil").exists());
+    }
+
+    #[test]
+    fn promote_switches_between_local_snapshots_offline() {
+        let root = tempfile::tempdir().unwrap();
+        for version in ["v1", "v2"] {
+            let (addr, handle) =
+                single_file_origin(version, &sha(version.as_bytes()), version.as_bytes());
+            let (code, summary) = run_json(&format!("http://{addr}"), root.path());
+            send_quit(addr);
+            handle.join().unwrap();
+            assert_eq!(code, Some(0), "{summary}");
+        }
+        // No --origin anywhere below.
+        let promote = |version: &str| {
+            let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .args([
+                    "promote",
+                    "--version",
+                    version,
+                    "--output",
+                    "json",
+                    "--root",
+                ])
+                .arg(root.path())
+                .output()
+                .unwrap();
+            let summary = serde_json::from_slice::<serde_json::Value>(&out.stdout)
+                .unwrap_or(serde_json::Value::Null);
+            (
+                out.status.code(),
+                summary,
+                String::from_utf8_lossy(&out.stderr).into_owned(),
+            )
+        };
+        let link = |name: &str| fs::read_link(root.path().join(name)).unwrap();
+        let state_version = || {
+            let state: serde_json::Value = serde_json::from_str(
+                &fs::read_to_string(root.path().join("deploy-state.json")).unwrap(),
+            )
+            .unwrap();
+            state["version"].clone()
+        };
+
+        let (code, summary, stderr) = promote("v1");
+        assert_eq!(code, Some(0), "{stderr}");
+        assert_eq!(summary["outcome"], "updated");
+        assert_eq!(summary["version"], "v1");
+        assert_eq!(summary["previous_version"], "v2");
+        assert_eq!(link("current"), std::path::Path::new("snapshots/v1"));
+        assert_eq!(link("previous"), std::path::Path::new("snapshots/v2"));
+        assert_eq!(state_version(), "v1");
+
+        let (code, _, stderr) = promote("v2");
+        assert_eq!(code, Some(0), "{stderr}");
+        assert_eq!(link("current"), std::path::Path::new("snapshots/v2"));
+        assert_eq!(link("previous"), std::path::Path::new("snapshots/v1"));
+        assert_eq!(state_version(), "v2");
+
+        let (code, summary, _) = promote("v2");
+        assert_eq!(code, Some(3));
+        assert_eq!(summary["outcome"], "already-current");
+
+        let (code, _, stderr) = promote("v9");
+        assert_eq!(code, Some(1));
+        assert!(stderr.contains("no snapshot"), "{stderr}");
+        let (code, _, stderr) = promote("../snapshots/v1");
+        assert_eq!(code, Some(1));
+        assert!(stderr.contains("invalid version"), "{stderr}");
+
+        // A snapshot missing a file from its manifest is refused.
+        fs::remove_file(root.path().join("snapshots/v1/index.html")).unwrap();
+        let (code, _, stderr) = promote("v1");
+        assert_eq!(code, Some(5));
+        assert!(
+            stderr.contains("snapshot v1 is incomplete") && stderr.contains("index.html: missing"),
+            "{stderr}"
+        );
+        assert_eq!(link("current"), std::path::Path::new("snapshots/v2"));
+        assert_eq!(state_version(), "v2");
+
+        // Without a recorded manifest the snapshot is taken as it is.
+        fs::write(root.path().join("snapshots/v1/index.html"), "v1").unwrap();
+        fs::remove_file(root.path().join("manifests/v1.json")).unwrap();
+        let (code, summary, stderr) = promote("v1");
+        assert_eq!(code, Some(0), "{stderr}");
+        assert_eq!(summary["file_count"], 1);
+        assert_eq!(link("current"), std::path::Path::new("snapshots/v1"));
+        assert!(root.path().join("manifests/v1.json").exists());
+    }
+
+    #[test]
+    fn status_reports_the_deployed_root() {
+        let root = tempfile::tempdir().unwrap();
+        let status = || {
+            let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .args(["status", "--output", "json", "--root"])
+                .arg(root.path())
+                .output()
+                .unwrap();
+            assert_eq!(
+                out.status.code(),
+                Some(0),
+                "{}",
+                String::from_utf8_lossy(&out.stderr)
+            );
+            serde_json::from_slice::<serde_json::Value>(&out.stdout).unwrap()
+        };
+        let empty = status();
+        assert_eq!(empty["version"], serde_json::Value::Null);
+        assert_eq!(empty["current_ok"], false);
+        assert_eq!(empty["snapshot_count"], 0);
+
+        for (version, body) in [("v1", &b"one"[..]), ("v2", &b"second"[..])] {
+            let (addr, handle) = single_file_origin(version, &sha(body), body);
+            let (code, summary) = run_json(&format!("http://{addr}"), root.path());
+            send_quit(addr);
+            handle.join().unwrap();
+            assert_eq!(code, Some(0), "{summary}");
+        }
+        fs::create_dir(root.path().join("snapshots/.v3.staging-1-dead")).unwrap();
+
+        let report = status();
+        assert_eq!(report["version"], "v2");
+        assert_eq!(report["current_ok"], true);
+        assert_eq!(report["previous_version"], "v1");
+        assert!(report["switched_at"].is_string(), "{report}");
+        assert!(report["origin"].as_str().unwrap().starts_with("http://"));
+        assert_eq!(report["snapshot_count"], 2);
+        assert_eq!(report["snapshot_bytes"], 9);
+        assert_eq!(report["snapshots"][1]["version"], "v2");
+        assert_eq!(report["snapshots"][1]["bytes"], 6);
+        assert_eq!(report["object_count"], 2);
+        assert_eq!(report["object_bytes"], 9);
+        let debris = report["debris"].as_array().unwrap();
+        assert_eq!(debris.len(), 1, "{report}");
+        assert!(debris[0]["path"]
+            .as_str()
+            .unwrap()
+            .ends_with(".v3.staging-1-dead"));
+        assert!(report["free_bytes"].as_u64().unwrap() > 0);
+        let history = report["history"].as_array().unwrap();
+        assert_eq!(history.len(), 2, "{report}");
+        assert_eq!(history[1]["previous_version"], "v1");
+        assert_eq!(history[1]["version"], "v2");
+
+        // A dangling current is reported, not fatal.
+        fs::rename(
+            root.path().join("snapshots/v2"),
+            root.path().join("snapshots/v2-moved"),
+        )
+        .unwrap();
+        let report = status();
+        assert_eq!(report["version"], "v2");
+        assert_eq!(report["current_ok"], false);
+
+        let text = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+            .args(["status", "--root"])
+            .arg(root.path())
+            .output()
+            .unwrap();
+        let text = String::from_utf8_lossy(&text.stdout);
+        assert!(text.contains("v2 (BROKEN: snapshot missing)"), "{text}");
+    }
+
+    #[test]
+    fn doctor_checks_a_new_box() {
+        let (addr, handle) = single_file_origin("v-doc", &h("doc"), b"doc");
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("site");
+        let doctor = |origins: &[&str]| {
+            let mut cmd = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"));
+            for origin in origins {
+                cmd.args(["--origin", origin]);
+            }
+            let out = cmd
+                .arg("--root")
+                .arg(&root)
+                .args(["doctor", "--output", "json"])
+                .env_remove("SSL_CERT_FILE")
+                .env_remove("SSL_CERT_DIR")
+                .output()
+                .unwrap();
+            let report: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+            (out.status.code(), report)
+        };
+        let origin = format!("http://{addr}");
+
+        let (code, report) = doctor(&[&origin]);
+        assert_eq!(code, Some(0), "{report}");
+        assert_eq!(report["ok"], true);
+        let checks = report["checks"].as_array().unwrap();
+        let names: Vec<&str> = checks.iter().map(|c| c["name"].as_str().unwrap()).collect();
+        assert_eq!(
+            names,
+            [
+                "root",
+                "write",
+                "symlinks",
+                "lock",
+                "clock",
+                "tls",
+                "origin",
+                "disk_space"
+            ]
+        );
+        assert!(checks.iter().all(|c| c["status"] == "pass"), "{report}");
+        assert_eq!(checks[6]["target"], origin.as_str());
+        assert!(checks[6]["detail"]
+            .as_str()
+            .unwrap()
+            .starts_with("manifest v-doc (1 files)"));
+        // The probes clean up after themselves and create nothing.
+        assert!(!root.exists());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        // An unreachable origin fails with the hint a deploy would give.
+        let closed = closed_origin();
+        let (code, report) = doctor(&[&origin, &closed]);
+        assert_eq!(code, Some(1), "{report}");
+        assert_eq!(report["ok"], false);
+        let failed = report["checks"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|c| c["status"] == "fail")
+            .unwrap();
+        assert_eq!(failed["name"], "origin");
+        assert_eq!(failed["target"], closed.as_str());
+        assert!(
+            failed["hint"]
+                .as_str()
+                .unwrap()
+                .starts_with("connection refused on 127.0.0.1"),
+            "{failed}"
+        );
+
+        // A held lock only warns.
+        fs::create_dir(&root).unwrap();
+        let lock = fs::File::create(root.join(".cityfeed-puller.lock")).unwrap();
+        lock.try_lock().unwrap();
+        let (code, report) = doctor(&[&origin]);
+        assert_eq!(code, Some(0), "{report}");
+        assert_eq!(report["checks"][3]["status"], "warn", "{report}");
+        drop(lock);
+
+        let (code, report) = doctor(&[]);
+        assert_eq!(code, Some(1), "{report}");
+
+        send_quit(addr);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn diff_lists_changed_paths_between_snapshots() {
+        let root = tempfile::tempdir().unwrap();
+        let deploy = |version: &str, files: &[(&str, &[u8])]| {
+            let mut objects = HashMap::new();
+            let entries: Vec<String> = files
+                .iter()
+                .map(|(path, body)| {
+                    let hash = sha(body);
+                    objects.insert(hash.clone(), body.to_vec());
+                    format!(
+                        r#"{{ "path": "{path}", "hash": "{hash}", "size": {} }}"#,
+                        body.len()
+                    )
+                })
+                .collect();
+            let manifest = format!(
+                r#"{{ "version": "{version}", "files": [{}] }}"#,
+                entries.join(", ")
+            );
+            let (addr, handle) = start_origin(
+                version,
+                manifest.into_bytes(),
+                objects,
+                Arc::new(AtomicUsize::new(0)),
+                Arc::new(AtomicUsize::new(0)),
+            );
+            let (code, summary) = run_json(&format!("http://{addr}"), root.path());
+            send_quit(addr);
+            handle.join().unwrap();
+            assert_eq!(code, Some(0), "{summary}");
+        };
+        deploy(
+            "v41",
+            &[
+                ("index.html", b"one"),
+                ("app.css", b"css"),
+                ("old.html", b"old"),
+            ],
+        );
+        deploy(
+            "v42",
+            &[
+                ("index.html", b"two"),
+                ("app.css", b"css"),
+                ("new.html", b"newer"),
+            ],
+        );
+        let diff = |extra: &[&str]| {
+            let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .args(["diff", "v41", "v42", "--root"])
+                .arg(root.path())
+                .args(extra)
+                .output()
+                .unwrap();
+            assert_eq!(
+                out.status.code(),
+                Some(0),
+                "{}",
+                String::from_utf8_lossy(&out.stderr)
+            );
+            String::from_utf8(out.stdout).unwrap()
+        };
+
+        // index.html keeps its size; only the hash says it changed.
+        assert_eq!(
+            diff(&[]),
+            "+ new.html (5 bytes)\n\
+             - old.html (3 bytes)\n\
+             ~ index.html (3 bytes -> 3 bytes)\n\
+             v41 -> v42: 1 added, 1 removed, 1 modified, +2 bytes\n"
+        );
+        assert_eq!(
+            diff(&["--stat"]),
+            "v41 -> v42: 1 added, 1 removed, 1 modified, +2 bytes\n"
+        );
+        let doc: serde_json::Value = serde_json::from_str(&diff(&["--output", "json"])).unwrap();
+        assert_eq!(doc["from_source"], "record");
+        assert_eq!(
+            doc["modified"],
+            serde_json::json!([{ "path": "index.html", "old_size": 3, "new_size": 3 }])
+        );
+        assert_eq!(doc["added"][0]["path"], "new.html");
+        assert_eq!(doc["removed"][0]["path"], "old.html");
+        let stat: serde_json::Value =
+            serde_json::from_str(&diff(&["--stat", "--output", "json"])).unwrap();
+        assert_eq!(
+            stat["counts"],
+            serde_json::json!({ "added": 1, "removed": 1, "modified": 1 })
+        );
+        assert!(stat.get("added").is_none());
+
+        // Without records the snapshots are hashed, with the same result.
+        fs::remove_file(root.path().join("manifests/v41.json")).unwrap();
+        fs::remove_file(root.path().join("manifests/v42.json")).unwrap();
+        let doc: serde_json::Value = serde_json::from_str(&diff(&["--output", "json"])).unwrap();
+        assert_eq!(doc["from_source"], "snapshot");
+        assert_eq!(doc["to_source"], "snapshot");
+        assert_eq!(
+            doc["counts"],
+            serde_json::json!({ "added": 1, "removed": 1, "modified": 1 })
+        );
+        assert_eq!(doc["modified"][0]["path"], "index.html");
+
+        let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+            .args(["diff", "v41", "v99", "--root"])
+            .arg(root.path())
+            .output()
+            .unwrap();
+        assert_eq!(out.status.code(), Some(1));
+        assert!(String::from_utf8_lossy(&out.stderr)
+            .contains("no recorded manifest or snapshot for v99"));
+    }
+
+    #[test]
+    fn keep_days_prunes_old_snapshots_but_never_current() {
+        let root = tempfile::tempdir().unwrap();
+        let deploy = |version: &str, body: &[u8]| {
+            let (addr, handle) = single_file_origin(version, &sha(body), body);
+            let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .args(["--origin", &format!("http://{addr}"), "--keep-days", "7"])
+                .args(["--output", "json", "--root"])
+                .arg(root.path())
+                .output()
+                .unwrap();
+            send_quit(addr);
+            handle.join().unwrap();
+            assert!(
+                matches!(out.status.code(), Some(0 | 3)),
+                "{}",
+                String::from_utf8_lossy(&out.stderr)
+            );
+            serde_json::from_slice::<serde_json::Value>(&out.stdout).unwrap()
+        };
+        for version in ["v1", "v2", "v3"] {
+            let summary = deploy(version, version.as_bytes());
+            assert_eq!(summary["snapshots_pruned"], serde_json::json!([]));
+        }
+
+        // Make every snapshot, including the live one, a month old.
+        let month_ago = std::time::SystemTime::now() - Duration::from_secs(30 * 24 * 60 * 60);
+        for version in ["v1", "v2", "v3"] {
+            for path in [
+                root.path().join("snapshots").join(version),
+                root.path().join(format!("manifests/{version}.json")),
+            ] {
+                fs::File::open(&path)
+                    .unwrap()
+                    .set_modified(month_ago)
+                    .unwrap();
+            }
+        }
+
+        let summary = deploy("v3", b"v3");
+        assert_eq!(summary["outcome"], "already-current");
+        assert_eq!(summary["snapshots_pruned"], serde_json::json!(["v1"]));
+        assert_eq!(summary["bytes_pruned"], 2);
+        let mut left = dir_entries(&root.path().join("snapshots"));
+        left.sort();
+        assert_eq!(left, ["v2", "v3"]);
+        assert_eq!(
+            fs::read_link(root.path().join("current")).unwrap(),
+            std::path::Path::new("snapshots/v3")
+        );
+    }
+
+    #[test]
+    fn wrong_sized_stored_object_is_quarantined_and_fetched_again() {
+        let body = b"<h1>whole</h1>";
+        let hash = sha(body);
+        let (addr, handle) = single_file_origin("v1", &hash, body);
+        let root = tempfile::tempdir().unwrap();
+        let objects = root.path().join("objects");
+        fs::create_dir_all(&objects).unwrap();
+        fs::write(objects.join(&hash), b"<h1>wh").unwrap();
+
+        let (code, summary) = run_json(&format!("http://{addr}"), root.path());
+        assert_eq!(code, Some(0), "{summary}");
+        assert_eq!(summary["objects_repaired"], 1);
+        assert_eq!(summary["objects_reused"], 0);
+        assert_eq!(summary["objects_downloaded"], 1);
+        assert_eq!(summary["bytes_needed"], body.len() as u64);
+        assert_eq!(fs::read(objects.join(&hash)).unwrap(), body);
+        assert_eq!(
+            fs::read(objects.join(".quarantine").join(&hash)).unwrap(),
+            b"<h1>wh"
+        );
+        assert_eq!(
+            fs::read(root.path().join("current/index.html")).unwrap(),
+            body
+        );
+
+        send_quit(addr);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn parts_are_assembled_into_one_object_and_corrupt_parts_fail() {
+        use sha2::{Digest, Sha256};
+
+        let sha256 = |bytes: &[u8]| {
+            Sha256::digest(bytes)
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect::<String>()
+        };
+        let video: Vec<u8> = (0..3000u32).map(|i| (i % 251) as u8).collect();
+        let pieces = [&video[..1000], &video[1000..2000], &video[2000..]];
+        let parts_json = pieces
+            .iter()
+            .map(|p| format!(r#"{{ "hash": "{}", "size": {} }}"#, sha256(p), p.len()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let manifest = format!(
+            r#"{{"version": "v-parts", "files": [
+                {{ "path": "tour.mp4", "hash": "{}", "size": {}, "parts": [{parts_json}] }}
+            ]}}"#,
+            sha256(&video),
+            video.len()
+        );
+        let origin_with = |middle: &[u8]| {
+            let mut objects = HashMap::new();
+            objects.insert(sha256(pieces[0]), pieces[0].to_vec());
+            objects.insert(sha256(pieces[1]), middle.to_vec());
+            objects.insert(sha256(pieces[2]), pieces[2].to_vec());
+            let object_hits = Arc::new(AtomicUsize::new(0));
+            let (addr, handle) = start_origin(
+                "v-parts",
+                manifest.clone().into_bytes(),
+                objects,
+                Arc::new(AtomicUsize::new(0)),
+                object_hits.clone(),
+            );
+            (addr, handle, object_hits)
+        };
+        let bin = env!("CARGO_BIN_EXE_cityfeed-puller");
+        let root = tempfile::tempdir().unwrap();
+
+        let mut corrupt = pieces[1].to_vec();
+        corrupt[10] ^= 0xff;
+        let (bad, bad_handle, _) = origin_with(&corrupt);
+        let out = Command::new(bin)
+            .args(["--origin", &format!("http://{bad}")])
+            .arg("--root")
+            .arg(root.path())
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(out.status.code(), Some(5), "{stderr}");
+        assert!(stderr.contains("part 2/3"), "{stderr}");
+        assert!(stderr.contains("hash mismatch"), "{stderr}");
+        assert!(!root.path().join("current").exists());
+        // No half-assembled object, and no temp file left behind.
+        assert!(dir_entries(&root.path().join("objects")).is_empty());
+
+        let (good, good_handle, object_hits) = origin_with(pieces[1]);
+        let out = Command::new(bin)
+            .args(["--origin", &format!("http://{good}")])
+            .arg("--root")
+            .arg(root.path())
+            .output()
+            .unwrap();
+        assert_eq!(
+            out.status.code(),
+            Some(0),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        assert_eq!(object_hits.load(Ordering::SeqCst), 3);
+        assert_eq!(
+            fs::read(root.path().join("current/tour.mp4")).unwrap(),
+            video
+        );
+        assert_eq!(dir_entries(&root.path().join("objects")), [sha256(&video)]);
+
+        send_quit(bad);
+        bad_handle.join().unwrap();
+        send_quit(good);
+        good_handle.join().unwrap();
+    }
+
+    /// Answers every request with 404 and counts them.
+    fn start_404_origin(hits: Arc<AtomicUsize>) -> (std::net::SocketAddr, thread::JoinHandle<()>) {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        let handle = thread::spawn(move || {
+            for req in server.incoming_requests() {
+                if req.url() == "/__quit" {
+                    let _ = req.respond(Response::empty(200));
+                    break;
+                }
+                hits.fetch_add(1, Ordering::SeqCst);
+                let _ = req.respond(Response::empty(StatusCode(404)));
+            }
+        });
+        (addr, handle)
+    }
+
+    #[test]
+    fn failing_origin_is_demoted_instead_of_retried_per_object() {
+        let mut objects = HashMap::new();
+        let mut entries = Vec::new();
+        for i in 0..10 {
+            let hash = sha(&[b'a' + i]);
+            entries.push(format!(
+                r#"{{ "path": "f{i}.txt", "hash": "{hash}", "size": 1 }}"#
+            ));
+            objects.insert(hash, vec![b'a' + i]);
+        }
+        let manifest = format!(
+            r#"{{"version": "v-health", "files": [{}]}}"#,
+            entries.join(",")
+        );
+        let (good, good_handle) = start_origin(
+            "v-health",
+            manifest.into_bytes(),
+            objects,
+            Arc::new(AtomicUsize::new(0)),
+            Arc::new(AtomicUsize::new(0)),
+        );
+        let bad_hits = Arc::new(AtomicUsize::new(0));
+        let (bad, bad_handle) = start_404_origin(bad_hits.clone());
+
+        let root = tempfile::tempdir().unwrap();
+        let bin = env!("CARGO_BIN_EXE_cityfeed-puller");
+        let out = Command::new(bin)
+            .args(["--origin", &format!("http://{bad}")])
+            .args(["--origin", &format!("http://{good}")])
+            .arg("--root")
+            .arg(root.path())
+            .args(["--output", "json"])
+            .output()
+            .unwrap();
+        assert_eq!(out.status.code(), Some(0));
+
+        // One manifest miss plus two object misses, then it is demoted.
+        assert_eq!(bad_hits.load(Ordering::SeqCst), 3);
+        let stderr = String::from_utf8(out.stderr).unwrap();
+        assert!(stderr.contains(&format!("origin http://{bad} demoted")));
+        assert!(stderr.contains(&format!("origin http://{bad}: 3 requests, 3 failed")));
+
+        let summary: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+        assert_eq!(summary["origins"][0]["origin"], format!("http://{bad}"));
+        assert_eq!(summary["origins"][0]["failures"], 3);
+        assert_eq!(summary["origins"][1]["requests"], 11);
+        assert_eq!(summary["origins"][1]["failures"], 0);
+
+        send_quit(good);
+        good_handle.join().unwrap();
+        send_quit(bad);
+        bad_handle.join().unwrap();
+    }
+
+    #[test]
+    fn round_robin_spreads_objects_across_origins() {
+        let mut objects = HashMap::new();
+        let mut entries = Vec::new();
+        for i in 0..20 {
+            let hash = sha(&[b'a' + i]);
+            entries.push(format!(
+                r#"{{ "path": "f{i}.txt", "hash": "{hash}", "size": 1 }}"#
+            ));
+            objects.insert(hash, vec![b'a' + i]);
+        }
+        let manifest = format!(r#"{{"version": "v-rr", "files": [{}]}}"#, entries.join(","));
+        let hits_a = Arc::new(AtomicUsize::new(0));
+        let hits_b = Arc::new(AtomicUsize::new(0));
+        let (a, handle_a) = start_origin(
+            "v-rr",
+            manifest.clone().into_bytes(),
+            objects.clone(),
+            Arc::new(AtomicUsize::new(0)),
+            hits_a.clone(),
+        );
+        let (b, handle_b) = start_origin(
+            "v-rr",
+            manifest.into_bytes(),
+            objects,
+            Arc::new(AtomicUsize::new(0)),
+            hits_b.clone(),
+        );
+
+        let root = tempfile::tempdir().unwrap();
+        let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+            .args(["--origin", &format!("http://{a}")])
+            .args(["--origin", &format!("http://{b}")])
+            .args(["--origin-strategy", "round-robin", "-v"])
+            .arg("--root")
+            .arg(root.path())
+            .output()
+            .unwrap();
+        assert_eq!(out.status.code(), Some(0));
+
+        let (a_hits, b_hits) = (hits_a.load(Ordering::SeqCst), hits_b.load(Ordering::SeqCst));
+        assert_eq!(a_hits + b_hits, 20);
+        assert!((8..=12).contains(&a_hits), "a={a_hits} b={b_hits}");
+        let stderr = String::from_utf8(out.stderr).unwrap();
+        assert_eq!(
+            stderr
+                .matches(&format!("starts at origin=http://{b}"))
+                .count(),
+            b_hits
+        );
+
+        send_quit(a);
+        handle_a.join().unwrap();
+        send_quit(b);
+        handle_b.join().unwrap();
+    }
+
+    /// Forwards connections to `target`, each one only after `delay`.
+    fn start_slow_proxy(target: std::net::SocketAddr, delay: Duration) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for client in listener.incoming() {
+                let Ok(client) = client else { break };
+                thread::spawn(move || {
+                    thread::sleep(delay);
+                    let Ok(upstream) = TcpStream::connect(target) else {
+                        return;
+                    };
+                    let (mut c_read, mut u_write) =
+                        (client.try_clone().unwrap(), upstream.try_clone().unwrap());
+                    thread::spawn(move || std::io::copy(&mut c_read, &mut u_write));
+                    let (mut u_read, mut c_write) = (upstream, client);
+                    let _ = std::io::copy(&mut u_read, &mut c_write);
+                });
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn latency_strategy_prefers_the_fastest_origin() {
+        let mut objects = HashMap::new();
+        let mut entries = Vec::new();
+        for i in 0..5 {
+            let hash = sha(&[b'a' + i]);
+            entries.push(format!(
+                r#"{{ "path": "f{i}.txt", "hash": "{hash}", "size": 1 }}"#
+            ));
+            objects.insert(hash, vec![b'a' + i]);
+        }
+        let manifest = format!(
+            r#"{{"version": "v-lat", "files": [{}]}}"#,
+            entries.join(",")
+        );
+        let slow_hits = Arc::new(AtomicUsize::new(0));
+        let fast_hits = Arc::new(AtomicUsize::new(0));
+        let (slow, slow_handle) = start_origin(
+            "v-lat",
+            manifest.clone().into_bytes(),
+            objects.clone(),
+            Arc::new(AtomicUsize::new(0)),
+            slow_hits.clone(),
+        );
+        let (fast, fast_handle) = start_origin(
+            "v-lat",
+            manifest.into_bytes(),
+            objects,
+            Arc::new(AtomicUsize::new(0)),
+            fast_hits.clone(),
+        );
+        let slow_proxy = start_slow_proxy(slow, Duration::from_millis(500));
+        let dead = closed_origin();
+
+        let root = tempfile::tempdir().unwrap();
+        let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+            .args(["--origin", &format!("http://{slow_proxy}")])
+            .args(["--origin", &dead])
+            .args(["--origin", &format!("http://{fast}")])
+            .args(["--origin-strategy", "latency", "--output", "json"])
+            .arg("--root")
+            .arg(root.path())
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(out.status.code(), Some(0), "{stderr}");
+        assert_eq!(fast_hits.load(Ordering::SeqCst), 5);
+        assert_eq!(slow_hits.load(Ordering::SeqCst), 0);
+        assert!(stderr.contains(&format!("latency probe origin=http://{fast} ")));
+        assert!(stderr.contains(&format!("latency probe origin={dead} failed")));
+
+        let summary: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+        assert_eq!(summary["origin"], format!("http://{fast}"));
+        let probes = summary["latency"].as_array().unwrap();
+        assert_eq!(probes.len(), 3);
+        assert_eq!(probes[0]["origin"], format!("http://{fast}"));
+        assert_eq!(probes[1]["origin"], format!("http://{slow_proxy}"));
+        assert!(probes[1]["ms"].as_u64().unwrap() >= 500);
+        assert_eq!(probes[2]["origin"], dead);
+        assert!(probes[2]["ms"].is_null());
+        assert!(probes[2]["error"].is_string());
+        // The failed origin was never tried for real.
+        assert!(!summary["origins"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|o| o["origin"] == dead));
+
+        send_quit(slow);
+        slow_handle.join().unwrap();
+        send_quit(fast);
+        fast_handle.join().unwrap();
+    }
+
+    #[test]
+    fn sharded_layout_downloads_and_migrates_existing_objects() {
+        let usb = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let objects = root.path().join("objects");
+        let (a, b, c) = (sha(b"one"), sha(b"two"), sha(b"three"));
+        let bin = env!("CARGO_BIN_EXE_cityfeed-puller");
+        let run = |extra: &[&str]| {
+            Command::new(bin)
+                .arg("--origin")
+                .arg(format!("file://{}/", usb.path().display()))
+                .arg("--root")
+                .arg(root.path())
+                .args(extra)
+                .output()
+                .unwrap()
+        };
+
+        // v1: flat, as every root was before sharding.
+        write_file_origin(usb.path(), "v-s1", &[("a.txt", &a, b"one")]);
+        assert_eq!(run(&[]).status.code(), Some(0));
+        assert!(objects.join(&a).is_file());
+
+        // v2: the origin switches to sharded URLs and only has the new object
+        // there, so a.txt's object has to come from the local migration.
+        fs::remove_dir_all(usb.path().join("objects")).unwrap();
+        let shard = usb.path().join("objects").join(&b[..2]);
+        fs::create_dir_all(&shard).unwrap();
+        fs::write(shard.join(&b), b"two").unwrap();
+        fs::write(
+            usb.path().join("manifests/latest.json"),
+            format!(
+                r#"{{"version": "v-s2", "object_layout": "sharded", "files": [
+                    {{ "path": "a.txt", "hash": "{a}", "size": 3 }},
+                    {{ "path": "b.txt", "hash": "{b}", "size": 3 }}
+                ]}}"#
+            ),
+        )
+        .unwrap();
+        let out = run(&[]);
+        assert_eq!(out.status.code(), Some(0));
+        assert!(String::from_utf8_lossy(&out.stderr)
+            .contains("migrated 1 objects from flat to sharded layout"));
+        assert!(!objects.join(&a).exists());
+        assert_eq!(fs::read(objects.join(&a[..2]).join(&a)).unwrap(), b"one");
+        assert_eq!(fs::read(objects.join(&b[..2]).join(&b)).unwrap(), b"two");
+        assert_eq!(fs::read(root.path().join("current/b.txt")).unwrap(), b"two");
+
+        // --object-layout overrides the manifest and migrates back.
+        write_file_origin(usb.path(), "v-s3", &[("c.txt", &c, b"three")]);
+        let out = run(&["--object-layout", "flat"]);
+        assert_eq!(out.status.code(), Some(0));
+        assert!(String::from_utf8_lossy(&out.stderr)
+            .contains("migrated 2 objects from sharded to flat layout"));
+        let mut names = dir_entries(&objects);
+        names.sort();
+        let mut expected = [".layout".to_string(), a, b, c];
+        expected.sort();
+        assert_eq!(names, expected);
+    }
+
+    #[test]
+    fn manifest_mode_is_applied_to_snapshot_files() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let usb = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        write_file_origin(
+            usb.path(),
+            "v-mode",
+            &[
+                ("page.html", &sha(b"<p>"), b"<p>"),
+                ("cgi/run.sh", &sha(b"#!/bin/sh"), b"#!/bin/sh"),
+                ("cgi/int.sh", &sha(b"#!/bin/sh\n"), b"#!/bin/sh\n"),
+                ("cgi/suid.sh", &sha(b"#!/bin/sh\n\n"), b"#!/bin/sh\n\n"),
+            ],
+        );
+        let manifest = format!(
+            r#"{{"version": "v-mode", "files": [
+                {{ "path": "page.html", "hash": "{}", "size": 3 }},
+                {{ "path": "cgi/run.sh", "hash": "{}", "size": 9, "mode": "0755" }},
+                {{ "path": "cgi/int.sh", "hash": "{}", "size": 10, "mode": 488 }},
+                {{ "path": "cgi/suid.sh", "hash": "{}", "size": 11, "mode": "4777" }}
+            ]}}"#,
+            sha(b"<p>"),
+            sha(b"#!/bin/sh"),
+            sha(b"#!/bin/sh\n"),
+            sha(b"#!/bin/sh\n\n")
+        );
+        fs::write(usb.path().join("manifests/latest.json"), manifest).unwrap();
+
+        let status = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+            .arg("--origin")
+            .arg(format!("file://{}/", usb.path().display()))
+            .arg("--root")
+            .arg(root.path())
+            .status()
+            .unwrap();
+        assert_eq!(status.code(), Some(0));
+
+        let mode = |rel: &str| {
+            fs::metadata(root.path().join("current").join(rel))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o7777
+        };
+        assert_eq!(mode("page.html"), 0o644);
+        assert_eq!(mode("cgi/run.sh"), 0o755);
+        assert_eq!(mode("cgi/int.sh"), 0o750);
+        // setuid and world-write are masked off.
+        assert_eq!(mode("cgi/suid.sh"), 0o755);
+    }
+
+    #[test]
+    fn manifest_symlinks_are_created_and_escapes_rejected() {
+        let usb = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        write_file_origin(
+            usb.path(),
+            "v-link",
+            &[("docs/new.html", &sha(b"new"), b"new")],
+        );
+        let publish = |version: &str, links: &str| {
+            let manifest = format!(
+                r#"{{"version": "{version}", "files": [
+                    {{ "path": "docs/new.html", "hash": "{}", "size": 3 }},
+                    {links}
+                ]}}"#,
+                sha(b"new")
+            );
+            fs::write(usb.path().join("manifests/latest.json"), manifest).unwrap();
+        };
+        let run = || {
+            Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .arg("--origin")
+                .arg(format!("file://{}/", usb.path().display()))
+                .arg("--root")
+                .arg(root.path())
+                .output()
+                .unwrap()
+        };
+
+        publish(
+            "v-link",
+            r#"{ "path": "old.html", "symlink": "docs/new.html" },
+               { "path": "docs/legacy/old.html", "symlink": "../new.html" },
+               { "path": "soon.html", "symlink": "docs/not-yet.html" }"#,
+        );
+        let out = run();
+        assert_eq!(
+            out.status.code(),
+            Some(0),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        let current = root.path().join("current");
+        assert_eq!(
+            fs::read_link(current.join("old.html")).unwrap(),
+            std::path::Path::new("docs/new.html")
+        );
+        assert_eq!(fs::read(current.join("old.html")).unwrap(), b"new");
+        assert_eq!(
+            fs::read(current.join("docs/legacy/old.html")).unwrap(),
+            b"new"
+        );
+        assert!(fs::symlink_metadata(current.join("soon.html"))
+            .unwrap()
+            .is_symlink());
+        assert!(!current.join("soon.html").exists());
+
+        publish(
+            "v-escape",
+            r#"{ "path": "docs/passwd", "symlink": "../../etc/passwd" }"#,
+        );
+        let out = run();
+        assert_eq!(out.status.code(), Some(4));
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(
+            stderr.contains("invalid symlink target for docs/passwd"),
+            "{stderr}"
+        );
+        assert!(!root.path().join("snapshots/v-escape").exists());
+    }
+
+    #[test]
+    fn deploy_reports_paths_changed_since_previous_version() {
+        let usb = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let run = || {
+            Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .arg("--origin")
+                .arg(format!("file://{}/", usb.path().display()))
+                .arg("--root")
+                .arg(root.path())
+                .args(["--show-diff", "--output", "json"])
+                .output()
+                .unwrap()
+        };
+
+        write_file_origin(
+            usb.path(),
+            "v-d1",
+            &[
+                ("keep.html", &sha(b"keep"), b"keep"),
+                ("gone.html", &sha(b"gone"), b"gone"),
+                ("edit.html", &sha(b"edit1"), b"edit1"),
+            ],
+        );
+        let out = run();
+        assert_eq!(out.status.code(), Some(0));
+        assert!(String::from_utf8_lossy(&out.stderr)
+            .contains("no previous deploy on this root; skipping change summary"));
+        let doc: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+        assert!(doc["changes"].is_null());
+
+        write_file_origin(
+            usb.path(),
+            "v-d2",
+            &[
+                ("keep.html", &sha(b"keep"), b"keep"),
+                ("edit.html", &sha(b"edit2"), b"edit2"),
+                ("new.html", &sha(b"new"), b"new"),
+            ],
+        );
+        let out = run();
+        assert_eq!(out.status.code(), Some(0));
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(
+            stderr.contains("changes v-d1 -> v-d2: 1 added, 1 removed, 1 modified"),
+            "{stderr}"
+        );
+        for line in ["  + new.html", "  - gone.html", "  ~ edit.html"] {
+            assert!(stderr.contains(line), "{line:?} missing from {stderr}");
+        }
+        assert!(!stderr.contains("keep.html"));
+        let doc: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+        assert_eq!(
+            doc["changes"],
+            serde_json::json!({ "added": 1, "removed": 1, "modified": 1 })
+        );
+
+        // Without a record for what `current` holds, say so instead of guessing.
+        fs::remove_file(root.path().join("manifests/v-d2.json")).unwrap();
+        write_file_origin(usb.path(), "v-d3", &[("keep.html", &sha(b"keep"), b"keep")]);
+        let out = run();
+        assert_eq!(out.status.code(), Some(0));
+        assert!(String::from_utf8_lossy(&out.stderr)
+            .contains("no manifest recorded for previous version v-d2; skipping change summary"));
+    }
+
+    /// Serves one file, but only to requests carrying `authorization`; anything
+    /// else gets a 401 that echoes the header it was sent, like some proxies do.
+    fn private_origin(authorization: &str) -> (std::net::SocketAddr, thread::JoinHandle<()>) {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        let authorization = authorization.to_string();
+        let manifest = format!(
+            r#"{{"version": "v-private", "files": [{{ "path": "index.html", "hash": "{}", "size": 6 }}]}}"#,
+            sha(b"secret")
+        );
+        let object_path = format!("/objects/{}", sha(b"secret"));
+        let handle = thread::spawn(move || {
+            for req in server.incoming_requests() {
+                if req.url() == "/__quit" {
+                    let _ = req.respond(Response::empty(200));
+                    break;
+                }
+                let sent = req
+                    .headers()
+                    .iter()
+                    .find(|header| header.field.equiv("Authorization"))
+                    .map(|header| header.value.to_string());
+                if sent.as_deref() != Some(authorization.as_str()) {
+                    let body = format!("rejected Authorization: {}", sent.unwrap_or_default());
+                    let _ = req.respond(Response::from_string(body).with_status_code(401));
+                    continue;
+                }
+                let _ = match req.url() {
+                    "/manifests/latest.json" => {
+                        req.respond(Response::from_string(manifest.clone()))
+                    }
+                    url if url == object_path => req.respond(Response::from_string("secret")),
+                    _ => req.respond(Response::empty(StatusCode(404))),
+                };
+            }
+        });
+        (addr, handle)
+    }
+
+    #[test]
+    fn auth_token_is_sent_and_redacted_from_failures() {
+        let (addr, handle) = private_origin("Bearer s3cret-token");
+        let bin = env!("CARGO_BIN_EXE_cityfeed-puller");
+        let run = |token: &str, root: &std::path::Path| {
+            Command::new(bin)
+                .args(["--origin", &format!("http://{addr}")])
+                .args(["--auth-token", token])
+                .arg("--root")
+                .arg(root)
+                .output()
+                .unwrap()
+        };
+
+        let root = tempfile::tempdir().unwrap();
+        let out = run("wrong-s3cret", root.path());
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(out.status.code(), Some(4), "{stderr}");
+        assert!(stderr.contains("HTTP 401 Unauthorized"), "{stderr}");
+        assert!(
+            stderr.contains("rejected Authorization: Bearer <redacted>"),
+            "{stderr}"
+        );
+        assert!(!stderr.contains("wrong-s3cret"), "{stderr}");
+
+        let out = run("s3cret-token", root.path());
+        assert_eq!(out.status.code(), Some(0));
+        assert_eq!(
+            fs::read(root.path().join("current/index.html")).unwrap(),
+            b"secret"
+        );
+
+        let out = Command::new(bin)
+            .args(["--origin", &format!("http://kiosk:pw@{addr}")])
+            .arg("--root")
+            .arg(root.path())
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(
+            stderr.contains("--origin must not contain credentials"),
+            "{stderr}"
+        );
+        assert!(!stderr.contains("kiosk:pw"), "{stderr}");
+
+        send_quit(addr);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn config_auth_applies_per_origin() {
+        // base64("kiosk:pw")
+        let (private, private_handle) = private_origin("Basic a2lvc2s6cHc=");
+        let public = closed_origin();
+        let root = tempfile::tempdir().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("puller.toml");
+        fs::write(
+            &config,
+            format!(
+                r#"
+origins = ["{public}", "http://{private}"]
+root = "{}"
+auth_token = "for-everyone-else"
+
+[auth."http://{private}/"]
+basic = "kiosk:pw"
+"#,
+                root.path().display()
+            ),
+        )
+        .unwrap();
+
+        let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+            .arg("--config")
+            .arg(&config)
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(out.status.code(), Some(0), "{stderr}");
+        assert!(!stderr.contains("kiosk:pw") && !stderr.contains("for-everyone-else"));
+        assert_eq!(
+            fs::read(root.path().join("current/index.html")).unwrap(),
+            b"secret"
+        );
+
+        send_quit(private);
+        private_handle.join().unwrap();
+    }
+
+    /// Each request's headers, names lowercased.
+    type SeenHeaders = Arc<Mutex<Vec<HashMap<String, String>>>>;
+
+    /// Serves one file and records the headers of every request.
+    fn header_recording_origin() -> (std::net::SocketAddr, thread::JoinHandle<()>, SeenHeaders) {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&requests);
+        let manifest = format!(
+            r#"{{"version": "v-headers", "files": [{{ "path": "index.html", "hash": "{}", "size": 4 }}]}}"#,
+            sha(b"page")
+        );
+        let object_path = format!("/objects/{}", sha(b"page"));
+        let handle = thread::spawn(move || {
+            for req in server.incoming_requests() {
+                if req.url() == "/__quit" {
+                    let _ = req.respond(Response::empty(200));
+                    break;
+                }
+                let headers = req
+                    .headers()
+                    .iter()
+                    .map(|h| {
+                        (
+                            h.field.to_string().to_ascii_lowercase(),
+                            h.value.to_string(),
+                        )
+                    })
+                    .collect();
+                seen.lock().unwrap().push(headers);
+                let _ = match req.url() {
+                    "/manifests/latest.json" => {
+                        req.respond(Response::from_string(manifest.clone()))
+                    }
+                    url if url == object_path => req.respond(Response::from_string("page")),
+                    _ => req.respond(Response::empty(StatusCode(404))),
+                };
+            }
+        });
+        (addr, handle, requests)
+    }
+
+    #[test]
+    fn custom_headers_reach_origins_and_secrets_stay_out_of_logs() {
+        let (a, a_handle, a_seen) = header_recording_origin();
+        let (b, b_handle, b_seen) = header_recording_origin();
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("puller.toml");
+        fs::write(
+            &config,
+            format!(
+                r#"
+origins = ["http://{a}"]
+headers = ["X-Org-Token: org-s3cret", "Accept: application/json"]
+
+[origin_headers."http://{b}/"]
+X-Org-Token = "b-s3cret"
+"#
+            ),
+        )
+        .unwrap();
+        let run = |origin: std::net::SocketAddr| {
+            let root = tempfile::tempdir().unwrap();
+            let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .arg("--config")
+                .arg(&config)
+                .args(["--origin", &format!("http://{origin}"), "-v"])
+                .arg("--root")
+                .arg(root.path())
+                .output()
+                .unwrap();
+            let stderr = String::from_utf8_lossy(&out.stderr).into_owned();
+            assert_eq!(out.status.code(), Some(0), "{stderr}");
+            stderr
+        };
+
+        let stderr = run(a);
+        assert!(
+            stderr.contains("with x-org-token: <redacted>, accept: application/json"),
+            "{stderr}"
+        );
+        assert!(!stderr.contains("s3cret"), "{stderr}");
+        let seen = a_seen.lock().unwrap();
+        assert_eq!(seen.len(), 2);
+        for headers in seen.iter() {
+            assert_eq!(headers["x-org-token"], "org-s3cret");
+            assert_eq!(headers["accept"], "application/json");
+        }
+        drop(seen);
+
+        // B's own token replaces the default; the other header still goes.
+        let stderr = run(b);
+        assert!(!stderr.contains("s3cret"), "{stderr}");
+        for headers in b_seen.lock().unwrap().iter() {
+            assert_eq!(headers["x-org-token"], "b-s3cret");
+            assert_eq!(headers["accept"], "application/json");
+        }
+
+        let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+            .args([
+                "--origin",
+                &format!("http://{a}"),
+                "--header",
+                "Bad Name: x",
+            ])
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(out.status.code(), Some(2), "{stderr}");
+        assert!(
+            stderr.contains(r#"invalid header name "Bad Name""#),
+            "{stderr}"
+        );
+
+        for (addr, handle) in [(a, a_handle), (b, b_handle)] {
+            send_quit(addr);
+            handle.join().unwrap();
+        }
+    }
+
+    /// Serves one file to requests whose query string passes `accept` and
+    /// answers 403 otherwise, echoing the query back like some CDNs do. Every
+    /// query string it sees is recorded.
+    fn signed_origin(
+        accept: fn(&str) -> bool,
+    ) -> (
+        std::net::SocketAddr,
+        thread::JoinHandle<()>,
+        Arc<Mutex<Vec<String>>>,
+    ) {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        let queries = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&queries);
+        let manifest = format!(
+            r#"{{"version": "v-signed", "files": [{{ "path": "index.html", "hash": "{}", "size": 6 }}]}}"#,
+            sha(b"signed")
+        );
+        let object_path = format!("/objects/{}", sha(b"signed"));
+        let handle = thread::spawn(move || {
+            for req in server.incoming_requests() {
+                if req.url() == "/__quit" {
+                    let _ = req.respond(Response::empty(200));
+                    break;
+                }
+                let (path, query) = req.url().split_once('?').unwrap_or((req.url(), ""));
+                let (path, query) = (path.to_string(), query.to_string());
+                seen.lock().unwrap().push(query.clone());
+                if !accept(&query) {
+                    let body = format!("token expired: {query}");
+                    let _ = req.respond(Response::from_string(body).with_status_code(403));
+                    continue;
+                }
+                let _ = match path.as_str() {
+                    "/manifests/latest.json" => {
+                        req.respond(Response::from_string(manifest.clone()))
+                    }
+                    url if url == object_path => req.respond(Response::from_string("signed")),
+                    _ => req.respond(Response::empty(StatusCode(404))),
+                };
+            }
+        });
+        (addr, handle, queries)
+    }
+
+    #[test]
+    fn query_auth_reaches_origin_and_expired_tokens_fail_over() {
+        let (expired, expired_handle, _) = signed_origin(|_| false);
+        let (fresh, fresh_handle, queries) =
+            signed_origin(|query| query == "token=s3+cret%2F%2B&expires=1767225600");
+        let root = tempfile::tempdir().unwrap();
+        let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+            .args(["--origin", &format!("http://{expired}")])
+            .args(["--origin", &format!("http://{fresh}")])
+            .args(["--query-auth", "token=s3 cret/+"])
+            .args(["--query-auth", "expires=1767225600"])
+            .arg("--root")
+            .arg(root.path())
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(out.status.code(), Some(0), "{stderr}");
+        assert_eq!(
+            fs::read(root.path().join("current/index.html")).unwrap(),
+            b"signed"
+        );
+        assert_eq!(queries.lock().unwrap().len(), 2);
+        assert!(stderr.contains("HTTP 403 Forbidden"), "{stderr}");
+        assert!(
+            stderr.contains("token expired: token=<redacted>&expires=<redacted>"),
+            "{stderr}"
+        );
+        assert!(
+            !stderr.contains("s3") && !stderr.contains("1767225600"),
+            "{stderr}"
+        );
+
+        send_quit(expired);
+        send_quit(fresh);
+        expired_handle.join().unwrap();
+        fresh_handle.join().unwrap();
+    }
+
+    #[test]
+    fn query_auth_command_runs_for_every_request() {
+        let (addr, handle, queries) = signed_origin(|query| query.starts_with("token=t"));
+        let root = tempfile::tempdir().unwrap();
+        let counter = root.path().join("counter");
+        let cmd = format!(
+            "n=$(($(cat {0} 2>/dev/null || echo 0) + 1)); echo $n > {0}; echo \"token=t$n&expires=9\"",
+            counter.display()
+        );
+        let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+            .args(["--origin", &format!("http://{addr}")])
+            .args(["--query-auth-command", &cmd])
+            .arg("--root")
+            .arg(root.path())
+            .output()
+            .unwrap();
+        assert_eq!(
+            out.status.code(),
+            Some(0),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        assert_eq!(
+            *queries.lock().unwrap(),
+            ["token=t1&expires=9", "token=t2&expires=9"]
+        );
+
+        let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+            .args(["--origin", &format!("http://{addr}")])
+            .args(["--query-auth-command", "exit 1"])
+            .arg("--root")
+            .arg(root.path())
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(out.status.code(), Some(4), "{stderr}");
+        assert!(
+            stderr.contains("query-auth command `exit 1` exited with"),
+            "{stderr}"
+        );
+
+        send_quit(addr);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn config_sites_deploy_in_one_process() {
+        let (a_addr, a_handle) = single_file_origin("v-site-a", &sha(b"site a"), b"site a");
+        let (b_addr, b_handle) = single_file_origin("v-site-b", &sha(b"site b"), b"site b");
+        let (root_a, root_b) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("puller.toml");
+        let write_config = |b_origin: &str| {
+            fs::write(
+                &config,
+                format!(
+                    r#"
+origins = ["http://{a_addr}"]
+
+[[site]]
+name = "a"
+root = "{}"
+
+[[site]]
+name = "b"
+root = "{}"
+origins = ["{b_origin}"]
+"#,
+                    root_a.path().display(),
+                    root_b.path().display()
+                ),
+            )
+            .unwrap();
+        };
+        let run = || {
+            Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .arg("--config")
+                .arg(&config)
+                .args(["--site-jobs", "2", "--output", "json"])
+                .output()
+                .unwrap()
+        };
+
+        write_config(&format!("http://{b_addr}"));
+        let out = run();
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(out.status.code(), Some(0), "{stderr}");
+        assert_eq!(
+            fs::read(root_a.path().join("current/index.html")).unwrap(),
+            b"site a"
+        );
+        assert_eq!(
+            fs::read(root_b.path().join("current/index.html")).unwrap(),
+            b"site b"
+        );
+        assert!(
+            stderr.contains("[a] site a finished with exit code 0"),
+            "{stderr}"
+        );
+        assert!(
+            stderr.contains("[b] site b finished with exit code 0"),
+            "{stderr}"
+        );
+        let mut sites: Vec<String> = String::from_utf8_lossy(&out.stdout)
+            .lines()
+            .map(|line| {
+                let doc: serde_json::Value = serde_json::from_str(line).unwrap();
+                format!(
+                    "{} {}",
+                    doc["site"].as_str().unwrap(),
+                    doc["version"].as_str().unwrap()
+                )
+            })
+            .collect();
+        sites.sort();
+        assert_eq!(sites, ["a v-site-a", "b v-site-b"]);
+
+        // One failing site fails the run with its code; the other still runs.
+        write_config(&closed_origin());
+        let out = run();
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(out.status.code(), Some(4), "{stderr}");
+        assert!(
+            stderr.contains("[a] site a finished with exit code 3"),
+            "{stderr}"
+        );
+        assert!(
+            stderr.contains("[b] site b finished with exit code 4"),
+            "{stderr}"
+        );
+
+        for (addr, handle) in [(a_addr, a_handle), (b_addr, b_handle)] {
+            send_quit(addr);
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn file_signatures_are_checked_against_the_pubkey() {
+        use ring::signature::{Ed25519KeyPair, KeyPair};
+        use sha2::{Digest, Sha256};
+
+        let hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+        // Fixture key: seed 0x01 repeated.
+        let key = Ed25519KeyPair::from_seed_unchecked(&[1; 32]).unwrap();
+        let pubkey = hex(key.public_key().as_ref());
+        let sign = |path: &str, hash: &str, size: usize| {
+            let mut message: Vec<u8> = (0..64)
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hash[i..i + 2], 16).unwrap())
+                .collect();
+            message.extend_from_slice(&(size as u64).to_be_bytes());
+            message.extend_from_slice(path.as_bytes());
+            hex(key.sign(&message).as_ref())
+        };
+        let usb = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let (a, b) = (b"route 2 on time".as_slice(), b"route 3 delayed".as_slice());
+        let (hash_a, hash_b) = (hex(&Sha256::digest(a)), hex(&Sha256::digest(b)));
+        write_file_origin(
+            usb.path(),
+            "v-unsigned",
+            &[("a.html", &hash_a, a), ("b.html", &hash_b, b)],
+        );
+        // Published path, hash and size, and the path and size it was signed as.
+        type Entry<'a> = (&'a str, &'a str, usize, Option<(&'a str, usize)>);
+        let publish = |version: &str, entries: &[Entry]| {
+            let entries: Vec<String> = entries
+                .iter()
+                .map(|(path, hash, size, signed)| {
+                    let signature = signed
+                        .map(|(as_path, as_size)| {
+                            format!(r#", "signature": "{}""#, sign(as_path, hash, as_size))
+                        })
+                        .unwrap_or_default();
+                    format!(
+                        r#"{{ "path": "{path}", "hash": "{hash}", "size": {size}{signature} }}"#
+                    )
+                })
+                .collect();
+            fs::write(
+                usb.path().join("manifests/latest.json"),
+                format!(
+                    r#"{{"version": "{version}", "files": [{}]}}"#,
+                    entries.join(",")
+                ),
+            )
+            .unwrap();
+        };
+        let run = |extra: &[&str]| {
+            let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .arg("--origin")
+                .arg(format!("file://{}/", usb.path().display()))
+                .arg("--root")
+                .arg(root.path())
+                .args(["--manifest-pubkey", &pubkey])
+                .args(extra)
+                .output()
+                .unwrap();
+            (
+                out.status.code(),
+                String::from_utf8_lossy(&out.stderr).into_owned(),
+            )
+        };
+        let current = || fs::read_link(root.path().join("current")).unwrap();
+
+        let (la, lb) = (a.len(), b.len());
+        publish(
+            "v-signed",
+            &[
+                ("a.html", &hash_a, la, Some(("a.html", la))),
+                ("b.html", &hash_b, lb, Some(("b.html", lb))),
+            ],
+        );
+        let (code, stderr) = run(&["--require-file-signatures"]);
+        assert_eq!(code, Some(0), "{stderr}");
+        assert!(current().ends_with("v-signed"));
+
+        // A signed entry moved to another path, or given another size.
+        publish(
+            "v-moved",
+            &[("admin.html", &hash_b, lb, Some(("b.html", lb)))],
+        );
+        let (code, stderr) = run(&[]);
+        assert_eq!(code, Some(4), "{stderr}");
+        assert!(stderr.contains("admin.html: bad signature"), "{stderr}");
+        publish(
+            "v-grown",
+            &[("b.html", &hash_b, lb + 1, Some(("b.html", lb)))],
+        );
+        let (code, stderr) = run(&[]);
+        assert_eq!(code, Some(4), "{stderr}");
+        assert!(current().ends_with("v-signed"));
+
+        // Unsigned files pass unless every file has to be signed.
+        publish(
+            "v-mixed",
+            &[
+                ("a.html", &hash_a, la, Some(("a.html", la))),
+                ("b.html", &hash_b, lb, None),
+            ],
+        );
+        let (code, stderr) = run(&["--require-file-signatures"]);
+        assert_eq!(code, Some(4), "{stderr}");
+        assert!(stderr.contains("(first: b.html)"), "{stderr}");
+        let (code, stderr) = run(&[]);
+        assert_eq!(code, Some(0), "{stderr}");
+        assert!(current().ends_with("v-mixed"));
+
+        // fsck checks the records again.
+        let fsck = || {
+            let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .arg("fsck")
+                .arg("--root")
+                .arg(root.path())
+                .args(["--manifest-pubkey", &pubkey, "--output", "json"])
+                .output()
+                .unwrap();
+            let doc: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+            (out.status.code(), doc)
+        };
+        let (code, doc) = fsck();
+        assert_eq!(code, Some(0), "{doc}");
+        assert_eq!(doc["manifests_checked"], 2);
+        let record = root.path().join("manifests/v-signed.json");
+        let text = fs::read_to_string(&record).unwrap();
+        fs::write(&record, text.replace("\"b.html\"", "\"c.html\"")).unwrap();
+        let (code, doc) = fsck();
+        assert_eq!(code, Some(5), "{doc}");
+        assert_eq!(doc["bad_signatures"], 1);
+        assert_eq!(doc["problems"][0]["kind"], "bad-signature");
+        assert_eq!(doc["problems"][0]["path"], record.to_str().unwrap());
+    }
+
+    #[test]
+    fn signed_file_swapped_by_a_proxy_is_refused() {
+        use ring::signature::{Ed25519KeyPair, KeyPair};
+
+        let hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+        // Fixture key: seed 0x01 repeated.
+        let key = Ed25519KeyPair::from_seed_unchecked(&[1; 32]).unwrap();
+        let pubkey = hex(key.public_key().as_ref());
+        let body = b"route 2 on time";
+        let hash = sha(body);
+        let mut message: Vec<u8> = (0..64)
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hash[i..i + 2], 16).unwrap())
+            .collect();
+        message.extend_from_slice(&(body.len() as u64).to_be_bytes());
+        message.extend_from_slice(b"index.html");
+        let manifest = format!(
+            r#"{{"version": "v-proxied", "files": [{{ "path": "index.html", "hash": "{hash}", "size": {}, "signature": "{}" }}]}}"#,
+            body.len(),
+            hex(key.sign(&message).as_ref())
+        );
+        // The proxy passes the signed manifest through untouched and swaps
+        // the object for bytes of the same size.
+        let origin = |object: &[u8]| {
+            let mut objects = HashMap::new();
+            objects.insert(hash.clone(), object.to_vec());
+            start_origin(
+                "v-proxied",
+                manifest.clone().into_bytes(),
+                objects,
+                Arc::new(AtomicUsize::new(0)),
+                Arc::new(AtomicUsize::new(0)),
+            )
+        };
+        let (proxy, proxy_handle) = origin(b"route 2 dropped");
+        let (honest, honest_handle) = origin(body);
+        let root = tempfile::tempdir().unwrap();
+        let run = |origins: &[std::net::SocketAddr]| {
+            let mut cmd = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"));
+            for addr in origins {
+                cmd.args(["--origin", &format!("http://{addr}")]);
+            }
+            let out = cmd
+                .arg("--root")
+                .arg(root.path())
+                .args(["--manifest-pubkey", &pubkey, "--require-file-signatures"])
+                .output()
+                .unwrap();
+            (
+                out.status.code(),
+                String::from_utf8_lossy(&out.stderr).into_owned(),
+            )
+        };
+
+        let (code, stderr) = run(&[proxy]);
+        assert_eq!(code, Some(5), "{stderr}");
+        assert!(
+            stderr.contains(&format!("object {hash} hash mismatch")),
+            "{stderr}"
+        );
+        assert!(!root.path().join("objects").join(&hash).exists());
+        assert!(!root.path().join("current").exists());
+
+        // Past the proxy, the honest origin's bytes are the ones deployed.
+        let (code, stderr) = run(&[proxy, honest]);
+        assert_eq!(code, Some(0), "{stderr}");
+        assert_eq!(
+            fs::read(root.path().join("current/index.html")).unwrap(),
+            body
+        );
+
+        for (addr, handle) in [(proxy, proxy_handle), (honest, honest_handle)] {
+            send_quit(addr);
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn import_checks_file_signatures_like_a_deploy() {
+        use ring::signature::{Ed25519KeyPair, KeyPair};
+
+        let hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+        // Fixture key: seed 0x01 repeated.
+        let key = Ed25519KeyPair::from_seed_unchecked(&[1; 32]).unwrap();
+        let pubkey = hex(key.public_key().as_ref());
+        let body = b"route 2 on time";
+        let hash = sha(body);
+        let mut message: Vec<u8> = (0..64)
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hash[i..i + 2], 16).unwrap())
+            .collect();
+        message.extend_from_slice(&(body.len() as u64).to_be_bytes());
+        message.extend_from_slice(b"index.html");
+        let usb = tempfile::tempdir().unwrap();
+        let source = tempfile::tempdir().unwrap();
+        let work = tempfile::tempdir().unwrap();
+        write_file_origin(usb.path(), "v-signed", &[("index.html", &hash, body)]);
+        fs::write(
+            usb.path().join("manifests/latest.json"),
+            format!(
+                r#"{{"version": "v-signed", "files": [{{ "path": "index.html", "hash": "{hash}", "size": {}, "signature": "{}" }}]}}"#,
+                body.len(),
+                hex(key.sign(&message).as_ref())
+            ),
+        )
+        .unwrap();
+        let puller = |args: &[&std::ffi::OsStr]| {
+            let out = Command::new(env!("CARGO_BIN_EXE_cityfeed-puller"))
+                .args(args)
+                .args(["--manifest-pubkey", &pubkey, "--require-file-signatures"])
+                .output()
+                .unwrap();
+            (
+                out.status.code(),
+                String::from_utf8_lossy(&out.stderr).into_owned(),
+            )
+        };
+        let origin = format!("file://{}/", usb.path().display());
+        let (code, stderr) = puller(&[
+            "--origin".as_ref(),
+            origin.as_ref(),
+            "--root".as_ref(),
+            source.path().as_os_str(),
+        ]);
+        assert_eq!(code, Some(0), "{stderr}");
+        let archive = work.path().join("site.tar.zst");
+        let (code, stderr) = puller(&[
+            "export".as_ref(),
+            "--root".as_ref(),
+            source.path().as_os_str(),
+            "--out".as_ref(),
+            archive.as_os_str(),
+        ]);
+        assert_eq!(code, Some(0), "{stderr}");
+
+        // Rewrites the archive's manifest, and renames snapshot entries to match.
+        let tamper = |name: &str, edit: &dyn Fn(serde_json::Value) -> serde_json::Value| {
+            let tampered = work.path().join(name);
+            let bytes = fs::read(&archive).unwrap();
+            let mut src = tar::Archive::new(zstd::Decoder::new(bytes.as_slice()).unwrap());
+            let out = zstd::Encoder::new(fs::File::create(&tampered).unwrap(), 3).unwrap();
+            let mut dst = tar::Builder::new(out);
+            for entry in src.entries().unwrap() {
+                let mut entry = entry.unwrap();
+                let mut header = entry.header().clone();
+                let path = entry.path().unwrap().to_string_lossy().into_owned();
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data).unwrap();
+                if path == "manifest.json" {
+                    let manifest = serde_json::from_slice(&data).unwrap();
+                    data = serde_json::to_vec(&edit(manifest)).unwrap();
+                    header.set_size(data.len() as u64);
+                    header.set_cksum();
+                }
+                let path = path.replace("snapshot/index.html", "snapshot/admin.html");
+                dst.append_data(&mut header, &path, data.as_slice())
+                    .unwrap();
+            }
+            dst.into_inner().unwrap().finish().unwrap();
+            tampered
+        };
+        let import = |archive: &std::path::Path| {
+            let root = tempfile::tempdir().unwrap();
+            let (code, stderr) = puller(&[
+                "import".as_ref(),
+                archive.as_os_str(),
+                "--root".as_ref(),
+                root.path().as_os_str(),
+            ]);
+            assert!(dir_entries(&root.path().join("objects")).is_empty());
+            (code, stderr)
+        };
+
+        // The signed file moved to another path, with the archive made to fit.
+        let moved = tamper("moved.tar.zst", &|mut manifest| {
+            manifest["files"][0]["path"] = "admin.html".into();
+            manifest
+        });
+        let (code, stderr) = import(&moved);
+        assert_eq!(code, Some(4), "{stderr}");
+        assert!(stderr.contains("admin.html: bad signature"), "{stderr}");
+
+        // Signatures stripped, against --require-file-signatures.
+        let unsigned = tamper("unsigned.tar.zst", &|mut manifest| {
+            manifest["files"][0]
+                .as_object_mut()
+                .unwrap()
+                .remove("signature");
+            manifest["files"][0]["path"] = "admin.html".into();
+            manifest
+        });
+        let (code, stderr) = import(&unsigned);
+        assert_eq!(code, Some(4), "{stderr}");
+        assert!(stderr.contains("(first: admin.html)"), "{stderr}");
     }
 }